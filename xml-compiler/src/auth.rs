@@ -0,0 +1,115 @@
+use std::{env, fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::config;
+
+/// Environment variable the thin client reads its token from.
+pub const TOKEN_ENV: &str = "KIWIXML_TOKEN";
+
+/// What a token may do. `Compile` implies `Read`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Read,
+    Compile,
+}
+
+impl Scope {
+    fn from_name(name: &str) -> Option<Scope> {
+        match name {
+            "read" => Some(Scope::Read),
+            "compile" => Some(Scope::Compile),
+            _ => None,
+        }
+    }
+}
+
+/// Tokens accepted by the daemon and preview server, loaded from a file of `<scope> <token>`
+/// lines or the `[tokens]` table of `kvy-xmls.toml`. A token of `*` grants its scope to every
+/// caller, so `read *` makes reads public. `env:NAME` and `file:PATH` read the token from
+/// elsewhere, so the list itself can be committed.
+pub struct Tokens {
+    entries: Vec<(Scope, String)>,
+}
+
+impl Tokens {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read token file {}", path.display()))?;
+
+        let mut entries = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (scope, token) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                anyhow!("{}:{}: expected '<scope> <token>'", path.display(), number + 1)
+            })?;
+            let scope = Scope::from_name(scope).ok_or_else(|| {
+                anyhow!(
+                    "{}:{}: unknown scope '{}' (expected read or compile)",
+                    path.display(),
+                    number + 1,
+                    scope
+                )
+            })?;
+            let token = resolve_secret(token.trim(), path)
+                .with_context(|| format!("{}:{}", path.display(), number + 1))?;
+            entries.push((scope, token));
+        }
+        Ok(Tokens { entries })
+    }
+
+    /// The tokens in the `[tokens]` table of the `kvy-xmls.toml` in `base_dir`, a list for each
+    /// scope such as `compile = ["env:KIWIXML_PIPELINE_TOKEN"]`, or `None` if it has none.
+    pub fn from_config(base_dir: &Path) -> Result<Option<Self>> {
+        let Some((path, scopes)) = config::string_lists(base_dir, "tokens", "tokens")? else {
+            return Ok(None);
+        };
+        let mut entries = Vec::new();
+        for (scope, tokens) in scopes {
+            let scope = Scope::from_name(&scope).ok_or_else(|| {
+                anyhow!(
+                    "{}: unknown scope tokens.{} (expected read or compile)",
+                    path.display(),
+                    scope
+                )
+            })?;
+            for token in tokens {
+                let token = resolve_secret(token.trim(), &path)
+                    .with_context(|| format!("{}: tokens", path.display()))?;
+                entries.push((scope, token));
+            }
+        }
+        Ok(Some(Tokens { entries }))
+    }
+
+    /// Whether `token` (if the caller sent one) grants at least `needed`.
+    pub fn allows(&self, token: Option<&str>, needed: Scope) -> bool {
+        self.entries.iter().any(|(scope, expected)| {
+            *scope >= needed
+                && (expected == "*" || token.is_some_and(|token| constant_time_eq(token, expected)))
+        })
+    }
+}
+
+/// Resolves an `env:` or `file:` reference (relative to the file listing it); anything else is
+/// literal.
+fn resolve_secret(value: &str, list: &Path) -> Result<String> {
+    if let Some(name) = value.strip_prefix("env:") {
+        env::var(name).map_err(|_| anyhow!("environment variable {} is not set", name))
+    } else if let Some(file) = value.strip_prefix("file:") {
+        let file = list.parent().unwrap_or(Path::new("")).join(file);
+        let secret = fs::read_to_string(&file)
+            .with_context(|| format!("failed to read secret {}", file.display()))?;
+        Ok(secret.trim().to_string())
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+/// Compares without stopping at the first differing byte, so timing doesn't leak a prefix.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,310 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::{
+    options::{lexical, relative, Options},
+    glob::{self, Glob},
+    schema::Schemas,
+    log_message, read_source, root_candidates, sha256_hex, Expansion, TargetResult,
+};
+
+/// Written into the output directory; the name never matches `DEFAULT_TARGET_PATTERN`.
+pub(crate) const CACHE_NAME: &str = ".kvy-xmls-cache";
+
+/// What the last clean build of one target read and wrote, paths relative to the base
+/// directory.
+struct Entry {
+    output: String,
+    /// SHA-256 of the output as written.
+    digest: String,
+    /// SHA-256 of the base XML and every file it included, directly or not.
+    inputs: BTreeMap<String, String>,
+    includes: Vec<String>,
+    edges: Vec<(String, String)>,
+    /// Deprecation warnings, logged again whenever the entry is reused.
+    deprecations: BTreeMap<String, String>,
+    /// Where includes found on a `-I` search path were looked for first.
+    missed: Vec<String>,
+    /// Include patterns, with the folder each is relative to, the file it is in and what it
+    /// matched.
+    globs: Vec<(String, String, String, Vec<String>)>,
+    /// The `{date}` in the output's `--header`, if it has one.
+    header_date: Option<String>,
+}
+
+/// Build state of the previous run, for skipping targets none of whose inputs changed.
+pub(crate) struct Cache {
+    entries: BTreeMap<String, Entry>,
+}
+
+/// Everything besides the inputs that goes into an output, so a build with other options or
+/// another KiwiXML version starts from scratch.
+fn fingerprint(opts: &Options) -> String {
+    let base = &opts.base_dir;
+    let roots: Vec<String> = opts.include_roots.iter().map(|root| relative(root, base)).collect();
+    let lock = opts
+        .lock
+        .as_ref()
+        .map(|lock| fs::read(&lock.path).ok().map(|data| sha256_hex(&data)));
+    let settings = format!(
+        "{:?}",
+        (
+            env!("CARGO_PKG_VERSION"),
+            (
+                (opts.engine, opts.normalization, opts.input_encoding, opts.output_encoding),
+                &opts.preserve_whitespace,
+                &opts.profile,
+            ),
+            (&opts.placeholder_lang, &opts.ssi_vars, roots, &opts.vendored, &opts.sparse_roots),
+            (opts.directives.as_str(), opts.inline_includes, opts.on_warnings, opts.on_cycle),
+            (opts.xinclude, opts.compiled_sources, &opts.header, opts.max_passes, lock),
+            (opts.max_size_change, opts.fail_on_size_change, opts.scan_pii, &opts.pii_allow),
+            (opts.output_mode, opts.read_only_outputs, opts.preserve_owner, &opts.copy_xattrs),
+            (
+                (opts.emit_depfile, opts.source_maps),
+                opts.emit_chunks,
+                opts.schemas.as_ref().map(Schemas::digest),
+            ),
+            (&opts.flags, &opts.defines, opts.fail_on_undefined_vars),
+            opts.partition_by.as_ref().map(|field| &field.expr),
+            opts.include_paths.iter().map(|dir| relative(dir, base)).collect::<Vec<_>>(),
+            opts.patches.as_ref().map(|dir| relative(dir, base)),
+        )
+    );
+    sha256_hex(settings.as_bytes())
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| Some(item.as_str()?.to_string()))
+        .collect()
+}
+
+fn string_map(value: &Value) -> BTreeMap<String, String> {
+    value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, item)| Some((key.clone(), item.as_str()?.to_string())))
+        .collect()
+}
+
+impl Cache {
+    /// The previous run's state, or nothing to reuse when there is none, it was built with
+    /// other options, the build is hermetic, or `--force` was given.
+    pub(crate) fn load(opts: &Options) -> Cache {
+        let mut cache = Cache {
+            entries: BTreeMap::new(),
+        };
+        if opts.force || opts.hermetic.is_some() {
+            return cache;
+        }
+        let Some(state) = fs::read_to_string(opts.output_dir.join(CACHE_NAME))
+            .ok()
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            .filter(|state| state["fingerprint"] == fingerprint(opts))
+        else {
+            return cache;
+        };
+        for (source, entry) in state["targets"].as_object().into_iter().flatten() {
+            let Some((output, digest)) = entry["output"].as_str().zip(entry["sha256"].as_str())
+            else {
+                continue;
+            };
+            let inputs = string_map(&entry["inputs"]);
+            let edges = entry["edges"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|edge| {
+                    Some((edge[0].as_str()?.to_string(), edge[1].as_str()?.to_string()))
+                })
+                .collect();
+            let globs = entry["globs"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|glob| {
+                    Some((
+                        glob["dir"].as_str()?.to_string(),
+                        glob["pattern"].as_str()?.to_string(),
+                        glob["from"].as_str()?.to_string(),
+                        strings(&glob["matches"]),
+                    ))
+                })
+                .collect();
+            cache.entries.insert(
+                source.clone(),
+                Entry {
+                    output: output.to_string(),
+                    digest: digest.to_string(),
+                    inputs,
+                    includes: strings(&entry["includes"]),
+                    edges,
+                    deprecations: string_map(&entry["deprecations"]),
+                    missed: strings(&entry["missed"]),
+                    globs,
+                    header_date: entry["header_date"].as_str().map(str::to_string),
+                },
+            );
+        }
+        cache
+    }
+
+    /// When `file` was built cleanly into `out_path` last time, that output is untouched, and
+    /// every input still hashes and resolves the same, fills `exp` with what the build read and
+    /// returns the output's SHA-256. Inputs are hashed rather than compared by modification
+    /// time, so a checkout or `touch` that leaves content alone doesn't force a rebuild.
+    pub(crate) fn reuse(
+        &self,
+        file: &Path,
+        out_path: &Path,
+        opts: &Options,
+        exp: &mut Expansion,
+    ) -> Option<String> {
+        let base = &opts.base_dir;
+        let entry = self.entries.get(&relative(file, base))?;
+        // With `--partition-by`, last time's output may be in any folder of the output directory.
+        let recorded = lexical(&base.join(&entry.output));
+        let partitioned = opts.partition_by.is_some()
+            && match (
+                recorded.strip_prefix(&opts.output_dir),
+                out_path.strip_prefix(&opts.output_dir),
+            ) {
+                (Ok(was), Ok(meant)) => was.components().skip(1).eq(meant.components()),
+                _ => false,
+            };
+        if recorded != out_path && !partitioned {
+            return None;
+        }
+        let written = fs::read(&recorded).ok()?;
+        if sha256_hex(&written) != entry.digest {
+            return None;
+        }
+        let mut reused = Expansion::default();
+        for (input, digest) in &entry.inputs {
+            let path = lexical(&base.join(input));
+            let read = read_source(&path, opts, &mut reused).ok()?;
+            if read.digest != *digest {
+                return None;
+            }
+            reused.sources.insert(path, read);
+        }
+        let path = |relative: &String| lexical(&base.join(relative));
+        reused.includes = entry.includes.iter().map(path).collect();
+        // A file added under a higher priority include root would now override an include.
+        for include in &reused.includes {
+            let Some(found) = root_candidates(include, opts) else {
+                continue;
+            };
+            match found.split_first() {
+                Some((used, shadowed)) if used == include => {
+                    if !shadowed.is_empty() {
+                        reused.shadowed.push((used.clone(), shadowed.to_vec()));
+                    }
+                }
+                _ => return None,
+            }
+        }
+        // An include found on a search path now has a file nearer by.
+        for missed in &entry.missed {
+            let path = lexical(&base.join(missed));
+            if path.exists() {
+                return None;
+            }
+            reused.missed.insert(path);
+        }
+        // A file matching an include pattern was added, or one that matched was removed.
+        for (dir, pattern, from, matches) in &entry.globs {
+            let (dir, from) = (path(dir), path(from));
+            let found = glob::matching(&dir, pattern, &from);
+            if !found.iter().map(|path| relative(path, base)).eq(matches.iter().cloned()) {
+                return None;
+            }
+            reused.globs.push(Glob {
+                dir,
+                pattern: pattern.clone(),
+                from,
+                matches: found,
+            });
+        }
+        reused.edges = entry.edges.iter().map(|(from, to)| (path(from), path(to))).collect();
+        for (fragment, warning) in &entry.deprecations {
+            log_message(warning);
+            reused.deprecations.insert(path(fragment), warning.clone());
+        }
+        reused.partitioned = (recorded != out_path).then_some(recorded);
+        reused.header_date = entry.header_date.clone();
+        *exp = reused;
+        Some(entry.digest.clone())
+    }
+}
+
+/// Records every target that built cleanly, for the next run to reuse. Targets that failed
+/// or had warnings are left out, so they are always built again.
+pub(crate) fn write(results: &[TargetResult], opts: &Options) -> Result<()> {
+    let base = &opts.base_dir;
+    let mut targets = serde_json::Map::new();
+    for result in results {
+        let exp = &result.expansion;
+        let Some(digest) = &result.digest else {
+            continue;
+        };
+        if result.error.is_some() || !exp.warnings.is_empty() || exp.suspect {
+            continue;
+        }
+        let inputs: BTreeMap<String, &String> = exp
+            .sources
+            .iter()
+            .map(|(path, read)| (relative(path, base), &read.digest))
+            .collect();
+        let includes: Vec<String> = exp.includes.iter().map(|path| relative(path, base)).collect();
+        let deprecations: BTreeMap<String, &String> = exp
+            .deprecations
+            .iter()
+            .map(|(path, warning)| (relative(path, base), warning))
+            .collect();
+        let missed: Vec<String> = exp.missed.iter().map(|path| relative(path, base)).collect();
+        let globs: Vec<Value> = exp
+            .globs
+            .iter()
+            .map(|glob| {
+                let matches: Vec<String> =
+                    glob.matches.iter().map(|path| relative(path, base)).collect();
+                json!({
+                    "dir": relative(&glob.dir, base),
+                    "pattern": glob.pattern,
+                    "from": relative(&glob.from, base),
+                    "matches": matches,
+                })
+            })
+            .collect();
+        let edges: Vec<[String; 2]> = exp
+            .edges
+            .iter()
+            .map(|(from, to)| [relative(from, base), relative(to, base)])
+            .collect();
+        targets.insert(
+            relative(&result.source, base),
+            json!({
+                "output": relative(&result.output, base),
+                "sha256": digest,
+                "inputs": inputs,
+                "includes": includes,
+                "edges": edges,
+                "deprecations": deprecations,
+                "missed": missed,
+                "globs": globs,
+                "header_date": exp.header_date,
+            }),
+        );
+    }
+    let state = json!({ "fingerprint": fingerprint(opts), "targets": targets });
+    fs::write(opts.output_dir.join(CACHE_NAME), serde_json::to_string_pretty(&state)?)?;
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use chrono::Local;
+use serde_json::{json, Value};
+
+use crate::{options::Options, TargetResult};
+
+/// Written into the output directory. Neither name matches `DEFAULT_TARGET_PATTERN`, so they
+/// are never picked up as base XMLs.
+pub(crate) const CHANGES_NAME: &str = "CHANGES.txt";
+pub(crate) const STATE_NAME: &str = ".changes.json";
+
+/// What the previous run recorded for one output.
+struct Recorded {
+    digest: String,
+    /// Every file the output was built from, with its SHA-256 at the time.
+    inputs: BTreeMap<String, String>,
+}
+
+/// Path relative to `base`, or as given when it lies elsewhere.
+fn shown(path: &Path, base: &Path) -> String {
+    path.strip_prefix(base).unwrap_or(path).display().to_string()
+}
+
+/// Previous run's state, keyed by output. A missing or unreadable state file means there was
+/// no previous run to compare with.
+fn load(path: &Path) -> (Option<String>, BTreeMap<String, Recorded>) {
+    let Some(state) = fs::read_to_string(path)
+        .ok()
+        .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+    else {
+        return (None, BTreeMap::new());
+    };
+    let outputs = state["outputs"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(output, entry)| {
+            let inputs = entry["inputs"]
+                .as_object()?
+                .iter()
+                .filter_map(|(input, digest)| Some((input.clone(), digest.as_str()?.to_string())))
+                .collect();
+            let digest = entry["sha256"].as_str()?.to_string();
+            Some((output.clone(), Recorded { digest, inputs }))
+        })
+        .collect();
+    (state["generated"].as_str().map(str::to_string), outputs)
+}
+
+/// How `to` was reached from the target's source, as the files in between, for example
+/// ` (via Wolf/Body.xml)`. Empty for the source itself and direct includes.
+fn via(result: &TargetResult, to: &Path, base: &Path) -> String {
+    let mut parents: HashMap<&Path, &Path> = HashMap::new();
+    for (parent, child) in &result.expansion.edges {
+        parents.entry(child.as_path()).or_insert(parent.as_path());
+    }
+    let mut chain = Vec::new();
+    let mut current = to;
+    while let Some(&parent) = parents.get(current) {
+        if parent == result.source || chain.len() > parents.len() {
+            break;
+        }
+        chain.push(shown(parent, base));
+        current = parent;
+    }
+    if chain.is_empty() {
+        return String::new();
+    }
+    chain.reverse();
+    format!(" (via {})", chain.join(" > "))
+}
+
+/// Writes `CHANGES.txt` into the output directory: every output that differs from the previous
+/// `--changelog` run, each with the source and fragment edits that caused it, traced through the
+/// include graph. Also records this run's state for the next one. Failed targets keep their
+/// previous state, since their previous output is what is still on disk.
+pub fn write(results: &[TargetResult], opts: &Options) -> Result<PathBuf> {
+    let base = &opts.base_dir;
+    let state_path = opts.output_dir.join(STATE_NAME);
+    let (previous_run, mut recorded) = load(&state_path);
+
+    let mut results: Vec<&TargetResult> = results.iter().collect();
+    results.sort_by(|a, b| a.output.cmp(&b.output));
+
+    let mut changed = Vec::new();
+    let mut added = Vec::new();
+    let mut failed = Vec::new();
+    let mut built = BTreeMap::new();
+    for result in results {
+        let output = shown(&result.output, base);
+        let Some(digest) = &result.digest else {
+            failed.push(format!("  {}", output));
+            if let Some(entry) = recorded.remove(&output) {
+                built.insert(output, entry);
+            }
+            continue;
+        };
+        let inputs: BTreeMap<String, String> = result
+            .expansion
+            .sources
+            .iter()
+            .map(|(path, read)| (shown(path, base), read.digest.clone()))
+            .collect();
+
+        match recorded.remove(&output) {
+            None => added.push(format!("  {}", output)),
+            Some(before) if before.digest != *digest => {
+                let mut lines = vec![format!("  {}", output)];
+                for (path, read) in &result.expansion.sources {
+                    let input = shown(path, base);
+                    let cause = match before.inputs.get(&input) {
+                        Some(was) if *was == read.digest => continue,
+                        Some(_) => "edited",
+                        None => "now includes",
+                    };
+                    lines.push(format!("    {} {}{}", cause, input, via(result, path, base)));
+                }
+                for input in before.inputs.keys().filter(|input| !inputs.contains_key(*input)) {
+                    lines.push(format!("    no longer includes {}", input));
+                }
+                if lines.len() == 1 {
+                    lines.push("    no source changed (options or KiwiXML version did)".into());
+                }
+                changed.extend(lines);
+            }
+            Some(_) => {}
+        }
+        built.insert(
+            output,
+            Recorded {
+                digest: digest.clone(),
+                inputs,
+            },
+        );
+    }
+    // Whatever is left was recorded last time but has no target now.
+    let removed: Vec<String> = recorded.keys().map(|output| format!("  {}", output)).collect();
+
+    let now = Local::now();
+    let mut text = format!("KiwiXML changes, {}\n", now.format("%Y-%m-%d %H:%M:%S"));
+    match &previous_run {
+        Some(previous) => {
+            let _ = writeln!(text, "Compared with the run of {}", previous);
+        }
+        None => text.push_str("No previous run recorded; every output is listed as new\n"),
+    }
+    let mut section = |title: &str, lines: &[String]| {
+        if !lines.is_empty() {
+            let _ = writeln!(text, "\n{}:", title);
+            for line in lines {
+                let _ = writeln!(text, "{}", line);
+            }
+        }
+    };
+    section("Changed outputs", &changed);
+    section("New outputs", &added);
+    section("Removed outputs", &removed);
+    section("Failed, previous output kept", &failed);
+    if changed.is_empty() && added.is_empty() && removed.is_empty() && failed.is_empty() {
+        text.push_str("\nNo outputs changed.\n");
+    }
+
+    let outputs: serde_json::Map<String, Value> = built
+        .into_iter()
+        .map(|(output, entry)| {
+            (output, json!({ "sha256": entry.digest, "inputs": entry.inputs }))
+        })
+        .collect();
+    let state = json!({
+        "generated": now.format("%Y-%m-%d %H:%M:%S").to_string(),
+        "outputs": outputs,
+    });
+
+    fs::create_dir_all(&opts.output_dir)?;
+    fs::write(&state_path, serde_json::to_string_pretty(&state)? + "\n")?;
+    let path = opts.output_dir.join(CHANGES_NAME);
+    fs::write(&path, text)?;
+    Ok(path)
+}
@@ -0,0 +1,204 @@
+use std::{borrow::Cow, io};
+
+use once_cell::sync::Lazy;
+use regex::{bytes, Regex};
+
+/// The XML declaration at the top of a document, after a byte order mark if any, with the
+/// value of its `encoding` pseudo-attribute.
+static DECLARATION_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^\u{FEFF}?<\?xml\s(?:[^>]*?\bencoding\s*=\s*["']([^"']*)["'])?[^>]*?\?>"#)
+        .unwrap()
+});
+static DECLARED_RE: Lazy<bytes::Regex> = Lazy::new(|| {
+    bytes::Regex::new(r#"^<\?xml\s[^>]*?\bencoding\s*=\s*["']([^"']*)["']"#).unwrap()
+});
+
+/// Windows-1252 characters for bytes 0x80 to 0x9F; the rest of the range is Latin-1. The five
+/// bytes it leaves undefined map to the C1 controls, like browsers do.
+const WINDOWS_1252: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+/// Character encoding of a source file or of the written outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Charset {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252,
+    Latin1,
+}
+
+impl Charset {
+    /// The encoding an XML `encoding` label or a command-line value names, if supported. Plain
+    /// `utf-16` is little-endian, as Windows writes it; a byte order mark tells readers which.
+    pub fn from_label(label: &str) -> Option<Charset> {
+        Some(match label.trim().to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" | "us-ascii" | "ascii" => Charset::Utf8,
+            "utf-16" | "utf16" | "utf-16le" | "unicode" => Charset::Utf16Le,
+            "utf-16be" => Charset::Utf16Be,
+            "windows-1252" | "cp1252" | "x-cp1252" => Charset::Windows1252,
+            "iso-8859-1" | "iso8859-1" | "latin1" | "l1" => Charset::Latin1,
+            _ => return None,
+        })
+    }
+
+    /// The name written into XML declarations.
+    pub fn label(self) -> &'static str {
+        match self {
+            Charset::Utf8 => "UTF-8",
+            Charset::Utf16Le | Charset::Utf16Be => "UTF-16",
+            Charset::Windows1252 => "windows-1252",
+            Charset::Latin1 => "ISO-8859-1",
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The text of a source file read as `bytes`. A byte order mark decides the encoding, then
+/// UTF-16 without one, then the encoding the XML declaration names, and `default` for files
+/// with none of these. The byte order mark is dropped, so it never ends up inside a document
+/// the file is included in. Anything but UTF-8 is transcoded, and a declaration in it then
+/// says UTF-8, which the text now is.
+pub(crate) fn decode(bytes: Vec<u8>, default: Charset) -> io::Result<String> {
+    let (charset, skip) = match bytes.as_slice() {
+        [0xEF, 0xBB, 0xBF, ..] => (Charset::Utf8, 3),
+        [0xFF, 0xFE, ..] => (Charset::Utf16Le, 2),
+        [0xFE, 0xFF, ..] => (Charset::Utf16Be, 2),
+        [0x3C, 0, 0x3F, 0, ..] => (Charset::Utf16Le, 0),
+        [0, 0x3C, 0, 0x3F, ..] => (Charset::Utf16Be, 0),
+        _ => match DECLARED_RE.captures(&bytes) {
+            Some(caps) => {
+                let label = String::from_utf8_lossy(&caps[1]).into_owned();
+                match Charset::from_label(&label) {
+                    Some(Charset::Utf16Le | Charset::Utf16Be) => {
+                        return Err(invalid(format!("declares {} but is not UTF-16", label)));
+                    }
+                    Some(charset) => (charset, 0),
+                    None => return Err(invalid(format!("unsupported encoding '{}'", label))),
+                }
+            }
+            None => (default, 0),
+        },
+    };
+    let body = &bytes[skip..];
+    let text: String = match charset {
+        Charset::Utf8 => {
+            let mut bytes = bytes;
+            bytes.drain(..skip);
+            return String::from_utf8(bytes).map_err(|_| {
+                invalid(match default {
+                    Charset::Utf8 => "not valid UTF-8; declare the file's encoding in its XML \
+                                      declaration, or pass --input-encoding"
+                        .to_string(),
+                    _ => "not valid UTF-8".to_string(),
+                })
+            });
+        }
+        Charset::Utf16Le | Charset::Utf16Be => {
+            if !body.len().is_multiple_of(2) {
+                return Err(invalid("not valid UTF-16: odd number of bytes".to_string()));
+            }
+            let units = body.chunks_exact(2).map(|pair| match charset {
+                Charset::Utf16Be => u16::from_be_bytes([pair[0], pair[1]]),
+                _ => u16::from_le_bytes([pair[0], pair[1]]),
+            });
+            char::decode_utf16(units)
+                .collect::<Result<_, _>>()
+                .map_err(|err| invalid(format!("not valid UTF-16: {}", err)))?
+        }
+        Charset::Windows1252 => body
+            .iter()
+            .map(|&byte| match byte {
+                0x80..=0x9F => WINDOWS_1252[byte as usize - 0x80],
+                _ => byte as char,
+            })
+            .collect(),
+        Charset::Latin1 => body.iter().map(|&byte| byte as char).collect(),
+    };
+    Ok(match DECLARATION_RE.is_match(&text) {
+        true => declare(&text, Charset::Utf8).into_owned(),
+        false => text,
+    })
+}
+
+/// `text` with an XML declaration naming `charset`: the encoding of the one it starts with
+/// replaced or added, or a new declaration put at the top.
+pub(crate) fn declare(text: &str, charset: Charset) -> Cow<'_, str> {
+    let label = charset.label();
+    let Some(caps) = DECLARATION_RE.captures(text) else {
+        let bom = if text.starts_with('\u{FEFF}') { "\u{FEFF}".len() } else { 0 };
+        return Cow::Owned(format!(
+            "{}<?xml version=\"1.0\" encoding=\"{}\"?>\n{}",
+            &text[..bom],
+            label,
+            &text[bom..]
+        ));
+    };
+    match caps.get(1) {
+        Some(value) if value.as_str() == label => Cow::Borrowed(text),
+        Some(value) => {
+            Cow::Owned(format!("{}{}{}", &text[..value.start()], label, &text[value.end()..]))
+        }
+        None => {
+            let end = caps.get(0).unwrap().end() - "?>".len();
+            let end = text[..end].trim_end().len();
+            Cow::Owned(format!("{} encoding=\"{}\"{}", &text[..end], label, &text[end..]))
+        }
+    }
+}
+
+/// A fragment's `text` without its XML declaration, which has no place inside the document
+/// it is included in. The line it was on stays, so lines keep their numbers.
+pub(crate) fn undeclared(text: &str) -> Cow<'_, str> {
+    DECLARATION_RE.replace(text, "")
+}
+
+/// `text` as `charset` bytes, UTF-16 with a byte order mark, and how many of its characters
+/// `charset` cannot represent. Those are written as character references, which stand for
+/// the character in content and attribute values but not in comments or CDATA.
+pub(crate) fn encode(text: &str, charset: Charset) -> (Cow<'_, [u8]>, usize) {
+    if charset == Charset::Utf8 {
+        return (Cow::Borrowed(text.as_bytes()), 0);
+    }
+    let text = text.strip_prefix('\u{FEFF}').unwrap_or(text);
+    let mut out = Vec::with_capacity(text.len() * 2);
+    let mut unrepresentable = 0;
+    match charset {
+        Charset::Utf16Le => {
+            out.extend([0xFF, 0xFE]);
+            out.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        }
+        Charset::Utf16Be => {
+            out.extend([0xFE, 0xFF]);
+            out.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        }
+        _ => {
+            for c in text.chars() {
+                let byte = match (charset, c as u32) {
+                    (_, 0..=0x7F) | (Charset::Latin1, 0x80..=0xFF) => Some(c as u8),
+                    (Charset::Windows1252, 0xA0..=0xFF) => Some(c as u8),
+                    (Charset::Windows1252, _) => WINDOWS_1252
+                        .iter()
+                        .position(|&mapped| mapped == c)
+                        .map(|at| 0x80 + at as u8),
+                    _ => None,
+                };
+                match byte {
+                    Some(byte) => out.push(byte),
+                    None => {
+                        unrepresentable += 1;
+                        out.extend(format!("&#x{:X};", c as u32).bytes());
+                    }
+                }
+            }
+        }
+    }
+    (Cow::Owned(out), unrepresentable)
+}
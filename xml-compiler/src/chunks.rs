@@ -0,0 +1,80 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+
+use crate::sha256_hex;
+
+const MIN_CHUNK: usize = 2 * 1024;
+const MAX_CHUNK: usize = 64 * 1024;
+/// 13 bits set gives an average chunk size of roughly 8 KiB past the minimum.
+const BOUNDARY_MASK: u64 = 0x0000_d903_0353_0000;
+
+/// Gear table for the rolling hash. Generated with splitmix64 from a fixed seed so chunk
+/// boundaries are identical across builds and platforms.
+static GEAR: Lazy<[u64; 256]> = Lazy::new(|| {
+    let mut state: u64 = 0x4b69_7769_584d_4c00;
+    let mut table = [0u64; 256];
+    for slot in table.iter_mut() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *slot = z ^ (z >> 31);
+    }
+    table
+});
+
+/// Splits `data` at content-defined boundaries, so an edit only shifts the chunks around it.
+fn chunk_boundaries(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < data.len() {
+        let remaining = data.len() - start;
+        if remaining <= MIN_CHUNK {
+            chunks.push((start, remaining));
+            break;
+        }
+
+        let limit = remaining.min(MAX_CHUNK);
+        let mut hash: u64 = 0;
+        let mut len = limit;
+        for (i, byte) in data[start + MIN_CHUNK..start + limit].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & BOUNDARY_MASK == 0 {
+                len = MIN_CHUNK + i + 1;
+                break;
+            }
+        }
+
+        chunks.push((start, len));
+        start += len;
+    }
+
+    chunks
+}
+
+/// Writes `<output>.chunks`: a header with the whole-file digest followed by one
+/// `<offset> <length> <sha256>` line per chunk, for delta-transfer tooling to diff against.
+pub fn write_manifest(out_path: &Path, data: &[u8], build_id: &str) -> Result<()> {
+    let mut manifest = String::from("# KiwiXML chunks v1\n");
+    let _ = writeln!(manifest, "# build {}", build_id);
+    let _ = writeln!(manifest, "# size {}", data.len());
+    let _ = writeln!(manifest, "# sha256 {}", sha256_hex(data));
+
+    for (offset, len) in chunk_boundaries(data) {
+        let _ = writeln!(
+            manifest,
+            "{} {} {}",
+            offset,
+            len,
+            sha256_hex(&data[offset..offset + len])
+        );
+    }
+
+    let mut manifest_path = out_path.as_os_str().to_owned();
+    manifest_path.push(".chunks");
+    fs::write(manifest_path, manifest)?;
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    cache::CACHE_NAME,
+    changes::{CHANGES_NAME, STATE_NAME},
+    discover_targets,
+    index::INDEX_NAME,
+    log_message, log_section,
+    options::{lexical, Options},
+    permissions,
+    quarantine_path,
+    report::{JSON_REPORT_NAME, REPORT_NAME},
+    walk_targets,
+    written::{self, WRITTEN_NAME},
+};
+
+/// `path` with `suffix` appended to its file name, as sidecar files are named.
+fn sidecar(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Every base XML under the base directory, including any in the output directory, which
+/// [`discover_targets`] leaves out. Outputs there are named like base XMLs, so one that is
+/// exactly what KiwiXML last wrote is left out.
+fn base_xmls(opts: &Options) -> Vec<PathBuf> {
+    let mut files =
+        walk_targets(&opts.base_dir, &opts.target_pattern, 1, opts.min_depth, opts.max_depth);
+    files.retain(|file| !written::unchanged(file, opts));
+    files
+}
+
+/// Deletes what a build with these options writes: each target's output with its depfile,
+/// chunk manifest and source map, quarantined copies and their reports, the build cache, the
+/// HTML report, changelog and index, and the `--json-report` and `--emit-msbuild` files if
+/// given. Anything else in the output directory is left alone, and directories are removed
+/// only once empty.
+/// With `--partition-by`, outputs are looked for in every folder of the output directory too.
+/// Nothing outside the output and quarantine directories is deleted, and no base XML ever is,
+/// wherever the options point. With `--all`, see [`clean_all`]. Returns how many files were
+/// deleted.
+pub fn clean(opts: &Options) -> Result<usize> {
+    if opts.clean_all {
+        return clean_all(opts);
+    }
+    log_section(&format!("Cleaning {}", opts.output_dir.display()));
+    let targets = match &opts.hermetic {
+        Some(hermetic) => hermetic.targets.clone(),
+        None => discover_targets(opts),
+    };
+    let sources: BTreeSet<PathBuf> = base_xmls(opts).iter().map(|file| lexical(file)).collect();
+    // With `--partition-by`, an output may be in any folder of the output directory.
+    let partitions: Vec<PathBuf> = match opts.partition_by {
+        Some(_) => fs::read_dir(&opts.output_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+            .map(|entry| entry.path())
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut outputs = Vec::new();
+    for (_, out_path) in &targets {
+        outputs.push(out_path.clone());
+        if let Ok(relative) = out_path.strip_prefix(&opts.output_dir) {
+            outputs.extend(partitions.iter().map(|partition| partition.join(relative)));
+        }
+    }
+    let mut files = Vec::new();
+    for out_path in &outputs {
+        files.push(out_path.clone());
+        files.push(sidecar(out_path, ".d"));
+        files.push(sidecar(out_path, ".chunks"));
+        files.push(sidecar(out_path, ".map"));
+        let quarantined = quarantine_path(out_path, opts);
+        files.push(sidecar(&quarantined, ".report.json"));
+        files.push(quarantined);
+    }
+    let names = [
+        CACHE_NAME,
+        WRITTEN_NAME,
+        REPORT_NAME,
+        JSON_REPORT_NAME,
+        CHANGES_NAME,
+        STATE_NAME,
+        INDEX_NAME,
+    ];
+    for name in names {
+        files.push(opts.output_dir.join(name));
+    }
+    files.extend(opts.json_report.clone());
+    files.extend(opts.msbuild_props.clone());
+    let allowed = [&opts.output_dir, &opts.quarantine_dir].map(|root| lexical(root));
+    files.retain(|file| {
+        let file = lexical(file);
+        let kept = match allowed.iter().any(|root| file.starts_with(root)) {
+            true if sources.contains(&file) => "it is a base XML",
+            true => return true,
+            false => "it is outside the output directory",
+        };
+        if file.exists() {
+            log_message(&format!("Kept: {} ({})", file.display(), kept));
+        }
+        false
+    });
+
+    let mut removed = 0;
+    for file in &files {
+        let _ = permissions::clear_readonly(file);
+        match fs::remove_file(file) {
+            Ok(()) => {
+                log_message(&format!("Removed: {}", file.display()));
+                removed += 1;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(anyhow!("Cannot remove {}: {}", file.display(), err)),
+        }
+    }
+    // Folders made for `--preserve-layout` too, deepest first so each is empty by the time
+    // its parent is tried. Removal fails, harmlessly, when something else is still inside.
+    let roots = [&opts.output_dir, &opts.quarantine_dir];
+    let mut dirs: BTreeSet<&Path> = files
+        .iter()
+        .filter_map(|file| file.parent())
+        .filter(|dir| roots.iter().any(|root| dir.starts_with(root)))
+        .collect();
+    dirs.extend(roots.map(PathBuf::as_path));
+    for dir in dirs.iter().rev() {
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(removed)
+}
+
+/// Deletes the whole output directory, whatever is in it, after checking that it holds nothing
+/// a build reads: it must not be or contain the base directory, the working directory, an
+/// include path or any base XML. Returns how many files were deleted.
+pub fn clean_all(opts: &Options) -> Result<usize> {
+    let output_dir = lexical(&opts.output_dir);
+    log_section(&format!("Removing {}", output_dir.display()));
+    let refuse = |what: &str| {
+        Err(anyhow!(
+            "Refusing to remove {}: it contains {}",
+            output_dir.display(),
+            what
+        ))
+    };
+    if output_dir.parent().is_none() {
+        return refuse("the whole file system");
+    }
+    if lexical(&opts.base_dir).starts_with(&output_dir) {
+        return refuse("the base directory");
+    }
+    if std::env::current_dir().is_ok_and(|cwd| lexical(&cwd).starts_with(&output_dir)) {
+        return refuse("the working directory");
+    }
+    if let Some(path) = opts.include_paths.iter().find(|path| path.starts_with(&output_dir)) {
+        return refuse(&format!("the include path {}", path.display()));
+    }
+    if let Some(file) = base_xmls(opts).iter().find(|file| lexical(file).starts_with(&output_dir)) {
+        return refuse(&format!("the base XML {}", file.display()));
+    }
+
+    let mut removed = 0;
+    let mut dirs = vec![output_dir.clone()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(anyhow!("Cannot read {}: {}", dir.display(), err)),
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // Symlinked folders are removed as links; what they point to is left alone.
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                dirs.push(path);
+                continue;
+            }
+            let _ = permissions::clear_readonly(&path);
+            fs::remove_file(&path)
+                .map_err(|err| anyhow!("Cannot remove {}: {}", path.display(), err))?;
+            removed += 1;
+        }
+    }
+    match fs::remove_dir_all(&output_dir) {
+        Ok(()) => log_message(&format!("Removed: {}", output_dir.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(anyhow!("Cannot remove {}: {}", output_dir.display(), err)),
+    }
+    Ok(removed)
+}
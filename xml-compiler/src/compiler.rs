@@ -0,0 +1,289 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    compile_target, expand_settled, log_message,
+    error::CompileError,
+    normalize::Normalization,
+    options::{self, lexical, Engine, InlineIncludes, Options, WarningPolicy},
+    check, clean, run, watch, written, Expansion,
+};
+
+/// Compiles base XMLs with one set of options. Built with [`Compiler::builder`], or from
+/// command line flags with [`Compiler::from_args`].
+pub struct Compiler {
+    opts: Options,
+}
+
+impl Compiler {
+    /// Starts from the defaults the command line tool uses when run in `base_dir`.
+    pub fn builder(base_dir: impl Into<PathBuf>) -> CompilerBuilder {
+        CompilerBuilder {
+            base_dir: base_dir.into(),
+            output_dir: None,
+            preserve_layout: false,
+            json_report: false,
+            engine: None,
+            max_passes: None,
+            include_roots: Vec::new(),
+            include_paths: Vec::new(),
+            directive_syntaxes: Vec::new(),
+            preserve_whitespace: Vec::new(),
+            placeholder_lang: None,
+            normalization: None,
+            inline_includes: None,
+            on_warnings: None,
+            profile: None,
+            header: None,
+            ssi_vars: Vec::new(),
+            flags: Vec::new(),
+            defines: Vec::new(),
+        }
+    }
+
+    /// Parses the same flags as the `KiwiXML` binary; relative paths resolve against `cwd`.
+    pub fn from_args(args: &[String], cwd: &Path) -> Result<Compiler> {
+        Ok(Compiler::new(Options::parse(args, cwd)?))
+    }
+
+    pub fn new(opts: Options) -> Compiler {
+        Compiler { opts }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.opts
+    }
+
+    /// Compiles every target under the base directory (or in the hermetic list) into the
+    /// output directory, with reports and checks as configured, exactly like a `KiwiXML` run.
+    pub fn run(&self) -> Result<()> {
+        run(&self.opts)
+    }
+
+    /// Runs once, then recompiles the targets affected by each change to their sources until
+    /// the process is stopped.
+    pub fn watch(&self) -> Result<()> {
+        watch::watch(&self.opts)
+    }
+
+    /// Expands every target and runs a build's checks without writing anything, failing if
+    /// any target fails or has warnings.
+    pub fn check(&self) -> Result<()> {
+        check(&self.opts)
+    }
+
+    /// Deletes the outputs and other files a run writes, returning how many were removed.
+    pub fn clean(&self) -> Result<usize> {
+        clean::clean(&self.opts)
+    }
+
+    /// Expands one base XML in memory: includes resolved, fragments cleaned up and wrapped
+    /// in CDATA. Nothing is written, and no `--header` comment is added.
+    pub fn expand(&self, source: &Path) -> Result<String, CompileError> {
+        expand_settled(source, &self.opts, &mut Expansion::default())
+    }
+
+    /// Compiles one base XML to `output`, with the same checks as a run, and returns the
+    /// SHA-256 of what was written.
+    pub fn compile(&self, source: &Path, output: &Path) -> Result<String, CompileError> {
+        let digest = compile_target(source, output, &self.opts, &mut Expansion::default())?;
+        if let Err(err) = written::save(&self.opts) {
+            log_message(&format!("Error recording written outputs: {}", err));
+        }
+        Ok(digest)
+    }
+}
+
+/// Options for a [`Compiler`]. Anything not set keeps the command line default.
+pub struct CompilerBuilder {
+    base_dir: PathBuf,
+    output_dir: Option<PathBuf>,
+    preserve_layout: bool,
+    json_report: bool,
+    engine: Option<Engine>,
+    max_passes: Option<usize>,
+    include_roots: Vec<PathBuf>,
+    include_paths: Vec<PathBuf>,
+    directive_syntaxes: Vec<(String, String)>,
+    preserve_whitespace: Vec<String>,
+    placeholder_lang: Option<String>,
+    normalization: Option<Normalization>,
+    inline_includes: Option<InlineIncludes>,
+    on_warnings: Option<WarningPolicy>,
+    profile: Option<String>,
+    header: Option<String>,
+    ssi_vars: Vec<(String, String)>,
+    flags: Vec<String>,
+    defines: Vec<(String, String)>,
+}
+
+impl CompilerBuilder {
+    /// Where outputs are written, relative to the base directory; `compiled` by default.
+    pub fn output_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.output_dir = Some(dir.into());
+        self
+    }
+
+    /// Mirrors each base XML's folder below the output directory (`--preserve-layout`).
+    pub fn preserve_layout(mut self, preserve: bool) -> Self {
+        self.preserve_layout = preserve;
+        self
+    }
+
+    /// Writes a JSON summary of the run to `report.json` in the output directory (`--report
+    /// json`).
+    pub fn json_report(mut self, write: bool) -> Self {
+        self.json_report = write;
+        self
+    }
+
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = Some(engine);
+        self
+    }
+
+    /// Upper bound on expansion passes over each target; at least 1.
+    pub fn max_passes(mut self, passes: usize) -> Self {
+        self.max_passes = Some(passes.max(1));
+        self
+    }
+
+    /// Adds a library root, relative to the base directory, shadowing those added before it
+    /// (`--include-root`).
+    pub fn include_root(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_roots.push(dir.into());
+        self
+    }
+
+    /// Adds a folder, relative to the base directory, to look for includes in when they aren't
+    /// where their directive points; searched in the order added (`-I`).
+    pub fn include_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_paths.push(dir.into());
+        self
+    }
+
+    /// Adds a directive form such as `("<?kvy include", "?>")`, replacing the default forms
+    /// (`--directive-syntax`).
+    pub fn directive_syntax(mut self, open: &str, close: &str) -> Self {
+        self.directive_syntaxes.push((open.to_string(), close.to_string()));
+        self
+    }
+
+    /// Elements whose content keeps its whitespace (`--preserve-whitespace`).
+    pub fn preserve_whitespace(mut self, element: &str) -> Self {
+        self.preserve_whitespace.push(element.to_string());
+        self
+    }
+
+    pub fn placeholder_lang(mut self, lang: &str) -> Self {
+        self.placeholder_lang = Some(lang.to_ascii_lowercase());
+        self
+    }
+
+    pub fn normalization(mut self, form: Normalization) -> Self {
+        self.normalization = Some(form);
+        self
+    }
+
+    pub fn inline_includes(mut self, mode: InlineIncludes) -> Self {
+        self.inline_includes = Some(mode);
+        self
+    }
+
+    pub fn on_warnings(mut self, policy: WarningPolicy) -> Self {
+        self.on_warnings = Some(policy);
+        self
+    }
+
+    /// Leaves out elements marked `kvy:only` for other profiles (`--profile`).
+    pub fn profile(mut self, profile: &str) -> Self {
+        self.profile = Some(profile.to_string());
+        self
+    }
+
+    /// Template for the comment put at the top of every output (the contents of a `--header`
+    /// file).
+    pub fn header(mut self, template: &str) -> Self {
+        self.header = Some(template.to_string());
+        self
+    }
+
+    /// Value for SSI `#echo var="..."` directives (`--ssi-var`).
+    pub fn ssi_var(mut self, name: &str, value: &str) -> Self {
+        self.ssi_vars.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets a flag for `#if flag="..."` conditionals (`--flag`).
+    pub fn flag(mut self, name: &str) -> Self {
+        self.flags.push(name.to_string());
+        self
+    }
+
+    /// Value for `@{NAME}` and `<!-- #value name="NAME" -->` variables (`--define`).
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Fails when a directive syntax or whitespace element list doesn't make a valid pattern,
+    /// when the output directory isn't valid Unicode, or when the base directory has an
+    /// unreadable vendor manifest.
+    pub fn build(self) -> Result<Compiler> {
+        // Given as flags, so that the paths derived from the output directory, such as the
+        // JSON report's, are worked out from the one set here.
+        let mut args = Vec::new();
+        if let Some(dir) = &self.output_dir {
+            let dir = dir.to_str().ok_or_else(|| {
+                anyhow!("Output directory is not valid Unicode: {}", dir.display())
+            })?;
+            args.extend(["--output-dir".to_string(), dir.to_string()]);
+        }
+        if self.preserve_layout {
+            args.push("--preserve-layout".to_string());
+        }
+        if self.json_report {
+            args.extend(["--report".to_string(), "json".to_string()]);
+        }
+        let mut opts = Options::parse(&args, &self.base_dir)?;
+        if let Some(engine) = self.engine {
+            opts.engine = engine;
+        }
+        if let Some(passes) = self.max_passes {
+            opts.max_passes = passes;
+        }
+        // The base directory stays last, so it overrides every library root.
+        let base = opts.include_roots.pop();
+        opts.include_roots
+            .extend(self.include_roots.iter().map(|root| lexical(&self.base_dir.join(root))));
+        opts.include_roots.extend(base);
+        opts.include_paths
+            .extend(self.include_paths.iter().map(|dir| lexical(&self.base_dir.join(dir))));
+        if !self.directive_syntaxes.is_empty() {
+            opts.directives = options::directives_re(&self.directive_syntaxes)?;
+            opts.directive_syntaxes = self.directive_syntaxes;
+        }
+        if !self.preserve_whitespace.is_empty() {
+            opts.preserved_re = options::preserved_elements_re(&self.preserve_whitespace)?;
+            opts.preserve_whitespace = self.preserve_whitespace;
+        }
+        opts.placeholder_lang = self.placeholder_lang.or(opts.placeholder_lang);
+        opts.normalization = self.normalization.or(opts.normalization);
+        if let Some(mode) = self.inline_includes {
+            opts.inline_includes = mode;
+        }
+        if let Some(policy) = self.on_warnings {
+            opts.on_warnings = policy;
+        }
+        opts.profile = self.profile.or(opts.profile);
+        opts.header = self.header.or(opts.header);
+        opts.ssi_vars.extend(self.ssi_vars);
+        opts.flags.extend(self.flags);
+        for (name, value) in self.defines {
+            opts.command_line_defines.insert(name.clone());
+            opts.defines.insert(name, value);
+        }
+        Ok(Compiler::new(opts))
+    }
+}
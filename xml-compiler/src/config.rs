@@ -0,0 +1,362 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use quick_xml::{events::Event, Reader};
+use serde_json::Value;
+
+use crate::{
+    error::CompileError,
+    log_message,
+    options::{relative, Options},
+    read_source, Expansion,
+};
+
+/// Name of the configuration file read from the base directory and its folders.
+pub const CONFIG_NAME: &str = "kvy-xmls.toml";
+
+/// Parses a configuration file as TOML into the same shape JSON would have. Errors are given
+/// with their line number.
+pub(crate) fn parse_toml(text: &str) -> Result<Value, (usize, String)> {
+    let table = text.parse::<toml::Table>().map_err(|err| {
+        let at = err.span().map_or(0, |span| span.start);
+        (text[..at].matches('\n').count() + 1, err.message().trim().to_string())
+    })?;
+    serde_json::to_value(table).map_err(|err| (1, err.to_string()))
+}
+
+/// Settings that apply to the targets in a folder and below it. A folder's own
+/// `kvy-xmls.toml` gives them, and so does a `[folders."<dir>"]` table in the base directory's.
+const FOLDER_KEYS: &[&str] = &["prologue", "epilogue", "defines", "flags"];
+
+/// Settings only the base directory's `kvy-xmls.toml` gives, as they apply to the whole run.
+const PROJECT_KEYS: &[&str] = &[
+    "pattern",
+    "min-depth",
+    "max-depth",
+    "output-dir",
+    "include-roots",
+    "include-paths",
+    "strict",
+    "xinclude",
+    "max-errors",
+    "jobs",
+    "watch-debounce",
+    "groups",
+    "tokens",
+];
+
+/// Command line arguments for the project settings in the base directory's `kvy-xmls.toml`,
+/// to be put before the ones given so that those win. Paths in the file are relative to the
+/// base directory and made absolute here. Empty without a file.
+pub(crate) fn project_args(base_dir: &Path) -> anyhow::Result<Vec<String>> {
+    let Some((path, config)) = project(base_dir)? else {
+        return Ok(Vec::new());
+    };
+    let wrong = |key: &str, expected: &str| {
+        anyhow!("{}: {} must be {}", path.display(), key, expected)
+    };
+    let absolute = |dir: &str| base_dir.join(dir).display().to_string();
+
+    let mut args = Vec::new();
+    for (key, value) in config.as_object().into_iter().flatten() {
+        match (key.as_str(), value) {
+            ("pattern", Value::String(pattern)) => {
+                args.extend(["--pattern".to_string(), pattern.clone()])
+            }
+            ("output-dir", Value::String(dir)) => {
+                args.extend(["--output-dir".to_string(), absolute(dir)])
+            }
+            ("include-roots" | "include-paths", Value::Array(dirs)) => {
+                let flag = match key.as_str() {
+                    "include-roots" => "--include-root",
+                    _ => "--include-path",
+                };
+                for dir in dirs {
+                    let dir = dir.as_str().ok_or_else(|| wrong(key, "a list of folders"))?;
+                    args.extend([flag.to_string(), absolute(dir)]);
+                }
+            }
+            ("strict" | "xinclude", Value::Bool(on)) => {
+                args.extend(on.then(|| format!("--{}", key)))
+            }
+            ("jobs", Value::Number(jobs)) => args.extend(["--jobs".to_string(), jobs.to_string()]),
+            ("min-depth" | "max-depth" | "max-errors" | "watch-debounce", Value::Number(count)) => {
+                args.extend([format!("--{}", key), count.to_string()])
+            }
+            ("folders" | "groups" | "tokens", Value::Object(_)) => {}
+            (key, _) if FOLDER_KEYS.contains(&key) => {}
+            ("pattern" | "output-dir", _) => return Err(wrong(key, "a string")),
+            ("include-roots" | "include-paths", _) => return Err(wrong(key, "a list of folders")),
+            ("strict" | "xinclude", _) => return Err(wrong(key, "true or false")),
+            ("jobs" | "min-depth" | "max-depth" | "max-errors" | "watch-debounce", _) => {
+                return Err(wrong(key, "a number"));
+            }
+            ("folders", _) => return Err(wrong(key, "a table of folders")),
+            ("groups", _) => return Err(wrong(key, "a table of target lists")),
+            ("tokens", _) => return Err(wrong(key, "a table of token lists")),
+            (key, _) => {
+                return Err(anyhow!("{}: unknown setting {}", path.display(), key));
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// The path and parsed content of the base directory's `kvy-xmls.toml`, if it has one.
+fn project(base_dir: &Path) -> anyhow::Result<Option<(PathBuf, Value)>> {
+    let path = base_dir.join(CONFIG_NAME);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let text = fs::read_to_string(&path)
+        .map_err(|err| anyhow!("Cannot read {}: {}", path.display(), err))?;
+    let config = parse_toml(&text)
+        .map_err(|(line, message)| anyhow!("{}:{}: {}", path.display(), line, message))?;
+    Ok(Some((path, config)))
+}
+
+/// Lists of strings by name, as `[groups]` and `[tokens]` hold them.
+pub(crate) type Lists = BTreeMap<String, Vec<String>>;
+
+/// The lists of strings in the `key` table of the base directory's `kvy-xmls.toml`, with the
+/// file's path. `what` names the strings in errors.
+pub(crate) fn string_lists(
+    base_dir: &Path,
+    key: &str,
+    what: &str,
+) -> anyhow::Result<Option<(PathBuf, Lists)>> {
+    let Some((path, config)) = project(base_dir)? else {
+        return Ok(None);
+    };
+    let Some(table) = config[key].as_object() else {
+        return Ok(None);
+    };
+    let mut lists = BTreeMap::new();
+    for (name, items) in table {
+        let items = items
+            .as_array()
+            .and_then(|items| items.iter().map(|item| Some(item.as_str()?.to_string())).collect())
+            .ok_or_else(|| {
+                anyhow!("{}: {}.{} must be a list of {}", path.display(), key, name, what)
+            })?;
+        lists.insert(name.clone(), items);
+    }
+    Ok(Some((path, lists)))
+}
+
+/// The patterns of each group of targets in the `[groups]` table of the base directory's
+/// `kvy-xmls.toml`, for `--group`. Empty without a file or table.
+pub(crate) fn groups(base_dir: &Path) -> anyhow::Result<Lists> {
+    let groups = string_lists(base_dir, "groups", "patterns")?;
+    Ok(groups.map(|(_, groups)| groups).unwrap_or_default())
+}
+
+/// Folder settings for one target, merged from the base directory down to its folder.
+#[derive(Default)]
+pub(crate) struct Folder {
+    /// Fragment included at the start of the target's root element.
+    pub prologue: Option<PathBuf>,
+    /// Fragment included at the end of the target's root element.
+    pub epilogue: Option<PathBuf>,
+    /// Variables, nearer folders winning.
+    pub defines: BTreeMap<String, String>,
+    /// Flags every folder on the way sets.
+    pub flags: BTreeSet<String>,
+}
+
+/// Reads and parses `path` as a source of the target, or `None` if there is no such file.
+fn read(path: &Path, opts: &Options, exp: &mut Expansion) -> Result<Option<Value>, CompileError> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let source = read_source(path, opts, exp)?;
+    let config = parse_toml(&source.content).map_err(|(line, message)| CompileError::Config {
+        path: path.to_path_buf(),
+        message: format!("line {}: {}", line, message),
+    })?;
+    exp.sources.insert(path.to_path_buf(), source);
+    Ok(Some(config))
+}
+
+/// The folder settings for `target`. Looking from its folder up to the base directory, each
+/// folder's own `kvy-xmls.toml` comes before the base directory's `[folders]` table for it, and
+/// the first to give `prologue`, `epilogue` or a variable wins. An empty `prologue` or
+/// `epilogue` turns off one set further up. Paths are relative to the file that names them.
+/// The files read count as the target's sources.
+pub(crate) fn folder(
+    target: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<Folder, CompileError> {
+    let base_path = opts.base_dir.join(CONFIG_NAME);
+    let base = read(&base_path, opts, exp)?;
+    let mut settings = Settings::default();
+    let dirs = target.parent().into_iter().flat_map(Path::ancestors);
+    for dir in dirs.take_while(|dir| dir.starts_with(&opts.base_dir)) {
+        if dir == opts.base_dir {
+            if let Some(base) = &base {
+                settings.apply(base, &base_path, true)?;
+            }
+            continue;
+        }
+        let own = dir.join(CONFIG_NAME);
+        if let Some(config) = read(&own, opts, exp)? {
+            settings.apply(&config, &own, false)?;
+        }
+        let name = relative(dir, &opts.base_dir);
+        let tables = base.as_ref().and_then(|base| base["folders"].as_object());
+        let table = tables
+            .into_iter()
+            .flatten()
+            .find(|(folder, _)| folder.replace('\\', "/").trim_matches('/') == name);
+        if let Some((_, table)) = table {
+            settings.apply(table, &base_path, false)?;
+        }
+    }
+    Ok(Folder {
+        prologue: settings.prologue.flatten(),
+        epilogue: settings.epilogue.flatten(),
+        defines: settings.defines,
+        flags: settings.flags,
+    })
+}
+
+/// Folder settings gathered so far, nearest first. `Some(None)` is a prologue or epilogue
+/// turned off.
+#[derive(Default)]
+struct Settings {
+    prologue: Option<Option<PathBuf>>,
+    epilogue: Option<Option<PathBuf>>,
+    defines: BTreeMap<String, String>,
+    flags: BTreeSet<String>,
+}
+
+impl Settings {
+    /// Adds the settings in `layer`, from the file at `path`, under those already gathered.
+    /// `whole` is the top level of the base directory's file, which has project settings too.
+    fn apply(&mut self, layer: &Value, path: &Path, whole: bool) -> Result<(), CompileError> {
+        let fail = |message: String| CompileError::Config {
+            path: path.to_path_buf(),
+            message,
+        };
+        let Some(layer) = layer.as_object() else {
+            return Err(fail("folder settings must be a table".to_string()));
+        };
+        for (key, value) in layer {
+            match key.as_str() {
+                "prologue" | "epilogue" => {
+                    let file = value
+                        .as_str()
+                        .ok_or_else(|| fail(format!("{} must be a string", key)))?;
+                    let slot = match key.as_str() {
+                        "prologue" => &mut self.prologue,
+                        _ => &mut self.epilogue,
+                    };
+                    if slot.is_none() {
+                        let dir = path.parent().unwrap_or(Path::new(""));
+                        *slot = Some((!file.is_empty()).then(|| dir.join(file)));
+                    }
+                }
+                "defines" => {
+                    let defines = value
+                        .as_object()
+                        .ok_or_else(|| fail("defines must be a table".to_string()))?;
+                    for (name, value) in defines {
+                        let value = match value {
+                            Value::String(value) => value.clone(),
+                            Value::Number(_) | Value::Bool(_) => value.to_string(),
+                            _ => return Err(fail(format!("defines.{} must be a string", name))),
+                        };
+                        self.defines.entry(name.clone()).or_insert(value);
+                    }
+                }
+                "flags" => {
+                    let flags = value.as_array().map(|flags| {
+                        flags.iter().map(Value::as_str).collect::<Option<Vec<&str>>>()
+                    });
+                    let flags = flags
+                        .flatten()
+                        .ok_or_else(|| fail("flags must be a list of names".to_string()))?;
+                    self.flags.extend(flags.into_iter().map(str::to_string));
+                }
+                _ if whole => {}
+                key if PROJECT_KEYS.contains(&key) || key == "folders" => {
+                    return Err(fail(format!("{} can only be set for the whole project", key)));
+                }
+                key => return Err(fail(format!("unknown setting {}", key))),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `content` with include directives for the folder's prologue right after the root element's
+/// start tag and its epilogue right before its end tag, each on a line of its own. A target
+/// whose root element is empty or can't be found is left alone, with a warning.
+pub(crate) fn surround(target: &Path, content: &str, folder: &Folder, opts: &Options) -> String {
+    let directive = |file: &Path| {
+        let (open, close) = &opts.directive_syntaxes[0];
+        format!("{} file=\"{}\" {}", open, file.display(), close)
+    };
+    let mut reader = Reader::from_str(content);
+    reader.config_mut().check_end_names = false;
+    let mut depth = 0;
+    let mut inner = None;
+    loop {
+        let start = reader.buffer_position() as usize;
+        match reader.read_event() {
+            Ok(Event::Start(_)) => {
+                depth += 1;
+                if depth == 1 {
+                    inner = Some((reader.buffer_position() as usize, content.len()));
+                }
+            }
+            Ok(Event::End(_)) => {
+                depth -= 1;
+                if depth == 0 {
+                    inner = inner.map(|(after_start, _)| (after_start, start));
+                    break;
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    let Some((after_start, before_end)) = inner.filter(|&(_, end)| end < content.len()) else {
+        log_message(&format!(
+            "Warning: {}: no root element to put the folder's prologue and epilogue in",
+            target.display()
+        ));
+        return content.to_string();
+    };
+
+    let mut out = content[..after_start].to_string();
+    if let Some(prologue) = &folder.prologue {
+        out.push('\n');
+        out.push_str(&directive(prologue));
+        if !content[after_start..].starts_with(['\r', '\n']) {
+            out.push('\n');
+        }
+    }
+    out.push_str(&content[after_start..before_end]);
+    if let Some(epilogue) = &folder.epilogue {
+        // Before the end tag's indentation, if it is alone on its line.
+        let line_start = out.rfind('\n').map_or(0, |i| i + 1);
+        let indent = match out[line_start..].trim().is_empty() {
+            true => out.split_off(line_start),
+            false => {
+                out.push('\n');
+                String::new()
+            }
+        };
+        out.push_str(&directive(epilogue));
+        out.push('\n');
+        out.push_str(&indent);
+    }
+    out.push_str(&content[before_end..]);
+    out
+}
@@ -0,0 +1,281 @@
+use std::{
+    cell::RefCell,
+    io::{self, IsTerminal, Write},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    error::CompileError,
+    entry_message, log_line, log_message,
+    options::{relative, Options, Verbosity},
+    write_log,
+};
+
+pub(crate) const GREEN: &str = "\x1b[1;32m";
+const YELLOW: &str = "\x1b[1;33m";
+pub(crate) const RED: &str = "\x1b[1;31m";
+pub(crate) const RESET: &str = "\x1b[0m";
+
+/// Width of the status column, so paths line up under each other like cargo's output.
+const STATUS_WIDTH: usize = 12;
+
+/// Width of the progress bar between its brackets.
+const BAR_WIDTH: usize = 30;
+
+/// Targets of the run in progress, for the progress line and the closing summary.
+struct Tally {
+    label: &'static str,
+    total: usize,
+    compiled: usize,
+    up_to_date: usize,
+    checked: usize,
+    warned: usize,
+    failed: usize,
+    /// Never started, because `--bail` stopped the run.
+    skipped: usize,
+    /// Keep a progress line below the status lines; only on a terminal.
+    progress: bool,
+}
+
+static TALLY: Mutex<Option<Tally>> = Mutex::new(None);
+
+thread_local! {
+    /// Log entries held back for the target this thread is compiling, innermost capture last.
+    static CAPTURED: RefCell<Vec<Vec<String>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The stream a piece of console output was meant for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Channel {
+    Stdout,
+    Stderr,
+}
+
+/// Console output of the request the daemon is running, in the order it was printed, for the
+/// client to print instead. The daemon runs one request at a time, so it is one for the process.
+static REDIRECTED: Mutex<Option<Vec<(Channel, String)>>> = Mutex::new(None);
+
+/// Runs `f`, collecting what it prints to the console rather than printing it, and returns that
+/// with its result. Nothing counts as a terminal meanwhile, so there is no progress line.
+pub(crate) fn redirect<T>(f: impl FnOnce() -> T) -> (T, Vec<(Channel, String)>) {
+    *REDIRECTED.lock().unwrap_or_else(|err| err.into_inner()) = Some(Vec::new());
+    let result = f();
+    let printed = REDIRECTED.lock().unwrap_or_else(|err| err.into_inner()).take();
+    (result, printed.unwrap_or_default())
+}
+
+/// Prints `text` to `channel`, or keeps it for the client while [`redirect`]ed.
+pub(crate) fn print(channel: Channel, text: &str) {
+    if let Some(printed) = REDIRECTED.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+        printed.push((channel, text.to_string()));
+        return;
+    }
+    let _ = match channel {
+        Channel::Stdout => io::stdout().lock().write_all(text.as_bytes()),
+        Channel::Stderr => io::stderr().lock().write_all(text.as_bytes()),
+    };
+}
+
+/// Whether stdout is a terminal that isn't being [`redirect`]ed.
+pub(crate) fn stdout_is_terminal() -> bool {
+    REDIRECTED.lock().unwrap_or_else(|err| err.into_inner()).is_none() && io::stdout().is_terminal()
+}
+
+/// Runs `f`, holding back every message it logs on this thread, and returns them with its
+/// result. Rayon may run another target on this thread while `f` waits; that one captures its
+/// own messages, so captures nest.
+pub(crate) fn capture<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    CAPTURED.with(|captured| captured.borrow_mut().push(Vec::new()));
+    let result = f();
+    let entries = CAPTURED.with(|captured| captured.borrow_mut().pop().unwrap_or_default());
+    (result, entries)
+}
+
+/// Keeps `entry` when this thread is capturing, and hands it back to be logged otherwise.
+pub(crate) fn hold(entry: String) -> Option<String> {
+    CAPTURED.with(|captured| match captured.borrow_mut().last_mut() {
+        Some(entries) => {
+            entries.push(entry);
+            None
+        }
+        None => Some(entry),
+    })
+}
+
+/// Starts counting the `total` targets about to be flushed, showing `label` (`Building`,
+/// `Checking`) and how many are done on a line of its own while stdout is a terminal.
+pub(crate) fn start(label: &'static str, total: usize, opts: &Options) {
+    let progress = total > 0
+        && opts.hermetic.is_none()
+        && opts.verbosity != Verbosity::Quiet
+        && stdout_is_terminal();
+    *TALLY.lock().unwrap_or_else(|err| err.into_inner()) = Some(Tally {
+        label,
+        total,
+        compiled: 0,
+        up_to_date: 0,
+        checked: 0,
+        warned: 0,
+        failed: 0,
+        skipped: 0,
+        progress,
+    });
+    if progress {
+        print(Channel::Stdout, &progress_line(label, 0, total, opts));
+    }
+}
+
+/// `    Building [=======>        ] 12/40`, without a line break, so the next write can
+/// replace it.
+fn progress_line(label: &str, done: usize, total: usize, opts: &Options) -> String {
+    let filled = BAR_WIDTH * done / total.max(1);
+    let bar = match filled {
+        0 => " ".repeat(BAR_WIDTH),
+        _ if filled >= BAR_WIDTH => "=".repeat(BAR_WIDTH),
+        _ => format!("{}>{}", "=".repeat(filled - 1), " ".repeat(BAR_WIDTH - filled)),
+    };
+    format!("{} [{}] {}/{}", status_label(GREEN, label, opts), bar, done, total)
+}
+
+/// `label` right-aligned in the status column, colored when enabled.
+fn status_label(color: &str, label: &str, opts: &Options) -> String {
+    let pad = " ".repeat(STATUS_WIDTH.saturating_sub(label.len()));
+    match opts.color {
+        true => format!("{}{}{}{}", pad, color, label, RESET),
+        false => format!("{}{}", pad, label),
+    }
+}
+
+/// Clears the progress line, then logs and (unless quiet or hermetic) prints how the targets
+/// counted since `start` went and how long they took.
+pub(crate) fn finish(elapsed: Duration, opts: &Options) {
+    let Some(tally) = TALLY.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return;
+    };
+    let mut parts = Vec::new();
+    for (count, what) in [
+        (tally.compiled, "compiled"),
+        (tally.up_to_date, "up to date"),
+        (tally.checked, "checked"),
+        (tally.warned, "with warnings"),
+        (tally.failed, "failed"),
+        (tally.skipped, "skipped"),
+    ] {
+        if count > 0 || what == "failed" {
+            parts.push(format!("{} {}", count, what));
+        }
+    }
+    let targets = match tally.total {
+        1 => "1 target".to_string(),
+        n => format!("{} targets", n),
+    };
+    let summary = format!("{} in {:.2}s: {}", targets, elapsed.as_secs_f64(), parts.join(", "));
+    log_message(&format!("Finished {}", summary));
+
+    let mut out = String::new();
+    if tally.progress {
+        out.push_str("\r\x1b[2K");
+    }
+    if opts.hermetic.is_none() && opts.verbosity != Verbosity::Quiet {
+        let color = if tally.failed > 0 { RED } else { GREEN };
+        out.push_str(&format!("{} {}\n", status_label(color, "Finished", opts), summary));
+    }
+    print(Channel::Stdout, &out);
+}
+
+/// How one target's compile went, for its status line.
+pub(crate) enum Status<'a> {
+    Compiled,
+    UpToDate,
+    /// Expanded by `check` without writing anything.
+    Checked,
+    Failed(&'a CompileError),
+}
+
+/// Counts a target that was never started, for the closing summary.
+pub(crate) fn skip() {
+    if let Some(tally) = TALLY.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+        tally.skipped += 1;
+    }
+}
+
+/// Writes a target's captured log entries as one block, then prints its status line with any
+/// warnings and error below it (or, with `--verbose`, everything it logged), all in a single
+/// write to stdout. Targets compiled in parallel therefore never interleave, in the log or on
+/// the console. With `--quiet`, targets that went cleanly print nothing, and in hermetic mode,
+/// where stdout carries one JSON line per target, nothing is printed at all.
+pub(crate) fn flush(
+    source: &Path,
+    status: Status,
+    warnings: &[String],
+    entries: &[String],
+    opts: &Options,
+) {
+    write_log(entries);
+    let failed = matches!(status, Status::Failed(_));
+    let mut tally = TALLY.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(tally) = tally.as_mut() {
+        match status {
+            Status::Failed(_) => tally.failed += 1,
+            _ if !warnings.is_empty() => tally.warned += 1,
+            Status::Compiled => tally.compiled += 1,
+            Status::UpToDate => tally.up_to_date += 1,
+            Status::Checked => tally.checked += 1,
+        }
+    }
+    // The progress line is replaced by this target's block and drawn again below it. There is
+    // none when quiet or hermetic, where some targets print nothing.
+    let progress = tally.as_ref().filter(|tally| tally.progress).map(|tally| {
+        let done = tally.compiled + tally.up_to_date + tally.checked + tally.warned + tally.failed;
+        progress_line(tally.label, done, tally.total, opts)
+    });
+    if opts.hermetic.is_some()
+        || (opts.verbosity == Verbosity::Quiet && !failed && warnings.is_empty())
+    {
+        return;
+    }
+    let paint = |color: &str, text: &str| match opts.color {
+        true => format!("{}{}{}", color, text, RESET),
+        false => text.to_string(),
+    };
+    let (color, label) = match (&status, warnings.len()) {
+        (Status::Failed(_), _) => (RED, "Failed"),
+        (Status::UpToDate, _) => (GREEN, "Up to date"),
+        (Status::Checked, 0) => (GREEN, "Checked"),
+        (Status::Checked, _) => (YELLOW, "Checked"),
+        (Status::Compiled, 0) => (GREEN, "Compiled"),
+        (Status::Compiled, _) => (YELLOW, "Compiled"),
+    };
+    let mut block =
+        format!("{} {}", status_label(color, label, opts), relative(source, &opts.base_dir));
+    match warnings.len() {
+        0 => {}
+        1 => block.push_str(" (1 warning)"),
+        n => block.push_str(&format!(" ({} warnings)", n)),
+    }
+    block.push('\n');
+    let indent = " ".repeat(STATUS_WIDTH + 1);
+    if opts.verbosity == Verbosity::Verbose {
+        for entry in entries {
+            // Without the timestamp, which the log keeps.
+            let message = entry_message(entry);
+            block.push_str(&format!("{}{}\n", indent, message));
+        }
+    } else {
+        for warning in warnings {
+            // Most warnings are stored ready for the log, where they start with "Warning: ".
+            let warning = log_line(warning.strip_prefix("Warning: ").unwrap_or(warning));
+            block.push_str(&format!("{}{} {}\n", indent, paint(YELLOW, "warning:"), warning));
+        }
+        if let Status::Failed(err) = status {
+            let err = log_line(&err.to_string()).into_owned();
+            block.push_str(&format!("{}{} {}\n", indent, paint(RED, "error:"), err));
+        }
+    }
+    if let Some(progress) = progress {
+        block = format!("\r\x1b[2K{}{}", block, progress);
+    }
+    print(Channel::Stdout, &block);
+}
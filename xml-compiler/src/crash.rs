@@ -0,0 +1,95 @@
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::RefCell,
+    fs,
+    panic::{self, AssertUnwindSafe, PanicHookInfo},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use chrono::Local;
+use serde_json::json;
+
+use crate::{error::CompileError, log_entry, log_json, log_path, write_log};
+
+/// Written next to the log whenever something panics, so a CI job can tell a crash from an
+/// ordinary failure and find out where it happened.
+pub const CRASH_NAME: &str = "kvy-xmls-crash.json";
+
+thread_local! {
+    /// The base XML this thread is compiling, for the panic hook to name.
+    static TARGET: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Installs a panic hook that records, before anything unwinds, the base XML being compiled
+/// on the panicking thread, the message, where it panicked and a backtrace: straight into the
+/// log, past any output held for the console, and into a crash marker file next to the log.
+/// Panics outside a target also get the standard message on stderr; inside one, the target
+/// fails with the message instead and the rest of the run goes on.
+pub fn install_hook() {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let target = TARGET.with(|target| target.borrow().clone());
+        record(info, target.as_deref());
+        if target.is_none() {
+            default(info);
+        }
+    }));
+}
+
+fn record(info: &PanicHookInfo, target: Option<&Path>) {
+    let message = message(info.payload());
+    let location = info.location().map(|at| at.to_string()).unwrap_or_default();
+    let backtrace = Backtrace::force_capture().to_string();
+    let current = thread::current();
+    let thread = current.name().unwrap_or("unnamed");
+    let target_shown = target.map_or("no target".to_string(), |path| path.display().to_string());
+
+    let mut entries = vec![log_entry(&format!(
+        "Panic while compiling {} (thread {}): {} at {}",
+        target_shown, thread, message, location
+    ))];
+    entries.extend(backtrace.lines().map(|line| match log_json() {
+        true => log_entry(&format!("    {}", line)),
+        false => format!("    {}", line),
+    }));
+    write_log(&entries);
+
+    let marker = json!({
+        "time": Local::now().to_rfc3339(),
+        "target": target.map(|path| path.display().to_string()),
+        "thread": thread,
+        "message": message,
+        "location": location,
+        "backtrace": backtrace,
+    });
+    let path = log_path().with_file_name(CRASH_NAME);
+    let _ = fs::write(path, serde_json::to_string_pretty(&marker).unwrap_or_default());
+}
+
+/// The text a panic was raised with.
+fn message(payload: &(dyn Any + Send)) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+/// Runs `compile` for the base XML `file`, naming it to the panic hook, and turns a panic into
+/// a failure of this target so that the other targets still build and the run still reports.
+pub(crate) fn guarded<T>(
+    file: &Path,
+    compile: impl FnOnce() -> Result<T, CompileError>,
+) -> Result<T, CompileError> {
+    let outer = TARGET.with(|target| target.replace(Some(file.to_path_buf())));
+    let result = panic::catch_unwind(AssertUnwindSafe(compile));
+    TARGET.with(|target| *target.borrow_mut() = outer);
+    result.unwrap_or_else(|payload| {
+        Err(CompileError::Panic {
+            path: file.to_path_buf(),
+            message: message(payload.as_ref()),
+        })
+    })
+}
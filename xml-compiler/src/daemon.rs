@@ -0,0 +1,225 @@
+#[cfg(not(unix))]
+use std::net::{TcpListener as Listener, TcpStream as Stream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener as Listener, UnixStream as Stream};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    auth::{Scope, Tokens, TOKEN_ENV},
+    console::{self, Channel},
+    log_message,
+    options::Options,
+    run,
+};
+
+/// File name of the daemon's socket in the user's runtime (or home) directory.
+#[cfg(unix)]
+const SOCKET_NAME: &str = ".kiwixml-daemon.sock";
+
+/// Where the daemon listens unless `--addr` is given: a socket only the user can open, in
+/// `$XDG_RUNTIME_DIR` or else the home directory.
+#[cfg(unix)]
+pub fn default_addr() -> String {
+    let dir = env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .or_else(|| env::var_os("HOME"))
+        .map_or_else(env::temp_dir, PathBuf::from);
+    dir.join(SOCKET_NAME).display().to_string()
+}
+
+/// Where the daemon listens unless `--addr` is given: a local port, which `bind` only opens to
+/// token holders.
+#[cfg(not(unix))]
+pub fn default_addr() -> String {
+    "127.0.0.1:47813".to_string()
+}
+
+/// Keeps the process (and with it the compiled regexes and source cache) alive, compiling one
+/// request per connection. Requests are handled in order; each compile is itself parallel.
+/// With `tokens`, only callers holding a `compile` token are served.
+pub fn serve(addr: &str, tokens: Option<&Tokens>) -> Result<()> {
+    let listener = bind(addr, tokens)?;
+    log_message(&format!("Daemon listening on {}", addr));
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = handle(stream, tokens) {
+                    log_message(&format!("Daemon request failed: {}", err));
+                }
+            }
+            Err(err) => log_message(&format!("Daemon connection error: {}", err)),
+        }
+    }
+    Ok(())
+}
+
+/// Binds the socket at `path` so that only the user running the daemon can connect: a request
+/// compiles, and writes, anywhere that user can. The socket is made in a private folder and
+/// moved into place once its permissions are set, so it is never open to others, not even
+/// briefly. One left behind by a daemon that is gone is replaced.
+#[cfg(unix)]
+fn bind(path: &str, _tokens: Option<&Tokens>) -> Result<Listener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    let path = Path::new(path);
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", path.display()));
+        }
+        if Stream::connect(path).is_ok() {
+            return Err(anyhow!("A daemon is already listening on {}", path.display()));
+        }
+        fs::remove_file(path)?;
+    }
+    let staging = path.with_extension(format!("{}.tmp", process::id()));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("Failed to bind {}", path.display()))?;
+    let staged = staging.join("socket");
+    let bound = Listener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    bound.with_context(|| format!("Failed to bind {}", path.display()))
+}
+
+/// Binds the local port `addr`. Every user of the machine can reach it, so the daemon only
+/// starts with tokens to check callers against.
+#[cfg(not(unix))]
+fn bind(addr: &str, tokens: Option<&Tokens>) -> Result<Listener> {
+    if tokens.is_none() {
+        return Err(anyhow!(
+            "--daemon needs --tokens, or a [tokens] table in kvy-xmls.toml, on this platform"
+        ));
+    }
+    Listener::bind(addr).with_context(|| format!("Failed to bind {}", addr))
+}
+
+/// Largest frame a request or reply may carry, so a stray connection can't make the daemon
+/// allocate without bound.
+const MAX_FRAME: usize = 64 << 20;
+
+/// Writes one frame: a `<kind> <length>` line, then that many bytes of `body`.
+fn write_frame(out: &mut impl Write, kind: &str, body: &str) -> io::Result<()> {
+    writeln!(out, "{} {}", kind, body.len())?;
+    out.write_all(body.as_bytes())
+}
+
+/// Reads one frame [`write_frame`] wrote, or `None` where the stream ends.
+fn read_frame(input: &mut impl BufRead) -> Result<Option<(String, String)>> {
+    let mut header = String::new();
+    if input.by_ref().take(64).read_line(&mut header)? == 0 {
+        return Ok(None);
+    }
+    let malformed = || anyhow!("Malformed frame: {:?}", header);
+    let (kind, len) = header.trim_end_matches(['\r', '\n']).split_once(' ').ok_or_else(malformed)?;
+    let len = len.parse().ok().filter(|len| *len <= MAX_FRAME).ok_or_else(malformed)?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    let body = String::from_utf8(body).map_err(|_| anyhow!("The {} frame is not UTF-8", kind))?;
+    Ok(Some((kind.to_string(), body)))
+}
+
+/// Wire format: frames both ways (see [`write_frame`]), so arguments, variables and messages
+/// travel as they are, line breaks and all. The client sends an optional `auth` frame with its
+/// token, its `cwd`, an `arg` frame per compile argument and an `env` frame (`NAME=VALUE`) per
+/// environment variable, then `run`. The daemon answers with what the run printed, as `stdout`
+/// and `stderr` frames in the order it was printed, and last `ok` with the output directory or
+/// `error` with the message.
+fn handle(stream: Stream, tokens: Option<&Tokens>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    let (mut token, mut cwd, mut args, mut env) = (None, None, Vec::new(), BTreeMap::new());
+    loop {
+        let frame = read_frame(&mut reader)?;
+        let (kind, body) = frame.ok_or_else(|| anyhow!("Request ended before it was run"))?;
+        match kind.as_str() {
+            "auth" => token = Some(body),
+            "cwd" => cwd = Some(body).filter(|cwd| !cwd.is_empty()),
+            "arg" => args.push(body),
+            "env" => {
+                let (name, value) = body
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Malformed environment variable: {:?}", body))?;
+                env.insert(name.to_string(), value.to_string());
+            }
+            "run" => break,
+            _ => return Err(anyhow!("Malformed request: unknown {:?} frame", kind)),
+        }
+    }
+    if let Some(tokens) = tokens
+        && !tokens.allows(token.as_deref(), Scope::Compile)
+    {
+        write_frame(&mut stream, "error", "unauthorized: a compile token is required")?;
+        return Err(anyhow!("Rejected request without a valid compile token"));
+    }
+    let cwd = cwd.ok_or_else(|| anyhow!("Malformed request: no cwd"))?;
+
+    let (result, printed) = console::redirect(|| {
+        Options::parse_in(&args, Path::new(&cwd), &env)
+            .and_then(|opts| run(&opts).map(|_| opts.output_dir))
+    });
+    for (channel, text) in printed {
+        let kind = match channel {
+            Channel::Stdout => "stdout",
+            Channel::Stderr => "stderr",
+        };
+        write_frame(&mut stream, kind, &text)?;
+    }
+    match result {
+        Ok(output_dir) => write_frame(&mut stream, "ok", &output_dir.display().to_string())?,
+        Err(err) => write_frame(&mut stream, "error", &format!("{:#}", err))?,
+    }
+    Ok(())
+}
+
+/// Thin client: forwards the compile request, with this process's environment, to a running
+/// daemon, then prints what the daemon's run printed and relays its answer.
+pub fn forward(addr: &str, args: &[String]) -> Result<()> {
+    let cwd = env::current_dir()?;
+    let mut stream = Stream::connect(addr)
+        .with_context(|| format!("No daemon reachable at {} (start one with --daemon)", addr))?;
+    let mut request = Vec::new();
+    if let Ok(token) = env::var(TOKEN_ENV) {
+        write_frame(&mut request, "auth", &token)?;
+    }
+    write_frame(&mut request, "cwd", &cwd.display().to_string())?;
+    for arg in args {
+        write_frame(&mut request, "arg", arg)?;
+    }
+    // Variables that aren't valid Unicode can't be `--env-prefix` values here either.
+    for (name, value) in env::vars_os() {
+        if let (Some(name), Some(value)) = (name.to_str(), value.to_str()) {
+            write_frame(&mut request, "env", &format!("{}={}", name, value))?;
+        }
+    }
+    write_frame(&mut request, "run", "")?;
+    stream.write_all(&request)?;
+
+    let mut reader = BufReader::new(stream);
+    while let Some((kind, body)) = read_frame(&mut reader)? {
+        match kind.as_str() {
+            "stdout" => io::stdout().lock().write_all(body.as_bytes())?,
+            "stderr" => io::stderr().lock().write_all(body.as_bytes())?,
+            "ok" => {
+                println!("Compiled XMLs saved in {}", body);
+                return Ok(());
+            }
+            "error" => return Err(anyhow!("Daemon reported: {}", body)),
+            _ => return Err(anyhow!("Unexpected daemon reply: {:?} frame", kind)),
+        }
+    }
+    Err(anyhow!("The daemon closed the connection without answering"))
+}
@@ -0,0 +1,308 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Why a target failed to compile. Each variant carries the file it concerns, so callers can
+/// tell failure kinds apart without parsing messages; `Display` matches what gets logged.
+#[derive(Debug)]
+pub enum CompileError {
+    /// Reading a source or writing an output failed. Displays as the underlying error alone,
+    /// since log lines already name the file.
+    Io { path: PathBuf, source: io::Error },
+    /// A source is not well-formed XML (`--engine parser`).
+    Malformed {
+        path: PathBuf,
+        position: u64,
+        message: String,
+    },
+    /// A directive is somewhere `--inline-includes error` rejects.
+    InvalidDirective {
+        path: PathBuf,
+        line: usize,
+        reason: &'static str,
+    },
+    /// A `kvy-xmls.toml` file can't be read as configuration.
+    Config { path: PathBuf, message: String },
+    /// An `#if flag="..."` conditional is unbalanced or malformed.
+    Conditional {
+        path: PathBuf,
+        line: usize,
+        reason: &'static str,
+    },
+    /// An `#extends` target's `#block` directives are unbalanced or malformed.
+    Block {
+        path: PathBuf,
+        line: usize,
+        reason: &'static str,
+    },
+    /// An `<xi:include>` element asks for something `--xinclude` doesn't do.
+    XInclude {
+        path: PathBuf,
+        line: usize,
+        reason: &'static str,
+    },
+    /// Compiling the target panicked; the message is the panic's.
+    Panic {
+        path: PathBuf,
+        message: String,
+    },
+    /// An operation in a target's `--patches` file is malformed or its selector doesn't pick
+    /// exactly one node.
+    Patch {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+    /// Includes were left unresolved where that is not allowed (hermetic mode), with the
+    /// warning logged for each.
+    Unresolved { path: PathBuf, warnings: Vec<String> },
+    /// The output would differ in size from the previous build by more than
+    /// `--max-size-change` allows, and `--fail-on-size-change` is set. The old output is kept.
+    SizeChange {
+        path: PathBuf,
+        previous: u64,
+        current: u64,
+    },
+    /// An include pins a version range the fragment doesn't declare a matching version for.
+    VersionMismatch {
+        path: PathBuf,
+        required: String,
+        declared: Option<String>,
+    },
+    /// `--locked` is set and an included fragment doesn't match the lockfile.
+    Locked { path: PathBuf, reason: &'static str },
+    /// The source looks like a compiled output (see `--compiled-sources`).
+    CompiledSource { path: PathBuf, reason: &'static str },
+    /// A text pass over one file ran longer than `--pass-timeout`.
+    SlowPass {
+        path: PathBuf,
+        pass: &'static str,
+        elapsed: Duration,
+    },
+    /// `--fail-on-undefined-vars` is set and a source uses a variable with no value.
+    UndefinedVariable {
+        path: PathBuf,
+        line: usize,
+        name: String,
+    },
+    /// `--fail-on-pii` is set and the output contains what looks like personal data, with the
+    /// masked finding for each. The old output is kept.
+    Pii { path: PathBuf, findings: Vec<String> },
+    /// A fragment includes itself, directly or through others. `chain` runs from the target
+    /// down to the include that closes the cycle; `path` is the file containing that include.
+    Cycle { path: PathBuf, chain: Vec<PathBuf> },
+    /// Includes nest more than `--max-include-depth` levels deep below the target; `path` is
+    /// the file containing the include that went too deep.
+    IncludeDepth { path: PathBuf, limit: usize },
+    /// The target's includes add up to more than `--max-expanded-size` bytes; `path` is the
+    /// file containing the include that went over.
+    ExpandedSize { path: PathBuf, limit: u64 },
+    /// The existing output no longer matches what KiwiXML last wrote there, so someone edited
+    /// it by hand. It is kept unless `--force` is given.
+    HandEdited { path: PathBuf },
+    /// `--verify-deterministic` compiled the target a second time and got different bytes.
+    Nondeterministic {
+        path: PathBuf,
+        first: String,
+        second: String,
+    },
+}
+
+impl CompileError {
+    /// The file the failure concerns.
+    pub fn path(&self) -> &Path {
+        match self {
+            CompileError::Io { path, .. }
+            | CompileError::Malformed { path, .. }
+            | CompileError::InvalidDirective { path, .. }
+            | CompileError::Unresolved { path, .. }
+            | CompileError::SizeChange { path, .. }
+            | CompileError::SlowPass { path, .. }
+            | CompileError::VersionMismatch { path, .. }
+            | CompileError::CompiledSource { path, .. }
+            | CompileError::Config { path, .. }
+            | CompileError::Conditional { path, .. }
+            | CompileError::Block { path, .. }
+            | CompileError::XInclude { path, .. }
+            | CompileError::Patch { path, .. }
+            | CompileError::Panic { path, .. }
+            | CompileError::UndefinedVariable { path, .. }
+            | CompileError::Pii { path, .. }
+            | CompileError::Locked { path, .. }
+            | CompileError::Cycle { path, .. }
+            | CompileError::IncludeDepth { path, .. }
+            | CompileError::ExpandedSize { path, .. }
+            | CompileError::HandEdited { path }
+            | CompileError::Nondeterministic { path, .. } => path,
+        }
+    }
+
+    /// Stable name of the variant, for machine-readable reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CompileError::Io { .. } => "io",
+            CompileError::Malformed { .. } => "malformed",
+            CompileError::InvalidDirective { .. } => "invalid_directive",
+            CompileError::Unresolved { .. } => "unresolved",
+            CompileError::SizeChange { .. } => "size_change",
+            CompileError::SlowPass { .. } => "slow_pass",
+            CompileError::VersionMismatch { .. } => "version_mismatch",
+            CompileError::CompiledSource { .. } => "compiled_source",
+            CompileError::Config { .. } => "config",
+            CompileError::Conditional { .. } => "conditional",
+            CompileError::Block { .. } => "block",
+            CompileError::XInclude { .. } => "xinclude",
+            CompileError::Patch { .. } => "patch",
+            CompileError::Panic { .. } => "panic",
+            CompileError::UndefinedVariable { .. } => "undefined_variable",
+            CompileError::Pii { .. } => "pii",
+            CompileError::Locked { .. } => "locked",
+            CompileError::Cycle { .. } => "cycle",
+            CompileError::IncludeDepth { .. } => "include_depth",
+            CompileError::ExpandedSize { .. } => "expanded_size",
+            CompileError::HandEdited { .. } => "hand_edited",
+            CompileError::Nondeterministic { .. } => "nondeterministic",
+        }
+    }
+
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> CompileError {
+        let path = path.into();
+        move |source| CompileError::Io { path, source }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Io { source, .. } => write!(f, "{}", source),
+            CompileError::Malformed {
+                path,
+                position,
+                message,
+            } => write!(f, "{}: XML error at byte {}: {}", path.display(), position, message),
+            CompileError::InvalidDirective { path, line, reason } => write!(
+                f,
+                "{}:{}: include directive {} (see --inline-includes)",
+                path.display(),
+                line,
+                reason
+            ),
+            CompileError::Config { path, message } => write!(f, "{}: {}", path.display(), message),
+            CompileError::Conditional { path, line, reason } => {
+                write!(f, "{}:{}: conditional directive {}", path.display(), line, reason)
+            }
+            CompileError::Block { path, line, reason } => {
+                write!(f, "{}:{}: block directive {}", path.display(), line, reason)
+            }
+            CompileError::XInclude { path, line, reason } => {
+                write!(f, "{}:{}: xi:include {}", path.display(), line, reason)
+            }
+            CompileError::Panic { path, message } => {
+                write!(f, "{}: internal error: {}", path.display(), message)
+            }
+            CompileError::Patch { path, line, reason } => {
+                write!(f, "{}:{}: patch {}", path.display(), line, reason)
+            }
+            CompileError::Unresolved { warnings, .. } => {
+                write!(f, "{} unresolved include(s)", warnings.len())
+            }
+            CompileError::SizeChange {
+                previous, current, ..
+            } => write!(
+                f,
+                "output would go from {} to {} bytes ({:+.1}%), beyond --max-size-change",
+                previous,
+                current,
+                size_change(*previous, *current)
+            ),
+            CompileError::VersionMismatch {
+                path,
+                required,
+                declared,
+            } => write!(
+                f,
+                "version {} of {} is required, but it declares {}",
+                required,
+                path.display(),
+                declared.as_deref().map_or("no version".to_string(), |v| format!("version {}", v))
+            ),
+            CompileError::CompiledSource { reason, .. } => {
+                write!(f, "source looks compiled ({}), see --compiled-sources", reason)
+            }
+            CompileError::Locked { path, reason } => {
+                write!(f, "{} {}, see --locked", path.display(), reason)
+            }
+            CompileError::UndefinedVariable { path, line, name } => write!(
+                f,
+                "{}:{}: variable {} has no value, see --fail-on-undefined-vars",
+                path.display(),
+                line,
+                name
+            ),
+            CompileError::Pii { findings, .. } => {
+                write!(f, "{} possible personal data match(es), see --fail-on-pii", findings.len())
+            }
+            CompileError::SlowPass {
+                path,
+                pass,
+                elapsed,
+            } => write!(
+                f,
+                "{}: {} took {:.1}s, over --pass-timeout",
+                path.display(),
+                pass,
+                elapsed.as_secs_f64()
+            ),
+            CompileError::Cycle { chain, .. } => {
+                // Named relative to the target's folder, as they are written in directives.
+                let dir = chain.first().and_then(|target| target.parent());
+                let names: Vec<String> = chain
+                    .iter()
+                    .map(|path| {
+                        let shown = dir.and_then(|dir| path.strip_prefix(dir).ok());
+                        shown.unwrap_or(path).display().to_string()
+                    })
+                    .collect();
+                write!(f, "include cycle: {}", names.join(" -> "))
+            }
+            CompileError::IncludeDepth { path, limit } => write!(
+                f,
+                "{}: includes nest more than {} levels deep, over --max-include-depth",
+                path.display(),
+                limit
+            ),
+            CompileError::ExpandedSize { path, limit } => write!(
+                f,
+                "{}: includes add up to more than {} bytes, over --max-expanded-size",
+                path.display(),
+                limit
+            ),
+            CompileError::HandEdited { path } => write!(
+                f,
+                "{} was edited since it was last compiled; rerun with --force to overwrite it",
+                path.display()
+            ),
+            CompileError::Nondeterministic { first, second, .. } => write!(
+                f,
+                "compiling twice gave different outputs (sha256 {} then {})",
+                first, second
+            ),
+        }
+    }
+}
+
+/// Percentage by which `current` differs from `previous`.
+pub fn size_change(previous: u64, current: u64) -> f64 {
+    (current as f64 - previous as f64) / previous as f64 * 100.0
+}
+
+impl std::error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompileError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
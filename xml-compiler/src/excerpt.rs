@@ -0,0 +1,67 @@
+use std::borrow::Cow;
+
+use unicode_normalization::char::is_combining_mark;
+
+/// Marks where text was cut.
+const ELLIPSIS: &str = "…";
+
+/// `text` cut to at most `max` bytes, `…` included, when it is longer. Cuts fall between user
+/// perceived characters, so neither a UTF-8 sequence nor an accent, emoji sequence or flag is
+/// ever split, and a viewer that truncates bytes itself has nothing left to break.
+pub fn truncate(text: &str, max: usize) -> Cow<'_, str> {
+    if text.len() <= max {
+        return Cow::Borrowed(text);
+    }
+    let end = boundary_before(text, max.saturating_sub(ELLIPSIS.len()));
+    Cow::Owned(format!("{}{}", &text[..end], ELLIPSIS))
+}
+
+/// Like [`truncate`], but keeps the start and the end and cuts the middle, for paths and log
+/// lines whose end (the file name, the reason) matters as much as their start.
+pub fn truncate_middle(text: &str, max: usize) -> Cow<'_, str> {
+    if text.len() <= max {
+        return Cow::Borrowed(text);
+    }
+    let budget = max.saturating_sub(ELLIPSIS.len());
+    let head = boundary_before(text, budget - budget / 2);
+    let tail = boundary_after(text, text.len() - budget / 2);
+    Cow::Owned(format!("{}{}{}", &text[..head], ELLIPSIS, &text[tail..]))
+}
+
+/// The last cluster boundary at or before `at`.
+pub(crate) fn boundary_before(text: &str, at: usize) -> usize {
+    let mut at = text.floor_char_boundary(at);
+    while at > 0 && joined(text, at) {
+        at = text.floor_char_boundary(at - 1);
+    }
+    at
+}
+
+/// The first cluster boundary at or after `at`.
+pub(crate) fn boundary_after(text: &str, at: usize) -> usize {
+    let mut at = text.ceil_char_boundary(at);
+    while at < text.len() && joined(text, at) {
+        at = text.ceil_char_boundary(at + 1);
+    }
+    at
+}
+
+/// Whether the characters either side of `at` belong to one cluster. An approximation of
+/// Unicode's segmentation rules covering what shows up in practice: combining marks, variation
+/// selectors, emoji modifiers and zero-width joiner sequences, flag pairs, and CR LF.
+fn joined(text: &str, at: usize) -> bool {
+    let (Some(before), Some(after)) = (text[..at].chars().next_back(), text[at..].chars().next())
+    else {
+        return false;
+    };
+    let regional = |c: char| ('\u{1F1E6}'..='\u{1F1FF}').contains(&c);
+    let extends = is_combining_mark(after)
+        || matches!(after, '\u{200C}' | '\u{200D}' | '\u{FE00}'..='\u{FE0F}')
+        || ('\u{1F3FB}'..='\u{1F3FF}').contains(&after)
+        || ('\u{E0020}'..='\u{E007F}').contains(&after);
+    // Regional indicators pair up from the start of their run.
+    let flag = regional(before)
+        && regional(after)
+        && text[..at].chars().rev().take_while(|&c| regional(c)).count() % 2 == 1;
+    extends || before == '\u{200D}' || flag || (before == '\r' && after == '\n')
+}
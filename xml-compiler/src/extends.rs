@@ -0,0 +1,215 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::{
+    error::CompileError,
+    load_source, log_message,
+    options::{self, Options},
+    xinclude, Expansion, LineCounter,
+};
+
+/// `<!-- #extends file="..." -->`, naming the document a target overrides blocks of.
+static EXTENDS_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"<!--\s*#extends\s+file="([^"]*)"\s*-->"#).unwrap());
+
+/// `<!-- #block name -->` and `<!-- #endblock -->`, with the indentation before and line break
+/// after them when they are alone on their line.
+static BLOCK_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?m)(^[ \t]*)?<!--\s*#(block|endblock)\b\s*([\w.-]*)\s*-->([ \t]*\r?\n)?").unwrap()
+});
+
+/// A named block, from the start of its `#block` line to the end of its `#endblock` line.
+struct Block {
+    name: String,
+    outer: Range<usize>,
+    inner: Range<usize>,
+}
+
+/// `content` of the target at `file` merged into the document its `#extends` names, if it has
+/// one: the base document, with each block the target also defines replaced by the target's
+/// version. Everything in the target outside its blocks is ignored. A base may extend another
+/// in turn. Block markers are removed from the result, so a base can be a target itself.
+pub(crate) fn apply<'a>(
+    file: &Path,
+    content: &'a str,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<Cow<'a, str>, CompileError> {
+    let merged = merge(file, content, opts, exp, &mut vec![options::lexical(file)])?;
+    if !BLOCK_RE.is_match(&merged) {
+        return Ok(merged);
+    }
+    let stripped = BLOCK_RE.replace_all(&merged, |caps: &regex::Captures| {
+        match (caps.get(1), caps.get(4)) {
+            (Some(_), Some(_)) => String::new(),
+            // Not alone on its line, so what surrounds it on the line stays.
+            (indent, line_break) => {
+                [indent, line_break].into_iter().flatten().map(|m| m.as_str()).collect()
+            }
+        }
+    });
+    Ok(Cow::Owned(stripped.into_owned()))
+}
+
+/// `content` merged into its base, keeping block markers so that a document extending this one
+/// can still override them. `chain` holds the documents being merged, the target first.
+fn merge<'a>(
+    file: &Path,
+    content: &'a str,
+    opts: &Options,
+    exp: &mut Expansion,
+    chain: &mut Vec<PathBuf>,
+) -> Result<Cow<'a, str>, CompileError> {
+    let Some(caps) = EXTENDS_RE.captures(content) else {
+        return Ok(Cow::Borrowed(content));
+    };
+    let dir = file.parent().unwrap_or_else(|| Path::new("."));
+    let base_path = options::lexical(&dir.join(&caps[1]));
+    if chain.contains(&base_path) {
+        let mut chain = chain.clone();
+        chain.push(base_path);
+        return Err(CompileError::Cycle {
+            path: file.to_path_buf(),
+            chain,
+        });
+    }
+    let base = load_source(&base_path, opts, exp)?;
+    let base = xinclude::apply(&base_path, &base, opts, exp)?;
+    chain.push(base_path.clone());
+    let base = merge(&base_path, &base, opts, exp, chain)?;
+    chain.pop();
+    let base = anchor(&base, &base_path, opts);
+
+    let mut overrides = BTreeMap::new();
+    for block in blocks(content, file)? {
+        if overrides.insert(block.name.clone(), &content[block.inner]).is_some() {
+            return Err(CompileError::Block {
+                path: file.to_path_buf(),
+                line: LineCounter::new(content).line(block.outer.start),
+                reason: "is defined twice",
+            });
+        }
+    }
+    let base_blocks = blocks(&base, &base_path)?;
+    for name in overrides.keys() {
+        if !base_blocks.iter().any(|block| &block.name == name) {
+            exp.warn(format!(
+                "Warning: {}: block {} is not in {}",
+                file.display(),
+                name,
+                base_path.display()
+            ));
+        }
+    }
+
+    log_message(&format!("Extends: {}", base_path.display()));
+    exp.includes.push(base_path.clone());
+    exp.edges.push((file.to_path_buf(), base_path));
+    let mut out = String::with_capacity(base.len());
+    fill(&base, 0..base.len(), &base_blocks, &overrides, &mut out);
+    Ok(Cow::Owned(out))
+}
+
+/// Appends `range` of `content` to `out`, with the blocks in it (`blocks`, in document order)
+/// replaced by their overrides, and the blocks within those that aren't overridden filled the
+/// same way.
+fn fill(
+    content: &str,
+    range: Range<usize>,
+    blocks: &[Block],
+    overrides: &BTreeMap<String, &str>,
+    out: &mut String,
+) {
+    let mut copied = range.start;
+    let mut next = 0;
+    while let Some(block) = blocks.get(next) {
+        let nested = blocks[next + 1..]
+            .iter()
+            .take_while(|inner| inner.outer.start < block.outer.end)
+            .count();
+        out.push_str(&content[copied..block.inner.start]);
+        match overrides.get(&block.name) {
+            Some(text) => out.push_str(text),
+            None => {
+                let nested = &blocks[next + 1..][..nested];
+                fill(content, block.inner.clone(), nested, overrides, out)
+            }
+        }
+        out.push_str(&content[block.inner.end..block.outer.end]);
+        copied = block.outer.end;
+        next += 1 + nested;
+    }
+    out.push_str(&content[copied..range.end]);
+}
+
+/// The blocks in `content`, in document order, nested ones after the block around them.
+fn blocks(content: &str, path: &Path) -> Result<Vec<Block>, CompileError> {
+    let mut found = Vec::new();
+    let mut open: Vec<(String, usize, usize)> = Vec::new();
+    let mut lines = LineCounter::new(content);
+    for caps in BLOCK_RE.captures_iter(content) {
+        let whole = caps.get(0).unwrap();
+        let fail = |lines: &mut LineCounter, reason| CompileError::Block {
+            path: path.to_path_buf(),
+            line: lines.line(whole.start()),
+            reason,
+        };
+        match (&caps[2], &caps[3]) {
+            ("block", "") => return Err(fail(&mut lines, "needs a name")),
+            ("block", name) => open.push((name.to_string(), whole.start(), whole.end())),
+            (_, _) => {
+                let Some((name, start, inner_start)) = open.pop() else {
+                    return Err(fail(&mut lines, "has no matching #block"));
+                };
+                found.push(Block {
+                    name,
+                    outer: start..whole.end(),
+                    inner: inner_start..whole.start(),
+                });
+            }
+        }
+    }
+    if let Some(&(_, start, _)) = open.first() {
+        return Err(CompileError::Block {
+            path: path.to_path_buf(),
+            line: lines.line(start),
+            reason: "has no matching #endblock",
+        });
+    }
+    found.sort_by_key(|block| block.outer.start);
+    Ok(found)
+}
+
+/// `content` from `file` with the relative paths of its `file="..."` includes made absolute,
+/// so they still resolve once the content is part of a document elsewhere.
+pub(crate) fn anchor<'a>(content: &'a str, file: &Path, opts: &Options) -> Cow<'a, str> {
+    let dir = file.parent().unwrap_or_else(|| Path::new("."));
+    let mut out = String::new();
+    let mut copied = 0;
+    for caps in opts.directives.captures_iter(content) {
+        let Some(path) = (1..caps.len()).step_by(3).find_map(|group| {
+            let kind = caps.get(group)?;
+            (kind.as_str() == "file").then(|| caps.get(group + 1)).flatten()
+        }) else {
+            continue;
+        };
+        if Path::new(path.as_str().trim()).is_absolute() {
+            continue;
+        }
+        out.push_str(&content[copied..path.start()]);
+        out.push_str(&options::lexical(&dir.join(path.as_str().trim())).display().to_string());
+        copied = path.end();
+    }
+    if copied == 0 {
+        return Cow::Borrowed(content);
+    }
+    out.push_str(&content[copied..]);
+    Cow::Owned(out)
+}
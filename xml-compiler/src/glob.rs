@@ -0,0 +1,176 @@
+use std::{
+    borrow::Cow,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+
+use crate::{
+    blank_line_end, blank_line_start,
+    options::{lexical, Options},
+    vendor, Expansion,
+};
+
+/// An include directive's path pattern, and the files it matched when the target was built.
+#[derive(Clone, Debug)]
+pub(crate) struct Glob {
+    /// The folder the pattern is relative to.
+    pub dir: PathBuf,
+    pub pattern: String,
+    /// The file the directive is in, which never matches its own pattern.
+    pub from: PathBuf,
+    pub matches: Vec<PathBuf>,
+}
+
+/// Whether an include path is a pattern rather than a file.
+fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?'])
+}
+
+/// `content` of the file at `path` with each include directive whose path has a wildcard
+/// replaced by one directive per matching file, in sorted order and with the same attributes.
+/// On its own line, each directive gets a line of its own. `*` and `?` match within a folder
+/// name and `**` any number of folders; hidden files only match a pattern starting with `.`.
+/// The patterns used, with what they matched, are added to `exp.globs` when `record` is set,
+/// along with a warning for each that matched nothing.
+pub(crate) fn expand<'a>(
+    path: &Path,
+    content: &'a str,
+    opts: &Options,
+    exp: &mut Expansion,
+    record: bool,
+) -> Cow<'a, str> {
+    if !content.contains(['*', '?']) {
+        return Cow::Borrowed(content);
+    }
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    // A vendored file resolves its includes from where it was copied from.
+    let original = vendor::original(dir, opts);
+    let dir = original.as_deref().unwrap_or(dir);
+
+    let mut out = String::new();
+    let mut copied = 0;
+    for caps in opts.directives.captures_iter(content) {
+        let Some((kind, pattern)) = (1..caps.len()).step_by(3).find_map(|group| {
+            Some((caps.get(group)?.as_str(), caps.get(group + 1)?))
+        }) else {
+            continue;
+        };
+        let text = pattern.as_str().trim();
+        if !is_pattern(text) {
+            continue;
+        }
+        let (start, text) = match kind {
+            "virtual" => (opts.base_dir.as_path(), text.trim_start_matches(['/', '\\'])),
+            _ => (dir, text),
+        };
+        let matches = matching(start, text, path);
+        let whole = caps.get(0).unwrap();
+        out.push_str(&content[copied..whole.start()]);
+        copied = whole.end();
+        if record {
+            if matches.is_empty() {
+                exp.warn(format!("Warning: {}: no files match {}", path.display(), text));
+            }
+            exp.globs.push(Glob {
+                dir: start.to_path_buf(),
+                pattern: text.to_string(),
+                from: lexical(path),
+                matches: matches.clone(),
+            });
+        }
+        if matches.is_empty() {
+            out.push_str(&format!("<!-- No files match: {} -->", text));
+            continue;
+        }
+
+        let own_line = blank_line_start(content, whole.start()).is_some()
+            && blank_line_end(content, whole.end()).is_some();
+        let separator = match (own_line, blank_line_start(content, whole.start())) {
+            (true, Some(line)) => format!("\n{}", &content[line..whole.start()]),
+            _ => String::new(),
+        };
+        let directives: Vec<String> = matches
+            .iter()
+            .map(|found| {
+                let relative = found.strip_prefix(start).unwrap_or(found);
+                let shown = relative.to_string_lossy().replace('\\', "/");
+                format!(
+                    "{}{}{}",
+                    &content[whole.start()..pattern.start()],
+                    shown,
+                    &content[pattern.end()..whole.end()]
+                )
+            })
+            .collect();
+        out.push_str(&directives.join(&separator));
+    }
+    if copied == 0 {
+        return Cow::Borrowed(content);
+    }
+    out.push_str(&content[copied..]);
+    Cow::Owned(out)
+}
+
+/// The files under `dir` matching `pattern`, sorted, leaving out `from`.
+pub(crate) fn matching(dir: &Path, pattern: &str, from: &Path) -> Vec<PathBuf> {
+    let parts: Vec<&str> =
+        pattern.split(['/', '\\']).filter(|part| !part.is_empty() && *part != ".").collect();
+    let mut found = Vec::new();
+    walk(dir, &parts, &mut found);
+    let from = lexical(from);
+    let mut found: Vec<PathBuf> =
+        found.into_iter().map(|path| lexical(&path)).filter(|path| *path != from).collect();
+    found.sort();
+    found.dedup();
+    found
+}
+
+fn walk(dir: &Path, parts: &[&str], found: &mut Vec<PathBuf>) {
+    let Some((&part, rest)) = parts.split_first() else {
+        if dir.is_file() {
+            found.push(dir.to_path_buf());
+        }
+        return;
+    };
+    if !is_pattern(part) {
+        return walk(&dir.join(part), rest, found);
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let name_re = (part != "**").then(|| component_regex(part));
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !part.starts_with('.') {
+            continue;
+        }
+        match &name_re {
+            Some(re) if re.is_match(&name) => walk(&entry.path(), rest, found),
+            Some(_) => {}
+            // `**` goes down into every folder, symlinks aside, and also matches no folder.
+            None if entry.file_type().is_ok_and(|kind| kind.is_dir()) => {
+                walk(&entry.path(), parts, found)
+            }
+            None => {}
+        }
+    }
+    if name_re.is_none() {
+        walk(dir, rest, found);
+    }
+}
+
+/// A regex matching a whole folder or file name against one part of a pattern.
+fn component_regex(part: &str) -> Regex {
+    let mut re = String::from("^");
+    for c in part.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).unwrap()
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::BTreeSet,
+    env,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    expand_targets,
+    options::{relative, Options},
+};
+
+/// Include graph of one revision, with paths relative to its base directory.
+#[derive(Default)]
+struct Graph {
+    fragments: BTreeSet<String>,
+    edges: BTreeSet<(String, String)>,
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .context("Cannot run git")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// A detached checkout of one revision in the temp directory, removed again when dropped.
+struct Checkout {
+    repo: PathBuf,
+    dir: PathBuf,
+}
+
+impl Checkout {
+    fn new(repo: &Path, rev: &str, label: &str) -> Result<Checkout> {
+        let commit = git(repo, &["rev-parse", "--verify", &format!("{}^{{commit}}", rev)])
+            .map_err(|_| anyhow!("Unknown revision: {}", rev))?;
+        let dir = env::temp_dir().join(format!("kiwixml-graph-{}-{}", process::id(), label));
+        let _ = fs::remove_dir_all(&dir);
+        git(
+            repo,
+            &["worktree", "add", "--detach", "--quiet", &dir.to_string_lossy(), &commit],
+        )?;
+        Ok(Checkout {
+            repo: repo.to_path_buf(),
+            dir,
+        })
+    }
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        let dir = self.dir.to_string_lossy();
+        if git(&self.repo, &["worktree", "remove", "--force", &dir]).is_err() {
+            let _ = fs::remove_dir_all(&self.dir);
+            let _ = git(&self.repo, &["worktree", "prune"]);
+        }
+    }
+}
+
+/// Checks out `rev` and expands every target in it with `args`, as if run from the same
+/// directory of that checkout.
+fn load(repo: &Path, prefix: &Path, rev: &str, label: &str, args: &[String]) -> Result<Graph> {
+    let checkout = Checkout::new(repo, rev, label)?;
+    let opts = Options::parse(args, &checkout.dir.join(prefix))?;
+    let mut graph = Graph::default();
+    for (_, exp) in expand_targets(&opts).with_context(|| format!("At {}", rev))? {
+        for (from, to) in &exp.edges {
+            let to = relative(to, &opts.base_dir);
+            graph.fragments.insert(to.clone());
+            graph.edges.insert((relative(from, &opts.base_dir), to));
+        }
+    }
+    Ok(graph)
+}
+
+/// Expands the targets of revisions `from` and `to` of the git repository around `cwd`, and
+/// lists as Markdown the fragments and include edges one has and the other doesn't. `args` are
+/// the usual compile options; relative paths in them resolve in each checkout, so the base
+/// directory is the same folder at both revisions. The working tree is not touched.
+pub fn diff(cwd: &Path, from: &str, to: &str, args: &[String]) -> Result<String> {
+    let repo = PathBuf::from(git(cwd, &["rev-parse", "--show-toplevel"])?);
+    let prefix = git(cwd, &["rev-parse", "--show-prefix"])?;
+    let old = load(&repo, Path::new(&prefix), from, "from", args)?;
+    let new = load(&repo, Path::new(&prefix), to, "to", args)?;
+
+    let mut notes = String::new();
+    let mut section = |title: &str, lines: Vec<String>| {
+        if lines.is_empty() {
+            return;
+        }
+        let _ = writeln!(notes, "## {}\n", title);
+        for line in lines {
+            let _ = writeln!(notes, "{}", line);
+        }
+        notes.push('\n');
+    };
+    let fragments = |a: &Graph, b: &Graph| {
+        a.fragments
+            .difference(&b.fragments)
+            .map(|fragment| format!("- `{}`", fragment))
+            .collect()
+    };
+    let edges = |a: &Graph, b: &Graph| {
+        a.edges
+            .difference(&b.edges)
+            .map(|(from, to)| format!("- `{}` includes `{}`", from, to))
+            .collect()
+    };
+    section("New fragments", fragments(&new, &old));
+    section("Removed fragments", fragments(&old, &new));
+    section("Added includes", edges(&new, &old));
+    section("Removed includes", edges(&old, &new));
+
+    if notes.is_empty() {
+        notes = format!("No include graph changes between {} and {}.\n", from, to);
+    }
+    Ok(notes)
+}
@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use crate::{options::Options, PROVENANCE_MARKER};
+
+/// Template variables and what they expand to. Anything else in braces is left as written.
+const VARIABLES: &[&str] = &["{date}", "{version}", "{build_id}", "{source}", "{output}"];
+
+/// Fills in the `--header` template for one target and returns it as a comment, one line per
+/// template line, with `date` for `{date}`. The comment always carries `PROVENANCE_MARKER`, so
+/// the output is recognised if it is ever fed back in as a source.
+pub fn render(template: &str, source: &Path, output: &Path, date: &str, opts: &Options) -> String {
+    let shown = |path: &Path| {
+        path.strip_prefix(&opts.base_dir).unwrap_or(path).display().to_string()
+    };
+    let mut text = template.trim_end().to_string();
+    for variable in VARIABLES {
+        if !text.contains(variable) {
+            continue;
+        }
+        let value = match *variable {
+            "{date}" => date.to_string(),
+            "{version}" => env!("CARGO_PKG_VERSION").to_string(),
+            "{build_id}" => opts.build_id.clone(),
+            "{source}" => shown(source),
+            _ => shown(output),
+        };
+        text = text.replace(variable, &value);
+    }
+    if !text.contains(PROVENANCE_MARKER) {
+        text = format!("{}\n{}", PROVENANCE_MARKER, text);
+    }
+
+    // "--" may not appear inside a comment, and a path or template could contain it.
+    let text = text.replace("--", "- -");
+    let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+    if lines.len() == 1 {
+        format!("<!-- {} -->\n", lines[0])
+    } else {
+        format!("<!--\n  {}\n-->\n", lines.join("\n  "))
+    }
+}
+
+/// Inserts `header` at the top of `expanded`, after the XML declaration if there is one, since
+/// nothing may come before that.
+pub fn prepend(expanded: &str, header: &str) -> String {
+    let bom = if expanded.starts_with('\u{FEFF}') { "\u{FEFF}".len() } else { 0 };
+    let declaration_end = expanded[bom..]
+        .starts_with("<?xml")
+        .then(|| expanded.find("?>"))
+        .flatten()
+        .map(|end| end + 2);
+    let Some(end) = declaration_end else {
+        return format!("{}{}{}", &expanded[..bom], header, &expanded[bom..]);
+    };
+    let rest = &expanded[end..];
+    let newline = rest.strip_prefix("\r\n").or(rest.strip_prefix('\n')).unwrap_or(rest);
+    let at = end + rest.len() - newline.len();
+    let separator = if at == end { "\n" } else { "" };
+    format!("{}{}{}{}", &expanded[..at], separator, header, &expanded[at..])
+}
@@ -0,0 +1,54 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::{options::Options, report::shown, TargetResult};
+
+/// Written into the output directory. It doesn't match `DEFAULT_TARGET_PATTERN`, so it is
+/// never picked up as a base XML.
+pub(crate) const INDEX_NAME: &str = "index.json";
+
+/// Writes `index.json` into the output directory: for every output written or up to date, its
+/// source, SHA-256 and the value of each `--index` field, read back from the file on disk. A
+/// field is `null` where it selects nothing, a string where it selects one value and a list
+/// where it selects several. Failed targets are left out.
+pub(crate) fn write(results: &[TargetResult], opts: &Options) -> Result<PathBuf> {
+    let mut results: Vec<&TargetResult> =
+        results.iter().filter(|result| result.digest.is_some()).collect();
+    results.sort_by(|a, b| a.output.cmp(&b.output));
+
+    let mut outputs = Map::new();
+    for result in results {
+        let text = fs::read_to_string(&result.output)?;
+        let values: Map<String, Value> = opts
+            .index_fields
+            .iter()
+            .map(|field| {
+                let mut found = field.values(&text);
+                let value = match found.len() {
+                    0 => Value::Null,
+                    1 => Value::String(found.remove(0)),
+                    _ => json!(found),
+                };
+                (field.expr.clone(), value)
+            })
+            .collect();
+        outputs.insert(
+            shown(&result.output, &opts.output_dir),
+            json!({
+                "source": shown(&result.source, &opts.base_dir),
+                "sha256": result.digest,
+                "values": values,
+            }),
+        );
+    }
+    let index = json!({
+        "build_id": opts.build_id,
+        "outputs": outputs,
+    });
+
+    let path = opts.output_dir.join(INDEX_NAME);
+    fs::write(&path, serde_json::to_string_pretty(&index)? + "\n")?;
+    Ok(path)
+}
@@ -0,0 +1,2635 @@
+//! Include expansion for KiwiXML base XMLs, usable from other programs and `build.rs` scripts.
+//!
+//! ```no_run
+//! use kvy_xmls::{Compiler, Engine};
+//!
+//! let compiler = Compiler::builder("Config")
+//!     .output_dir("../build/xml")
+//!     .engine(Engine::Parser)
+//!     .include_root("../SharedFragments")
+//!     .build()?;
+//! compiler.run()?;
+//! let preview = compiler.expand("Config/Wolf/0_Wolf.xml".as_ref())?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Like the command line tool, a compile logs to `processing.log` in the working directory.
+
+mod cache;
+mod changes;
+mod charset;
+mod chunks;
+mod clean;
+mod compiler;
+mod config;
+mod console;
+pub mod error;
+mod excerpt;
+mod extends;
+mod glob;
+mod header;
+mod index;
+mod msbuild;
+mod normalize;
+pub mod options;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod transform;
+mod parser;
+mod permissions;
+mod pii;
+mod placeholder;
+mod patch;
+mod profile;
+mod retry;
+mod sourcemap;
+mod ssi;
+mod vars;
+mod version;
+mod watch;
+mod written;
+mod xinclude;
+
+// The rest of the KiwiXML command line tool; not part of the stable API.
+#[doc(hidden)]
+pub mod auth;
+#[doc(hidden)]
+pub mod crash;
+#[doc(hidden)]
+pub mod daemon;
+#[doc(hidden)]
+pub mod graph;
+#[doc(hidden)]
+pub mod lock;
+#[doc(hidden)]
+pub mod preview;
+#[doc(hidden)]
+pub mod replay;
+#[doc(hidden)]
+pub mod report;
+#[doc(hidden)]
+pub mod schema;
+#[doc(hidden)]
+pub mod search;
+#[doc(hidden)]
+pub mod serve;
+#[doc(hidden)]
+pub mod storage;
+#[doc(hidden)]
+pub mod vendor;
+#[doc(hidden)]
+pub mod workspace;
+
+pub use charset::Charset;
+pub use compiler::{Compiler, CompilerBuilder};
+pub use error::CompileError;
+pub use normalize::Normalization;
+pub use placeholder::Encoding;
+pub use options::{
+    CompiledSources, CyclePolicy, Engine, InlineIncludes, Options, Verbosity, WarningPolicy,
+};
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use once_cell::sync::{Lazy, OnceCell};
+use rayon::prelude::*;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+
+/// Log of every run, in the working directory unless `--log` says otherwise.
+const LOG_NAME: &str = "processing.log";
+
+static LOG_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+static LOG_FILE: Lazy<Mutex<fs::File>> = Lazy::new(|| {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(log_path())
+        .expect("Failed to create or open log file");
+    Mutex::new(file)
+});
+
+/// Where the log is written. Only takes effect before the first message is logged; returns
+/// false if that has already happened.
+#[doc(hidden)]
+pub fn set_log_path(path: PathBuf) -> bool {
+    LOG_PATH.set(path).is_ok() && Lazy::get(&LOG_FILE).is_none()
+}
+
+fn log_path() -> &'static Path {
+    LOG_PATH.get().map_or(Path::new(LOG_NAME), PathBuf::as_path)
+}
+
+static LOG_LINE_LENGTH: OnceCell<usize> = OnceCell::new();
+
+/// The most bytes of a message logged or printed; longer ones lose their middle. Unlimited
+/// unless set, and only the first setting counts.
+#[doc(hidden)]
+pub fn set_log_line_length(max: usize) {
+    let _ = LOG_LINE_LENGTH.set(max);
+}
+
+static LOG_JSON: OnceCell<bool> = OnceCell::new();
+
+/// Writes the log as JSON lines (`--log-format json`) instead of text. Only the first setting
+/// counts.
+#[doc(hidden)]
+pub fn set_log_json(json: bool) {
+    let _ = LOG_JSON.set(json);
+}
+
+fn log_json() -> bool {
+    LOG_JSON.get().copied().unwrap_or(false)
+}
+
+/// The message of a log entry, without its timestamp.
+pub(crate) fn entry_message(entry: &str) -> Cow<'_, str> {
+    if log_json() {
+        let value: serde_json::Value = serde_json::from_str(entry).unwrap_or_default();
+        return Cow::Owned(value["message"].as_str().unwrap_or(entry).to_string());
+    }
+    Cow::Borrowed(entry.split_once("]  ").map_or(entry, |(_, message)| message))
+}
+
+/// `msg` cut to the `--log-line-length` limit, if there is one.
+pub(crate) fn log_line(msg: &str) -> Cow<'_, str> {
+    match LOG_LINE_LENGTH.get() {
+        Some(&max) => excerpt::truncate_middle(msg, max),
+        None => Cow::Borrowed(msg),
+    }
+}
+
+/// One `name="value"` pair after a directive's `file` attribute.
+static DIRECTIVE_ATTR_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"([\w-]+)="([^"]*)""#).unwrap());
+/// `<!-- #deprecated since="..." use="..." -->` in a fragment; both attributes are optional.
+static DEPRECATED_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"<!--\s*#deprecated((?:\s+[\w-]+="[^"]*")*)\s*-->"#).unwrap());
+/// `<!-- #once -->` in a fragment that a target should only ever include once.
+static ONCE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"<!--\s*#once\s*-->").unwrap());
+/// A directive escaped with an extra `#` (`<!-- ##include ... -->`), to be output with one less.
+static ESCAPED_DIRECTIVE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"<!--(\s*)#(#+include\b.*?-->)").unwrap());
+/// Stands in for the content of a raw include until expansion is finished.
+static RAW_TOKEN_RE: Lazy<Regex> = Lazy::new(|| Regex::new("\u{E000}(\\d+)\u{E001}").unwrap());
+static PLACEHOLDER_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?is)<placeholder[^>]*>(.*?)</placeholder>").unwrap());
+static COMMENT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<!--.*?-->").unwrap());
+static SPACE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s{2,}").unwrap());
+
+/// Text the `--header` comment always carries, so an output with one can be recognised if it
+/// is ever fed back in as a source.
+const PROVENANCE_MARKER: &str = "Generated by KiwiXML";
+
+/// How often a target is expanded before changing sources are given up on.
+const SETTLE_ATTEMPTS: usize = 3;
+/// A file modified this close to when it was read may have changed again within the same
+/// timestamp tick (FAT has 2 s resolution), so its mtime and length can't be trusted.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Raw file contents keyed by path, revalidated against mtime and length on every lookup.
+/// Lives for the whole process, so a daemon keeps fragments warm between requests.
+static SOURCE_CACHE: Lazy<Mutex<HashMap<PathBuf, CachedSource>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A source as read from disk, with the metadata and digest it had at the time.
+#[derive(Clone)]
+struct CachedSource {
+    modified: SystemTime,
+    len: u64,
+    read_at: SystemTime,
+    digest: String,
+    content: Arc<str>,
+    /// Given as `Options::buffer` rather than read from disk, so never out of date.
+    buffer: bool,
+}
+
+impl CachedSource {
+    /// Whether `meta` proves the file is still what was read, without reading it again.
+    fn matches(&self, meta: &fs::Metadata) -> bool {
+        let settled = self
+            .read_at
+            .duration_since(self.modified)
+            .is_ok_and(|gap| gap >= RACY_WINDOW);
+        settled && meta.len() == self.len && meta.modified().ok() == Some(self.modified)
+    }
+}
+
+fn timestamp() -> String {
+    let now = SystemTime::now();
+    let datetime: DateTime<Local> = now.into();
+    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+#[doc(hidden)]
+pub fn log_message(msg: &str) {
+    if let Some(entry) = console::hold(log_entry(msg)) {
+        write_log(&[entry]);
+    }
+}
+
+/// `msg` as a line of the log, with its time.
+fn log_entry(msg: &str) -> String {
+    match log_json() {
+        true => serde_json::json!({
+            "time": Local::now().to_rfc3339(),
+            "message": log_line(msg),
+        })
+        .to_string(),
+        false => format!("[{}]  {}", timestamp(), log_line(msg)),
+    }
+}
+
+/// Appends `entries` to the log with nothing from other threads in between.
+fn write_log(entries: &[String]) {
+    if let Ok(mut f) = LOG_FILE.lock() {
+        for entry in entries {
+            let _ = writeln!(f, "{}", entry);
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn log_section(title: &str) {
+    if let Ok(mut f) = LOG_FILE.lock() {
+        if log_json() {
+            let entry = serde_json::json!({"time": Local::now().to_rfc3339(), "section": title});
+            let _ = writeln!(f, "{}", entry);
+            return;
+        }
+        let _ = writeln!(f, "\n────────────────────────────────────────────");
+        let _ = writeln!(f, "{}", title);
+        let _ = writeln!(f, "────────────────────────────────────────────");
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use std::fmt::Write as _;
+    Sha256::digest(data)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+fn normalize_include_path(base_dir: &Path, include: &str) -> PathBuf {
+    let normalized = if cfg!(windows) {
+        include.to_string()
+    } else {
+        include.replace('\\', "/")
+    };
+    base_dir.join(normalized)
+}
+
+fn read_source(
+    path: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<CachedSource, CompileError> {
+    if let Some((buffer_path, content)) = &opts.buffer
+        && *buffer_path == options::lexical(path)
+    {
+        return Ok(CachedSource {
+            modified: SystemTime::UNIX_EPOCH,
+            len: content.len() as u64,
+            read_at: SystemTime::now(),
+            digest: sha256_hex(content.as_bytes()),
+            content: content.clone(),
+            buffer: true,
+        });
+    }
+    let meta = retry::io(opts, "reading", path, &mut exp.retries, || fs::metadata(path))
+        .map_err(CompileError::io(path))?;
+    if let Ok(cache) = SOURCE_CACHE.lock()
+        && let Some(cached) = cache.get(path)
+        && cached.matches(&meta)
+    {
+        if !exp.sources.contains_key(path) {
+            exp.trace(|| format!("  source cache hit: {}", path.display()));
+        }
+        return Ok(cached.clone());
+    }
+    if !exp.sources.contains_key(path) {
+        exp.trace(|| format!("  source cache miss, reading {}", path.display()));
+    }
+
+    let read_at = SystemTime::now();
+    let bytes = retry::io(opts, "reading", path, &mut exp.retries, || fs::read(path))
+        .map_err(CompileError::io(path))?;
+    // The digest is of the file as it is on disk, so rereading it tells whether it changed.
+    let digest = sha256_hex(&bytes);
+    let content = charset::decode(bytes, opts.input_encoding).map_err(CompileError::io(path))?;
+    let source = CachedSource {
+        modified: meta.modified().map_err(CompileError::io(path))?,
+        len: meta.len(),
+        read_at,
+        digest,
+        content: content.into(),
+        buffer: false,
+    };
+    if let Ok(mut cache) = SOURCE_CACHE.lock() {
+        cache.insert(path.to_path_buf(), source.clone());
+    }
+    Ok(source)
+}
+
+/// Reads a source through the cache, remembers what was read for `exp`, and applies the
+/// per-run content transforms.
+fn load_source(
+    path: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<Arc<str>, CompileError> {
+    let source = read_source(path, opts, exp)?;
+    let content = source.content.clone();
+    let first_read = exp.sources.insert(path.to_path_buf(), source).is_none();
+    let mut content: Arc<str> = match opts.normalization {
+        Some(form) => normalize::normalize_document(&content, form).into(),
+        None => content,
+    };
+    if let Cow::Owned(filtered) = profile::apply(&content, path, opts.profile.as_deref())? {
+        content = filtered.into();
+    }
+    let flags = exp.flags.as_ref().unwrap_or(&opts.flags);
+    if let Cow::Owned(kept) = ssi::conditionals(&content, path, flags)? {
+        content = kept.into();
+    }
+    if let Cow::Owned(replaced) = ssi::apply(&content, path, &opts.ssi_vars, first_read) {
+        content = replaced.into();
+    }
+    let defines = exp.defines.as_ref().unwrap_or(&opts.defines);
+    let (replaced, undefined) = vars::apply(&content, defines);
+    if let Some((line, name)) = undefined.first().filter(|_| opts.fail_on_undefined_vars) {
+        return Err(CompileError::UndefinedVariable {
+            path: path.to_path_buf(),
+            line: *line,
+            name: name.clone(),
+        });
+    }
+    if first_read {
+        for (line, name) in undefined {
+            exp.warn(format!("Warning: {}:{}: undefined variable {}", path.display(), line, name));
+        }
+    }
+    if let Cow::Owned(replaced) = replaced {
+        content = replaced.into();
+    }
+    if let Cow::Owned(expanded) = glob::expand(path, &content, opts, exp, first_read) {
+        content = expanded.into();
+    }
+    Ok(content)
+}
+
+/// Per-target bookkeeping collected while expanding one base XML.
+#[derive(Default)]
+struct Expansion {
+    /// Every file pulled in through a directive, in the order they were included.
+    includes: Vec<PathBuf>,
+    /// `(including file, included file)` for each of `includes`, in the same order.
+    edges: Vec<(PathBuf, PathBuf)>,
+    /// Includes that could not be resolved or expanded, as logged.
+    warnings: Vec<String>,
+    /// Includes that were not on disk (or not checked out), in the order they were met.
+    missing: Vec<PathBuf>,
+    /// Includes that were on disk but failed to read or expand, as warned about.
+    unreadable: Vec<PathBuf>,
+    /// Every file read, as it was when read.
+    sources: BTreeMap<PathBuf, CachedSource>,
+    /// Transient IO failures that were retried, as logged.
+    retries: Vec<String>,
+    /// Sources kept changing on disk while this target was expanded, so the output may mix
+    /// old and new content.
+    suspect: bool,
+    /// Deprecated fragments this target includes, with the warning logged for each. Unlike
+    /// `warnings`, these never stop an output from being written.
+    deprecations: BTreeMap<PathBuf, String>,
+    /// Includes found under more than one `--include-root`: the file used, then those it
+    /// shadowed.
+    shadowed: Vec<(PathBuf, Vec<PathBuf>)>,
+    /// Text that must reach the output untouched (raw includes and escaped directives), indexed
+    /// by the token left in its place.
+    raw: Vec<String>,
+    /// Files being expanded right now, the target first, each lexically normalized.
+    open: Vec<PathBuf>,
+    /// Variables for this target's sources when its folder settings add any.
+    defines: Option<BTreeMap<String, String>>,
+    /// Flags for this target's conditionals when its folder settings add any.
+    flags: Option<BTreeSet<String>>,
+    /// Where `--partition-by` put the output, when that isn't the path it was compiled for.
+    partitioned: Option<PathBuf>,
+    /// The `{date}` the `--header` was filled in with, so rendering the target again for
+    /// `--verify-deterministic` gives the same header on the other side of midnight.
+    header_date: Option<String>,
+    /// Bytes of fragments included so far, counting each one every time it is included.
+    included_bytes: u64,
+    /// Places an include was looked for before it was found on a `-I` search path. A file
+    /// appearing at one of them would change what the include resolves to.
+    missed: BTreeSet<PathBuf>,
+    /// Include patterns and the files each matched, which the output depends on as a whole.
+    globs: Vec<glob::Glob>,
+    /// Files that panicked or ran a pass over `--pass-timeout`, with the error each caused.
+    /// They cost only what included them; the rest of the run goes on.
+    poisoned: BTreeMap<PathBuf, String>,
+    /// With `--source-map`, the source file and line each mark left in the text stands for.
+    marks: Option<Vec<(PathBuf, usize)>>,
+    /// Where the lines of the output came from, with `--source-map`.
+    source_map: Option<sourcemap::SourceMap>,
+    /// Canonical paths of the fragments included so far, for include-once.
+    included: BTreeSet<PathBuf>,
+    /// Every include resolution step, when this is the target `--trace-includes` names.
+    trace: Option<Vec<String>>,
+}
+
+impl Expansion {
+    fn warn(&mut self, msg: String) {
+        log_message(&msg);
+        self.warnings.push(msg);
+    }
+
+    /// Adds a resolution step to the trace, if this target is traced.
+    fn trace(&mut self, step: impl FnOnce() -> String) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(step());
+        }
+    }
+
+    /// Forgets everything one attempt at expanding the target found, for `expand_settled` to
+    /// start over. What belongs to the target's whole build stays: the IO retries, the
+    /// `--trace-includes` steps, the `--header` date, and whether `--source-map` marks are kept.
+    fn restart(&mut self) {
+        *self = Expansion {
+            retries: std::mem::take(&mut self.retries),
+            trace: self.trace.take(),
+            header_date: self.header_date.take(),
+            marks: self.marks.as_ref().map(|_| Vec::new()),
+            ..Expansion::default()
+        };
+    }
+
+    /// Records the file behind `err` as poisoned when a panic or a slow pass caused it.
+    fn poison(&mut self, err: &CompileError) {
+        if matches!(err, CompileError::Panic { .. } | CompileError::SlowPass { .. }) {
+            self.poisoned.insert(err.path().to_path_buf(), err.to_string());
+        }
+    }
+
+    /// Each distinct shadowed resolution once, in the order first seen.
+    fn shadowed_once(&self) -> Vec<&(PathBuf, Vec<PathBuf>)> {
+        let mut seen = HashSet::new();
+        self.shadowed.iter().filter(|entry| seen.insert(*entry)).collect()
+    }
+
+    /// Sources that no longer match what was read. Size and modification time are checked
+    /// first; when those differ or can't be trusted, the file is hashed again to confirm.
+    fn changed_sources(&self) -> Vec<&Path> {
+        self.sources
+            .iter()
+            .filter(|(path, read)| {
+                if read.buffer {
+                    return false;
+                }
+                let unchanged = fs::metadata(path).is_ok_and(|meta| read.matches(&meta));
+                !unchanged && fs::read(path).map_or(true, |data| sha256_hex(&data) != read.digest)
+            })
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+}
+
+/// The file and extra attributes of a match of `Options::directives`, from whichever syntax
+/// matched.
+fn directive_parts<'c>(caps: &regex::Captures<'c>) -> (Include<'c>, &'c str) {
+    (1..caps.len())
+        .step_by(3)
+        .find_map(|group| {
+            let path = caps.get(group + 1)?.as_str().trim();
+            let include = match caps.get(group)?.as_str() {
+                "virtual" => Include::Virtual(path),
+                _ => Include::File(path),
+            };
+            Some((include, caps.get(group + 2).map_or("", |attrs| attrs.as_str())))
+        })
+        .unwrap_or((Include::File(""), ""))
+}
+
+/// The path of an include directive.
+#[derive(Clone, Copy)]
+enum Include<'a> {
+    /// `file="..."`, relative to the including file's folder.
+    File(&'a str),
+    /// `virtual="..."`, relative to the base directory.
+    Virtual(&'a str),
+}
+
+/// What a directive's attributes after `file` ask for.
+#[derive(Default)]
+struct DirectiveAttrs {
+    /// Paste the file verbatim (`raw="true"`).
+    raw: bool,
+    /// Version requirement the fragment must meet (`version=">=2"`).
+    version: Option<String>,
+    /// Skip the fragment when the target already includes it (`once="true"`).
+    once: bool,
+}
+
+/// Reads a directive's extra attributes. Attributes this version doesn't know are warned about
+/// and otherwise ignored.
+fn directive_attrs(attrs: &str, file_path: &Path, exp: &mut Expansion) -> DirectiveAttrs {
+    let mut parsed = DirectiveAttrs::default();
+    for caps in DIRECTIVE_ATTR_RE.captures_iter(attrs) {
+        match (&caps[1], &caps[2]) {
+            ("raw", value) => parsed.raw = value == "true",
+            ("version", value) => parsed.version = Some(value.trim().to_string()),
+            ("once", value) => parsed.once = value == "true",
+            (name, _) => exp.warn(format!(
+                "Warning: {}: unknown include attribute '{}' ignored",
+                file_path.display(),
+                name
+            )),
+        }
+    }
+    parsed
+}
+
+/// Whether the fragment at `path` was already included into this target, by whatever path,
+/// and this include of it should be skipped: because the directive says `once="true"` or the
+/// fragment is marked `#once`. A skipped include is warned about. Every fragment is noted as
+/// included the first time, so even a repeat of one included without either is recognised.
+fn repeated_include(
+    file_path: &Path,
+    path: &Path,
+    once: bool,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> bool {
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| options::lexical(path));
+    if exp.included.insert(canonical) {
+        return false;
+    }
+    let once = once || load_source(path, opts, exp).is_ok_and(|content| ONCE_RE.is_match(&content));
+    if once {
+        exp.warn(format!(
+            "Warning: {}: {} is already included, skipped (include once)",
+            file_path.display(),
+            path.display()
+        ));
+    }
+    once
+}
+
+/// Warns, once per target, when the fragment at `path` is marked `#deprecated`, naming its
+/// replacement if it gives one.
+fn note_deprecation(file_path: &Path, path: &Path, opts: &Options, exp: &mut Expansion) {
+    if exp.deprecations.contains_key(path) {
+        return;
+    }
+    let Ok(content) = load_source(path, opts, exp) else {
+        return;
+    };
+    let Some(caps) = DEPRECATED_RE.captures(&content) else {
+        return;
+    };
+    let mut since = None;
+    let mut replacement = None;
+    for attr in DIRECTIVE_ATTR_RE.captures_iter(&caps[1]) {
+        match &attr[1] {
+            "since" => since = Some(attr[2].to_string()),
+            "use" => replacement = Some(attr[2].to_string()),
+            _ => {}
+        }
+    }
+
+    let mut msg = format!(
+        "Warning: {} includes deprecated {}",
+        file_path.display(),
+        path.display()
+    );
+    if let Some(since) = since {
+        msg.push_str(&format!(" (since {})", since));
+    }
+    if let Some(replacement) = replacement {
+        msg.push_str(&format!(", use {} instead", replacement));
+    }
+    log_message(&msg);
+    exp.deprecations.insert(path.to_path_buf(), msg);
+}
+
+/// Fails unless the fragment at `path` declares a version meeting `required`. A mismatch fails
+/// the whole target rather than just the include, so a breaking fragment change can't slip
+/// into a product that hasn't opted in.
+fn check_fragment_version(
+    path: &Path,
+    required: &str,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<(), CompileError> {
+    let content = load_source(path, opts, exp)?;
+    let declared = version::declared(&content);
+    if declared.and_then(|declared| version::satisfies(declared, required)) == Some(true) {
+        return Ok(());
+    }
+    Err(CompileError::VersionMismatch {
+        path: path.to_path_buf(),
+        required: required.to_string(),
+        declared: declared.map(str::to_string),
+    })
+}
+
+/// Reads a `raw="true"` include and returns a token to put where the directive was. The content
+/// is not expanded, cleaned or wrapped; it replaces the token once the whole target is expanded,
+/// so neither later cleanup nor a later pass can touch it.
+fn include_raw(
+    file_path: &Path,
+    include_path: &Path,
+    in_attribute: bool,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> String {
+    // Read as it is on disk: no normalization or profile filtering.
+    match read_source(include_path, opts, exp) {
+        Ok(source) => {
+            let content = source.content.clone();
+            exp.sources.insert(include_path.to_path_buf(), source);
+            log_message(&format!("Included (raw): {}", include_path.display()));
+            exp.includes.push(include_path.to_path_buf());
+            exp.edges.push((file_path.to_path_buf(), include_path.to_path_buf()));
+            protect(
+                if in_attribute {
+                    escape_attribute(&content)
+                } else {
+                    content.to_string()
+                },
+                exp,
+            )
+        }
+        Err(err) => {
+            exp.poison(&err);
+            exp.warn(format!("Error including {}: {}", include_path.display(), err));
+            format!("<!-- Error including {}: {} -->", include_path.display(), err)
+        }
+    }
+}
+
+/// Sets `text` aside until expansion is finished and returns the token that stands in for it.
+fn protect(text: String, exp: &mut Expansion) -> String {
+    exp.raw.push(text);
+    format!("\u{E000}{}\u{E001}", exp.raw.len() - 1)
+}
+
+/// The literal directive an escaped one stands for, if `comment` is one.
+fn unescape_directive(comment: &str) -> Option<String> {
+    let caps = ESCAPED_DIRECTIVE_RE.captures(comment)?;
+    (caps[0].len() == comment.len()).then(|| format!("<!--{}{}", &caps[1], &caps[2]))
+}
+
+/// Protects every escaped directive in `content`, so neither expansion nor comment stripping
+/// touches it, and the output gets it with one `#` removed.
+fn protect_escaped_directives<'a>(content: &'a str, exp: &mut Expansion) -> Cow<'a, str> {
+    ESCAPED_DIRECTIVE_RE.replace_all(content, |caps: &regex::Captures| {
+        protect(format!("<!--{}{}", &caps[1], &caps[2]), exp)
+    })
+}
+
+/// Puts the content of raw includes back in place of their tokens.
+fn restore_raw(expanded: String, exp: &Expansion) -> String {
+    if exp.raw.is_empty() {
+        return expanded;
+    }
+    RAW_TOKEN_RE
+        .replace_all(&expanded, |caps: &regex::Captures| {
+            let index: usize = caps[1].parse().unwrap_or(usize::MAX);
+            exp.raw.get(index).cloned().unwrap_or_default()
+        })
+        .into_owned()
+}
+
+/// An include that lands under an include root is looked up by its path relative to that root
+/// in every root, highest priority first, so a project can override single library fragments.
+/// Paths outside every root resolve as written.
+fn apply_include_roots(path: PathBuf, opts: &Options, exp: &mut Expansion) -> PathBuf {
+    let found = root_candidates(&path, opts).unwrap_or_default();
+    if opts.include_roots.len() > 1 {
+        let roots: Vec<String> =
+            opts.include_roots.iter().rev().map(|root| root.display().to_string()).collect();
+        let found: Vec<String> = found.iter().map(|path| path.display().to_string()).collect();
+        exp.trace(|| match found.is_empty() {
+            true => format!("    include roots {}: under none of them", roots.join(", ")),
+            false => format!("    include roots {}: found {}", roots.join(", "), found.join(", ")),
+        });
+    }
+    let Some((used, shadowed)) = found.split_first() else {
+        return path;
+    };
+    if !shadowed.is_empty() {
+        let list: Vec<String> = shadowed.iter().map(|p| p.display().to_string()).collect();
+        log_message(&format!("Shadowed: {} overrides {}", used.display(), list.join(", ")));
+        exp.shadowed.push((used.clone(), shadowed.to_vec()));
+    }
+    used.clone()
+}
+
+/// The files under the include roots that `path` may resolve to, highest priority first, or
+/// `None` when there is only one root or `path` lies under none of them.
+fn root_candidates(path: &Path, opts: &Options) -> Option<Vec<PathBuf>> {
+    if opts.include_roots.len() < 2 {
+        return None;
+    }
+    let lexical = options::lexical(path);
+    let relative = opts
+        .include_roots
+        .iter()
+        .filter_map(|root| lexical.strip_prefix(root).ok())
+        .min_by_key(|relative| relative.components().count())?;
+    Some(
+        opts.include_roots
+            .iter()
+            .rev()
+            .map(|root| root.join(relative))
+            .filter(|candidate| candidate.is_file())
+            .collect(),
+    )
+}
+
+/// Checks that `include_path` isn't already being expanded further up, which would recurse
+/// until the stack overflows. Under `--on-cycle comment` the cycle is warned about and the
+/// comment to leave in place of the include is returned.
+fn check_cycle(
+    file_path: &Path,
+    include_path: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<Option<String>, CompileError> {
+    let include = options::lexical(include_path);
+    if !exp.open.contains(&include) {
+        return Ok(None);
+    }
+    let mut chain = exp.open.clone();
+    chain.push(include);
+    let err = CompileError::Cycle {
+        path: file_path.to_path_buf(),
+        chain,
+    };
+    match opts.on_cycle {
+        CyclePolicy::Error => Err(err),
+        CyclePolicy::Comment => {
+            exp.warn(format!("Warning: {}: {}", file_path.display(), err));
+            Ok(Some(format!("<!-- {} -->", err)))
+        }
+    }
+}
+
+/// Checks that including `include_path` from `file_path` stays within `--max-include-depth`
+/// and `--max-expanded-size`, counting its size towards the latter. Either limit fails the
+/// whole target before anything more is read, whatever `--on-cycle` says.
+fn check_limits(
+    file_path: &Path,
+    include_path: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<(), CompileError> {
+    // The target is open too, at level 0.
+    if exp.open.len() > opts.max_include_depth {
+        return Err(CompileError::IncludeDepth {
+            path: file_path.to_path_buf(),
+            limit: opts.max_include_depth,
+        });
+    }
+    let size = load_source(include_path, opts, exp).map_or(0, |content| content.len() as u64);
+    exp.included_bytes = exp.included_bytes.saturating_add(size);
+    if exp.included_bytes > opts.max_expanded_size {
+        return Err(CompileError::ExpandedSize {
+            path: file_path.to_path_buf(),
+            limit: opts.max_expanded_size,
+        });
+    }
+    Ok(())
+}
+
+/// Expands `file_path` with it marked open, for `check_cycle`.
+fn expand_open<T>(
+    file_path: &Path,
+    exp: &mut Expansion,
+    expand: impl FnOnce(&mut Expansion) -> Result<T, CompileError>,
+) -> Result<T, CompileError> {
+    exp.open.push(options::lexical(file_path));
+    let result = expand(exp);
+    exp.open.pop();
+    result
+}
+
+/// The outermost folder missing between a `--sparse-root` and `include_path`, when the include
+/// is missing because a sparse checkout left that folder out rather than because it doesn't
+/// exist. A missing file in a folder that is present is just missing.
+fn not_checked_out(include_path: &Path, opts: &Options) -> Option<PathBuf> {
+    let include_path = options::lexical(include_path);
+    let root = opts.sparse_roots.iter().find(|root| include_path.starts_with(root))?;
+    let mut folder = root.clone();
+    if !folder.is_dir() {
+        return Some(folder);
+    }
+    let parent = include_path.parent()?.strip_prefix(root).ok()?;
+    for component in parent.components() {
+        folder.push(component);
+        if !folder.is_dir() {
+            return Some(folder);
+        }
+    }
+    None
+}
+
+/// Resolves an include directive to a file that may be expanded. When the file isn't where the
+/// directive points, each `-I` search path is tried in turn with the path as written. On
+/// failure the problem is recorded and the comment to leave in its place is returned instead.
+fn resolve_include(
+    dir: &Path,
+    include: Include,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> std::result::Result<PathBuf, String> {
+    exp.trace(|| match include {
+        Include::File(path) => format!("Include file=\"{}\" from {}", path, dir.display()),
+        Include::Virtual(path) => format!("Include virtual=\"{}\" from {}", path, dir.display()),
+    });
+    // A vendored file resolves its includes from where it was copied from.
+    let original = vendor::original(dir, opts);
+    if let Some(original) = &original {
+        exp.trace(|| format!("  vendored, resolving from {}", original.display()));
+    }
+    let dir = original.as_deref().unwrap_or(dir);
+    let (start, include) = match include {
+        Include::File(include) => (dir, include),
+        Include::Virtual(include) => {
+            (opts.base_dir.as_path(), include.trim_start_matches(['/', '\\']))
+        }
+    };
+    let mut missed = Vec::new();
+    let mut found = None;
+    for candidate in [start].into_iter().chain(opts.include_paths.iter().map(PathBuf::as_path)) {
+        exp.trace(|| format!("  searching {}", candidate.display()));
+        let path = apply_include_roots(normalize_include_path(candidate, include), opts, exp);
+        let path = match vendor::copy(&path, opts) {
+            Some(copy) => {
+                exp.trace(|| format!("    vendored copy {}", copy.display()));
+                copy
+            }
+            None => path,
+        };
+        let exists = path.exists();
+        exp.trace(|| match exists {
+            true => format!("    tried {}: found", path.display()),
+            false => format!("    tried {}: not found", path.display()),
+        });
+        if exists {
+            found = Some(path);
+            break;
+        }
+        missed.push(path);
+    }
+    match &found {
+        Some(path) => exp.trace(|| format!("  chose {}", path.display())),
+        None => exp.trace(|| "  not found anywhere".to_string()),
+    }
+    let include_path = match found {
+        Some(path) => {
+            exp.missed.extend(missed.into_iter().map(|path| options::lexical(&path)));
+            path
+        }
+        None => missed.swap_remove(0),
+    };
+    if !include_path.exists() {
+        exp.missing.push(include_path.clone());
+        if let Some(folder) = not_checked_out(&include_path, opts) {
+            exp.warn(format!(
+                "Not checked out: {} (add {} to the sparse checkout)",
+                include_path.display(),
+                options::relative(&folder, &opts.base_dir)
+            ));
+            return Err(format!("<!-- Include not checked out: {} -->", include_path.display()));
+        }
+        exp.warn(format!("Missing include: {}", include_path.display()));
+        return Err(format!("<!-- Include not found: {} -->", include_path.display()));
+    }
+    if let Some(hermetic) = &opts.hermetic
+        && !hermetic.declares(&include_path)
+    {
+        exp.warn(format!("Undeclared input: {}", include_path.display()));
+        return Err(format!("<!-- Undeclared input: {} -->", include_path.display()));
+    }
+    Ok(include_path)
+}
+
+fn expand_includes(
+    file_path: &Path,
+    is_root: bool,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let content = load_source(file_path, opts, exp)?;
+    let content = charset::undeclared(&content);
+    expand_open(file_path, exp, |exp| expand_content(&content, file_path, is_root, opts, exp))
+}
+
+/// Expands the directives in `content`, which was read from (or generated for) `file_path`.
+fn expand_content(
+    content: &str,
+    file_path: &Path,
+    is_root: bool,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let dir = file_path.parent().unwrap_or_else(|| Path::new("."));
+    let protected = protect_escaped_directives(content, exp);
+    let content = protected.as_ref();
+
+    let mut failure = None;
+    let mut lines = LineCounter::new(content);
+
+    let replaced = opts.directives.replace_all(content, |caps: &regex::Captures| {
+        let directive = caps.get(0).unwrap();
+        // Past a limit, the rest would only add to what was too much already.
+        if matches!(
+            failure,
+            Some(CompileError::IncludeDepth { .. } | CompileError::ExpandedSize { .. })
+        ) {
+            return directive.as_str().to_string();
+        }
+        let placement = directive_placement(content, directive.start(), directive.end());
+        if placement != Placement::OwnLine {
+            match opts.inline_includes {
+                InlineIncludes::Expand => {}
+                InlineIncludes::Ignore => return directive.as_str().to_string(),
+                InlineIncludes::Error => {
+                    failure.get_or_insert_with(|| {
+                        inline_directive_error(file_path, content, directive.start(), placement)
+                    });
+                    return directive.as_str().to_string();
+                }
+            }
+        }
+
+        let (include, attrs) = directive_parts(caps);
+        let attrs = directive_attrs(attrs, file_path, exp);
+        let include_path = match resolve_include(dir, include, opts, exp) {
+            Ok(path) => path,
+            Err(replacement) => return replacement,
+        };
+        if let Some(required) = &attrs.version
+            && let Err(err) = check_fragment_version(&include_path, required, opts, exp)
+        {
+            failure.get_or_insert(err);
+            return directive.as_str().to_string();
+        }
+        note_deprecation(file_path, &include_path, opts, exp);
+        if repeated_include(file_path, &include_path, attrs.once, opts, exp) {
+            return String::new();
+        }
+        if attrs.raw {
+            let in_attribute = placement == Placement::Attribute;
+            return include_raw(file_path, &include_path, in_attribute, opts, exp);
+        }
+        match check_cycle(file_path, &include_path, opts, exp) {
+            Ok(None) => {}
+            Ok(Some(comment)) => return comment,
+            Err(err) => {
+                failure.get_or_insert(err);
+                return directive.as_str().to_string();
+            }
+        }
+        if let Err(err) = check_limits(file_path, &include_path, opts, exp) {
+            failure.get_or_insert(err);
+            return directive.as_str().to_string();
+        }
+
+        match expand_includes(&include_path, false, opts, exp) {
+            Ok(included_content) => {
+                let lang = is_root.then(|| placeholder_lang(&include_path, opts, exp)).flatten();
+                let cleaned = timed_pass("placeholder/comment cleanup", &include_path, opts, || {
+                    let inner = remove_placeholders(&included_content);
+                    if lang.is_some() {
+                        inner
+                    } else {
+                        strip_comments_and_format_spaces(&inner, opts)
+                    }
+                });
+                let inner = match cleaned {
+                    Ok(inner) => inner,
+                    Err(err) => {
+                        failure.get_or_insert(err);
+                        return String::new();
+                    }
+                };
+
+                log_message(&format!("Included: {}", include_path.display()));
+                exp.includes.push(include_path.clone());
+                exp.edges.push((file_path.to_path_buf(), include_path.clone()));
+
+                if placement == Placement::Attribute {
+                    return escape_attribute(&inner);
+                }
+                // What follows the fragment continues the including file: the end of a
+                // placeholder wrapped around it, and the lines after it.
+                let line = lines.line(directive.end());
+                let inner = sourcemap::open(exp, &include_path, &inner);
+                let inner = sourcemap::close(exp, file_path, line, &inner);
+                match is_root {
+                    true => {
+                        let wrapped =
+                            wrap_placeholder(&include_path, lang.as_deref(), &inner, opts, exp);
+                        sourcemap::close(exp, file_path, line, &wrapped)
+                    }
+                    false => inner,
+                }
+            }
+            // A pin, cycle or limit deeper down fails the target, not just this include.
+            Err(
+                err @ (CompileError::VersionMismatch { .. }
+                | CompileError::Cycle { .. }
+                | CompileError::IncludeDepth { .. }
+                | CompileError::ExpandedSize { .. }),
+            ) => {
+                failure.get_or_insert(err);
+                directive.as_str().to_string()
+            }
+            Err(err) => {
+                exp.poison(&err);
+                exp.unreadable.push(include_path.clone());
+                exp.warn(format!("Error including {}: {}", include_path.display(), err));
+                format!("<!-- Error including {}: {} -->", include_path.display(), err)
+            }
+        }
+    });
+
+    if let Some(err) = failure {
+        return Err(err);
+    }
+
+    if is_root {
+        Ok(replaced.to_string())
+    } else {
+        timed_pass("placeholder/comment cleanup", file_path, opts, || {
+            remove_comments(&remove_placeholders(&replaced))
+        })
+    }
+}
+
+/// Expands a compilation root. With `--max-passes` above one, the output is expanded again
+/// until it stops changing, so directives produced by earlier passes are resolved too.
+fn expand_target(
+    file: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let folder = config::folder(file, opts, exp)?;
+    if !folder.defines.is_empty() {
+        let mut defines = opts.defines.clone();
+        for (name, value) in &folder.defines {
+            if !opts.command_line_defines.contains(name) {
+                defines.insert(name.clone(), value.clone());
+            }
+        }
+        exp.defines = Some(defines);
+    }
+    if !folder.flags.is_empty() {
+        exp.flags = Some(opts.flags.union(&folder.flags).cloned().collect());
+    }
+    let mut content = load_source(file, opts, exp)?;
+    if let Cow::Owned(included) = xinclude::apply(file, &content, opts, exp)? {
+        content = included.into();
+    }
+    if let Cow::Owned(merged) = extends::apply(file, &content, opts, exp)? {
+        content = merged.into();
+    }
+    if folder.prologue.is_some() || folder.epilogue.is_some() {
+        content = config::surround(file, &content, &folder, opts).into();
+    }
+    let expanded = expand_passes(file, &content, opts, exp)?;
+    patch::apply(file, expanded, opts, exp)
+}
+
+/// Expands the includes in `content`, the document at `file`, again and again until nothing
+/// changes or `--max-passes` is reached, then puts raw includes back.
+fn expand_passes(
+    file: &Path,
+    content: &str,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let expand = |content: &str, exp: &mut Expansion| {
+        expand_open(file, exp, |exp| match opts.engine {
+            Engine::Regex => expand_content(content, file, true, opts, exp),
+            Engine::Parser => parser::expand_content(content, file, true, opts, exp),
+        })
+    };
+
+    let content = sourcemap::mark(exp, file, 1) + content;
+    let mut expanded = expand(&content, exp)?;
+    // Only what the first pass includes is mapped; later passes count lines of its output.
+    let marks = exp.marks.take();
+    for pass in 2..=opts.max_passes {
+        let next = expand(&expanded, exp)?;
+        if next == expanded {
+            break;
+        }
+        if pass == opts.max_passes {
+            log_message(&format!(
+                "Warning: {} still changed in pass {}, output may contain unresolved directives",
+                file.display(),
+                pass
+            ));
+        }
+        expanded = next;
+    }
+    exp.marks = marks;
+    Ok(restore_raw(expanded, exp))
+}
+
+/// Where a directive sits in its document.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Placement {
+    /// Alone on its line, apart from whitespace.
+    OwnLine,
+    /// Sharing its line with other content.
+    Inline,
+    /// Inside a start tag, i.e. part of an attribute value.
+    Attribute,
+}
+
+fn directive_placement(content: &str, start: usize, end: usize) -> Placement {
+    let before = &content[..start];
+    if let Some(open) = before.rfind('<')
+        && before.rfind('>').is_none_or(|close| close < open)
+        && !content[open..].starts_with("<!")
+        && !content[open..].starts_with("<?")
+    {
+        return Placement::Attribute;
+    }
+
+    if blank_line_start(content, start).is_some() && blank_line_end(content, end).is_some() {
+        Placement::OwnLine
+    } else {
+        Placement::Inline
+    }
+}
+
+/// Where the line holding `at` starts, when only whitespace precedes `at` on it. Looks back
+/// no further than the nearest non-blank character, never along the whole line, which in a
+/// generated fragment may be megabytes long.
+pub(crate) fn blank_line_start(content: &str, at: usize) -> Option<usize> {
+    for (i, c) in content[..at].char_indices().rev() {
+        if c == '\n' {
+            return Some(i + 1);
+        }
+        if !c.is_whitespace() {
+            return None;
+        }
+    }
+    Some(0)
+}
+
+/// Just past the line break ending the line holding `at`, when only whitespace follows `at`
+/// on it; the counterpart of `blank_line_start`.
+pub(crate) fn blank_line_end(content: &str, at: usize) -> Option<usize> {
+    for (i, c) in content[at..].char_indices() {
+        if c == '\n' {
+            return Some(at + i + 1);
+        }
+        if !c.is_whitespace() {
+            return None;
+        }
+    }
+    Some(content.len())
+}
+
+/// Line numbers of offsets into one text. Counting resumes from the previous offset, so asking
+/// for each match of a pass in order costs one scan of the text rather than one per match, and
+/// an offset a little before the last one only a short scan back.
+pub(crate) struct LineCounter<'a> {
+    text: &'a str,
+    offset: usize,
+    line: usize,
+}
+
+impl<'a> LineCounter<'a> {
+    pub(crate) fn new(text: &'a str) -> LineCounter<'a> {
+        LineCounter {
+            text,
+            offset: 0,
+            line: 1,
+        }
+    }
+
+    /// The 1-based line `offset` is on.
+    pub(crate) fn line(&mut self, offset: usize) -> usize {
+        if offset < self.offset {
+            self.line -= self.text[offset..self.offset].matches('\n').count();
+        } else {
+            self.line += self.text[self.offset..offset].matches('\n').count();
+        }
+        self.offset = offset;
+        self.line
+    }
+}
+
+fn inline_directive_error(
+    path: &Path,
+    content: &str,
+    start: usize,
+    placement: Placement,
+) -> CompileError {
+    CompileError::InvalidDirective {
+        path: path.to_path_buf(),
+        line: content[..start].matches('\n').count() + 1,
+        reason: match placement {
+            Placement::Attribute => "inside an attribute value",
+            _ => "not on its own line",
+        },
+    }
+}
+
+fn escape_attribute(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Language of a fragment's placeholder content, from its `lang` attribute or the run default.
+/// Fragments with a language keep their line structure, since collapsing it breaks line comments.
+fn placeholder_lang(path: &Path, opts: &Options, exp: &mut Expansion) -> Option<String> {
+    read_source(path, opts, exp)
+        .ok()
+        .and_then(|source| placeholder::declared_lang(&source.content))
+        .or_else(|| opts.placeholder_lang.clone())
+}
+
+/// Checks a fragment's placeholder content if it has a language, with a warning per problem,
+/// and writes it out in the encoding the fragment declares.
+fn wrap_placeholder(
+    path: &Path,
+    lang: Option<&str>,
+    content: &str,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> String {
+    // Marks for `--source-map` are not part of the content.
+    let plain = sourcemap::strip(content);
+    if let Some(lang) = lang {
+        match placeholder::check(lang, &plain) {
+            Some(problems) => {
+                for problem in problems {
+                    exp.warn(format!("Warning: {} ({}): {}", path.display(), lang, problem));
+                }
+            }
+            None => log_message(&format!(
+                "Warning: {}: unknown placeholder language '{}', not validated",
+                path.display(),
+                lang
+            )),
+        }
+    }
+    let encoding = read_source(path, opts, exp)
+        .map(|source| placeholder::declared_encoding(path, &source.content))
+        .unwrap_or(placeholder::Encoding::Cdata);
+    match encoding {
+        // Encoded text maps to nothing, but what follows it still does.
+        placeholder::Encoding::Base64 => {
+            placeholder::wrap(path, encoding, &plain) + sourcemap::last(content)
+        }
+        _ => placeholder::wrap(path, encoding, content),
+    }
+}
+
+/// Runs a text pass over `path`, failing if it took longer than `--pass-timeout`. The regex
+/// engine has no backtracking and matches in linear time, so a slow pass means sheer volume
+/// (a huge generated fragment, say) rather than a pathological pattern. Passes can't be
+/// interrupted, so the limit is checked when the pass returns.
+fn timed_pass<T>(
+    pass: &'static str,
+    path: &Path,
+    opts: &Options,
+    run: impl FnOnce() -> T,
+) -> Result<T, CompileError> {
+    let started = Instant::now();
+    let result = run();
+    check_pass_time(pass, path, started.elapsed(), opts)?;
+    Ok(result)
+}
+
+/// Fails a pass over `path` that took `elapsed`, if that is longer than `--pass-timeout`. For
+/// passes that run in pieces, like the parser engine's, `elapsed` is the pieces' total.
+fn check_pass_time(
+    pass: &'static str,
+    path: &Path,
+    elapsed: Duration,
+    opts: &Options,
+) -> Result<(), CompileError> {
+    match opts.pass_timeout {
+        Some(limit) if elapsed > limit => Err(CompileError::SlowPass {
+            path: path.to_path_buf(),
+            pass,
+            elapsed,
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn remove_placeholders(input: &str) -> String {
+    PLACEHOLDER_RE.replace_all(input, "$1").to_string()
+}
+
+fn remove_comments(input: &str) -> String {
+    COMMENT_RE.replace_all(input, "").to_string()
+}
+
+fn strip_comments_and_format_spaces(input: &str, opts: &Options) -> String {
+    let temp = COMMENT_RE.replace_all(input, "");
+    let Some(preserved) = &opts.preserved_re else {
+        return collapse_whitespace(&temp);
+    };
+
+    // Whitespace-significant elements are copied through untouched.
+    let mut out = String::with_capacity(temp.len());
+    let mut last = 0;
+    for element in preserved.find_iter(&temp) {
+        out.push_str(&collapse_whitespace(&temp[last..element.start()]));
+        out.push_str(element.as_str());
+        last = element.end();
+    }
+    out.push_str(&collapse_whitespace(&temp[last..]));
+    out
+}
+
+/// Drops line breaks and folds every remaining whitespace run into a single space.
+fn collapse_whitespace(input: &str) -> String {
+    let temp = input.replace(['\n', '\r'], "");
+    SPACE_RE.replace_all(&temp, " ").into_owned()
+}
+
+/// Outcome of compiling one base XML.
+struct TargetResult {
+    source: PathBuf,
+    output: PathBuf,
+    /// The output path the target was compiled for, before `--partition-by` moved it.
+    meant_for: PathBuf,
+    expansion: Expansion,
+    /// SHA-256 of the written output.
+    digest: Option<String>,
+    error: Option<CompileError>,
+    /// How long the target took, from the cache check to the output being written.
+    duration: Duration,
+}
+
+/// Compiles every target, recording in `builds` what each one read.
+fn process_xml_files(opts: &Options, builds: &mut watch::Builds) -> Result<()> {
+    let jobs = match &opts.hermetic {
+        Some(hermetic) => hermetic.targets.clone(),
+        None => {
+            fs::create_dir_all(&opts.output_dir)?;
+            discover_targets(opts)
+        }
+    };
+
+    if jobs.is_empty() {
+        log_message("No XML files found to process.");
+    }
+    let jobs = skip_compiled_sources(jobs, opts);
+    check_output_clashes(&jobs)?;
+    if let Some(traced) = &opts.trace_includes
+        && !jobs.iter().any(|(file, _)| options::lexical(file) == *traced)
+    {
+        return Err(anyhow!("--trace-includes: {} is not a target", traced.display()));
+    }
+
+    let started = Instant::now();
+    let cache = cache::Cache::load(opts);
+    console::start("Building", jobs.len(), opts);
+    // Set by the first target to fail under `--bail`; targets not started by then never are.
+    let bailed = AtomicBool::new(false);
+    let mut results: Vec<TargetResult> = jobs
+        .par_iter()
+        .filter_map(|(file, out_path)| {
+            if bailed.load(Ordering::Relaxed) {
+                log_message(&format!("Skipped: {} (--bail)", file.display()));
+                console::skip();
+                return None;
+            }
+            let started = Instant::now();
+            let ((result, up_to_date), entries) = console::capture(|| {
+                let mut expansion = Expansion::default();
+                let traced = opts.trace_includes.as_ref() == Some(&options::lexical(file));
+                // A traced target is always compiled, so there is something to trace.
+                let reused = match traced {
+                    true => None,
+                    false => cache.reuse(file, out_path, opts, &mut expansion),
+                };
+                if traced {
+                    expansion.trace = Some(vec![format!(
+                        "Tracing {} (build cache not used)",
+                        file.display()
+                    )]);
+                }
+                let up_to_date = reused.is_some();
+                let (digest, error) = match reused {
+                    Some(digest) => {
+                        log_message(&format!("Up to date: {}", file.display()));
+                        (Some(digest), None)
+                    }
+                    None => match crash::guarded(file, || {
+                        compile_target(file, out_path, opts, &mut expansion)
+                    }) {
+                        Ok(digest) => (Some(digest), None),
+                        Err(err) => {
+                            expansion.poison(&err);
+                            (None, Some(err))
+                        }
+                    },
+                };
+                let result = TargetResult {
+                    source: file.clone(),
+                    output: expansion.partitioned.clone().unwrap_or_else(|| out_path.clone()),
+                    meant_for: out_path.clone(),
+                    expansion,
+                    digest,
+                    error,
+                    duration: started.elapsed(),
+                };
+                (result, up_to_date)
+            });
+            let status = match (&result.error, up_to_date) {
+                (Some(err), _) => console::Status::Failed(err),
+                (None, true) => console::Status::UpToDate,
+                (None, false) => console::Status::Compiled,
+            };
+            console::flush(file, status, &result.expansion.warnings, &entries, opts);
+            if let Some(trace) = &result.expansion.trace {
+                console::print(console::Channel::Stderr, &(trace.join("\n") + "\n"));
+            }
+            if opts.bail && (result.error.is_some() || !result.expansion.poisoned.is_empty()) {
+                bailed.store(true, Ordering::Relaxed);
+            }
+            Some(result)
+        })
+        .collect();
+    console::finish(started.elapsed(), opts);
+    for result in &results {
+        let build = watch::Build::new(&result.expansion, result.error.is_none());
+        builds.insert(options::lexical(&result.source), build);
+    }
+
+    let nondeterministic = if opts.verify_deterministic {
+        verify_deterministic(&mut results, opts)
+    } else {
+        0
+    };
+
+    if let Some(props) = &opts.msbuild_props
+        && let Err(err) = msbuild::write_props(props, &results, &opts.build_id)
+    {
+        log_message(&format!("Error writing {}: {}", props.display(), err));
+    }
+
+    if !opts.index_fields.is_empty() {
+        match index::write(&results, opts) {
+            Ok(path) => log_message(&format!("Index: {}", path.display())),
+            Err(err) => log_message(&format!("Error writing index: {}", err)),
+        }
+    }
+
+    if opts.html_report {
+        match report::write_html(&results, opts, started.elapsed()) {
+            Ok(path) => log_message(&format!("Report: {}", path.display())),
+            Err(err) => log_message(&format!("Error writing HTML report: {}", err)),
+        }
+    }
+
+    if let Some(path) = &opts.json_report
+        && let Err(err) = report::write_json(path, &results, opts)
+    {
+        log_message(&format!("Error writing {}: {}", path.display(), err));
+    }
+
+    if opts.changelog && opts.hermetic.is_none() {
+        match changes::write(&results, opts) {
+            Ok(path) => log_message(&format!("Changes: {}", path.display())),
+            Err(err) => log_message(&format!("Error writing changelog: {}", err)),
+        }
+    }
+
+    if opts.hermetic.is_none()
+        && let Err(err) = cache::write(&results, opts)
+    {
+        log_message(&format!("Error writing build cache: {}", err));
+    }
+    if let Err(err) = written::save(opts) {
+        log_message(&format!("Error recording written outputs: {}", err));
+    }
+
+    report_poisoned(&results, opts)?;
+    if opts.hermetic.is_some() {
+        report_hermetic(&results)?;
+    }
+    if opts.hermetic.is_none() {
+        report_failures(&results, opts)?;
+    }
+    if bailed.into_inner() {
+        return Err(anyhow!(
+            "Stopped after the first failure (--bail): {} of {} targets not built",
+            jobs.len() - results.len(),
+            jobs.len()
+        ));
+    }
+    if nondeterministic > 0 {
+        return Err(anyhow!(
+            "{} of {} targets compiled to different outputs on a second pass",
+            nondeterministic,
+            results.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Fails when two targets would write the same output, which only flat output can cause, as
+/// whichever finished last would silently win.
+fn check_output_clashes(jobs: &[(PathBuf, PathBuf)]) -> Result<()> {
+    let mut sources: BTreeMap<&PathBuf, &PathBuf> = BTreeMap::new();
+    for (source, out_path) in jobs {
+        if let Some(other) = sources.insert(out_path, source) {
+            return Err(anyhow!(
+                "{} and {} would both be compiled to {} (use --preserve-layout)",
+                other.display(),
+                source.display(),
+                out_path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Expands every compiled target again, in reverse order so the thread pool interleaves them
+/// differently, and fails those whose output doesn't hash the same as what was written.
+/// Returns how many differed.
+fn verify_deterministic(results: &mut [TargetResult], opts: &Options) -> usize {
+    results
+        .par_iter_mut()
+        .rev()
+        .filter(|result| result.digest.is_some())
+        .map(|result| {
+            // Rendered for the same output path and header date as the first time.
+            let mut expansion = Expansion {
+                header_date: result.expansion.header_date.clone(),
+                ..Expansion::default()
+            };
+            let first = result.digest.take().unwrap_or_default();
+            let rendered = render_output(&result.source, &result.meant_for, opts, &mut expansion);
+            // The digest is of what was written, so in the `--output-encoding`.
+            let second = rendered.map(|expanded| {
+                sha256_hex(&encode_output(&result.output, &expanded, opts, &mut expansion))
+            });
+            let err = match second {
+                Ok(second) if second == first => {
+                    result.digest = Some(first);
+                    return 0;
+                }
+                Ok(second) => CompileError::Nondeterministic {
+                    path: result.source.clone(),
+                    first,
+                    second,
+                },
+                Err(err) => err,
+            };
+            log_message(&format!("Error verifying {}: {}", result.source.display(), err));
+            result.error = Some(err);
+            1
+        })
+        .sum()
+}
+
+/// Expands every target without writing anything, for commands that only need to know what
+/// the targets include. The first failing target fails the whole command.
+fn expand_targets(opts: &Options) -> Result<Vec<(PathBuf, Expansion)>> {
+    let jobs = match &opts.hermetic {
+        Some(hermetic) => hermetic.targets.clone(),
+        None => discover_targets(opts),
+    };
+    jobs.par_iter()
+        .map(|(file, _)| {
+            let mut exp = Expansion::default();
+            expand_settled(file, opts, &mut exp)
+                .map_err(|err| anyhow!("{}: {}", file.display(), err))?;
+            Ok((file.clone(), exp))
+        })
+        .collect()
+}
+
+/// Base XMLs `--min-depth` to `--max-depth` levels below `base_dir` (one folder below it by
+/// default), paired with their output path, in path order. With `--group`, only those matching
+/// one of the group's patterns.
+/// Compiled and quarantined outputs are not sources, even though they sit where sources are
+/// looked for. Neither is anything in a folder an earlier build wrote to: one holding the
+/// record of what was written there, or named like the default output folder in any case, as
+/// builds before that record was kept left. Outputs go directly into `output_dir`, or with
+/// `--preserve-layout` into the same folder below it as their source is below `base_dir`.
+fn discover_targets(opts: &Options) -> Vec<(PathBuf, PathBuf)> {
+    let mut sources =
+        walk_targets(&opts.base_dir, &opts.target_pattern, 1, opts.min_depth, opts.max_depth);
+    sources.sort();
+    let grouped: Option<BTreeSet<PathBuf>> = opts.target_groups.as_ref().map(|patterns| {
+        patterns
+            .iter()
+            .flat_map(|pattern| {
+                let pattern = pattern.trim_start_matches(['/', '\\']);
+                // A bare file name pattern matches in every folder.
+                let pattern = match pattern.contains(['/', '\\']) {
+                    true => pattern.to_string(),
+                    false => format!("**/{}", pattern),
+                };
+                glob::matching(&opts.base_dir, &pattern, Path::new(""))
+            })
+            .collect()
+    });
+    let in_group = |source: &Path| {
+        grouped.as_ref().is_none_or(|grouped| grouped.contains(&options::lexical(source)))
+    };
+    let mut earlier_outputs: HashMap<PathBuf, bool> = HashMap::new();
+    let mut in_earlier_output = |source: &Path| {
+        let mut dirs = source.ancestors().skip(1).take_while(|dir| *dir != opts.base_dir);
+        dirs.any(|dir| {
+            *earlier_outputs.entry(dir.to_path_buf()).or_insert_with(|| {
+                let name = dir.file_name().unwrap_or_default();
+                name.eq_ignore_ascii_case(options::DEFAULT_OUTPUT_DIR)
+                    || dir.join(written::WRITTEN_NAME).is_file()
+            })
+        })
+    };
+    sources
+        .into_iter()
+        .filter(|source| {
+            !source.starts_with(&opts.output_dir)
+                && !source.starts_with(&opts.quarantine_dir)
+                && !opts.patches.as_ref().is_some_and(|dir| source.starts_with(dir))
+                && in_group(source)
+                && !in_earlier_output(source)
+        })
+        .filter_map(|source| {
+            let out_path = match opts.preserve_layout {
+                true => opts.output_dir.join(source.strip_prefix(&opts.base_dir).ok()?),
+                false => opts.output_dir.join(source.file_name()?),
+            };
+            Some((source, out_path))
+        })
+        .collect()
+}
+
+/// Files between `min_depth` and `max_depth` levels below the walk's root whose name matches
+/// `pattern`; the entries of `dir` are at `depth`. Every subdirectory is listed as its own rayon
+/// task, and names are matched before the entry's type is looked up. Symlinks are not followed.
+fn walk_targets(
+    dir: &Path,
+    pattern: &Regex,
+    depth: usize,
+    min_depth: usize,
+    max_depth: usize,
+) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut files = Vec::new();
+    let mut subdirs = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let wanted = depth >= min_depth && pattern.is_match(&entry.file_name().to_string_lossy());
+        if !wanted && depth == max_depth {
+            continue;
+        }
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() && depth < max_depth {
+            subdirs.push(entry.path());
+        } else if file_type.is_file() && wanted {
+            files.push(entry.path());
+        }
+    }
+
+    files.par_extend(
+        subdirs
+            .par_iter()
+            .flat_map_iter(|subdir| {
+                walk_targets(subdir, pattern, depth + 1, min_depth, max_depth)
+            }),
+    );
+    files
+}
+
+/// The full text of `file`'s output: its expansion, declared in the `--output-encoding` if one
+/// is given and under the `--header` comment if any. With `--source-map`, where each of its
+/// lines came from is left in `exp.source_map`.
+fn render_output(
+    file: &Path,
+    out_path: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    if opts.source_maps {
+        exp.marks = Some(Vec::new());
+    }
+    let mut expanded = expand_settled(file, opts, exp)?;
+    if let Some(charset) = opts.output_encoding
+        && let Cow::Owned(declared) = charset::declare(&expanded, charset)
+    {
+        expanded = declared;
+    }
+    let output = match &opts.header {
+        Some(template) => {
+            let date = exp
+                .header_date
+                .get_or_insert_with(|| Local::now().format("%Y-%m-%d").to_string());
+            header::prepend(&expanded, &header::render(template, file, out_path, date, opts))
+        }
+        None => expanded,
+    };
+    Ok(match exp.marks.take() {
+        Some(marks) => {
+            let (output, map) = sourcemap::resolve(&output, &marks);
+            exp.source_map = Some(map);
+            output
+        }
+        None => output,
+    })
+}
+
+/// Expands `file`, then checks that nothing it read changed on disk in the meantime (e.g. rsync
+/// delivering new content mid-build). If something did, the target is expanded again from
+/// scratch; after `SETTLE_ATTEMPTS` the last output is kept but marked suspect.
+fn expand_settled(
+    file: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let mut attempt = 1;
+    loop {
+        let expanded = expand_target(file, opts, exp)?;
+        let changed = exp.changed_sources();
+        if changed.is_empty() {
+            return Ok(expanded);
+        }
+
+        let changed: Vec<String> = changed.iter().map(|path| path.display().to_string()).collect();
+        if attempt == SETTLE_ATTEMPTS {
+            exp.suspect = true;
+            exp.warn(format!(
+                "Suspect output for {}: sources kept changing while being read: {}",
+                file.display(),
+                changed.join(", ")
+            ));
+            return Ok(expanded);
+        }
+        log_message(&format!(
+            "Sources changed while expanding {}, retrying: {}",
+            file.display(),
+            changed.join(", ")
+        ));
+        exp.trace(|| format!("Sources changed, expanding again: {}", changed.join(", ")));
+        exp.restart();
+        attempt += 1;
+    }
+}
+
+/// Expands and writes one target, returning the SHA-256 of what was written.
+fn compile_target(
+    file: &Path,
+    out_path: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    check_compiled_source(file, opts, exp)?;
+    let expanded = render_output(file, out_path, opts, exp).inspect_err(|err| {
+        log_message(&format!("Error processing {}: {}", file.display(), err));
+    })?;
+    check_lock(file, opts, exp)?;
+    check_schema(file, out_path, &expanded, opts, exp);
+    let encoded = encode_output(out_path, &expanded, opts, exp);
+    let meant_for = out_path;
+    let out_path = &partition_path(file, out_path, &expanded, opts, exp);
+    if out_path != meant_for {
+        exp.partitioned = Some(out_path.clone());
+    }
+
+    // Hermetic builds must never produce an output that is missing content, and strict ones
+    // not one missing an include.
+    let include_failed = !exp.missing.is_empty() || !exp.unreadable.is_empty();
+    let strict_failed = opts.strict && include_failed && opts.on_warnings == WarningPolicy::Write;
+    let policy = if opts.hermetic.is_some() || strict_failed {
+        WarningPolicy::Skip
+    } else {
+        opts.on_warnings
+    };
+    if policy != WarningPolicy::Write && !exp.warnings.is_empty() {
+        let unresolved = CompileError::Unresolved {
+            path: file.to_path_buf(),
+            warnings: exp.warnings.clone(),
+        };
+        if policy == WarningPolicy::Skip {
+            log_message(&format!("Not writing {}: {}", out_path.display(), unresolved));
+            return Err(unresolved);
+        }
+
+        let quarantined = quarantine_path(out_path, opts);
+        write_output(&quarantined, file, &encoded, opts, exp)?;
+        if let Err(err) = write_quarantine_report(&quarantined, file, out_path, exp, &unresolved) {
+            log_message(&format!(
+                "Error writing report for {}: {}",
+                quarantined.display(),
+                err
+            ));
+        }
+        log_message(&format!("Quarantined: {} ({})", quarantined.display(), unresolved));
+        return Err(unresolved);
+    }
+
+    check_size_change(out_path, &encoded, opts, exp)?;
+    check_pii(out_path, &expanded, opts, exp)?;
+    write_output(out_path, file, &encoded, opts, exp)?;
+    log_message(&format!("Processed: {}", file.display()));
+    if opts.partition_by.is_some() {
+        remove_stale_partitions(meant_for, out_path, opts);
+    }
+
+    if opts.emit_depfile
+        && let Err(err) = write_depfile(out_path, file, &exp.includes)
+    {
+        log_message(&format!(
+            "Error writing depfile for {}: {}",
+            out_path.display(),
+            err
+        ));
+    }
+
+    if let Some(map) = &exp.source_map
+        && let Err(err) = sourcemap::write(out_path, map, opts)
+    {
+        log_message(&format!(
+            "Error writing source map for {}: {}",
+            out_path.display(),
+            err
+        ));
+    }
+
+    if opts.emit_chunks
+        && let Err(err) = chunks::write_manifest(out_path, &encoded, &opts.build_id)
+    {
+        log_message(&format!(
+            "Error writing chunk manifest for {}: {}",
+            out_path.display(),
+            err
+        ));
+    }
+
+    Ok(sha256_hex(&encoded))
+}
+
+/// The bytes written for `expanded`: as they are, or in the `--output-encoding`, with a
+/// warning if some characters had to be written as character references.
+fn encode_output<'a>(
+    out_path: &Path,
+    expanded: &'a str,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Cow<'a, [u8]> {
+    let Some(charset) = opts.output_encoding else {
+        return Cow::Borrowed(expanded.as_bytes());
+    };
+    let (encoded, unrepresentable) = charset::encode(expanded, charset);
+    if unrepresentable > 0 {
+        exp.warn(format!(
+            "Warning: {}: {} characters not in {}, written as character references",
+            out_path.display(),
+            unrepresentable,
+            charset.label()
+        ));
+    }
+    encoded
+}
+
+/// Where `--partition-by` puts the output meant for `out_path`: below the output directory, in
+/// a folder named after the first value the field selects in `expanded`. Without a value that
+/// makes a folder name the output stays where it is, with a warning.
+fn partition_path(
+    file: &Path,
+    out_path: &Path,
+    expanded: &str,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> PathBuf {
+    let (Some(field), Ok(relative)) =
+        (&opts.partition_by, out_path.strip_prefix(&opts.output_dir))
+    else {
+        return out_path.to_path_buf();
+    };
+    let problem = match field.values(expanded).into_iter().next() {
+        None => "selects nothing".to_string(),
+        Some(key) if partition_key_valid(&key) => return opts.output_dir.join(key).join(relative),
+        Some(key) => format!(
+            "selects {:?}, which is not a folder name",
+            excerpt::truncate(&key, opts.excerpt_length)
+        ),
+    };
+    exp.warn(format!(
+        "Warning: {}: --partition-by {} {}; output left in {}",
+        file.display(),
+        field.expr,
+        problem,
+        opts.output_dir.display()
+    ));
+    out_path.to_path_buf()
+}
+
+/// A single, ordinary path component on every platform.
+fn partition_key_valid(key: &str) -> bool {
+    !matches!(key, "" | "." | "..")
+        && key.len() <= 255
+        && !key.chars().any(|c| c.is_control() || "/\\:*?\"<>|".contains(c))
+}
+
+/// Removes earlier outputs of the target now written to `out_path` from the other partitions
+/// and from `meant_for`, left there when its key changed. Only files still exactly as KiwiXML
+/// wrote them go, with their depfile and chunk manifest.
+fn remove_stale_partitions(meant_for: &Path, out_path: &Path, opts: &Options) {
+    let Ok(relative) = meant_for.strip_prefix(&opts.output_dir) else {
+        return;
+    };
+    let Ok(entries) = fs::read_dir(&opts.output_dir) else {
+        return;
+    };
+    let partitions = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .map(|entry| entry.path().join(relative));
+    for stale in partitions.chain([meant_for.to_path_buf()]) {
+        if stale == out_path || !stale.is_file() || !written::unchanged(&stale, opts) {
+            continue;
+        }
+        let _ = permissions::clear_readonly(&stale);
+        match fs::remove_file(&stale) {
+            Ok(()) => log_message(&format!("Removed stale output: {}", stale.display())),
+            Err(err) => {
+                log_message(&format!("Error removing {}: {}", stale.display(), err));
+                continue;
+            }
+        }
+        for suffix in [".d", ".chunks", ".map"] {
+            let mut sidecar = stale.clone().into_os_string();
+            sidecar.push(suffix);
+            let _ = fs::remove_file(sidecar);
+        }
+        // The partition folder too, once nothing else is left in it.
+        if let Some(dir) = stale.parent().filter(|dir| *dir != opts.output_dir) {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+/// Where `WarningPolicy::Quarantine` puts the output meant for `out_path`: the same place
+/// relative to `quarantine_dir` as it has relative to `output_dir`.
+fn quarantine_path(out_path: &Path, opts: &Options) -> PathBuf {
+    match out_path.strip_prefix(&opts.output_dir) {
+        Ok(relative) => opts.quarantine_dir.join(relative),
+        Err(_) => opts.quarantine_dir.join(out_path.file_name().unwrap_or_default()),
+    }
+}
+
+/// Round-tripping a compiled file through the tool again double-wraps its content, so a source
+/// that looks compiled is skipped or warned about: one that is byte for byte an output KiwiXML
+/// wrote to the output directory that differs from the base XML it came from, or that carries
+/// `PROVENANCE_MARKER` from a `--header`. Targets named explicitly, by a hermetic manifest or
+/// through the library, are also caught when they lie in the output or quarantine directory,
+/// which scanning leaves out.
+fn compiled_source(
+    file: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<Option<CompileError>, CompileError> {
+    if opts.compiled_sources == CompiledSources::Allow {
+        return Ok(None);
+    }
+    let reason = if file.starts_with(&opts.output_dir) || file.starts_with(&opts.quarantine_dir) {
+        "it is inside the output directory"
+    } else {
+        // As written, since variables and conditionals depend on the target's folder settings.
+        let source = read_source(file, opts, exp)?;
+        // Only the head is checked: the marker is in a header comment, not in included content.
+        let head = &source.content[..source.content.floor_char_boundary(1024)];
+        if let Some(output) = written::output_with_digest(&source.digest, opts) {
+            log_message(&format!("{} is a copy of {}", file.display(), output.display()));
+            "it is identical to an output KiwiXML wrote"
+        } else if head.contains(PROVENANCE_MARKER) {
+            "it carries a KiwiXML provenance marker"
+        } else {
+            return Ok(None);
+        }
+    };
+    Ok(Some(CompileError::CompiledSource {
+        path: file.to_path_buf(),
+        reason,
+    }))
+}
+
+/// Leaves out of a run's `jobs` the sources that look compiled under `--compiled-sources
+/// skip`, before their outputs are checked for clashes with the real targets'. Each is logged
+/// and printed as a warning; the run doesn't fail for them.
+fn skip_compiled_sources(jobs: Vec<(PathBuf, PathBuf)>, opts: &Options) -> Vec<(PathBuf, PathBuf)> {
+    if opts.compiled_sources != CompiledSources::Skip {
+        return jobs;
+    }
+    let checked: Vec<((PathBuf, PathBuf), Option<CompileError>)> = jobs
+        .into_par_iter()
+        .map(|job| {
+            // A source that can't be read fails when it's compiled, with the reason.
+            let err = compiled_source(&job.0, opts, &mut Expansion::default()).ok().flatten();
+            (job, err)
+        })
+        .collect();
+    let mut jobs = Vec::with_capacity(checked.len());
+    for (job, err) in checked {
+        let Some(err) = err else {
+            jobs.push(job);
+            continue;
+        };
+        log_message(&format!("Skipping {}: {}", job.0.display(), err));
+        let source = options::relative(&job.0, &opts.base_dir);
+        let warning = format!("Warning: skipped {}: {}\n", source, err);
+        console::print(console::Channel::Stderr, &warning);
+    }
+    jobs
+}
+
+/// Fails `file` when it looks compiled under `--compiled-sources skip`, or warns about it
+/// under `warn`; see [`compiled_source`].
+fn check_compiled_source(
+    file: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<(), CompileError> {
+    let Some(err) = compiled_source(file, opts, exp)? else {
+        return Ok(());
+    };
+    if opts.compiled_sources == CompiledSources::Skip {
+        log_message(&format!("Skipping {}: {}", file.display(), err));
+        return Err(err);
+    }
+    exp.warn(format!("Warning: {}: {}", file.display(), err));
+    Ok(())
+}
+
+/// Compares the new output's size with what the previous build left at `out_path`. A large
+/// swing usually means an include silently went missing (or was pulled in twice).
+fn check_size_change(
+    out_path: &Path,
+    output: &[u8],
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<(), CompileError> {
+    let Some(limit) = opts.max_size_change else {
+        return Ok(());
+    };
+    let Ok(meta) = fs::metadata(out_path) else {
+        return Ok(());
+    };
+    let (previous, current) = (meta.len(), output.len() as u64);
+    if previous == 0 || error::size_change(previous, current).abs() <= limit {
+        return Ok(());
+    }
+
+    let err = CompileError::SizeChange {
+        path: out_path.to_path_buf(),
+        previous,
+        current,
+    };
+    if opts.fail_on_size_change {
+        log_message(&format!("Not writing {}: {}", out_path.display(), err));
+        return Err(err);
+    }
+    exp.warn(format!("Warning: {}: {}", out_path.display(), err));
+    Ok(())
+}
+
+/// Fails the target if a fragment it includes is missing from the `--locked` lockfile or has
+/// changed since. Every offending fragment is logged; the error names the first.
+fn check_lock(file: &Path, opts: &Options, exp: &Expansion) -> Result<(), CompileError> {
+    let Some(lock) = &opts.lock else {
+        return Ok(());
+    };
+    let mut first = None;
+    for fragment in exp.includes.iter().collect::<BTreeSet<_>>() {
+        let Some(read) = exp.sources.get(fragment) else {
+            continue;
+        };
+        if let Some(reason) = lock.check(fragment, &read.digest) {
+            log_message(&format!(
+                "Error processing {}: {} {} ({})",
+                file.display(),
+                fragment.display(),
+                reason,
+                lock.path.display()
+            ));
+            first.get_or_insert(CompileError::Locked {
+                path: fragment.clone(),
+                reason,
+            });
+        }
+    }
+    first.map_or(Ok(()), Err)
+}
+
+/// Violations reported one by one for an output; a fragment that drifted from the schema
+/// tends to repeat the same mistake many times.
+const MAX_SCHEMA_VIOLATIONS: usize = 20;
+
+/// Checks the new output against the `--schema` XSDs, with a warning per violation, so the
+/// target goes through `--on-warnings` like one with an unresolved include.
+fn check_schema(file: &Path, out_path: &Path, expanded: &str, opts: &Options, exp: &mut Expansion) {
+    let Some(schemas) = &opts.schemas else {
+        return;
+    };
+    let source = options::relative(file, &opts.base_dir);
+    match schemas.validate(&source, expanded, opts.excerpt_length) {
+        Some(violations) => {
+            for (line, message) in violations.iter().take(MAX_SCHEMA_VIOLATIONS) {
+                // With `--source-map`, a violation points at the file to fix.
+                let located = exp.source_map.as_ref().and_then(|map| map.locate(*line));
+                exp.warn(match located {
+                    Some((source, source_line)) => format!(
+                        "Warning: {}:{}: schema: {} (at {}:{})",
+                        source.display(),
+                        source_line,
+                        message,
+                        out_path.display(),
+                        line
+                    ),
+                    None => format!(
+                        "Warning: {}:{}: schema: {}",
+                        out_path.display(),
+                        line,
+                        message
+                    ),
+                });
+            }
+            if violations.len() > MAX_SCHEMA_VIOLATIONS {
+                exp.warn(format!(
+                    "Warning: {}: schema: {} more violations",
+                    out_path.display(),
+                    violations.len() - MAX_SCHEMA_VIOLATIONS
+                ));
+            }
+        }
+        None => log_message(&format!("No schema for {}", out_path.display())),
+    }
+}
+
+/// Looks for personal data in the new output, naming the source each match was copied from.
+/// Test customer data occasionally slips into a fragment and from there into shipped config.
+fn check_pii(
+    out_path: &Path,
+    expanded: &str,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<(), CompileError> {
+    if !opts.scan_pii {
+        return Ok(());
+    }
+    let findings: Vec<String> = pii::scan(expanded, &opts.pii_allow)
+        .into_iter()
+        .map(|finding| {
+            let origin = exp
+                .sources
+                .iter()
+                .find(|(_, read)| read.content.contains(&finding.text))
+                .map(|(path, _)| format!(", from {}", path.display()))
+                .unwrap_or_default();
+            format!(
+                "{}:{}: possible {} {}{}",
+                out_path.display(),
+                finding.line,
+                finding.kind,
+                finding.masked,
+                origin
+            )
+        })
+        .collect();
+    for finding in &findings {
+        exp.warn(format!("Warning: {}", finding));
+    }
+
+    if opts.fail_on_pii && !findings.is_empty() {
+        let err = CompileError::Pii {
+            path: out_path.to_path_buf(),
+            findings,
+        };
+        log_message(&format!("Not writing {}: {}", out_path.display(), err));
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Writes `expanded` to `out_path` and gives it the configured permissions and attributes.
+fn write_output(
+    out_path: &Path,
+    file: &Path,
+    output: &[u8],
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<(), CompileError> {
+    if let Some(parent) = out_path.parent() {
+        retry::io(opts, "creating", parent, &mut exp.retries, || fs::create_dir_all(parent))
+            .map_err(CompileError::io(parent))?;
+    }
+    written::check(out_path, opts).inspect_err(|err| {
+        log_message(&format!("Not writing {}: {}", out_path.display(), err));
+    })?;
+    permissions::prepare(out_path, opts)?;
+    retry::io(opts, "writing", out_path, &mut exp.retries, || fs::write(out_path, output))
+        .map_err(CompileError::io(out_path))
+        .inspect_err(|err| {
+            log_message(&format!("Error writing {}: {}", out_path.display(), err));
+        })?;
+    let source = exp.sources.get(file).map(|read| read.digest.as_str());
+    written::record(out_path, output, source, opts);
+    permissions::apply(out_path, file, opts).inspect_err(|err| {
+        log_message(&format!("Error processing {}: {}", file.display(), err));
+    })
+}
+
+/// Writes `<output>.report.json` next to a quarantined output, saying why it is there.
+fn write_quarantine_report(
+    quarantined: &Path,
+    source: &Path,
+    out_path: &Path,
+    exp: &Expansion,
+    err: &CompileError,
+) -> Result<()> {
+    let includes: Vec<String> =
+        exp.includes.iter().map(|path| path.display().to_string()).collect();
+    let report = serde_json::json!({
+        "source": source.display().to_string(),
+        "output": out_path.display().to_string(),
+        "quarantined": quarantined.display().to_string(),
+        "time": Local::now().to_rfc3339(),
+        "error": err.to_string(),
+        "error_kind": err.kind(),
+        "includes": includes,
+        "warnings": exp.warnings,
+        "suspect": exp.suspect,
+    });
+
+    let mut report_path = quarantined.as_os_str().to_owned();
+    report_path.push(".report.json");
+    fs::write(report_path, serde_json::to_string_pretty(&report)? + "\n")?;
+    Ok(())
+}
+
+/// Lists every failed target on stderr, with its error and warnings, and fails the run if
+/// there are more than `--max-errors` of them.
+fn report_failures(results: &[TargetResult], opts: &Options) -> Result<()> {
+    let failed: Vec<&TargetResult> = results.iter().filter(|r| r.error.is_some()).collect();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let mut listed = String::from("Failed targets:\n");
+    for result in &failed {
+        let source = options::relative(&result.source, &opts.base_dir);
+        let error = result.error.as_ref().map(|err| err.to_string()).unwrap_or_default();
+        listed.push_str(&format!("  {}: {}\n", source, error));
+        for warning in &result.expansion.warnings {
+            listed.push_str(&format!("    {}\n", warning));
+        }
+    }
+    console::print(console::Channel::Stderr, &listed);
+    if failed.len() <= opts.max_errors {
+        return Ok(());
+    }
+    match opts.max_errors {
+        0 => Err(anyhow!("{} of {} targets failed", failed.len(), results.len())),
+        max => Err(anyhow!(
+            "{} of {} targets failed, more than --max-errors {}",
+            failed.len(),
+            results.len(),
+            max
+        )),
+    }
+}
+
+/// Every poisoned file of the run, with the error it caused and the targets it was met in.
+fn poisoned_files(results: &[TargetResult]) -> BTreeMap<&Path, (&str, Vec<&Path>)> {
+    let mut poisoned: BTreeMap<&Path, (&str, Vec<&Path>)> = BTreeMap::new();
+    for result in results {
+        for (path, error) in &result.expansion.poisoned {
+            let entry = poisoned.entry(path).or_insert((error, Vec::new()));
+            entry.1.push(&result.source);
+        }
+    }
+    poisoned
+}
+
+/// Logs, and prints to stderr, the files that panicked or ran over `--pass-timeout`, so they
+/// can be looked at (or left out) before the next run.
+fn report_poisoned(results: &[TargetResult], opts: &Options) -> Result<()> {
+    let poisoned = poisoned_files(results);
+    if poisoned.is_empty() {
+        return Ok(());
+    }
+    let mut listed = String::from("Poisoned files:\n");
+    for (path, (error, targets)) in &poisoned {
+        let targets: Vec<String> =
+            targets.iter().map(|target| options::relative(target, &opts.base_dir)).collect();
+        let line = format!(
+            "{}: {} (in {})",
+            options::relative(path, &opts.base_dir),
+            error,
+            targets.join(", ")
+        );
+        log_message(&format!("Poisoned: {}", line));
+        listed.push_str(&format!("  {}\n", line));
+    }
+    console::print(console::Channel::Stderr, &listed);
+    Ok(())
+}
+
+/// Prints one JSON object per target to stdout, in list order, and fails the run if any
+/// target failed so the calling build rule fails too.
+fn report_hermetic(results: &[TargetResult]) -> Result<()> {
+    let mut lines = String::new();
+    for result in results {
+        let includes: Vec<String> = result
+            .expansion
+            .includes
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        let line = serde_json::json!({
+            "source": result.source.display().to_string(),
+            "output": result.output.display().to_string(),
+            "status": if result.error.is_none() { "ok" } else { "failed" },
+            "error": result.error.as_ref().map(|err| err.to_string()),
+            "error_kind": result.error.as_ref().map(|err| err.kind()),
+            "error_path": result.error.as_ref().map(|err| err.path().display().to_string()),
+            "includes": includes,
+            "warnings": result.expansion.warnings,
+            "suspect": result.expansion.suspect,
+            "retries": result.expansion.retries,
+            "shadowed": shadowed_json(&result.expansion),
+            "deprecations": result.expansion.deprecations.values().collect::<Vec<_>>(),
+        });
+        lines.push_str(&format!("{}\n", line));
+    }
+    console::print(console::Channel::Stdout, &lines);
+
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    if failed > 0 {
+        return Err(anyhow!("{} of {} targets failed", failed, results.len()));
+    }
+    Ok(())
+}
+
+/// `{"used": ..., "shadowed": [...]}` for each distinct shadowed include of a target.
+fn shadowed_json(exp: &Expansion) -> Vec<serde_json::Value> {
+    exp.shadowed_once()
+        .into_iter()
+        .map(|(used, shadowed)| {
+            let shadowed: Vec<String> = shadowed.iter().map(|p| p.display().to_string()).collect();
+            serde_json::json!({ "used": used.display().to_string(), "shadowed": shadowed })
+        })
+        .collect()
+}
+
+/// Writes `<output>.d` in Make syntax (which Ninja also reads): the output depends on its
+/// source and every file in its include closure.
+fn write_depfile(out_path: &Path, source: &Path, includes: &[PathBuf]) -> Result<()> {
+    fn escape(path: &Path) -> String {
+        path.display()
+            .to_string()
+            .replace('$', "$$")
+            .replace('#', "\\#")
+            .replace(' ', "\\ ")
+    }
+
+    let mut seen = HashSet::new();
+    let mut depfile = format!("{}:", escape(out_path));
+    for dep in std::iter::once(source).chain(includes.iter().map(PathBuf::as_path)) {
+        if seen.insert(dep) {
+            depfile.push_str(" \\\n  ");
+            depfile.push_str(&escape(dep));
+        }
+    }
+    depfile.push('\n');
+
+    let mut depfile_path = out_path.as_os_str().to_owned();
+    depfile_path.push(".d");
+    fs::write(depfile_path, depfile)?;
+    Ok(())
+}
+
+fn run(opts: &Options) -> Result<()> {
+    if opts.dry_run {
+        return dry_run(opts);
+    }
+    log_section(&format!(
+        "Starting processing in {} (build {})",
+        opts.base_dir.display(),
+        opts.build_id
+    ));
+    process_xml_files(opts, &mut watch::Builds::new())?;
+    log_section(&format!(
+        "Processing complete. Compiled XMLs saved in {}",
+        opts.output_dir.display()
+    ));
+    Ok(())
+}
+
+/// What a build would do with one target's output.
+enum Planned {
+    Write { out_path: PathBuf, existing: Option<bool> },
+    Quarantine(PathBuf),
+    Skip { out_path: PathBuf, warnings: usize },
+    Fail(CompileError),
+}
+
+/// Expands every target and runs a build's checks like `check`, then prints what a build would
+/// write, quarantine or skip under the current options, and every missing include. Nothing is
+/// written and the output directory isn't created. Fails like a build when targets fail.
+fn dry_run(opts: &Options) -> Result<()> {
+    log_section(&format!(
+        "Dry run in {} (build {})",
+        opts.base_dir.display(),
+        opts.build_id
+    ));
+    let jobs = skip_compiled_sources(discover_targets(opts), opts);
+    check_output_clashes(&jobs)?;
+    let started = Instant::now();
+    console::start("Checking", jobs.len(), opts);
+    let planned: Vec<(PathBuf, Planned, Vec<PathBuf>)> = jobs
+        .par_iter()
+        .map(|(file, out_path)| {
+            let ((exp, planned), entries) = console::capture(|| {
+                let mut exp = Expansion::default();
+                let planned = plan_target(file, out_path, opts, &mut exp)
+                    .unwrap_or_else(Planned::Fail);
+                (exp, planned)
+            });
+            let status = match &planned {
+                Planned::Fail(err) => console::Status::Failed(err),
+                _ => console::Status::Checked,
+            };
+            console::flush(file, status, &exp.warnings, &entries, opts);
+            (file.clone(), planned, exp.missing)
+        })
+        .collect();
+    console::finish(started.elapsed(), opts);
+
+    let shown = |path: &Path| options::relative(path, &opts.base_dir);
+    let mut plan = String::new();
+    let mut failed = 0;
+    for (source, planned, _) in &planned {
+        let line = match planned {
+            Planned::Write { out_path, existing: None } => {
+                format!("Would write {} (new)", shown(out_path))
+            }
+            Planned::Write { out_path, existing: Some(true) } => {
+                format!("Unchanged   {}", shown(out_path))
+            }
+            Planned::Write { out_path, existing: Some(false) } => {
+                format!("Would write {} (changed)", shown(out_path))
+            }
+            Planned::Quarantine(path) => format!("Would quarantine {}", shown(path)),
+            Planned::Skip { out_path, warnings: 1 } => {
+                format!("Would skip  {} (1 warning)", shown(out_path))
+            }
+            Planned::Skip { out_path, warnings } => {
+                format!("Would skip  {} ({} warnings)", shown(out_path), warnings)
+            }
+            Planned::Fail(err) => {
+                failed += 1;
+                format!("Would fail  {}: {}", shown(source), err)
+            }
+        };
+        log_message(&line);
+        plan.push_str(&line);
+        plan.push('\n');
+    }
+    let missing: BTreeSet<(&PathBuf, &PathBuf)> = planned
+        .iter()
+        .flat_map(|(source, _, missing)| missing.iter().map(move |include| (include, source)))
+        .collect();
+    if !missing.is_empty() {
+        plan.push_str("Missing includes:\n");
+        for (include, source) in missing {
+            plan.push_str(&format!("  {} (from {})\n", shown(include), shown(source)));
+        }
+    }
+    console::print(console::Channel::Stdout, &plan);
+
+    if failed > opts.max_errors {
+        return Err(anyhow!("{} of {} targets would fail", failed, jobs.len()));
+    }
+    log_section("Dry run complete, nothing was written");
+    Ok(())
+}
+
+/// What a build would do with `file`'s output, after the same expansion and checks.
+fn plan_target(
+    file: &Path,
+    out_path: &Path,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<Planned, CompileError> {
+    check_compiled_source(file, opts, exp)?;
+    let expanded = render_output(file, out_path, opts, exp)?;
+    check_lock(file, opts, exp)?;
+    check_schema(file, out_path, &expanded, opts, exp);
+    let encoded = encode_output(out_path, &expanded, opts, exp);
+    let out_path = partition_path(file, out_path, &expanded, opts, exp);
+    if !exp.warnings.is_empty() {
+        match opts.on_warnings {
+            WarningPolicy::Write => {}
+            WarningPolicy::Quarantine => {
+                return Ok(Planned::Quarantine(quarantine_path(&out_path, opts)));
+            }
+            WarningPolicy::Skip => {
+                return Ok(Planned::Skip {
+                    out_path,
+                    warnings: exp.warnings.len(),
+                });
+            }
+        }
+    }
+    check_size_change(&out_path, &encoded, opts, exp)?;
+    check_pii(&out_path, &expanded, opts, exp)?;
+    written::check(&out_path, opts)?;
+    let existing = fs::read(&out_path).ok().map(|current| *current == *encoded);
+    Ok(Planned::Write { out_path, existing })
+}
+
+/// Expands every target and runs the same checks as a build, without writing anything. Fails
+/// when a target fails or has warnings, so CI can gate on it whatever `--on-warnings` says.
+fn check(opts: &Options) -> Result<()> {
+    log_section(&format!(
+        "Checking {} (build {})",
+        opts.base_dir.display(),
+        opts.build_id
+    ));
+    let jobs = match &opts.hermetic {
+        Some(hermetic) => hermetic.targets.clone(),
+        None => discover_targets(opts),
+    };
+    let started = Instant::now();
+    console::start("Checking", jobs.len(), opts);
+    let failed: usize = jobs
+        .par_iter()
+        .map(|(file, out_path)| {
+            let ((exp, result), entries) = console::capture(|| {
+                let mut exp = Expansion::default();
+                let result = check_compiled_source(file, opts, &mut exp)
+                    .and_then(|()| render_output(file, out_path, opts, &mut exp))
+                    .and_then(|expanded| {
+                        check_lock(file, opts, &exp)?;
+                        check_schema(file, out_path, &expanded, opts, &mut exp);
+                        let encoded = encode_output(out_path, &expanded, opts, &mut exp);
+                        check_size_change(out_path, &encoded, opts, &mut exp)?;
+                        check_pii(out_path, &expanded, opts, &mut exp)
+                    });
+                if let Err(err) = &result {
+                    log_message(&format!("Error checking {}: {}", file.display(), err));
+                }
+                (exp, result)
+            });
+            let status = match &result {
+                Ok(()) => console::Status::Checked,
+                Err(err) => console::Status::Failed(err),
+            };
+            console::flush(file, status, &exp.warnings, &entries, opts);
+            usize::from(result.is_err() || !exp.warnings.is_empty())
+        })
+        .sum();
+    console::finish(started.elapsed(), opts);
+    if failed > 0 {
+        return Err(anyhow!("{} of {} targets failed the check", failed, jobs.len()));
+    }
+    log_section(&format!("Check passed for {} targets", jobs.len()));
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    expand_targets, log_message,
+    options::{lexical, relative, Options},
+};
+
+/// Default lockfile, in the base directory.
+pub const LOCK_NAME: &str = "kiwixml.lock";
+
+/// Fragment hashes loaded from a lockfile, for `--locked` builds.
+pub struct Lock {
+    pub path: PathBuf,
+    /// SHA-256 of each locked fragment, keyed by its lexically normalized path.
+    hashes: HashMap<PathBuf, String>,
+}
+
+impl Lock {
+    /// Lockfile format, one fragment per line: `<sha256>  <path>`, the path relative to
+    /// `base_dir`. Blank lines and `#` comments are skipped.
+    pub fn load(path: &Path, base_dir: &Path) -> Result<Lock> {
+        let text = fs::read_to_string(path).map_err(|err| {
+            anyhow!("Cannot read lockfile {} ({}), run `KiwiXML lock`", path.display(), err)
+        })?;
+        let mut hashes = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (digest, fragment) = line.split_once("  ").ok_or_else(|| {
+                anyhow!("{}:{}: expected '<sha256>  <path>'", path.display(), n + 1)
+            })?;
+            hashes.insert(lexical(&base_dir.join(fragment.trim())), digest.to_string());
+        }
+        Ok(Lock {
+            path: path.to_path_buf(),
+            hashes,
+        })
+    }
+
+    /// Why `fragment`, read with hash `digest`, breaks the lock, if it does.
+    pub fn check(&self, fragment: &Path, digest: &str) -> Option<&'static str> {
+        match self.hashes.get(&lexical(fragment)) {
+            Some(locked) if locked == digest => None,
+            Some(_) => Some("has changed since the lockfile was written"),
+            None => Some("is not in the lockfile"),
+        }
+    }
+}
+
+/// Expands every target and writes the hash of each fragment they include to `lockfile`,
+/// returning how many there are. Nothing is written to the output directory.
+pub fn write(opts: &Options, lockfile: &Path) -> Result<usize> {
+    let mut hashes = BTreeMap::new();
+    for (_, exp) in expand_targets(opts)? {
+        for fragment in &exp.includes {
+            if let Some(read) = exp.sources.get(fragment) {
+                hashes.insert(relative(fragment, &opts.base_dir), read.digest.clone());
+            }
+        }
+    }
+
+    let mut text = String::from(
+        "# SHA-256 of every fragment the targets include. Regenerate with `KiwiXML lock`.\n",
+    );
+    for (fragment, digest) in &hashes {
+        text.push_str(&format!("{}  {}\n", digest, fragment));
+    }
+    fs::write(lockfile, text)?;
+    log_message(&format!("Locked {} fragments in {}", hashes.len(), lockfile.display()));
+    Ok(hashes.len())
+}
@@ -1,178 +1,482 @@
 use std::{
-    env,
-    fs::{self, OpenOptions},
-    io::Write,
+    env, fs,
+    io::{self, IsTerminal, Write},
     path::{Path, PathBuf},
-    sync::Mutex,
-    time::SystemTime,
+    process::{Command as Process, Stdio},
 };
 
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, Local};
-use once_cell::sync::Lazy;
-use rayon::prelude::*;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 use regex::Regex;
-use walkdir::WalkDir;
-
-static LOG_FILE: Lazy<Mutex<fs::File>> = Lazy::new(|| {
-    let file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open("processing.log")
-        .expect("Failed to create or open log file");
-    Mutex::new(file)
-});
-
-fn timestamp() -> String {
-    let now = SystemTime::now();
-    let datetime: DateTime<Local> = now.into();
-    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
-}
-
-fn log_message(msg: &str) {
-    let entry = format!("[{}]  {}", timestamp(), msg);
-    if let Ok(mut f) = LOG_FILE.lock() {
-        let _ = writeln!(f, "{}", entry);
-    }
+
+use kvy_xmls::{
+    auth, crash, daemon, graph, lock, log_message, log_section,
+    options::{self, lexical, CompileArgs, Options},
+    preview, replay, report, schema, search, serve, set_log_json, set_log_line_length, set_log_path,
+    storage, vendor, workspace, Compiler,
+};
+
+/// Compiles base XMLs and the fragments they include into standalone XML files.
+#[derive(Parser)]
+#[command(
+    name = "KiwiXML",
+    version,
+    args_override_self = true,
+    after_help = "Every option is described in README.md."
+)]
+struct Cli {
+    /// Log file (default: processing.log)
+    #[arg(long, global = true, value_name = "FILE")]
+    log: Option<PathBuf>,
+    /// Log format: text, or json for one JSON object per line
+    #[arg(long, global = true, value_name = "FORMAT", value_parser = ["text", "json"])]
+    log_format: Option<String>,
+    /// Cap every log line and console message at this many bytes
+    #[arg(long, global = true, value_name = "BYTES", value_parser = options::positive)]
+    log_line_length: Option<usize>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    build: Build,
 }
 
-fn log_section(title: &str) {
-    if let Ok(mut f) = LOG_FILE.lock() {
-        let _ = writeln!(f, "\n────────────────────────────────────────────");
-        let _ = writeln!(f, "{}", title);
-        let _ = writeln!(f, "────────────────────────────────────────────");
-    }
+#[derive(Subcommand)]
+enum Command {
+    /// Compile every target (the default)
+    Build(Build),
+    /// Compile, then recompile targets as their sources change
+    Watch(Compile),
+    /// Expand and check every target without writing; fails on any warning
+    Check(Batch),
+    /// Delete the outputs and other files a build writes; with --all, the whole output directory
+    Clean(Batch),
+    /// Serve the targets over HTTP, expanded on every request
+    Serve(Serve),
+    /// Recompile the targets a --json-report file lists as failed
+    Replay(Replay),
+    /// Show how compiling now would change the output directory; fails if anything would
+    Diff(Compile),
+    /// Estimate how the outputs compress and how much they repeat each other, for sizing an
+    /// artifact store
+    Storage(Storage),
+    /// Print one target compiled in memory, marking the lines that differ from its output
+    Preview(Preview),
+    /// Record the hash of every included fragment in the lockfile
+    Lock(Compile),
+    /// Copy the fragments included from outside the base dir into vendor/
+    Vendor(Compile),
+    /// Compare the include graphs of two git revisions
+    #[command(subcommand)]
+    Graph(Graph),
+    /// Find where text or elements end up in the compiled targets
+    Search(Search),
+    /// Work with --json-report files
+    #[command(subcommand)]
+    Report(ReportCommand),
+    /// Write editor completion data for the elements XSDs declare
+    Completions(Completions),
 }
 
-fn normalize_include_path(base_dir: &Path, include: &str) -> PathBuf {
-    let normalized = if cfg!(windows) {
-        include.to_string()
-    } else {
-        include.replace('\\', "/")
-    };
-    base_dir.join(normalized)
+#[derive(Args)]
+struct Build {
+    #[command(flatten)]
+    mode: Mode,
+    /// Socket the daemon listens on and the client connects to; host:port on Windows
+    #[arg(long, value_name = "PATH")]
+    addr: Option<String>,
+    /// Access tokens for the daemon (default: the [tokens] of kvy-xmls.toml)
+    #[arg(long, value_name = "FILE")]
+    tokens: Option<PathBuf>,
+    #[command(flatten)]
+    batch: Batch,
 }
 
-fn expand_includes(file_path: &Path, is_root: bool) -> Result<String> {
-    let content = fs::read_to_string(file_path)?;
-    let include_re = Regex::new(r#"<!--\s*#include file="(.*?)"\s*-->"#)?;
-    let dir = file_path.parent().unwrap_or_else(|| Path::new("."));
+#[derive(Args)]
+#[group(multiple = false)]
+struct Mode {
+    /// Keep running and compile for --client calls
+    #[arg(long)]
+    daemon: bool,
+    /// Have the daemon compile, with the options given here
+    #[arg(long)]
+    client: bool,
+    /// The same as the watch command
+    #[arg(long)]
+    watch: bool,
+}
 
-    let replaced = include_re.replace_all(&content, |caps: &regex::Captures| {
-        let include_path = normalize_include_path(dir, caps[1].trim());
-        if !include_path.exists() {
-            log_message(&format!("Missing include: {}", include_path.display()));
-            return format!("<!-- Include not found: {} -->", include_path.display());
-        }
+/// Options of the commands that can run on every project of a workspace.
+#[derive(Args)]
+struct Batch {
+    /// Compile the projects listed in a workspace file
+    #[arg(long, value_name = "FILE")]
+    workspace: Option<PathBuf>,
+    /// Only the workspace projects with these names, comma-separated (repeatable)
+    #[arg(long, value_name = "NAMES", requires = "workspace")]
+    project: Vec<String>,
+    /// Only accepted to point at serve --preview
+    #[arg(long, hide = true)]
+    preview: bool,
+    #[command(flatten)]
+    options: CompileArgs,
+}
 
-        match expand_includes(&include_path, false) {
-            Ok(included_content) => {
-                let inner = remove_placeholders(&included_content);
-                let inner = strip_comments_and_format_spaces(&inner);
+#[derive(Args)]
+struct Compile {
+    #[command(flatten)]
+    options: CompileArgs,
+}
 
-                log_message(&format!("Included: {}", include_path.display()));
+#[derive(Args)]
+struct Serve {
+    /// Expand every target from source on each request
+    #[arg(long, required = true)]
+    preview: bool,
+    /// Address to listen on (default: 127.0.0.1:47814)
+    #[arg(long, value_name = "HOST:PORT")]
+    addr: Option<String>,
+    /// Stylesheet every served XML references
+    #[arg(long, value_name = "FILE")]
+    xslt: Option<PathBuf>,
+    /// File every request is appended to as a JSON line
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+    /// Access tokens (default: the [tokens] of kvy-xmls.toml)
+    #[arg(long, value_name = "FILE")]
+    tokens: Option<PathBuf>,
+    /// Also compile on every change, as watch does, announcing rebuilt outputs at /_events
+    #[arg(long)]
+    watch: bool,
+    #[command(flatten)]
+    options: CompileArgs,
+}
 
-                if is_root {
-                    format!("<![CDATA[\n{}\n]]>", inner)
-                } else {
-                    inner
-                }
-            }
-            Err(err) => {
-                log_message(&format!(
-                    "Error including {}: {}",
-                    include_path.display(),
-                    err
-                ));
-                format!("<!-- Error including {}: {} -->", include_path.display(), err)
-            }
-        }
-    });
+#[derive(Args)]
+struct Replay {
+    /// The --json-report file of the run to replay
+    #[arg(value_name = "REPORT")]
+    json_file: PathBuf,
+    #[command(flatten)]
+    options: CompileArgs,
+}
 
-    if is_root {
-        Ok(replaced.to_string())
-    } else {
-        let cleaned = remove_comments(&remove_placeholders(&replaced));
-        Ok(cleaned)
-    }
+#[derive(Args)]
+struct Storage {
+    /// Fail when more of the output content than this percentage repeats across outputs
+    #[arg(long, value_name = "PERCENT")]
+    max_duplication: Option<f64>,
+    #[command(flatten)]
+    options: CompileArgs,
 }
 
-fn remove_placeholders(input: &str) -> String {
-    let re = Regex::new(r"(?is)<placeholder[^>]*>(.*?)</placeholder>").unwrap();
-    re.replace_all(input, "$1").to_string()
+#[derive(Args)]
+struct Preview {
+    /// Base XML to preview (default: the --stdin-filename buffer)
+    #[arg(value_name = "SOURCE", required_unless_present = "stdin_filename")]
+    source: Option<PathBuf>,
+    /// Read the content of this file from stdin instead of from disk
+    #[arg(long, value_name = "FILE")]
+    stdin_filename: Option<PathBuf>,
+    #[command(flatten)]
+    options: CompileArgs,
 }
 
-fn remove_comments(input: &str) -> String {
-    let re = Regex::new(r"(?s)<!--.*?-->").unwrap();
-    re.replace_all(input, "").to_string()
+#[derive(Subcommand)]
+enum Graph {
+    /// List the fragments and include edges added or removed between two revisions
+    Diff(GraphDiff),
 }
 
-fn strip_comments_and_format_spaces(input: &str) -> String {
-    let comment_re = Regex::new(r"(?s)<!--.*?-->").unwrap();
-    let space_re = Regex::new(r"\s{2,}").unwrap();
-    let temp = comment_re.replace_all(input, "");
-    let temp = temp.replace('\n', "").replace('\r', "");
-    space_re.replace_all(&temp, " ").into_owned()
+#[derive(Args)]
+struct GraphDiff {
+    /// Revision to compare from
+    #[arg(long, value_name = "REV")]
+    from: String,
+    /// Revision to compare to
+    #[arg(long, value_name = "REV", default_value = "HEAD")]
+    to: String,
+    #[command(flatten)]
+    options: CompileArgs,
 }
 
-fn process_xml_files(base_dir: &Path, output_dir: &Path) -> Result<()> {
-    fs::create_dir_all(output_dir)?;
-    let file_re = Regex::new(r"^\d_.*\.xml$")?;
+#[derive(Args)]
+struct Search {
+    /// Regular expression matched against the output text
+    #[arg(value_name = "REGEX", required_unless_present = "xpath")]
+    regex: Option<String>,
+    /// Location path of the elements to find instead; a plain argument is then the base dir
+    #[arg(long, value_name = "PATH")]
+    xpath: Option<String>,
+    #[command(flatten)]
+    options: CompileArgs,
+}
 
-    let files: Vec<PathBuf> = WalkDir::new(base_dir)
-        .min_depth(2)
-        .max_depth(2)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .filter(|e| file_re.is_match(&e.file_name().to_string_lossy()))
-        .map(|e| e.path().to_path_buf())
-        .collect();
+#[derive(Subcommand)]
+enum ReportCommand {
+    /// Compare two --json-report files
+    Compare {
+        #[arg(value_name = "OLD")]
+        old: PathBuf,
+        #[arg(value_name = "NEW")]
+        new: PathBuf,
+    },
+}
 
-    if files.is_empty() {
-        log_message("No XML files found to process.");
+#[derive(Args)]
+struct Completions {
+    /// XSDs to read
+    #[arg(value_name = "XSD", required = true)]
+    xsds: Vec<PathBuf>,
+    /// Write the JSON to this file instead of printing it
+    #[arg(long, value_name = "FILE")]
+    out: Option<PathBuf>,
+}
+
+/// Prints `text` through `$PAGER` (`less -R` by default) when stdout is a terminal, and
+/// directly otherwise or when the pager can't be started. An empty `PAGER` or `cat` turns
+/// paging off.
+fn page(text: &str) -> Result<()> {
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less -R".to_string());
+    let mut words = pager.split_whitespace();
+    if let Some(program) = words.next().filter(|&program| program != "cat")
+        && io::stdout().is_terminal()
+        && let Ok(mut child) = Process::new(program).args(words).stdin(Stdio::piped()).spawn()
+    {
+        if let Some(mut stdin) = child.stdin.take() {
+            // The pager quitting early isn't an error.
+            let _ = stdin.write_all(text.as_bytes());
+        }
+        child.wait()?;
+        return Ok(());
     }
+    print!("{}", text);
+    Ok(())
+}
 
-    files.par_iter().for_each(|file| {
-        match expand_includes(file, true) {
-            Ok(expanded) => {
-                let out_path = output_dir.join(file.file_name().unwrap());
-                if let Err(err) = fs::write(&out_path, expanded) {
-                    log_message(&format!("Error writing {}: {}", out_path.display(), err));
-                } else {
-                    log_message(&format!("Processed: {}", file.display()));
-                }
-            }
-            Err(err) => {
-                log_message(&format!("Error processing {}: {}", file.display(), err));
-            }
+/// Runs `action` on the selected projects of a workspace one after another, each compiler
+/// built from its own line of arguments followed by `extra_args`. They share the process-wide
+/// source cache, so a fragment used by several projects is read once. A failing project
+/// doesn't stop the others.
+fn run_workspace(
+    path: &Path,
+    selected: &[String],
+    extra_args: &[String],
+    action: fn(&Compiler) -> Result<()>,
+) -> Result<()> {
+    let projects = workspace::load(path)?;
+    let selected = workspace::select(&projects, selected)?;
+    // Relative paths in the workspace file are relative to the file itself.
+    let root = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut failed = Vec::new();
+    for project in &selected {
+        log_section(&format!("Project {}", project.name));
+        let args = [project.args.as_slice(), extra_args].concat();
+        if let Err(err) = Compiler::from_args(&args, root).and_then(|compiler| action(&compiler)) {
+            log_message(&format!("Project {} failed: {}", project.name, err));
+            failed.push(project.name.as_str());
         }
-    });
+    }
 
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "{} of {} projects failed: {}",
+            failed.len(),
+            selected.len(),
+            failed.join(", ")
+        ));
+    }
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+/// `--tokens <file>`, or else the `[tokens]` of the project's kvy-xmls.toml.
+fn tokens(file: Option<PathBuf>, args: &[String], cwd: &Path) -> Result<Option<auth::Tokens>> {
+    match file {
+        Some(path) => Ok(Some(auth::Tokens::load(&cwd.join(path))?)),
+        None => auth::Tokens::from_config(&Options::parse(args, cwd)?.base_dir),
+    }
+}
 
-    let (base_dir, output_dir) = if args.len() > 1 {
-        let dir = PathBuf::from(&args[1]);
-        if !dir.exists() {
-            return Err(anyhow!("Specified directory does not exist: {}", dir.display()));
+/// Runs `action` on the compiler `args` describe, or with `--workspace`, on each selected
+/// project.
+fn run_batch(
+    batch: Batch,
+    args: &[String],
+    cwd: &Path,
+    action: fn(&Compiler) -> Result<()>,
+) -> Result<()> {
+    if batch.preview {
+        return Err(anyhow!("Use serve --preview to start the preview server"));
+    }
+    match batch.workspace {
+        Some(path) => run_workspace(&cwd.join(path), &batch.project, args, action),
+        None => action(&Compiler::from_args(args, cwd)?),
+    }
+}
+
+fn main() -> Result<()> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let cwd = env::current_dir()?;
+    crash::install_hook();
+    // These apply to the whole process, whatever the command.
+    if let Some(path) = cli.log {
+        set_log_path(cwd.join(path));
+    }
+    if let Some(format) = cli.log_format {
+        set_log_json(format == "json");
+    }
+    if let Some(length) = cli.log_line_length {
+        set_log_line_length(length);
+    }
+    // The compile options as given, for `Options::parse` to apply kvy-xmls.toml under. Those
+    // before the command apply to it too.
+    let mut compile_args = options::compile_args(&matches);
+    let mut command = &matches;
+    while let Some((_, sub)) = command.subcommand() {
+        if sub.try_contains_id("dir").is_ok() {
+            compile_args.extend(options::compile_args(sub));
         }
-        (dir.clone(), dir.join("compiled"))
-    } else {
-        let dir = env::current_dir()?;
-        (dir.clone(), dir.join("compiled"))
-    };
-
-    log_section(&format!("Starting processing in {}", base_dir.display()));
-    process_xml_files(&base_dir, &output_dir)?;
-    log_section(&format!(
-        "Processing complete. Compiled XMLs saved in {}",
-        output_dir.display()
-    ));
+        command = sub;
+    }
+    let compile_args = || compile_args.clone();
 
-    Ok(())
-}
\ No newline at end of file
+    match cli.command.unwrap_or(Command::Build(cli.build)) {
+        Command::Build(build) => {
+            let args = compile_args();
+            let Mode { daemon, client, watch } = build.mode;
+            let addr = build.addr.unwrap_or_else(daemon::default_addr);
+            if daemon {
+                daemon::serve(&addr, tokens(build.tokens, &args, &cwd)?.as_ref())
+            } else if client {
+                daemon::forward(&addr, &args)
+            } else if watch {
+                Compiler::from_args(&args, &cwd)?.watch()
+            } else {
+                run_batch(build.batch, &args, &cwd, Compiler::run)
+            }
+        }
+        Command::Watch(_) => Compiler::from_args(&compile_args(), &cwd)?.watch(),
+        Command::Check(batch) => run_batch(batch, &compile_args(), &cwd, Compiler::check),
+        Command::Clean(batch) => run_batch(batch, &compile_args(), &cwd, |compiler| {
+            let removed = compiler.clean()?;
+            let output_dir = &compiler.options().output_dir;
+            println!("Removed {} files from {}", removed, output_dir.display());
+            Ok(())
+        }),
+        Command::Serve(serve) => {
+            let args = compile_args();
+            serve::serve(
+                serve.addr.as_deref().unwrap_or(serve::DEFAULT_ADDR),
+                Options::parse(&args, &cwd)?,
+                serve.xslt.map(|path| cwd.join(path)),
+                serve.audit_log.map(|path| cwd.join(path)).as_deref(),
+                tokens(serve.tokens, &args, &cwd)?,
+                serve.watch,
+            )
+        }
+        Command::Replay(replay) => {
+            replay::replay(&cwd.join(replay.json_file), Options::parse(&compile_args(), &cwd)?)
+        }
+        Command::Diff(_) => {
+            let opts = Options::parse(&compile_args(), &cwd)?;
+            let (text, differing) = preview::diff(&opts)?;
+            page(&text)?;
+            if differing > 0 {
+                return Err(anyhow!("{} outputs are not up to date", differing));
+            }
+            Ok(())
+        }
+        Command::Storage(Storage { max_duplication, .. }) => {
+            let storage = storage::analyze(&Options::parse(&compile_args(), &cwd)?)?;
+            print!("{}", storage.text);
+            if let Some(max) = max_duplication.filter(|&max| storage.duplication > max) {
+                return Err(anyhow!(
+                    "{:.0}% of the output content repeats across outputs, \
+                     over --max-duplication {}",
+                    storage.duplication,
+                    max
+                ));
+            }
+            Ok(())
+        }
+        Command::Preview(preview) => {
+            // An editor passes its unsaved buffer on stdin, named by the path it will be saved to.
+            let buffer = match preview.stdin_filename {
+                Some(name) => Some((lexical(&cwd.join(name)), io::read_to_string(io::stdin())?)),
+                None => None,
+            };
+            let mut args = compile_args();
+            // A folder as the only plain argument is the base directory, and the buffer the
+            // base XML.
+            let source = match preview.source.map(|source| cwd.join(source)) {
+                Some(dir) if dir.is_dir() && buffer.is_some() && preview.options.dir.is_none() => {
+                    args.push(dir.display().to_string());
+                    None
+                }
+                source => source,
+            };
+            let source = source
+                .or_else(|| buffer.as_ref().map(|(path, _)| path.clone()))
+                .ok_or_else(|| anyhow!("preview needs a <source.xml> or --stdin-filename"))?;
+            let mut opts = Options::parse(&args, &cwd)?;
+            opts.buffer = buffer.map(|(path, content)| (path, content.into()));
+            let (text, warnings) = preview::preview(&source, &opts)?;
+            page(&text)?;
+            for warning in warnings {
+                eprintln!("warning: {}", warning);
+            }
+            Ok(())
+        }
+        Command::Lock(_) => {
+            let opts = Options::parse(&compile_args(), &cwd)?;
+            let count = lock::write(&opts, &opts.lockfile)?;
+            println!("Locked {} fragments in {}", count, opts.lockfile.display());
+            Ok(())
+        }
+        Command::Vendor(_) => {
+            let mut opts = Options::parse(&compile_args(), &cwd)?;
+            // Vendor from the live roots, not from the previous copies.
+            opts.vendored.clear();
+            let count = vendor::write(&opts)?;
+            let vendor_dir = opts.base_dir.join(vendor::VENDOR_DIR);
+            println!("Vendored {} files into {}", count, vendor_dir.display());
+            Ok(())
+        }
+        Command::Graph(Graph::Diff(diff)) => {
+            print!("{}", graph::diff(&cwd, &diff.from, &diff.to, &compile_args())?);
+            Ok(())
+        }
+        Command::Search(search) => {
+            let mut args = compile_args();
+            let query = match (search.xpath, search.regex) {
+                (Some(path), dir) => {
+                    args.extend(dir);
+                    search::Query::xpath(&path)?
+                }
+                (None, regex) => search::Query::Regex(Regex::new(&regex.unwrap_or_default())?),
+            };
+            let hits = search::search(&query, &Options::parse(&args, &cwd)?);
+            for hit in &hits {
+                println!("{}", hit);
+            }
+            if hits.is_empty() {
+                eprintln!("No matches.");
+            }
+            Ok(())
+        }
+        Command::Report(ReportCommand::Compare { old, new }) => {
+            print!("{}", report::compare(&cwd.join(old), &cwd.join(new))?);
+            Ok(())
+        }
+        Command::Completions(Completions { xsds, out }) => {
+            let xsds: Vec<PathBuf> = xsds.iter().map(|xsd| cwd.join(xsd)).collect();
+            let json = serde_json::to_string_pretty(&schema::completions(&xsds)?)? + "\n";
+            match out {
+                Some(path) => fs::write(cwd.join(path), json)?,
+                None => print!("{}", json),
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::TargetResult;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Path as MSBuild should see it: relative to the `.props` file when possible, so the fragment
+/// keeps working if the tree is moved.
+fn item_path(path: &Path, props_dir: &Path) -> String {
+    match path.strip_prefix(props_dir) {
+        Ok(relative) => format!("$(MSBuildThisFileDirectory){}", relative.display()),
+        Err(_) => path.display().to_string(),
+    }
+}
+
+/// Writes an MSBuild `.props` fragment with one `KiwiXmlArtifact` item per compiled output,
+/// carrying its SHA-256 and source file as metadata. Failed targets are left out.
+pub fn write_props(props_path: &Path, results: &[TargetResult], build_id: &str) -> Result<()> {
+    let props_dir = props_path.parent().unwrap_or_else(|| Path::new("."));
+    let mut written: Vec<(&PathBuf, &PathBuf, &String)> = results
+        .iter()
+        .filter_map(|r| r.digest.as_ref().map(|digest| (&r.output, &r.source, digest)))
+        .collect();
+    written.sort();
+
+    let mut props = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    props.push_str("<!-- Generated by KiwiXML. Do not edit. -->\n");
+    props.push_str("<Project>\n  <PropertyGroup>\n");
+    let _ = writeln!(props, "    <KiwiXmlBuildId>{}</KiwiXmlBuildId>", escape(build_id));
+    props.push_str("  </PropertyGroup>\n  <ItemGroup>\n");
+    for (output, source, digest) in written {
+        let _ = writeln!(
+            props,
+            "    <KiwiXmlArtifact Include=\"{}\">",
+            escape(&item_path(output, props_dir))
+        );
+        let _ = writeln!(props, "      <Sha256>{}</Sha256>", digest);
+        let _ = writeln!(
+            props,
+            "      <Source>{}</Source>",
+            escape(&item_path(source, props_dir))
+        );
+        props.push_str("    </KiwiXmlArtifact>\n");
+    }
+    props.push_str("  </ItemGroup>\n</Project>\n");
+
+    if let Some(parent) = props_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(props_path, props)?;
+    Ok(())
+}
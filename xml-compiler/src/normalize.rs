@@ -0,0 +1,116 @@
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use unicode_normalization::UnicodeNormalization;
+
+/// Unicode normalization form applied to text content and attribute values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    Nfc,
+    Nfkc,
+}
+
+static ENTITY_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"&[^;\s<&]*;").unwrap());
+static ATTR_VALUE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(=\s*)(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+fn apply(text: &str, form: Normalization) -> String {
+    match form {
+        Normalization::Nfc => text.nfc().collect(),
+        Normalization::Nfkc => text.nfkc().collect(),
+    }
+}
+
+/// Normalizes character data while leaving entity references alone. Compatibility forms can
+/// turn characters such as `＜` into real markup, so anything that becomes `<`, `&` or the
+/// enclosing quote is escaped again.
+fn normalize_text(text: &str, form: Normalization, quote: Option<char>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    let push = |out: &mut String, piece: &str| {
+        for c in apply(piece, form).chars() {
+            match c {
+                '<' => out.push_str("&lt;"),
+                '&' => out.push_str("&amp;"),
+                '"' if quote == Some('"') => out.push_str("&quot;"),
+                '\'' if quote == Some('\'') => out.push_str("&apos;"),
+                c => out.push(c),
+            }
+        }
+    };
+
+    for entity in ENTITY_RE.find_iter(text) {
+        push(&mut out, &text[last..entity.start()]);
+        out.push_str(entity.as_str());
+        last = entity.end();
+    }
+    push(&mut out, &text[last..]);
+    out
+}
+
+fn normalize_tag(tag: &str, form: Normalization) -> String {
+    ATTR_VALUE_RE
+        .replace_all(tag, |caps: &Captures| match (caps.get(2), caps.get(3)) {
+            (Some(value), _) => {
+                format!("{}\"{}\"", &caps[1], normalize_text(value.as_str(), form, Some('"')))
+            }
+            (_, Some(value)) => {
+                format!("{}'{}'", &caps[1], normalize_text(value.as_str(), form, Some('\'')))
+            }
+            _ => caps[0].to_string(),
+        })
+        .into_owned()
+}
+
+/// Normalizes text nodes, CDATA content and attribute values of a source document.
+/// Comments (and therefore directives), processing instructions and names are copied as-is.
+pub fn normalize_document(input: &str, form: Normalization) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(open) = rest.find('<') {
+        out.push_str(&normalize_text(&rest[..open], form, None));
+        rest = &rest[open..];
+
+        let (terminator, normalize_inner) = if rest.starts_with("<!--") {
+            ("-->", false)
+        } else if rest.starts_with("<![CDATA[") {
+            ("]]>", true)
+        } else if rest.starts_with("<?") || rest.starts_with("<!") {
+            (">", false)
+        } else {
+            let end = tag_end(rest).unwrap_or(rest.len());
+            out.push_str(&normalize_tag(&rest[..end], form));
+            rest = &rest[end..];
+            continue;
+        };
+
+        let end = rest
+            .find(terminator)
+            .map_or(rest.len(), |i| i + terminator.len());
+        if normalize_inner && end >= "<![CDATA[]]>".len() {
+            out.push_str("<![CDATA[");
+            out.push_str(&apply(&rest["<![CDATA[".len()..end - "]]>".len()], form));
+            out.push_str("]]>");
+        } else {
+            out.push_str(&rest[..end]);
+        }
+        rest = &rest[end..];
+    }
+
+    out.push_str(&normalize_text(rest, form, None));
+    out
+}
+
+/// Byte offset just past the `>` closing the tag at the start of `input`, skipping quoted values.
+fn tag_end(input: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '>') => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
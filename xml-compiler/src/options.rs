@@ -0,0 +1,1073 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    env, fs,
+    path::{Component, Path, PathBuf},
+    process,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use clap::{parser::ValueSource, ArgMatches, Args, CommandFactory, Parser, ValueEnum};
+use regex::Regex;
+
+use crate::{
+    charset::Charset,
+    config, console,
+    sha256_hex,
+    lock::{Lock, LOCK_NAME},
+    normalize::Normalization,
+    report,
+    schema::Schemas,
+    search,
+    vars,
+    vendor::{self, Vendored},
+};
+
+/// Which implementation performs the include and cleanup passes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Engine {
+    /// Regex passes over the raw text. Fast, and the default.
+    Regex,
+    /// An XML pull parser. Slower, but never matches markup inside CDATA, comments or
+    /// attribute values.
+    Parser,
+}
+
+/// What to do with include directives that are not alone on their line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InlineIncludes {
+    /// Expand them in place. Inside attribute values the content is attribute-escaped
+    /// instead of wrapped in CDATA.
+    Expand,
+    /// Fail the file.
+    Error,
+    /// Leave the directive text untouched.
+    Ignore,
+}
+
+/// What happens when a fragment includes itself, directly or through others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CyclePolicy {
+    /// Fail the target.
+    Error,
+    /// Leave a comment naming the cycle in place of the include, and warn.
+    Comment,
+}
+
+/// What happens to a target whose expansion logged warnings, such as a missing include.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WarningPolicy {
+    /// Write the output as usual.
+    Write,
+    /// Write it to the quarantine directory instead, and count the target as failed.
+    Quarantine,
+    /// Don't write it, and count the target as failed.
+    Skip,
+}
+
+/// What happens to a source that looks like a compiled output fed back in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CompiledSources {
+    /// Don't compile it, and warn that it was skipped.
+    Skip,
+    /// Compile it, logging a warning for the target.
+    Warn,
+    /// Compile it without comment.
+    Allow,
+}
+
+/// How much each target prints to the console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Only targets that failed or had warnings.
+    Quiet,
+    /// A status line for every target, with its warnings and error.
+    Normal,
+    /// Everything the target logged as well, such as each include.
+    Verbose,
+}
+
+/// Permissions given to compiled outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Copy the permissions of the base XML.
+    Source,
+    /// Set these Unix permission bits.
+    Explicit(u32),
+}
+
+/// Explicit inputs and outputs for hermetic build systems, loaded from `--hermetic <list>`.
+pub struct Hermetic {
+    /// `(source, output)` pairs to compile, in list order.
+    pub targets: Vec<(PathBuf, PathBuf)>,
+    /// Canonical paths of every declared input; includes outside this set are rejected.
+    inputs: HashSet<PathBuf>,
+}
+
+impl Hermetic {
+    /// List format, one entry per line: `<source>\t<output>` declares a target, a lone
+    /// `<path>` declares a fragment it may include. Blank lines and `#` comments are skipped.
+    fn load(list: &Path, cwd: &Path) -> Result<Hermetic> {
+        let text = fs::read_to_string(list)
+            .map_err(|err| anyhow!("Cannot read input list {}: {}", list.display(), err))?;
+        let mut targets = Vec::new();
+        let mut inputs = HashSet::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            let (input, output) = match line.split_once('\t') {
+                Some((input, output)) => (input, Some(output)),
+                None => (line, None),
+            };
+
+            let input = cwd.join(input.trim());
+            let canonical = fs::canonicalize(&input).map_err(|err| {
+                anyhow!("{}:{}: {}: {}", list.display(), n + 1, input.display(), err)
+            })?;
+            inputs.insert(canonical);
+            if let Some(output) = output {
+                targets.push((input, cwd.join(output.trim())));
+            }
+        }
+
+        Ok(Hermetic { targets, inputs })
+    }
+
+    pub fn declares(&self, path: &Path) -> bool {
+        fs::canonicalize(path).is_ok_and(|canonical| self.inputs.contains(&canonical))
+    }
+}
+
+/// Directive forms accepted unless `--directive-syntax` is given: the original comment, and a
+/// processing instruction, which survives tooling that strips comments.
+const DEFAULT_DIRECTIVE_SYNTAXES: &[(&str, &str)] =
+    &[("<!-- #include", "-->"), ("<?include", "?>")];
+
+/// File names of base XMLs unless `--pattern` is given: a digit and an underscore, then the
+/// name. Nothing KiwiXML writes into the output directory matches it.
+pub const DEFAULT_TARGET_PATTERN: &str = r"^\d_.*\.xml$";
+
+/// Folder below the base directory outputs go to unless `--output-dir` is given.
+pub const DEFAULT_OUTPUT_DIR: &str = "compiled";
+
+/// How deep base XMLs are looked for unless `--min-depth` or `--max-depth` is given: one
+/// folder below the base directory.
+pub const DEFAULT_TARGET_DEPTH: usize = 2;
+
+/// How much content warnings, reports and search hits quote unless `--excerpt-length` is
+/// given, in bytes.
+pub const DEFAULT_EXCERPT_LENGTH: usize = 200;
+
+/// How deeply includes may nest unless `--max-include-depth` is given; the target itself is
+/// level 0.
+pub const DEFAULT_MAX_INCLUDE_DEPTH: usize = 64;
+
+/// How many bytes of fragments a target may pull in unless `--max-expanded-size` is given.
+pub const DEFAULT_MAX_EXPANDED_SIZE: u64 = 256 * 1024 * 1024;
+
+/// How long watch mode waits for more changes unless `--watch-debounce` is given, in
+/// milliseconds.
+pub const DEFAULT_WATCH_DEBOUNCE_MS: u64 = 200;
+
+/// Settings for a single compile run, parsed from the command line (or from a daemon request).
+pub struct Options {
+    /// Identifies this run in the log, reports, manifests and `{build_id}` in headers, so an
+    /// artifact can be traced back to the run that produced it.
+    pub build_id: String,
+    pub base_dir: PathBuf,
+    pub output_dir: PathBuf,
+    /// Put each output in a copy of its base XML's folder (`compiled/KFM/0_Wolf.xml`) instead
+    /// of directly in `output_dir`, so equally named targets in different folders don't clash.
+    pub preserve_layout: bool,
+    /// Matches the file names of base XMLs.
+    pub target_pattern: Regex,
+    /// Patterns from the `[groups]` picked with `--group`, relative to `base_dir`; only base
+    /// XMLs matching one of them are targets. A pattern without a `/` matches file names in any
+    /// folder. `None` without `--group`.
+    pub target_groups: Option<Vec<String>>,
+    /// How many levels below `base_dir` base XMLs are looked for, both inclusive: files
+    /// directly in it are at depth 1.
+    pub min_depth: usize,
+    pub max_depth: usize,
+    /// Where `WarningPolicy::Quarantine` puts outputs, each with a `.report.json` beside it.
+    pub quarantine_dir: PathBuf,
+    /// Write a `<output>.chunks` manifest next to every compiled file.
+    pub emit_chunks: bool,
+    /// Write a Make/Ninja `<output>.d` depfile next to every compiled file.
+    pub emit_depfile: bool,
+    /// Write a `<output>.map` source map next to every compiled file, and report schema
+    /// violations at the source line they came from.
+    pub source_maps: bool,
+    /// MSBuild `.props` file listing every compiled output and its hash.
+    pub msbuild_props: Option<PathBuf>,
+    /// Write a static HTML summary of the run into `output_dir`.
+    pub html_report: bool,
+    /// JSON summary of the run, for `report compare`.
+    pub json_report: Option<PathBuf>,
+    /// The base XML whose include resolution steps are printed (`--trace-includes`).
+    pub trace_includes: Option<PathBuf>,
+    /// Values written into `index.json` in `output_dir` for every output (`--index`).
+    pub index_fields: Vec<search::Field>,
+    /// At most how many bytes of content a warning, report or search hit quotes.
+    pub excerpt_length: usize,
+    /// Folders an include is looked for in, in order, when it isn't where its directive points.
+    pub include_paths: Vec<PathBuf>,
+    /// Puts each output in a folder of `output_dir` named after the value this selects in it.
+    pub partition_by: Option<search::Field>,
+    /// Write `CHANGES.txt` into `output_dir`, comparing outputs with the previous such run.
+    pub changelog: bool,
+    /// Template for the comment put at the top of every output, read from `--header <file>`.
+    /// Never used in hermetic mode, where outputs must not depend on the date or the checkout.
+    pub header: Option<String>,
+    pub engine: Engine,
+    /// Unicode normalization applied to text and attribute values of every source read.
+    pub normalization: Option<Normalization>,
+    /// Encoding of sources with no byte order mark or XML declaration saying otherwise.
+    pub input_encoding: Charset,
+    /// Encoding outputs are written in, with an XML declaration naming it. Without one,
+    /// outputs are written as UTF-8, the text they are expanded to.
+    pub output_encoding: Option<Charset>,
+    /// Elements whose content keeps its whitespace when fragments are collapsed.
+    pub preserve_whitespace: Vec<String>,
+    /// Matches a whole `preserve_whitespace` element, start tag to end tag.
+    pub preserved_re: Option<Regex>,
+    /// Elements marked `kvy:only` for other profiles are left out of every source read.
+    pub profile: Option<String>,
+    /// Folder mirroring the base directory with a patch for some targets, applied to their
+    /// compiled output; see `--patches`.
+    pub patches: Option<PathBuf>,
+    /// Language assumed for placeholders that don't declare `lang` themselves.
+    pub placeholder_lang: Option<String>,
+    /// Directories copied into `base_dir/vendor` by `KiwiXML vendor`. Includes resolve to the
+    /// copies whenever they exist.
+    pub vendored: Vendored,
+    /// Values for legacy SSI `#echo var="..."` directives, from `--ssi-var NAME=VALUE`.
+    pub ssi_vars: BTreeMap<String, String>,
+    /// Flags `#if flag="..."` conditionals test (`--flag`).
+    pub flags: BTreeSet<String>,
+    /// Values for `@{NAME}` and `<!-- #value name="NAME" -->`: `--define NAME=VALUE` over
+    /// `--defines` files over environment variables with the `--env-prefix`.
+    pub defines: BTreeMap<String, String>,
+    /// Names given a value with `--define`, which folder settings in `kvy-xmls.toml` don't
+    /// override.
+    pub command_line_defines: BTreeSet<String>,
+    /// Fail the target when a source uses a variable with no value, instead of warning.
+    pub fail_on_undefined_vars: bool,
+    /// Roots an include may be found under, lowest priority first; `base_dir` is always last,
+    /// so the project overrides every library root. Paths are lexically normalized.
+    pub include_roots: Vec<PathBuf>,
+    /// Directories a sparse checkout may leave out, from `--sparse-root`. A missing include
+    /// below one whose folder is absent is reported as not checked out. Lexically normalized.
+    pub sparse_roots: Vec<PathBuf>,
+    /// Open and close delimiters of each directive form; directives KiwiXML adds itself use
+    /// the first.
+    pub directive_syntaxes: Vec<(String, String)>,
+    /// Matches an include directive in any configured syntax; `crate::directive_parts` reads
+    /// its file and attributes.
+    pub directives: Regex,
+    pub inline_includes: InlineIncludes,
+    pub on_cycle: CyclePolicy,
+    pub on_warnings: WarningPolicy,
+    /// Make a missing or unreadable include fail its target, or quarantine it under
+    /// `WarningPolicy::Quarantine`, whatever `on_warnings` does with other warnings.
+    pub strict: bool,
+    /// Resolve W3C XInclude `<xi:include>` elements as well as include directives.
+    pub xinclude: bool,
+    /// How many targets may fail before the run fails; 0 unless `--max-errors` is given.
+    pub max_errors: usize,
+    /// Stop starting targets once one fails or poisons a file, and fail the run.
+    pub bail: bool,
+    pub compiled_sources: CompiledSources,
+    /// Largest change in output size, in percent of the previous build's output, that passes
+    /// without a warning.
+    pub max_size_change: Option<f64>,
+    /// Keep the previous output and fail the target instead of warning.
+    pub fail_on_size_change: bool,
+    /// Scan outputs for email addresses, IBANs and card numbers before writing them.
+    pub scan_pii: bool,
+    /// Keep the previous output and fail the target when the scan finds something.
+    pub fail_on_pii: bool,
+    /// Scan matches that are known to be fine, such as a support address.
+    pub pii_allow: Vec<Regex>,
+    /// Longest a single text pass over one file may run before its target fails.
+    pub pass_timeout: Option<Duration>,
+    /// How long watch mode waits for more changes after one arrives, so an editor saving several
+    /// files, or writing one in steps, triggers a single rebuild.
+    pub watch_debounce: Duration,
+    /// Rebuild every target, ignoring what the build cache says is up to date.
+    pub force: bool,
+    /// Compile every target a second time, in reverse order, and fail those whose output differs.
+    pub verify_deterministic: bool,
+    /// Expand and check every target like a build, but only report what would be written and
+    /// which includes are missing, without touching the output directory.
+    pub dry_run: bool,
+    /// For `clean`: remove the whole output directory rather than just the files a build
+    /// writes.
+    pub clean_all: bool,
+    /// Upper bound on expansion passes over each target; 1 disables re-expansion.
+    pub max_passes: usize,
+    /// Deepest include nesting before a target fails, so a runaway chain can't exhaust the
+    /// stack.
+    pub max_include_depth: usize,
+    /// Most bytes of fragments a target may include, counting a fragment each time it is
+    /// included, so includes that multiply can't exhaust memory.
+    pub max_expanded_size: u64,
+    /// Written by `KiwiXML lock`; `base_dir/kiwixml.lock` unless `--lockfile` says otherwise.
+    pub lockfile: PathBuf,
+    /// Fail targets that include a fragment whose hash differs from `lockfile` (`--locked`).
+    pub lock: Option<Lock>,
+    /// XSDs each output is checked against (`--schema`, `--schema-for`).
+    pub schemas: Option<Schemas>,
+    /// Compile exactly the listed targets instead of scanning `base_dir`.
+    pub hermetic: Option<Hermetic>,
+    /// An unsaved editor buffer used in place of the file at its path, which is lexically
+    /// normalized (`preview --stdin-filename`).
+    pub buffer: Option<(PathBuf, Arc<str>)>,
+    /// Extra attempts for reads and writes that fail with a transient error.
+    pub io_retries: usize,
+    /// Wait before the first retry; doubled for each one after it.
+    pub io_backoff: Duration,
+    /// Permissions for outputs; `None` leaves whatever the process umask produces.
+    pub output_mode: Option<OutputMode>,
+    /// Make outputs read-only once written, after `output_mode`, so hand edits are refused
+    /// rather than silently overwritten by the next run. That run makes them writable again
+    /// first.
+    pub read_only_outputs: bool,
+    /// Give outputs the owner and group of their base XML, where the process is allowed to.
+    pub preserve_owner: bool,
+    /// Extended attributes (NTFS alternate data streams on Windows) copied from each base XML.
+    pub copy_xattrs: Vec<String>,
+    /// Color the per-target status lines printed to stdout; from `--color`, by default when
+    /// stdout is a terminal and `NO_COLOR` is unset.
+    pub color: bool,
+    pub verbosity: Verbosity,
+}
+
+/// Compile options as given on the command line, in a workspace file or by a daemon client.
+/// [`Options::parse`] turns them into [`Options`], resolving paths and filling in defaults.
+#[derive(Args, Debug)]
+#[command(next_help_heading = "Compile options")]
+pub struct CompileArgs {
+    /// Folder the targets are found in (default: the working directory)
+    #[arg(value_name = "BASE DIR")]
+    pub dir: Option<PathBuf>,
+    /// Where outputs go (default: <base dir>/compiled)
+    #[arg(long, alias = "out-dir", value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+    /// Put each output in a copy of its base XML's folder
+    #[arg(long)]
+    pub preserve_layout: bool,
+    /// File names of base XMLs (default: ^\d_.*\.xml$)
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub pattern: Option<Regex>,
+    /// Only compile the targets of a [groups] entry in kvy-xmls.toml
+    #[arg(long, value_name = "NAME")]
+    pub group: Vec<String>,
+    /// Shallowest folder level searched; 1 is the base dir (default: 2)
+    #[arg(long, value_name = "N", value_parser = positive)]
+    pub min_depth: Option<usize>,
+    /// Deepest folder level searched (default: 2)
+    #[arg(long, value_name = "N", value_parser = positive)]
+    pub max_depth: Option<usize>,
+    /// Number of targets compiled at once (default: one per CPU)
+    #[arg(short, long, value_name = "N", value_parser = positive)]
+    pub jobs: Option<usize>,
+    /// Only print targets that failed or had warnings
+    #[arg(short, long, overrides_with = "verbose")]
+    pub quiet: bool,
+    /// Print everything each target logs
+    #[arg(short, long, overrides_with = "quiet")]
+    pub verbose: bool,
+    /// Color the status lines: auto, always or never
+    #[arg(long, value_name = "WHEN", value_parser = ["auto", "always", "never"])]
+    pub color: Option<String>,
+    /// Targets that may fail without failing the run (default: 0)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub max_errors: usize,
+    /// Stop starting targets after the first one fails
+    #[arg(long)]
+    pub bail: bool,
+    /// Rebuild every target, even those the build cache says are up to date
+    #[arg(long)]
+    pub force: bool,
+    /// Report what a build would write, without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+    /// With clean: remove the whole output directory
+    #[arg(long)]
+    pub all: bool,
+    /// Identifies the run in the log, reports and headers (default: time and a random suffix)
+    #[arg(long, value_name = "ID", value_parser = build_id)]
+    pub build_id: Option<String>,
+    /// Engine for the include and cleanup passes
+    #[arg(long, value_enum, default_value_t = Engine::Regex)]
+    pub engine: Engine,
+    /// Another folder to look for includes in, after the directive's own (repeatable)
+    #[arg(short = 'I', long, value_name = "DIR")]
+    pub include_path: Vec<PathBuf>,
+    /// A root includes resolve under, below the base dir in priority (repeatable)
+    #[arg(long, value_name = "DIR")]
+    pub include_root: Vec<PathBuf>,
+    /// A folder a sparse checkout may leave out (repeatable)
+    #[arg(long, value_name = "DIR")]
+    pub sparse_root: Vec<PathBuf>,
+    /// Directive form to accept instead of the defaults, like '<?kvy include ... ?>'
+    #[arg(long, value_name = "SYNTAX", value_parser = directive_syntax)]
+    pub directive_syntax: Vec<(String, String)>,
+    /// What to do with includes that aren't alone on their line
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = InlineIncludes::Expand)]
+    pub inline_includes: InlineIncludes,
+    /// What to do when a fragment includes itself
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = CyclePolicy::Error)]
+    pub on_cycle: CyclePolicy,
+    /// What to do with targets that logged warnings (default: write)
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub on_warnings: Option<WarningPolicy>,
+    /// Where quarantined outputs go (default: <base dir>/quarantine)
+    #[arg(long, value_name = "DIR")]
+    pub quarantine_dir: Option<PathBuf>,
+    /// Fail targets with a missing or unreadable include
+    #[arg(long)]
+    pub strict: bool,
+    /// Resolve XInclude elements too
+    #[arg(long)]
+    pub xinclude: bool,
+    /// What to do with sources that look like compiled outputs
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = CompiledSources::Skip)]
+    pub compiled_sources: CompiledSources,
+    /// Deepest include nesting before a target fails
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = positive,
+        default_value_t = DEFAULT_MAX_INCLUDE_DEPTH
+    )]
+    pub max_include_depth: usize,
+    /// Most bytes of fragments a target may include, such as 50M (default: 256M)
+    #[arg(long, value_name = "SIZE", value_parser = size)]
+    pub max_expanded_size: Option<u64>,
+    /// Expansion passes over each target
+    #[arg(long, value_name = "N", value_parser = positive, default_value_t = 1)]
+    pub max_passes: usize,
+    /// Longest a text pass over one file may run, in seconds
+    #[arg(long, value_name = "SECS", value_parser = seconds)]
+    pub pass_timeout: Option<Duration>,
+    /// Unicode normalization for sources: none, nfc or nfkc
+    #[arg(long, value_name = "FORM", value_parser = ["none", "nfc", "nfkc"])]
+    pub normalize: Option<String>,
+    /// Encoding of sources that don't declare one (default: utf-8)
+    #[arg(long, value_name = "ENCODING", value_parser = charset)]
+    pub input_encoding: Option<Charset>,
+    /// Encoding outputs are written in (default: utf-8, without a declaration)
+    #[arg(long, value_name = "ENCODING", value_parser = charset)]
+    pub output_encoding: Option<Charset>,
+    /// Elements whose whitespace is kept, comma-separated (repeatable)
+    #[arg(long, value_name = "NAMES")]
+    pub preserve_whitespace: Vec<String>,
+    /// Language of placeholders that don't declare one
+    #[arg(long, value_name = "LANG")]
+    pub placeholder_lang: Option<String>,
+    /// Leave out elements marked kvy:only for other profiles
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Folder of patches applied to the outputs
+    #[arg(long, value_name = "DIR")]
+    pub patches: Option<PathBuf>,
+    /// Value for legacy SSI #echo directives (repeatable)
+    #[arg(long, value_name = "NAME=VALUE", value_parser = name_value)]
+    pub ssi_var: Vec<(String, String)>,
+    /// Flags #if conditionals test, comma-separated (repeatable)
+    #[arg(long, value_name = "NAMES")]
+    pub flag: Vec<String>,
+    /// Value of a variable, over --defines and the environment (repeatable)
+    #[arg(long, value_name = "NAME=VALUE", value_parser = name_value)]
+    pub define: Vec<(String, String)>,
+    /// File of NAME=VALUE variables (repeatable)
+    #[arg(long, value_name = "FILE")]
+    pub defines: Vec<PathBuf>,
+    /// Take variables from environment variables with this prefix
+    #[arg(long, value_name = "PREFIX")]
+    pub env_prefix: Option<String>,
+    /// Fail targets that use a variable with no value
+    #[arg(long)]
+    pub fail_on_undefined_vars: bool,
+    /// Comment put at the top of every output, read from a template file
+    #[arg(long, value_name = "FILE", overrides_with = "no_header")]
+    pub header: Option<PathBuf>,
+    /// Leave out the --header comment
+    #[arg(long, overrides_with = "header")]
+    pub no_header: bool,
+    /// Write a <output>.chunks manifest next to every output
+    #[arg(long)]
+    pub chunks: bool,
+    /// Write a Make/Ninja <output>.d depfile next to every output
+    #[arg(long)]
+    pub emit_depfile: bool,
+    /// Write a <output>.map source map next to every output
+    #[arg(long)]
+    pub source_map: bool,
+    /// Write an MSBuild .props file listing every output
+    #[arg(long, value_name = "FILE")]
+    pub emit_msbuild: Option<PathBuf>,
+    /// Write an HTML summary of the run into the output directory
+    #[arg(long)]
+    pub html_report: bool,
+    /// Write an html or json summary of the run into the output directory (repeatable)
+    #[arg(long, value_name = "FORMAT", value_parser = ["html", "json"])]
+    pub report: Vec<String>,
+    /// Write a JSON summary of the run to this file
+    #[arg(long, value_name = "FILE")]
+    pub json_report: Option<PathBuf>,
+    /// Write CHANGES.txt into the output directory
+    #[arg(long)]
+    pub changelog: bool,
+    /// Print how the includes of this base XML resolve
+    #[arg(long, value_name = "FILE")]
+    pub trace_includes: Option<PathBuf>,
+    /// Value written into index.json for every output (repeatable)
+    #[arg(long, value_name = "XPATH")]
+    pub index: Vec<String>,
+    /// Put each output in a folder named after the value this selects in it
+    #[arg(long, value_name = "XPATH")]
+    pub partition_by: Option<String>,
+    /// Most bytes of content a warning, report or search hit quotes (default: 200)
+    #[arg(long, value_name = "BYTES", value_parser = positive)]
+    pub excerpt_length: Option<usize>,
+    /// Warn when an output changes size by more than this percentage
+    #[arg(long, value_name = "PERCENT", value_parser = percentage)]
+    pub max_size_change: Option<f64>,
+    /// Keep the previous output and fail the target instead of warning
+    #[arg(long)]
+    pub fail_on_size_change: bool,
+    /// Scan outputs for personal data
+    #[arg(long)]
+    pub scan_pii: bool,
+    /// Scan outputs for personal data and fail the targets that have some
+    #[arg(long)]
+    pub fail_on_pii: bool,
+    /// Scan match that is fine (repeatable)
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub pii_allow: Vec<Regex>,
+    /// XSD every output is checked against (repeatable)
+    #[arg(long, value_name = "XSD")]
+    pub schema: Vec<PathBuf>,
+    /// XSD the outputs of matching targets are checked against (repeatable)
+    #[arg(long, value_name = "PATTERN=XSD", value_parser = schema_for)]
+    pub schema_for: Vec<(String, PathBuf)>,
+    /// Compile every target twice and fail those whose output differs
+    #[arg(long)]
+    pub verify_deterministic: bool,
+    /// How long watch mode waits for more changes, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_WATCH_DEBOUNCE_MS)]
+    pub watch_debounce: u64,
+    /// Lockfile to read or write (default: <base dir>/kiwixml.lock)
+    #[arg(long, value_name = "FILE")]
+    pub lockfile: Option<PathBuf>,
+    /// Fail targets that include a fragment whose hash differs from the lockfile
+    #[arg(long)]
+    pub locked: bool,
+    /// Compile exactly the targets an input list declares
+    #[arg(long, value_name = "LIST")]
+    pub hermetic: Option<PathBuf>,
+    /// Extra attempts for reads and writes that fail with a transient error
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub io_retries: usize,
+    /// Wait before the first retry, in milliseconds; doubled for each one after it
+    #[arg(long, value_name = "MS", default_value_t = 100)]
+    pub io_backoff: u64,
+    /// Permissions of outputs: source, or octal bits like 644
+    #[arg(long, value_name = "MODE", value_parser = output_mode)]
+    pub output_mode: Option<OutputMode>,
+    /// Make outputs read-only once written
+    #[arg(long)]
+    pub read_only_outputs: bool,
+    /// Give outputs the owner and group of their base XML
+    #[arg(long)]
+    pub preserve_owner: bool,
+    /// Extended attributes copied from each base XML, comma-separated (repeatable)
+    #[arg(long, value_name = "NAMES")]
+    pub copy_xattr: Vec<String>,
+}
+
+/// The compile options on their own, as [`Options::parse`] takes them.
+#[derive(Parser)]
+#[command(no_binary_name = true, args_override_self = true)]
+struct CompileCommand {
+    #[command(flatten)]
+    args: CompileArgs,
+}
+
+impl Options {
+    /// Parses compile flags plus the optional positional directory. Relative paths resolve
+    /// against `cwd`, which is the caller's directory even when running inside the daemon.
+    /// Project settings from the base directory's `kvy-xmls.toml` apply unless a flag gives
+    /// another value.
+    pub fn parse(args: &[String], cwd: &Path) -> Result<Options> {
+        Options::parse_args(args, cwd, None, true)
+    }
+
+    /// Like [`Options::parse`], for a caller whose environment is `env` rather than this
+    /// process's, as a daemon client's is: `--env-prefix` takes its variables from there.
+    pub fn parse_in(
+        args: &[String],
+        cwd: &Path,
+        env: &BTreeMap<String, String>,
+    ) -> Result<Options> {
+        Options::parse_args(args, cwd, Some(env), true)
+    }
+
+    fn parse_args(
+        args: &[String],
+        cwd: &Path,
+        env: Option<&BTreeMap<String, String>>,
+        read_config: bool,
+    ) -> Result<Options> {
+        let given = CompileCommand::try_parse_from(args).map_err(usage_error)?.args;
+        let base_dir = match &given.dir {
+            Some(dir) => {
+                let dir = cwd.join(dir);
+                if !dir.exists() {
+                    return Err(anyhow!("Specified directory does not exist: {}", dir.display()));
+                }
+                dir
+            }
+            None => cwd.to_path_buf(),
+        };
+        if read_config {
+            let config = config::project_args(&base_dir)?;
+            if !config.is_empty() {
+                return Options::parse_args(&[config, args.to_vec()].concat(), cwd, env, false);
+            }
+        }
+        let mut include_roots: Vec<PathBuf> =
+            given.include_root.iter().map(|root| lexical(&cwd.join(root))).collect();
+        include_roots.push(lexical(&base_dir));
+        let vendored = vendor::load(&base_dir)?;
+        let output_dir = match &given.output_dir {
+            Some(dir) => cwd.join(dir),
+            None => base_dir.join(DEFAULT_OUTPUT_DIR),
+        };
+        // `--report json`: a JSON report in the output directory, unless `--json-report` names
+        // a file.
+        let json_in_output = given.report.iter().any(|format| format == "json");
+        let json_report = match &given.json_report {
+            Some(path) => Some(cwd.join(path)),
+            None => json_in_output.then(|| output_dir.join(report::JSON_REPORT_NAME)),
+        };
+        let target_pattern = match given.pattern {
+            Some(pattern) => pattern,
+            None => Regex::new(DEFAULT_TARGET_PATTERN)?,
+        };
+        // Giving one bound moves the default of the other out of its way.
+        let (min_depth, max_depth) = (given.min_depth, given.max_depth);
+        let min_depth = min_depth
+            .unwrap_or(max_depth.map_or(DEFAULT_TARGET_DEPTH, |max| max.min(DEFAULT_TARGET_DEPTH)));
+        let max_depth = max_depth.unwrap_or(DEFAULT_TARGET_DEPTH.max(min_depth));
+        if min_depth > max_depth {
+            return Err(anyhow!(
+                "--min-depth {} is deeper than --max-depth {}",
+                min_depth,
+                max_depth
+            ));
+        }
+        // Naming a quarantine directory asks for quarantining, unless a policy was given too.
+        let strict = given.strict;
+        let on_warnings = given.on_warnings.unwrap_or(match &given.quarantine_dir {
+            Some(_) => WarningPolicy::Quarantine,
+            None => WarningPolicy::Write,
+        });
+        let quarantine_dir = match &given.quarantine_dir {
+            Some(dir) => cwd.join(dir),
+            None => base_dir.join("quarantine"),
+        };
+        let preserve_whitespace = names(&given.preserve_whitespace);
+        let preserved_re = preserved_elements_re(&preserve_whitespace)?;
+        let mut directive_syntaxes = given.directive_syntax;
+        if directive_syntaxes.is_empty() {
+            directive_syntaxes = DEFAULT_DIRECTIVE_SYNTAXES
+                .iter()
+                .map(|&(open, close)| (open.to_string(), close.to_string()))
+                .collect();
+        }
+        let directives = directives_re(&directive_syntaxes)?;
+        let hermetic = match &given.hermetic {
+            Some(list) => Some(Hermetic::load(&cwd.join(list), cwd)?),
+            None => None,
+        };
+        let header = match &given.header {
+            Some(path) => {
+                let path = cwd.join(path);
+                Some(fs::read_to_string(&path).map_err(|err| {
+                    anyhow!("Cannot read header template {}: {}", path.display(), err)
+                })?)
+            }
+            None => None,
+        };
+        let header = header.filter(|_| hermetic.is_none());
+        let groups = given.group;
+        let target_groups = match groups.is_empty() {
+            true => None,
+            false if hermetic.is_some() => {
+                return Err(anyhow!("--group cannot be combined with --hermetic"));
+            }
+            false => {
+                let defined = config::groups(&base_dir)?;
+                let mut patterns = Vec::new();
+                for name in &groups {
+                    let group = defined.get(name).ok_or_else(|| {
+                        let names: Vec<&str> = defined.keys().map(String::as_str).collect();
+                        anyhow!(
+                            "Unknown group {}; {} defines: {}",
+                            name,
+                            config::CONFIG_NAME,
+                            if names.is_empty() { "none".to_string() } else { names.join(", ") }
+                        )
+                    })?;
+                    patterns.extend(group.iter().cloned());
+                }
+                Some(patterns)
+            }
+        };
+        let lockfile = match &given.lockfile {
+            Some(path) => cwd.join(path),
+            None => base_dir.join(LOCK_NAME),
+        };
+        let lock = given.locked.then(|| Lock::load(&lockfile, &base_dir)).transpose()?;
+        let schema_paths: Vec<PathBuf> = given.schema.iter().map(|xsd| cwd.join(xsd)).collect();
+        let schema_for: Vec<(String, PathBuf)> = given
+            .schema_for
+            .into_iter()
+            .map(|(pattern, xsd)| (pattern, cwd.join(xsd)))
+            .collect();
+        let schemas = Schemas::load(&schema_paths, &schema_for)?;
+        // Later sources override earlier ones, and the command line overrides them all.
+        let mut vars = match &given.env_prefix {
+            Some(prefix) => match env {
+                Some(env) => vars::from_env(prefix, env.clone()),
+                None => vars::from_env(prefix, env::vars()),
+            },
+            None => BTreeMap::new(),
+        };
+        for file in &given.defines {
+            vars.extend(vars::load(&cwd.join(file))?);
+        }
+        let command_line_defines = given.define.iter().map(|(name, _)| name.clone()).collect();
+        vars.extend(given.define);
+        let defines = vars;
+        if let Some(jobs) = given.jobs {
+            // Fails, leaving it be, once the pool is running, as it is when an earlier project
+            // of a workspace started it.
+            let _ = rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global();
+        }
+
+        let build_id = given.build_id.unwrap_or_else(|| new_build_id(&base_dir));
+        let color = match given.color.as_deref() {
+            Some("always") => true,
+            Some("never") => false,
+            _ => {
+                console::stdout_is_terminal()
+                    && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+        };
+        let verbosity = match (given.quiet, given.verbose) {
+            (_, true) => Verbosity::Verbose,
+            (true, false) => Verbosity::Quiet,
+            (false, false) => Verbosity::Normal,
+        };
+        let normalization = match given.normalize.as_deref() {
+            Some("nfc") => Some(Normalization::Nfc),
+            Some("nfkc") => Some(Normalization::Nfkc),
+            _ => None,
+        };
+        let lexical_in = |dirs: &[PathBuf]| -> Vec<PathBuf> {
+            dirs.iter().map(|dir| lexical(&cwd.join(dir))).collect()
+        };
+
+        Ok(Options {
+            build_id,
+            base_dir,
+            output_dir,
+            preserve_layout: given.preserve_layout,
+            target_pattern,
+            target_groups,
+            min_depth,
+            max_depth,
+            quarantine_dir,
+            emit_chunks: given.chunks,
+            emit_depfile: given.emit_depfile,
+            source_maps: given.source_map,
+            msbuild_props: given.emit_msbuild.map(|path| cwd.join(path)),
+            html_report: given.html_report || given.report.iter().any(|format| format == "html"),
+            json_report,
+            trace_includes: given.trace_includes.map(|path| lexical(&cwd.join(path))),
+            index_fields: given
+                .index
+                .iter()
+                .map(|expr| search::Field::parse(expr))
+                .collect::<Result<_>>()?,
+            excerpt_length: given.excerpt_length.unwrap_or(DEFAULT_EXCERPT_LENGTH),
+            partition_by: given.partition_by.as_deref().map(search::Field::parse).transpose()?,
+            include_paths: lexical_in(&given.include_path),
+            header,
+            changelog: given.changelog,
+            engine: given.engine,
+            normalization,
+            input_encoding: given.input_encoding.unwrap_or(Charset::Utf8),
+            output_encoding: given.output_encoding,
+            preserve_whitespace,
+            preserved_re,
+            placeholder_lang: given.placeholder_lang.map(|lang| lang.to_ascii_lowercase()),
+            profile: given.profile,
+            patches: given.patches.map(|dir| lexical(&cwd.join(dir))),
+            vendored,
+            ssi_vars: given.ssi_var.into_iter().collect(),
+            flags: names(&given.flag).into_iter().collect(),
+            defines,
+            command_line_defines,
+            fail_on_undefined_vars: given.fail_on_undefined_vars,
+            include_roots,
+            sparse_roots: lexical_in(&given.sparse_root),
+            directive_syntaxes,
+            directives,
+            inline_includes: given.inline_includes,
+            on_cycle: given.on_cycle,
+            on_warnings,
+            strict,
+            xinclude: given.xinclude,
+            max_errors: given.max_errors,
+            bail: given.bail,
+            compiled_sources: given.compiled_sources,
+            max_size_change: given.max_size_change,
+            fail_on_size_change: given.fail_on_size_change,
+            verify_deterministic: given.verify_deterministic,
+            dry_run: given.dry_run,
+            clean_all: given.all,
+            force: given.force,
+            scan_pii: given.scan_pii || given.fail_on_pii,
+            fail_on_pii: given.fail_on_pii,
+            pii_allow: given.pii_allow,
+            pass_timeout: given.pass_timeout,
+            watch_debounce: Duration::from_millis(given.watch_debounce),
+            max_passes: given.max_passes,
+            max_include_depth: given.max_include_depth,
+            max_expanded_size: given.max_expanded_size.unwrap_or(DEFAULT_MAX_EXPANDED_SIZE),
+            lockfile,
+            lock,
+            schemas,
+            hermetic,
+            buffer: None,
+            io_retries: given.io_retries,
+            io_backoff: Duration::from_millis(given.io_backoff),
+            output_mode: given.output_mode,
+            read_only_outputs: given.read_only_outputs,
+            preserve_owner: given.preserve_owner,
+            copy_xattrs: names(&given.copy_xattr),
+            color,
+            verbosity,
+        })
+    }
+
+    pub fn preserves_whitespace(&self, name: &[u8]) -> bool {
+        self.preserve_whitespace.iter().any(|p| p.as_bytes() == name)
+    }
+}
+
+pub(crate) fn preserved_elements_re(names: &[String]) -> Result<Option<Regex>> {
+    if names.is_empty() {
+        return Ok(None);
+    }
+    let alternatives: Vec<String> = names
+        .iter()
+        .map(|name| {
+            let name = regex::escape(name);
+            format!(r"<{0}(?:\s[^>]*)?>.*?</{0}\s*>", name)
+        })
+        .collect();
+    Ok(Some(Regex::new(&format!("(?s){}", alternatives.join("|")))?))
+}
+
+/// Start time of the run, then 8 hex digits that tell runs started in the same second apart,
+/// e.g. `20240611T143005-3f9a1c2e`.
+fn new_build_id(base_dir: &Path) -> String {
+    let seed = format!("{:?} {} {}", SystemTime::now(), process::id(), base_dir.display());
+    format!("{}-{}", Local::now().format("%Y%m%dT%H%M%S"), &sha256_hex(seed.as_bytes())[..8])
+}
+
+/// A byte count, optionally followed by `K`, `M` or `G` (powers of 1024).
+fn parse_size(text: &str) -> Option<u64> {
+    let (number, unit) = match text.char_indices().last()? {
+        (at, 'K' | 'k') => (&text[..at], 1 << 10),
+        (at, 'M' | 'm') => (&text[..at], 1 << 20),
+        (at, 'G' | 'g') => (&text[..at], 1 << 30),
+        _ => (text, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit).filter(|&size| size > 0)
+}
+
+/// `path` with `.` and `..` components resolved without touching the filesystem.
+pub fn lexical(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(out.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// `path` relative to `base`, stepping up with `..` where it lies outside it, so paths written
+/// into committed files work from any checkout. Paths with nothing in common (another drive)
+/// stay absolute.
+pub fn relative(path: &Path, base: &Path) -> String {
+    let (path, base) = (lexical(path), lexical(base));
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path.display().to_string();
+    }
+    let mut parts: Vec<String> = base.components().skip(common).map(|_| "..".into()).collect();
+    parts.extend(
+        path.components()
+            .skip(common)
+            .map(|component| component.as_os_str().to_string_lossy().into_owned()),
+    );
+    parts.join("/")
+}
+
+/// One alternative per `(open, close)` syntax, each with three groups: `file` or `virtual`, the
+/// path, then any further `name="value"` attributes. Whitespace inside `open` or `close` may be
+/// any amount, or none.
+pub(crate) fn directives_re(syntaxes: &[(String, String)]) -> Result<Regex> {
+    let delimiter = |text: &str| {
+        text.split_whitespace()
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join(r"\s*")
+    };
+    let alternatives: Vec<String> = syntaxes
+        .iter()
+        .map(|(open, close)| {
+            format!(
+                r#"(?:{} (file|virtual)="(.*?)"((?:\s+[\w-]+="[^"]*")*)\s*{})"#,
+                delimiter(open),
+                delimiter(close)
+            )
+        })
+        .collect();
+    Ok(Regex::new(&alternatives.join("|"))?)
+}
+
+/// The compile options `matches` holds, as arguments for [`Options::parse`] again, in the
+/// order they were given: what the daemon client forwards and what `kvy-xmls.toml` settings
+/// go in front of. `matches` is for a command that flattens [`CompileArgs`].
+pub fn compile_args(matches: &ArgMatches) -> Vec<String> {
+    let mut given = Vec::new();
+    for arg in CompileCommand::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let indices = matches.indices_of(id).into_iter().flatten();
+        let values = matches.get_raw(id).into_iter().flatten().map(|value| value.to_string_lossy());
+        match arg.get_long() {
+            None => given.extend(indices.zip(values).map(|(at, value)| (at, value.into_owned()))),
+            Some(long) if arg.get_action().takes_values() => given.extend(
+                indices.zip(values).map(|(at, value)| (at, format!("--{}={}", long, value))),
+            ),
+            Some(long) => given.extend(indices.map(|at| (at, format!("--{}", long)))),
+        }
+    }
+    given.sort_by_key(|&(at, _)| at);
+    given.into_iter().map(|(_, arg)| arg).collect()
+}
+
+/// A command line error as a single line, for errors that don't end the process, such as
+/// those of a daemon request or a workspace project.
+fn usage_error(err: clap::Error) -> anyhow::Error {
+    let text = err.to_string();
+    let message = text.split("\n\n").next().unwrap_or_default();
+    anyhow!("{}", message.trim_start_matches("error: ").trim())
+}
+
+/// Comma-separated names given to a repeatable option, in order.
+fn names(lists: &[String]) -> Vec<String> {
+    let names = lists.iter().flat_map(|list| list.split(',')).map(str::trim);
+    names.filter(|name| !name.is_empty()).map(str::to_string).collect()
+}
+
+/// Value parser for counts that must be at least 1.
+pub fn positive(text: &str) -> Result<usize, String> {
+    text.parse().ok().filter(|&n| n > 0).ok_or_else(|| "expected a positive number".to_string())
+}
+
+fn name_value(text: &str) -> Result<(String, String), String> {
+    let (name, value) = text.split_once('=').ok_or("expected NAME=VALUE")?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn schema_for(text: &str) -> Result<(String, PathBuf), String> {
+    let (pattern, xsd) = text.rsplit_once('=').ok_or("expected <pattern>=<schema.xsd>")?;
+    Ok((pattern.to_string(), PathBuf::from(xsd)))
+}
+
+fn directive_syntax(text: &str) -> Result<(String, String), String> {
+    text.split_once("...")
+        .map(|(open, close)| (open.trim(), close.trim()))
+        .filter(|(open, close)| !open.is_empty() && !close.is_empty())
+        .map(|(open, close)| (open.to_string(), close.to_string()))
+        .ok_or_else(|| "expected '<open> ... <close>', like '<?kvy include ... ?>'".to_string())
+}
+
+fn percentage(text: &str) -> Result<f64, String> {
+    let percent = text.trim_end_matches('%').parse().ok();
+    percent.filter(|&percent: &f64| percent >= 0.0).ok_or_else(|| "expected a percentage".into())
+}
+
+fn seconds(text: &str) -> Result<Duration, String> {
+    let secs = text.parse().ok().filter(|&secs: &f64| secs > 0.0);
+    secs.map(Duration::from_secs_f64).ok_or_else(|| "expected seconds".to_string())
+}
+
+fn size(text: &str) -> Result<u64, String> {
+    parse_size(text).ok_or_else(|| "expected a size such as 50M".to_string())
+}
+
+fn output_mode(text: &str) -> Result<OutputMode, String> {
+    match text {
+        "source" => Ok(OutputMode::Source),
+        octal => u32::from_str_radix(octal, 8)
+            .ok()
+            .filter(|&mode| mode <= 0o7777)
+            .map(OutputMode::Explicit)
+            .ok_or_else(|| "expected 'source' or octal bits like 644".to_string()),
+    }
+}
+
+fn build_id(text: &str) -> Result<String, String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "._-".contains(c);
+    if text.is_empty() || !text.chars().all(valid) {
+        return Err("may only contain letters, digits, '.', '_' and '-'".to_string());
+    }
+    Ok(text.to_string())
+}
+
+/// An encoding named on the command line.
+fn charset(label: &str) -> Result<Charset, String> {
+    Charset::from_label(label).ok_or_else(|| {
+        "use utf-8/utf-16/utf-16le/utf-16be/windows-1252/iso-8859-1".to_string()
+    })
+}
@@ -0,0 +1,242 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use quick_xml::{events::Event, Reader};
+
+use crate::{
+    charset,
+    error::CompileError,
+    check_cycle, check_fragment_version, check_limits, check_pass_time, collapse_whitespace,
+    directive_attrs, directive_parts, directive_placement, expand_open, include_raw,
+    inline_directive_error, load_source, log_message, note_deprecation, protect,
+    repeated_include, resolve_include, unescape_directive,
+    options::{InlineIncludes, Options},
+    placeholder_lang, sourcemap, wrap_placeholder, DirectiveAttrs, Expansion, Include, LineCounter,
+    Placement,
+};
+
+/// Pull-parser counterpart of `crate::expand_content`. Events are copied byte-for-byte from
+/// the source unless they are directives, comments or `<placeholder>` tags, so CDATA sections
+/// and attribute values can never be mistaken for markup the way a regex pass can. A directive
+/// in an attribute value is left as written, or fails the file under `--inline-includes error`.
+pub fn expand_content(
+    content: &str,
+    file_path: &Path,
+    is_root: bool,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    expand(content, file_path, is_root, false, opts, exp)
+}
+
+/// `keep_lines` copies fragment markup through without collapsing whitespace, for placeholder
+/// content with a declared language.
+fn expand(
+    content: &str,
+    file_path: &Path,
+    is_root: bool,
+    keep_lines: bool,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let mut reader = Reader::from_str(content);
+    let mut lines = LineCounter::new(content);
+    let mut out = String::with_capacity(content.len());
+    // Fragment markup waiting to be whitespace-collapsed; flushed before verbatim sections.
+    let mut pending = String::new();
+    // Nesting depth inside whitespace-significant elements, whose content bypasses `pending`.
+    let mut preserved_depth = 0usize;
+    // Time spent collapsing this file's own text, nested includes aside, for `--pass-timeout`.
+    let mut cleanup = Duration::ZERO;
+
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event().map_err(|err| CompileError::Malformed {
+            path: file_path.to_path_buf(),
+            position: reader.error_position(),
+            message: err.to_string(),
+        })?;
+        let end = reader.buffer_position() as usize;
+        let raw = &content[start..end];
+        // Directives are comments or processing instructions, depending on the configured syntax.
+        let comment = matches!(event, Event::Comment(_));
+        let directive = match event {
+            Event::Comment(_) | Event::PI(_) => opts
+                .directives
+                .captures(raw)
+                .filter(|caps| caps[0].len() == raw.len()),
+            _ => None,
+        };
+
+        // Attribute values are copied as they are, but `--inline-includes error` rejects a
+        // directive in one all the same, as the regex engine does.
+        if opts.inline_includes == InlineIncludes::Error
+            && matches!(event, Event::Start(_) | Event::Empty(_))
+            && let Some(found) = opts.directives.find(raw)
+        {
+            let at = start + found.start();
+            return Err(inline_directive_error(file_path, content, at, Placement::Attribute));
+        }
+
+        match event {
+            Event::Eof => break,
+            Event::Comment(_) | Event::PI(_) if comment || directive.is_some() => {
+                let placement = directive_placement(content, start, end);
+                let expand = match opts.inline_includes {
+                    _ if placement == Placement::OwnLine => true,
+                    InlineIncludes::Expand => true,
+                    InlineIncludes::Ignore => false,
+                    InlineIncludes::Error if directive.is_some() => {
+                        return Err(inline_directive_error(file_path, content, start, placement));
+                    }
+                    InlineIncludes::Error => false,
+                };
+
+                let included = if let Some(literal) = unescape_directive(raw) {
+                    Some(protect(literal, exp))
+                } else if let Some(caps) = directive.filter(|_| expand) {
+                    let (include_file, attrs) = directive_parts(&caps);
+                    let attrs = directive_attrs(attrs, file_path, exp);
+                    let line = lines.line(end);
+                    Some(include(file_path, include_file, &attrs, is_root, line, opts, exp)?)
+                } else {
+                    None
+                };
+                match included {
+                    Some(included) if is_root || keep_lines || preserved_depth > 0 => {
+                        out.push_str(&included)
+                    }
+                    Some(included) => {
+                        flush(&mut pending, &mut out, &mut cleanup);
+                        push_joined(&mut out, &included);
+                    }
+                    None if is_root => out.push_str(raw),
+                    None => {}
+                }
+            }
+            Event::Start(ref tag) | Event::Empty(ref tag)
+                if !is_root && tag.name().as_ref().eq_ignore_ascii_case(b"placeholder") => {}
+            Event::End(ref tag)
+                if !is_root && tag.name().as_ref().eq_ignore_ascii_case(b"placeholder") => {}
+            _ if is_root => out.push_str(raw),
+            Event::Start(ref tag) if opts.preserves_whitespace(tag.name().as_ref()) => {
+                flush(&mut pending, &mut out, &mut cleanup);
+                out.push_str(raw);
+                preserved_depth += 1;
+            }
+            Event::End(ref tag)
+                if preserved_depth > 0 && opts.preserves_whitespace(tag.name().as_ref()) =>
+            {
+                out.push_str(raw);
+                preserved_depth -= 1;
+            }
+            _ if keep_lines || preserved_depth > 0 => out.push_str(raw),
+            Event::CData(_) => {
+                flush(&mut pending, &mut out, &mut cleanup);
+                out.push_str(raw);
+            }
+            _ => pending.push_str(raw),
+        }
+    }
+
+    flush(&mut pending, &mut out, &mut cleanup);
+    check_pass_time("placeholder/comment cleanup", file_path, cleanup, opts)?;
+    Ok(out)
+}
+
+fn flush(pending: &mut String, out: &mut String, cleanup: &mut Duration) {
+    if !pending.is_empty() {
+        let started = Instant::now();
+        push_joined(out, &collapse_whitespace(pending));
+        pending.clear();
+        *cleanup += started.elapsed();
+    }
+}
+
+/// Appends collapsed fragment text, folding the space at the seam the way a single
+/// whole-document pass would.
+fn push_joined(out: &mut String, text: &str) {
+    match text.strip_prefix(' ') {
+        Some(rest) if out.ends_with(' ') => out.push_str(rest),
+        _ => out.push_str(text),
+    }
+}
+
+/// `line` is where the directive ends in `file_path`, for `--source-map`.
+fn include(
+    file_path: &Path,
+    include: Include,
+    attrs: &DirectiveAttrs,
+    is_root: bool,
+    line: usize,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let dir = file_path.parent().unwrap_or_else(|| Path::new("."));
+    let include_path = match resolve_include(dir, include, opts, exp) {
+        Ok(path) => path,
+        Err(replacement) if is_root => return Ok(replacement),
+        Err(_) => return Ok(String::new()),
+    };
+    if let Some(required) = &attrs.version {
+        check_fragment_version(&include_path, required, opts, exp)?;
+    }
+    note_deprecation(file_path, &include_path, opts, exp);
+    if repeated_include(file_path, &include_path, attrs.once, opts, exp) {
+        return Ok(String::new());
+    }
+    if attrs.raw {
+        return Ok(include_raw(file_path, &include_path, false, opts, exp));
+    }
+    if let Some(comment) = check_cycle(file_path, &include_path, opts, exp)? {
+        // Comments inside fragments are stripped anyway.
+        return Ok(if is_root { comment } else { String::new() });
+    }
+    check_limits(file_path, &include_path, opts, exp)?;
+
+    let lang = is_root
+        .then(|| placeholder_lang(&include_path, opts, exp))
+        .flatten();
+    let expanded = load_source(&include_path, opts, exp).and_then(|content| {
+        let content = charset::undeclared(&content);
+        expand_open(&include_path, exp, |exp| {
+            expand(&content, &include_path, false, lang.is_some(), opts, exp)
+        })
+    });
+    Ok(match expanded {
+        Ok(inner) => {
+            log_message(&format!("Included: {}", include_path.display()));
+            exp.includes.push(include_path.clone());
+            exp.edges.push((file_path.to_path_buf(), include_path.clone()));
+            let inner = sourcemap::open(exp, &include_path, &inner);
+            let inner = sourcemap::close(exp, file_path, line, &inner);
+            match is_root {
+                true => {
+                    let wrapped =
+                        wrap_placeholder(&include_path, lang.as_deref(), &inner, opts, exp);
+                    sourcemap::close(exp, file_path, line, &wrapped)
+                }
+                false => inner,
+            }
+        }
+        // A pin, cycle or limit deeper down fails the target, not just this include.
+        Err(
+            err @ (CompileError::VersionMismatch { .. }
+            | CompileError::Cycle { .. }
+            | CompileError::IncludeDepth { .. }
+            | CompileError::ExpandedSize { .. }),
+        ) => return Err(err),
+        Err(err) => {
+            exp.poison(&err);
+            exp.unreadable.push(include_path.clone());
+            exp.warn(format!("Error including {}: {}", include_path.display(), err));
+            if is_root {
+                format!("<!-- Error including {}: {} -->", include_path.display(), err)
+            } else {
+                String::new()
+            }
+        }
+    })
+}
@@ -0,0 +1,430 @@
+use std::{ops::Range, path::Path};
+
+use once_cell::sync::Lazy;
+use quick_xml::{events::Event, Reader};
+use regex::Regex;
+
+use crate::{
+    blank_line_end, blank_line_start, error::CompileError, load_source, log_message,
+    options::Options, Expansion, LineCounter,
+};
+
+/// An attribute in a start tag: `name="value"` or `name='value'`, with the whitespace before it.
+static ATTR_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"\s+([\w:.-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap()
+});
+
+/// A predicate of a selector step: a position, or an attribute that is present or has a value.
+static PREDICATE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^(?:(\d+)|@([\w:.-]+)(?:\s*=\s*(?:"([^"]*)"|'([^']*)'))?)$"#).unwrap()
+});
+
+struct Attr {
+    name: String,
+    /// From the whitespace before the name to the closing quote.
+    whole: Range<usize>,
+    /// Between the quotes.
+    value: Range<usize>,
+}
+
+struct Element {
+    name: String,
+    attrs: Vec<Attr>,
+    start_tag: Range<usize>,
+    /// From the start tag's `<` to the end tag's `>`.
+    outer: Range<usize>,
+    /// Between the tags; `None` for an empty element, `<name/>`.
+    inner: Option<Range<usize>>,
+    children: Vec<usize>,
+}
+
+/// The elements of a document, in document order; the top-level ones are `roots`.
+struct Tree {
+    elements: Vec<Element>,
+    roots: Vec<usize>,
+}
+
+/// What a selector picks: an element, or one of its attributes.
+enum Node {
+    Element(usize),
+    Attr(usize, String),
+}
+
+/// The compiled output of the target at `file` with the operations in its patch file applied,
+/// when `--patches` is given and the folder has one at the target's path relative to the base
+/// directory. A patch is an RFC 5261 `<diff>` document of `<add>`, `<replace>` and `<remove>`
+/// operations, applied in order, each picking exactly one element or attribute with an XPath
+/// `sel`. A selector that matches nothing, or more than one node, fails the target.
+pub(crate) fn apply(
+    file: &Path,
+    output: String,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let (Some(patches), Ok(relative)) = (&opts.patches, file.strip_prefix(&opts.base_dir)) else {
+        return Ok(output);
+    };
+    let patch_path = patches.join(relative);
+    if !patch_path.is_file() {
+        // Adding one later changes the output.
+        exp.missed.insert(patch_path);
+        return Ok(output);
+    }
+    let patch = load_source(&patch_path, opts, exp)?;
+    let ops = operations(tree(&patch, &patch_path)?);
+    let mut output = output;
+    let mut lines = LineCounter::new(&patch);
+    for op in &ops {
+        let fail = |lines: &mut LineCounter, reason: String| CompileError::Patch {
+            path: patch_path.clone(),
+            line: lines.line(op.outer.start),
+            reason,
+        };
+        let doc = tree(&output, file)?;
+        let sel = op_attr(&patch, op, "sel")
+            .ok_or_else(|| fail(&mut lines, format!("<{}> has no sel", op.name)))?;
+        let node = select(&doc, &output, sel).map_err(|reason| fail(&mut lines, reason))?;
+        let content = op.inner.clone().map_or("", |inner| &patch[inner]);
+        let edit = match op.name.as_str() {
+            "remove" => remove(&doc, &output, &node),
+            "replace" => replace(&doc, &output, &node, content),
+            "add" => {
+                let pos = op_attr(&patch, op, "pos").unwrap_or("append");
+                match op_attr(&patch, op, "type") {
+                    Some(kind) => add_attr(&doc, &node, kind, content),
+                    None => add(&doc, &output, &node, pos, content),
+                }
+            }
+            name => Err(format!("<{}> is not add, replace or remove", name)),
+        }
+        .map_err(|reason| fail(&mut lines, format!("sel=\"{}\": {}", sel, reason)))?;
+        let (range, text) = edit;
+        output.replace_range(range, &text);
+    }
+    log_message(&format!("Patched: {} with {}", file.display(), patch_path.display()));
+    Ok(output)
+}
+
+/// The operations of a patch document: the children of its root element.
+fn operations(tree: Tree) -> Vec<Element> {
+    let Some(&root) = tree.roots.first() else {
+        return Vec::new();
+    };
+    let children = tree.elements[root].children.clone();
+    let mut elements: Vec<Option<Element>> = tree.elements.into_iter().map(Some).collect();
+    children.into_iter().filter_map(|child| elements[child].take()).collect()
+}
+
+fn op_attr<'a>(patch: &'a str, op: &Element, name: &str) -> Option<&'a str> {
+    op.attrs.iter().find(|attr| attr.name == name).map(|attr| &patch[attr.value.clone()])
+}
+
+/// Parses `content` into its elements; text, comments and CDATA are skipped.
+fn tree(content: &str, path: &Path) -> Result<Tree, CompileError> {
+    let mut reader = Reader::from_str(content);
+    let mut tree = Tree {
+        elements: Vec::new(),
+        roots: Vec::new(),
+    };
+    let mut open: Vec<usize> = Vec::new();
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event().map_err(|err| CompileError::Malformed {
+            path: path.to_path_buf(),
+            position: reader.error_position(),
+            message: err.to_string(),
+        })?;
+        let end = reader.buffer_position() as usize;
+        match &event {
+            Event::Eof => break,
+            Event::Start(tag) | Event::Empty(tag) => {
+                let name = String::from_utf8_lossy(tag.name().as_ref()).into_owned();
+                let attrs = ATTR_RE
+                    .captures_iter(&content[start..end])
+                    .map(|caps| {
+                        let value = caps.get(2).or(caps.get(3)).unwrap();
+                        Attr {
+                            name: caps[1].to_string(),
+                            whole: start + caps.get(0).unwrap().start()..start + value.end() + 1,
+                            value: start + value.start()..start + value.end(),
+                        }
+                    })
+                    .collect();
+                let index = tree.elements.len();
+                let empty = matches!(event, Event::Empty(_));
+                tree.elements.push(Element {
+                    name,
+                    attrs,
+                    start_tag: start..end,
+                    outer: start..end,
+                    inner: (!empty).then_some(end..end),
+                    children: Vec::new(),
+                });
+                match open.last() {
+                    Some(&parent) => tree.elements[parent].children.push(index),
+                    None => tree.roots.push(index),
+                }
+                if !empty {
+                    open.push(index);
+                }
+            }
+            Event::End(_) => {
+                if let Some(index) = open.pop() {
+                    let element = &mut tree.elements[index];
+                    element.outer.end = end;
+                    element.inner = Some(element.start_tag.end..start);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(tree)
+}
+
+/// The one node `sel` picks: an absolute location path of element steps, each a name or `*`
+/// with optional predicates (`[2]`, `[@id]`, `[@id='x']`), optionally ending in `@name`.
+fn select(tree: &Tree, content: &str, sel: &str) -> Result<Node, String> {
+    let Some(path) = sel.trim().strip_prefix('/') else {
+        return Err(format!("sel=\"{}\" is not an absolute path", sel));
+    };
+    let steps = split_steps(path);
+    let (attr, steps) = match steps.split_last() {
+        Some((last, rest)) if last.starts_with('@') => (Some(last[1..].to_string()), rest),
+        _ => (None, &steps[..]),
+    };
+    // Each step picks from groups of siblings: the document's root first, then the children
+    // of each element the step before picked.
+    let mut groups = vec![tree.roots.clone()];
+    let mut nodes = Vec::new();
+    for step in steps {
+        let (name, predicates) = parse_step(step)
+            .ok_or_else(|| format!("sel=\"{}\": cannot read step {}", sel, step))?;
+        nodes.clear();
+        for group in &groups {
+            let mut matched: Vec<usize> = group
+                .iter()
+                .copied()
+                .filter(|&child| name == "*" || tree.elements[child].name == name)
+                .collect();
+            for predicate in &predicates {
+                matched = filter(tree, content, matched, predicate);
+            }
+            nodes.extend(matched);
+        }
+        groups = nodes.iter().map(|&node| tree.elements[node].children.clone()).collect();
+    }
+    let found = match nodes.as_slice() {
+        [] => return Err(format!("sel=\"{}\" matches nothing", sel)),
+        [one] => *one,
+        many => return Err(format!("sel=\"{}\" matches {} elements", sel, many.len())),
+    };
+    match attr {
+        Some(name) if tree.elements[found].attrs.iter().any(|attr| attr.name == name) => {
+            Ok(Node::Attr(found, name))
+        }
+        Some(name) => Err(format!("sel=\"{}\": the element has no attribute {}", sel, name)),
+        None => Ok(Node::Element(found)),
+    }
+}
+
+/// The steps of a location path, split on the slashes outside predicates.
+fn split_steps(path: &str) -> Vec<&str> {
+    let mut steps = Vec::new();
+    let (mut depth, mut quote, mut from) = (0, None, 0);
+    for (i, c) in path.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            (_, Some(_)) => {}
+            ('[', None) => depth += 1,
+            (']', None) => depth -= 1,
+            ('/', None) if depth == 0 => {
+                steps.push(&path[from..i]);
+                from = i + 1;
+            }
+            _ => {}
+        }
+    }
+    steps.push(&path[from..]);
+    steps
+}
+
+/// The name of a step and the text of each of its predicates.
+fn parse_step(step: &str) -> Option<(&str, Vec<&str>)> {
+    let (name, mut rest) = step.split_at(step.find('[').unwrap_or(step.len()));
+    if name.is_empty() {
+        return None;
+    }
+    let mut predicates = Vec::new();
+    while !rest.is_empty() {
+        let close = rest.find(']')?;
+        predicates.push(rest.get(1..close)?.trim());
+        rest = &rest[close + 1..];
+    }
+    Some((name, predicates))
+}
+
+fn filter(tree: &Tree, content: &str, matched: Vec<usize>, predicate: &str) -> Vec<usize> {
+    let Some(caps) = PREDICATE_RE.captures(predicate) else {
+        return Vec::new();
+    };
+    if let Some(position) = caps.get(1) {
+        let position: usize = position.as_str().parse().unwrap_or(0);
+        return matched.get(position.wrapping_sub(1)).copied().into_iter().collect();
+    }
+    let name = &caps[2];
+    let wanted = caps.get(3).or(caps.get(4)).map(|value| value.as_str());
+    matched
+        .into_iter()
+        .filter(|&index| {
+            let has = |attr: &Attr| wanted.is_none_or(|want| content[attr.value.clone()] == *want);
+            tree.elements[index].attrs.iter().any(|attr| attr.name == name && has(attr))
+        })
+        .collect()
+}
+
+/// A range of the document and what replaces it.
+type Edit = (Range<usize>, String);
+
+fn remove(tree: &Tree, content: &str, node: &Node) -> Result<Edit, String> {
+    match node {
+        Node::Attr(index, name) => Ok((attr(tree, *index, name).whole.clone(), String::new())),
+        Node::Element(index) => {
+            let outer = tree.elements[*index].outer.clone();
+            // An element alone on its line takes the line with it.
+            let line = (blank_line_start(content, outer.start), blank_line_end(content, outer.end));
+            let range = match line {
+                (Some(start), Some(end)) => start..end,
+                _ => outer,
+            };
+            Ok((range, String::new()))
+        }
+    }
+}
+
+fn replace(tree: &Tree, content: &str, node: &Node, with: &str) -> Result<Edit, String> {
+    match node {
+        Node::Attr(index, name) => {
+            let value = attr(tree, *index, name).value.clone();
+            let quote = &content[value.end..value.end + 1];
+            let escaped = match quote {
+                "\"" => with.replace('"', "&quot;"),
+                _ => with.replace('\'', "&apos;"),
+            };
+            Ok((value, escaped))
+        }
+        Node::Element(index) => {
+            let outer = tree.elements[*index].outer.clone();
+            let indent = indent_at(content, outer.start);
+            Ok((outer, reindent(with, &indent)))
+        }
+    }
+}
+
+/// Adds an attribute, `kind` being `@name`, at the end of the element's start tag.
+fn add_attr(tree: &Tree, node: &Node, kind: &str, value: &str) -> Result<Edit, String> {
+    let Node::Element(index) = node else {
+        return Err("can only add attributes to an element".to_string());
+    };
+    let Some(name) = kind.strip_prefix('@') else {
+        return Err(format!("type=\"{}\" is not an attribute", kind));
+    };
+    let element = &tree.elements[*index];
+    if element.attrs.iter().any(|attr| attr.name == name) {
+        return Err(format!("the element already has attribute {}", name));
+    }
+    let at = match element.attrs.last() {
+        Some(last) => last.whole.end,
+        None => element.start_tag.start + 1 + element.name.len(),
+    };
+    Ok((at..at, format!(" {}=\"{}\"", name, value.replace('"', "&quot;"))))
+}
+
+/// Adds `content` to the element, as its last child (`append`) or first (`prepend`), or as its
+/// sibling just `before` or `after` it. On their own lines, added elements are indented to
+/// match their new siblings.
+fn add(tree: &Tree, content: &str, node: &Node, pos: &str, added: &str) -> Result<Edit, String> {
+    let Node::Element(index) = node else {
+        return Err("can only add next to or inside an element".to_string());
+    };
+    let element = &tree.elements[*index];
+    let outer = &element.outer;
+    let own_line = blank_line_start(content, outer.start).is_some();
+    let indent = indent_at(content, outer.start);
+    let child_indent = match element.children.first() {
+        Some(&child) => indent_at(content, tree.elements[child].outer.start),
+        None if indent.contains('\t') => format!("{}\t", indent),
+        None => format!("{}  ", indent),
+    };
+    Ok(match (pos, &element.inner) {
+        ("before", _) if own_line => {
+            (outer.start..outer.start, format!("{}\n{}", reindent(added, &indent), indent))
+        }
+        ("before", _) => (outer.start..outer.start, added.trim().to_string()),
+        ("after", _) if own_line => {
+            (outer.end..outer.end, format!("\n{}{}", indent, reindent(added, &indent)))
+        }
+        ("after", _) => (outer.end..outer.end, added.trim().to_string()),
+        ("append" | "prepend", None) => {
+            let close = element.start_tag.end - 2;
+            let text = match own_line {
+                true => format!(
+                    ">\n{}{}\n{}</{}>",
+                    child_indent,
+                    reindent(added, &child_indent),
+                    indent,
+                    element.name
+                ),
+                false => format!(">{}</{}>", added.trim(), element.name),
+            };
+            (close..element.start_tag.end, text)
+        }
+        ("append", Some(inner)) => match blank_line_start(content, inner.end) {
+            Some(line) if own_line => {
+                let text = format!("{}{}\n", child_indent, reindent(added, &child_indent));
+                (line..line, text)
+            }
+            _ => (inner.end..inner.end, added.trim().to_string()),
+        },
+        ("prepend", Some(inner)) => match blank_line_end(content, inner.start) {
+            Some(line) if own_line => {
+                let text = format!("{}{}\n", child_indent, reindent(added, &child_indent));
+                (line..line, text)
+            }
+            _ => (inner.start..inner.start, added.trim().to_string()),
+        },
+        (pos, _) => return Err(format!("pos=\"{}\" is not before, after, prepend or append", pos)),
+    })
+}
+
+fn attr<'a>(tree: &'a Tree, index: usize, name: &str) -> &'a Attr {
+    tree.elements[index].attrs.iter().find(|attr| attr.name == name).unwrap()
+}
+
+/// The whitespace at the start of the line holding `at`.
+fn indent_at(content: &str, at: usize) -> String {
+    let line = content[..at].rfind('\n').map_or(0, |i| i + 1);
+    content[line..].chars().take_while(|c| *c == ' ' || *c == '\t').collect()
+}
+
+/// `text` trimmed, with the indentation its lines share replaced by `indent` on every line
+/// but the first, which goes where the caller puts it.
+fn reindent(text: &str, indent: &str) -> String {
+    let text = text.trim_matches(|c| c == '\r' || c == '\n').trim_end();
+    let shared = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<String> = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| match (i, line.trim().is_empty()) {
+            (_, true) => String::new(),
+            (0, false) => line[shared.min(line.len() - line.trim_start().len())..].to_string(),
+            (_, false) => format!("{}{}", indent, &line[shared..]),
+        })
+        .collect();
+    lines.join("\n")
+}
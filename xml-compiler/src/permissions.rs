@@ -0,0 +1,176 @@
+use std::{fs, io, path::Path};
+
+use crate::{
+    error::CompileError,
+    log_message,
+    options::{Options, OutputMode},
+};
+
+/// Prefixes an IO error with what was being attempted, keeping its kind.
+fn context(what: String) -> impl FnOnce(io::Error) -> io::Error {
+    move |err| io::Error::new(err.kind(), format!("{}: {}", what, err))
+}
+
+/// Applies `--output-mode`, `--preserve-owner`, `--copy-xattr` and `--read-only-outputs` to a
+/// freshly written output. Ownership is best effort: changing it usually needs privileges, so a
+/// refusal is only logged.
+pub fn apply(out_path: &Path, source: &Path, opts: &Options) -> Result<(), CompileError> {
+    if let Some(mode) = opts.output_mode {
+        set_mode(out_path, source, mode)
+            .map_err(context(format!("Failed to set permissions on {}", out_path.display())))
+            .map_err(CompileError::io(out_path))?;
+    }
+
+    if opts.preserve_owner
+        && let Err(err) = copy_owner(out_path, source)
+    {
+        log_message(&format!(
+            "Warning: could not give {} the owner of {}: {}",
+            out_path.display(),
+            source.display(),
+            err
+        ));
+    }
+
+    for name in &opts.copy_xattrs {
+        copy_xattr(out_path, source, name)
+            .map_err(context(format!(
+                "Failed to copy attribute {} to {}",
+                name,
+                out_path.display()
+            )))
+            .map_err(CompileError::io(out_path))?;
+    }
+
+    // Last, as copying attributes needs write access on some platforms.
+    if opts.read_only_outputs {
+        let permissions = fs::metadata(out_path).map_err(CompileError::io(out_path))?.permissions();
+        make_readonly(out_path, permissions)
+            .map_err(context(format!("Failed to make {} read-only", out_path.display())))
+            .map_err(CompileError::io(out_path))?;
+    }
+    Ok(())
+}
+
+/// An earlier run may have left the output read-only; make it writable so it can be replaced.
+pub fn prepare(out_path: &Path, opts: &Options) -> Result<(), CompileError> {
+    if opts.output_mode.is_some() || opts.read_only_outputs {
+        clear_readonly(out_path)
+            .map_err(context(format!("Failed to make {} writable", out_path.display())))
+            .map_err(CompileError::io(out_path))?;
+    }
+    Ok(())
+}
+
+/// Makes `path` writable if it exists and is read-only; Windows won't even delete it otherwise.
+pub fn clear_readonly(path: &Path) -> io::Result<()> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.permissions().readonly() => make_writable(path, meta.permissions()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn make_readonly(out_path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(out_path, fs::Permissions::from_mode(permissions.mode() & !0o222))
+}
+
+#[cfg(not(unix))]
+fn make_readonly(out_path: &Path, mut permissions: fs::Permissions) -> io::Result<()> {
+    permissions.set_readonly(true);
+    fs::set_permissions(out_path, permissions)
+}
+
+#[cfg(unix)]
+fn make_writable(out_path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(out_path, fs::Permissions::from_mode(permissions.mode() | 0o200))
+}
+
+#[cfg(not(unix))]
+fn make_writable(out_path: &Path, mut permissions: fs::Permissions) -> io::Result<()> {
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(out_path, permissions)
+}
+
+#[cfg(unix)]
+fn set_mode(out_path: &Path, source: &Path, mode: OutputMode) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let permissions = match mode {
+        OutputMode::Source => fs::metadata(source)?.permissions(),
+        OutputMode::Explicit(bits) => fs::Permissions::from_mode(bits),
+    };
+    fs::set_permissions(out_path, permissions)
+}
+
+/// Windows only has the read-only attribute: it is copied from the source, or set when the
+/// explicit mode grants no write bits.
+#[cfg(not(unix))]
+fn set_mode(out_path: &Path, source: &Path, mode: OutputMode) -> io::Result<()> {
+    let readonly = match mode {
+        OutputMode::Source => fs::metadata(source)?.permissions().readonly(),
+        OutputMode::Explicit(bits) => bits & 0o222 == 0,
+    };
+    let mut permissions = fs::metadata(out_path)?.permissions();
+    permissions.set_readonly(readonly);
+    fs::set_permissions(out_path, permissions)
+}
+
+#[cfg(unix)]
+fn copy_owner(out_path: &Path, source: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = fs::metadata(source)?;
+    std::os::unix::fs::chown(out_path, Some(meta.uid()), Some(meta.gid()))
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_out_path: &Path, _source: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "ownership is only preserved on Unix",
+    ))
+}
+
+/// Copies one extended attribute; a source without it leaves the output without it too.
+#[cfg(unix)]
+fn copy_xattr(out_path: &Path, source: &Path, name: &str) -> io::Result<()> {
+    match xattr::get(source, name)? {
+        Some(value) => xattr::set(out_path, name, &value),
+        None => match xattr::remove(out_path, name) {
+            Err(err) if err.raw_os_error() == Some(xattr_missing()) => Ok(()),
+            result => result,
+        },
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn xattr_missing() -> i32 {
+    61 // ENODATA
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn xattr_missing() -> i32 {
+    93 // ENOATTR
+}
+
+/// NTFS alternate data streams are reachable as `<file>:<stream>` through the normal file API.
+#[cfg(windows)]
+fn copy_xattr(out_path: &Path, source: &Path, name: &str) -> io::Result<()> {
+    let stream = |path: &Path| {
+        let mut path = path.as_os_str().to_owned();
+        path.push(":");
+        path.push(name);
+        std::path::PathBuf::from(path)
+    };
+    match fs::read(stream(source)) {
+        Ok(value) => fs::write(stream(out_path), value),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
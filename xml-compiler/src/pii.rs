@@ -0,0 +1,107 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::LineCounter;
+
+static EMAIL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}\b").unwrap()
+});
+static IBAN_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\b[A-Z]{2}\d{2}(?: ?[A-Z0-9]{4}){2,7}(?: ?[A-Z0-9]{1,3})?\b").unwrap()
+});
+static CARD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").unwrap());
+
+/// Something in an output that looks like personal data.
+pub struct Finding {
+    pub kind: &'static str,
+    /// 1-based line in the output.
+    pub line: usize,
+    /// The matched text, for finding where it came from. Never log this; log `masked`.
+    pub text: String,
+    /// The match with most of it hidden, safe to put in logs and reports.
+    pub masked: String,
+}
+
+/// What a match must also pass to count as a finding.
+type Validator = fn(&str) -> bool;
+
+/// Email addresses, IBANs with a valid checksum and card numbers passing the Luhn check in
+/// `text`, in order, skipping any that one of `allow` matches.
+pub fn scan(text: &str, allow: &[Regex]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let candidates: [(&str, &Regex, Validator); 3] = [
+        ("email address", &EMAIL_RE, |_| true),
+        ("IBAN", &IBAN_RE, valid_iban),
+        ("card number", &CARD_RE, valid_card),
+    ];
+    for (kind, re, valid) in candidates {
+        let mut lines = LineCounter::new(text);
+        for found in re.find_iter(text) {
+            let matched = found.as_str();
+            if !valid(matched) || allow.iter().any(|allowed| allowed.is_match(matched)) {
+                continue;
+            }
+            findings.push(Finding {
+                kind,
+                line: lines.line(found.start()),
+                text: matched.to_string(),
+                masked: mask(kind, matched),
+            });
+        }
+    }
+    findings.sort_by_key(|finding| finding.line);
+    findings
+}
+
+/// ISO 13616: move the first four characters to the end, turn letters into numbers (A = 10),
+/// and the result must be 1 modulo 97.
+fn valid_iban(iban: &str) -> bool {
+    let compact: String = iban.chars().filter(|c| !c.is_whitespace()).collect();
+    if !(15..=34).contains(&compact.len()) {
+        return false;
+    }
+    let rearranged = compact[4..].chars().chain(compact[..4].chars());
+    let mut remainder = 0u32;
+    for c in rearranged {
+        let Some(value) = c.to_digit(36) else {
+            return false;
+        };
+        let width = if value < 10 { 10 } else { 100 };
+        remainder = (remainder * width + value) % 97;
+    }
+    remainder == 1
+}
+
+/// Luhn check over the digits, ignoring separators.
+fn valid_card(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &digit)| match i % 2 {
+            0 => digit,
+            _ if digit * 2 > 9 => digit * 2 - 9,
+            _ => digit * 2,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+fn mask(kind: &str, matched: &str) -> String {
+    let compact: String = matched.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    let tail = &compact[compact.len().saturating_sub(4)..];
+    match kind {
+        "email address" => match matched.split_once('@') {
+            Some((local, domain)) => {
+                format!("{}***@{}", local.chars().next().unwrap_or('*'), domain)
+            }
+            None => "***".to_string(),
+        },
+        "IBAN" => format!("{}****{}", &compact[..4], tail),
+        _ => format!("****{}", tail),
+    }
+}
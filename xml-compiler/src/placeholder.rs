@@ -0,0 +1,268 @@
+use std::path::Path;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::{log_message, remove_comments};
+
+static LANG_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?is)<placeholder\b[^>]*?\b(?:lang|type)\s*=\s*["']([^"']+)["']"#).unwrap()
+});
+static ENCODING_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?is)<placeholder\b[^>]*?\bencoding\s*=\s*["']([^"']+)["']"#).unwrap()
+});
+static HTML_TAG_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)<(/?)([A-Za-z][\w:-]*)[^>]*?(/?)>").unwrap());
+
+const HTML_VOID: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Language declared on the fragment's `<placeholder lang="...">` (or `type="..."`), if any.
+pub fn declared_lang(source: &str) -> Option<String> {
+    LANG_RE
+        .captures(source)
+        .map(|caps| caps[1].trim().to_ascii_lowercase())
+}
+
+/// How placeholder content is written into the base XML.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// In a CDATA section, as written.
+    Cdata,
+    /// As text, with `&`, `<` and `>` escaped.
+    Escape,
+    /// Base64 of the UTF-8 content, for consumers that take it as an opaque blob.
+    Base64,
+}
+
+/// Encoding declared on the fragment's `<placeholder encoding="...">`; CDATA if it declares
+/// none, or one this version doesn't know.
+pub fn declared_encoding(source_path: &Path, source: &str) -> Encoding {
+    let Some(caps) = ENCODING_RE.captures(source) else {
+        return Encoding::Cdata;
+    };
+    match caps[1].trim().to_ascii_lowercase().as_str() {
+        "cdata" => Encoding::Cdata,
+        "escape" => Encoding::Escape,
+        "base64" => Encoding::Base64,
+        other => {
+            log_message(&format!(
+                "Warning: {}: unknown placeholder encoding '{}', using CDATA",
+                source_path.display(),
+                other
+            ));
+            Encoding::Cdata
+        }
+    }
+}
+
+/// Writes the placeholder content of `source_path` out in `encoding`. In CDATA, a `]]>` inside
+/// the content would end the section early, so it is split across two sections.
+pub fn wrap(source_path: &Path, encoding: Encoding, content: &str) -> String {
+    match encoding {
+        Encoding::Cdata => {}
+        Encoding::Escape => {
+            let escaped = content.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+            return format!("\n{}\n", escaped);
+        }
+        Encoding::Base64 => return base64(content.as_bytes()),
+    }
+    if content.contains("]]>") {
+        log_message(&format!(
+            "Warning: {} contains ']]>', splitting the CDATA section around it",
+            source_path.display()
+        ));
+    }
+    format!("<![CDATA[\n{}\n]]>", content.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+/// Standard base64 with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Problems found in placeholder content in `lang`, or `None` for a language there is no
+/// check for.
+pub fn check(lang: &str, content: &str) -> Option<Vec<String>> {
+    Some(match lang {
+        "js" | "javascript" => check_delimiters(content, &['\'', '"', '`'], "//"),
+        "json" => check_json(content),
+        "sql" => check_sql(content),
+        "html" => check_html(content),
+        _ => return None,
+    })
+}
+
+/// A full parse, once XML comments around the content (such as a fragment's `#version`) are
+/// removed. The error names the line and column.
+fn check_json(content: &str) -> Vec<String> {
+    match serde_json::from_str::<serde_json::Value>(&remove_comments(content)) {
+        Ok(_) => Vec::new(),
+        Err(err) => vec![format!("invalid JSON: {}", err)],
+    }
+}
+
+/// Characters that may start an SQL token besides letters, digits and quotes.
+const SQL_SYMBOLS: &str = "_.,;:+-*/%=<>!@#$?&|^~";
+
+/// Lexes SQL far enough to find unterminated strings, quoted identifiers and comments,
+/// unbalanced parentheses, and characters no SQL token starts with. Quotes are escaped by
+/// doubling them, as in SQL, not with a backslash.
+fn check_sql(content: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut open = Vec::new();
+    let mut chars = content.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let closing = match c {
+            '\'' => Some(('\'', "string")),
+            '"' => Some(('"', "quoted identifier")),
+            '`' => Some(('`', "quoted identifier")),
+            '[' => Some((']', "bracketed identifier")),
+            _ => None,
+        };
+        if let Some((end, what)) = closing {
+            let mut closed = false;
+            while let Some((_, d)) = chars.next() {
+                // A doubled quote stands for itself.
+                if d == end && chars.next_if(|&(_, next)| next == end && end != ']').is_none() {
+                    closed = true;
+                    break;
+                }
+            }
+            if !closed {
+                problems.push(format!("unterminated {} starting at byte {}", what, i));
+            }
+        } else if content[i..].starts_with("--") {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else if content[i..].starts_with("/*") {
+            chars.next();
+            let mut closed = false;
+            while let Some((j, _)) = chars.next() {
+                if content[j..].starts_with("*/") {
+                    chars.next();
+                    closed = true;
+                    break;
+                }
+            }
+            if !closed {
+                problems.push(format!("unterminated block comment starting at byte {}", i));
+            }
+        } else if c == '(' {
+            open.push(i);
+        } else if c == ')' {
+            if open.pop().is_none() {
+                problems.push(format!("unmatched ')' at byte {}", i));
+            }
+        } else if !(c.is_alphanumeric() || c.is_whitespace() || SQL_SYMBOLS.contains(c)) {
+            problems.push(format!("unexpected '{}' at byte {}", c, i));
+        }
+    }
+
+    problems.extend(open.into_iter().map(|at| format!("unclosed '(' at byte {}", at)));
+    problems
+}
+
+/// Bracket and quote balance, skipping string literals and line/block comments.
+fn check_delimiters(content: &str, quotes: &[char], line_comment: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut stack = Vec::new();
+    let mut chars = content.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if content[i..].starts_with(line_comment) {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else if content[i..].starts_with("/*") {
+            chars.next();
+            let mut closed = false;
+            while let Some((j, _)) = chars.next() {
+                if content[j..].starts_with("*/") {
+                    chars.next();
+                    closed = true;
+                    break;
+                }
+            }
+            if !closed {
+                problems.push(format!("unterminated block comment starting at byte {}", i));
+            }
+        } else if quotes.contains(&c) {
+            let mut closed = false;
+            while let Some((_, d)) = chars.next() {
+                if d == '\\' {
+                    chars.next();
+                } else if d == c {
+                    closed = true;
+                    break;
+                }
+            }
+            if !closed {
+                problems.push(format!("unterminated {} string starting at byte {}", c, i));
+            }
+        } else if matches!(c, '(' | '[' | '{') {
+            stack.push((c, i));
+        } else if let Some(open) = match c {
+            ')' => Some('('),
+            ']' => Some('['),
+            '}' => Some('{'),
+            _ => None,
+        } {
+            match stack.pop() {
+                Some((o, _)) if o == open => {}
+                Some((o, at)) => problems.push(format!(
+                    "'{}' at byte {} does not match '{}' at byte {}",
+                    c, i, o, at
+                )),
+                None => problems.push(format!("unmatched '{}' at byte {}", c, i)),
+            }
+        }
+    }
+
+    problems.extend(
+        stack
+            .into_iter()
+            .map(|(o, at)| format!("unclosed '{}' at byte {}", o, at)),
+    );
+    problems
+}
+
+fn check_html(content: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut stack: Vec<String> = Vec::new();
+
+    for caps in HTML_TAG_RE.captures_iter(content) {
+        let name = caps[2].to_ascii_lowercase();
+        let closing = !caps[1].is_empty();
+        let self_closing = !caps[3].is_empty();
+
+        if closing {
+            match stack.iter().rposition(|open| *open == name) {
+                Some(pos) => {
+                    for unclosed in stack.drain(pos..).skip(1) {
+                        problems.push(format!("<{}> is not closed before </{}>", unclosed, name));
+                    }
+                }
+                None => problems.push(format!("</{}> has no matching start tag", name)),
+            }
+        } else if !self_closing && !HTML_VOID.contains(&name.as_str()) {
+            stack.push(name);
+        }
+    }
+
+    problems.extend(stack.into_iter().map(|open| format!("<{}> is never closed", open)));
+    problems
+}
@@ -0,0 +1,249 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+
+use crate::{
+    console::{GREEN, RED, RESET},
+    discover_targets,
+    error::CompileError,
+    options::{lexical, relative, Options},
+    partition_path, render_output, written, Expansion,
+};
+
+/// Unchanged lines shown around each change in `diff`.
+const CONTEXT_LINES: usize = 3;
+
+/// Past this many line pairs between the first and last difference, changed lines are shown
+/// as one removed block followed by one added block instead of being matched up.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// How one line of the preview relates to the compiled output.
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// The output `source` would compile to right now, in full, marking the lines that differ from
+/// the compiled output on disk with `-` (only on disk) and `+` (only in the preview), colored
+/// when `opts.color` is set. Nothing is written. Warnings the expansion logs are returned too,
+/// to be shown separately.
+pub fn preview(source: &Path, opts: &Options) -> Result<(String, Vec<String>)> {
+    let wanted = lexical(source);
+    let (file, out_path) = discover_targets(opts)
+        .into_iter()
+        .find(|(file, _)| lexical(file) == wanted)
+        .ok_or_else(|| {
+            anyhow!("{} is not a base XML under {}", source.display(), opts.base_dir.display())
+        })?;
+
+    let mut exp = Expansion::default();
+    let new = render_output(&file, &out_path, opts, &mut exp)?;
+    let out_path = partition_path(&file, &out_path, &new, opts, &mut exp);
+    let shown = relative(&out_path, &opts.base_dir);
+    let Ok(old) = fs::read_to_string(&out_path) else {
+        let text = format!("# {} is not compiled yet\n{}", shown, new);
+        return Ok((text, exp.warnings));
+    };
+
+    let lines = diff_lines(&old.lines().collect::<Vec<_>>(), &new.lines().collect::<Vec<_>>());
+    let changes = lines.iter().filter(|line| !matches!(line, Line::Same(_))).count();
+    let mut text = match changes {
+        0 => format!("# {} is up to date\n", shown),
+        n => format!("# {} lines differ from {}\n", n, shown),
+    };
+    for line in &lines {
+        push_line(&mut text, line, opts);
+    }
+    Ok((text, exp.warnings))
+}
+
+/// How an output compiled now compares with the file in the output directory.
+enum Outcome {
+    Added(String),
+    Changed(String, String),
+    Unchanged,
+    Failed(CompileError),
+}
+
+/// Compiles every target in memory and compares the results with the output directory: a list
+/// of the outputs that would be added, changed or removed (written by an earlier build but no
+/// longer produced by any target) and the targets that fail, followed by a unified diff of
+/// each. Nothing is written. Also returns how many outputs differ or failed, so that none
+/// means the compiled files are up to date.
+pub fn diff(opts: &Options) -> Result<(String, usize)> {
+    let targets = discover_targets(opts);
+    let compared: Vec<(String, Outcome)> = targets
+        .par_iter()
+        .map(|(file, out_path)| {
+            let mut exp = Expansion::default();
+            let new = match render_output(file, out_path, opts, &mut exp) {
+                Ok(new) => new,
+                Err(err) => return (relative(file, &opts.base_dir), Outcome::Failed(err)),
+            };
+            let out_path = partition_path(file, out_path, &new, opts, &mut exp);
+            let outcome = match fs::read_to_string(&out_path) {
+                Ok(old) if old == new => Outcome::Unchanged,
+                Ok(old) => Outcome::Changed(old, new),
+                Err(_) => Outcome::Added(new),
+            };
+            (relative(&out_path, &opts.base_dir), outcome)
+        })
+        .collect();
+    let produced: BTreeSet<String> = compared.iter().map(|(shown, _)| shown.clone()).collect();
+    let mut removed = Vec::new();
+    for output in written::recorded(opts) {
+        let shown = relative(&output, &opts.base_dir);
+        if !produced.contains(&shown)
+            && let Ok(old) = fs::read_to_string(&output)
+        {
+            removed.push((shown, old));
+        }
+    }
+
+    let mut summary = String::new();
+    let mut diffs = String::new();
+    let mut differing = 0;
+    for (shown, outcome) in &compared {
+        let (status, old, new) = match outcome {
+            Outcome::Unchanged => continue,
+            Outcome::Failed(err) => {
+                summary.push_str(&format!("failed   {}: {}\n", shown, err));
+                differing += 1;
+                continue;
+            }
+            Outcome::Added(new) => ("added  ", None, Some(new)),
+            Outcome::Changed(old, new) => ("changed", Some(old), Some(new)),
+        };
+        summary.push_str(&format!("{}  {}\n", status, shown));
+        diffs.push_str(&unified(shown, old.map(String::as_str), new.map(String::as_str), opts));
+        differing += 1;
+    }
+    for (shown, old) in &removed {
+        summary.push_str(&format!("removed  {}\n", shown));
+        diffs.push_str(&unified(shown, Some(old), None, opts));
+        differing += 1;
+    }
+    if differing == 0 {
+        return Ok((format!("{} outputs are up to date\n", compared.len()), 0));
+    }
+    Ok((format!("{}\n{}", summary, diffs), differing))
+}
+
+/// A unified diff of one output, `None` standing for a file that doesn't exist.
+fn unified(shown: &str, old: Option<&str>, new: Option<&str>, opts: &Options) -> String {
+    let old_lines: Vec<&str> = old.map_or(Vec::new(), |old| old.lines().collect());
+    let new_lines: Vec<&str> = new.map_or(Vec::new(), |new| new.lines().collect());
+    let lines = diff_lines(&old_lines, &new_lines);
+    let name = |exists: bool| match exists {
+        true => shown.to_string(),
+        false => "/dev/null".to_string(),
+    };
+    let mut text = format!("--- {}\n+++ {}\n", name(old.is_some()), name(new.is_some()));
+
+    // Where each line falls in the old and the new file, to number the hunks.
+    let mut positions = Vec::with_capacity(lines.len());
+    let (mut old_at, mut new_at) = (0, 0);
+    for line in &lines {
+        positions.push((old_at, new_at));
+        match line {
+            Line::Same(_) => (old_at, new_at) = (old_at + 1, new_at + 1),
+            Line::Removed(_) => old_at += 1,
+            Line::Added(_) => new_at += 1,
+        }
+    }
+    let changed: Vec<usize> = (0..lines.len())
+        .filter(|&i| !matches!(lines[i], Line::Same(_)))
+        .collect();
+    let mut next = 0;
+    while next < changed.len() {
+        // A hunk runs until two changes are more than twice the context apart.
+        let start = changed[next].saturating_sub(CONTEXT_LINES);
+        let mut last = changed[next];
+        next += 1;
+        while next < changed.len() && changed[next] - last <= 2 * CONTEXT_LINES {
+            last = changed[next];
+            next += 1;
+        }
+        let end = (last + 1 + CONTEXT_LINES).min(lines.len());
+        let hunk = &lines[start..end];
+        let old_count = hunk.iter().filter(|line| !matches!(line, Line::Added(_))).count();
+        let new_count = hunk.iter().filter(|line| !matches!(line, Line::Removed(_))).count();
+        let (old_start, new_start) = positions[start];
+        // An empty side is numbered by the line before it, as `diff -u` does.
+        let first = |at: usize, count: usize| if count == 0 { at } else { at + 1 };
+        text.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            first(old_start, old_count),
+            old_count,
+            first(new_start, new_count),
+            new_count
+        ));
+        for line in hunk {
+            push_line(&mut text, line, opts);
+        }
+    }
+    text
+}
+
+/// Appends `line` with its ` `, `-` or `+` mark, colored when `opts.color` is set.
+fn push_line(text: &mut String, line: &Line, opts: &Options) {
+    let (marker, color, content) = match line {
+        Line::Same(content) => (' ', "", content),
+        Line::Removed(content) => ('-', RED, content),
+        Line::Added(content) => ('+', GREEN, content),
+    };
+    match opts.color && !color.is_empty() {
+        true => text.push_str(&format!("{}{}{}{}\n", color, marker, content, RESET)),
+        false => text.push_str(&format!("{}{}\n", marker, content)),
+    }
+}
+
+/// `old` turned into `new` line by line, through a longest common subsequence of the part
+/// between their common start and end.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    let head = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let tail = old[head..]
+        .iter()
+        .rev()
+        .zip(new[head..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_mid, new_mid) = (&old[head..old.len() - tail], &new[head..new.len() - tail]);
+
+    let mut lines: Vec<Line> = old[..head].iter().map(|line| Line::Same(line)).collect();
+    if old_mid.len().saturating_mul(new_mid.len()) > MAX_DIFF_CELLS {
+        lines.extend(old_mid.iter().map(|line| Line::Removed(line)));
+        lines.extend(new_mid.iter().map(|line| Line::Added(line)));
+    } else {
+        // lengths[i][j]: the longest common subsequence of old_mid[i..] and new_mid[j..].
+        let width = new_mid.len() + 1;
+        let mut lengths = vec![0u32; (old_mid.len() + 1) * width];
+        for i in (0..old_mid.len()).rev() {
+            for j in (0..new_mid.len()).rev() {
+                lengths[i * width + j] = match old_mid[i] == new_mid[j] {
+                    true => lengths[(i + 1) * width + j + 1] + 1,
+                    false => lengths[(i + 1) * width + j].max(lengths[i * width + j + 1]),
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old_mid.len() || j < new_mid.len() {
+            if i < old_mid.len() && j < new_mid.len() && old_mid[i] == new_mid[j] {
+                lines.push(Line::Same(old_mid[i]));
+                (i, j) = (i + 1, j + 1);
+            } else if j == new_mid.len()
+                || (i < old_mid.len() && lengths[(i + 1) * width + j] >= lengths[i * width + j + 1])
+            {
+                lines.push(Line::Removed(old_mid[i]));
+                i += 1;
+            } else {
+                lines.push(Line::Added(new_mid[j]));
+                j += 1;
+            }
+        }
+    }
+    lines.extend(old[old.len() - tail..].iter().map(|line| Line::Same(line)));
+    lines
+}
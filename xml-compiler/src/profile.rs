@@ -0,0 +1,91 @@
+use std::{borrow::Cow, path::Path};
+
+use once_cell::sync::Lazy;
+use quick_xml::{events::Event, Reader};
+use regex::Regex;
+
+use crate::{blank_line_end, blank_line_start, error::CompileError};
+
+/// Attribute limiting an element to some profiles.
+const ONLY_ATTR: &str = "kvy:only";
+
+static ONLY_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"\s+kvy:only\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+/// Whether a `kvy:only` value, a list of profile names separated by commas or spaces, admits
+/// `profile`. Without a profile every element is kept.
+fn admits(only: &str, profile: Option<&str>) -> bool {
+    profile.is_none_or(|profile| {
+        only.split([',', ' ']).any(|name| name.trim() == profile)
+    })
+}
+
+/// Removes elements whose `kvy:only` doesn't name `profile`, along with their content, and
+/// strips the attribute from the rest. An element alone on its line takes the line with it.
+pub fn apply<'a>(
+    content: &'a str,
+    path: &Path,
+    profile: Option<&str>,
+) -> Result<Cow<'a, str>, CompileError> {
+    if !content.contains(ONLY_ATTR) {
+        return Ok(Cow::Borrowed(content));
+    }
+
+    let mut reader = Reader::from_str(content);
+    let mut out = String::with_capacity(content.len());
+    // Where the element being dropped starts, and how many of its descendants are still open.
+    let mut dropping: Option<(usize, usize)> = None;
+    let mut copied = 0;
+
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event().map_err(|err| CompileError::Malformed {
+            path: path.to_path_buf(),
+            position: reader.error_position(),
+            message: err.to_string(),
+        })?;
+        let end = reader.buffer_position() as usize;
+
+        match (&event, &mut dropping) {
+            (Event::Eof, _) => break,
+            (Event::Start(_), Some((_, depth))) => *depth += 1,
+            (Event::End(_), Some((from, 0))) => {
+                let from = *from;
+                copied = drop_range(content, &mut out, copied, from, end);
+                dropping = None;
+            }
+            (Event::End(_), Some((_, depth))) => *depth -= 1,
+            (Event::Start(_) | Event::Empty(_), None) => {
+                let tag = &content[start..end];
+                let Some(caps) = ONLY_RE.captures(tag) else {
+                    continue;
+                };
+                let only = caps.get(1).or(caps.get(2)).map_or("", |value| value.as_str());
+                if admits(only, profile) {
+                    let attr = caps.get(0).map_or(0..0, |attr| attr.range());
+                    out.push_str(&content[copied..start + attr.start]);
+                    copied = start + attr.end;
+                } else if matches!(event, Event::Empty(_)) {
+                    copied = drop_range(content, &mut out, copied, start, end);
+                } else {
+                    dropping = Some((start, 0));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    out.push_str(&content[copied..]);
+    Ok(Cow::Owned(out))
+}
+
+/// Copies what precedes `start` and skips `start..end`, widened to whole lines when nothing
+/// else shares them. Returns where copying resumes.
+fn drop_range(content: &str, out: &mut String, copied: usize, start: usize, end: usize) -> usize {
+    let (start, end) = match (blank_line_start(content, start), blank_line_end(content, end)) {
+        (Some(line_start), Some(line_end)) if line_start >= copied => (line_start, line_end),
+        _ => (start, end),
+    };
+    out.push_str(&content[copied..start]);
+    end
+}
@@ -0,0 +1,67 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+
+use crate::{
+    compile_target, console, discover_targets, log_message, log_section,
+    options::{Options, Verbosity},
+    report::{self, shown},
+    written, Expansion,
+};
+
+/// Recompiles only the targets that `--json-report` file `report_path` records as failed,
+/// printing everything each one logs, for triage after a large build. The report's own error
+/// is logged first for comparison. Sources that are no longer targets are listed and skipped.
+/// Fails if any replayed target still fails or has warnings.
+pub fn replay(report_path: &Path, mut opts: Options) -> Result<()> {
+    let failed = report::failed_sources(report_path)?;
+    log_section(&format!(
+        "Replaying {} failed targets from {} (build {})",
+        failed.len(),
+        report_path.display(),
+        opts.build_id
+    ));
+    if failed.is_empty() {
+        println!("No failed targets in {}", report_path.display());
+        return Ok(());
+    }
+    opts.verbosity = Verbosity::Verbose;
+    let opts = &opts;
+    let targets: Vec<(PathBuf, PathBuf, &String)> = discover_targets(opts)
+        .into_iter()
+        .filter_map(|(source, out_path)| {
+            let error = failed.get(&shown(&source, &opts.base_dir))?;
+            Some((source, out_path, error))
+        })
+        .collect();
+    for source in failed.keys() {
+        if !targets.iter().any(|(target, _, _)| shown(target, &opts.base_dir) == *source) {
+            println!("{} is no longer a target; skipped", source);
+            log_message(&format!("Not replayed {}: no longer a target", source));
+        }
+    }
+
+    let still_failing: usize = targets
+        .par_iter()
+        .map(|(source, out_path, reported)| {
+            let ((exp, result), entries) = console::capture(|| {
+                log_message(&format!("Reported error: {}", reported));
+                let mut exp = Expansion::default();
+                let result = compile_target(source, out_path, opts, &mut exp);
+                (exp, result)
+            });
+            let status = match &result {
+                Ok(_) => console::Status::Compiled,
+                Err(err) => console::Status::Failed(err),
+            };
+            console::flush(source, status, &exp.warnings, &entries, opts);
+            usize::from(result.is_err() || !exp.warnings.is_empty())
+        })
+        .sum();
+    written::save(opts)?;
+    if still_failing > 0 {
+        return Err(anyhow!("{} of {} replayed targets still fail", still_failing, targets.len()));
+    }
+    Ok(())
+}
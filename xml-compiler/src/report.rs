@@ -0,0 +1,380 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use serde_json::{json, Value};
+
+use crate::{options::Options, shadowed_json, TargetResult};
+
+/// File name of the report inside the output directory. It doesn't match
+/// `DEFAULT_TARGET_PATTERN`, so it is never picked up as a base XML.
+pub(crate) const REPORT_NAME: &str = "report.html";
+/// Where `--report json` writes the JSON summary, inside the output directory.
+pub(crate) const JSON_REPORT_NAME: &str = "report.json";
+
+const STYLE: &str = "
+body { font: 14px/1.4 sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; width: 100%; }
+th, td { text-align: left; padding: .3em .6em; vertical-align: top; }
+td { border-top: 1px solid #ddd; }
+.summary span { display: inline-block; margin-right: 1.5em; font-weight: bold; }
+.ok { color: #2a7a2a; } .warnings { color: #a66a00; } .failed { color: #b22222; }
+details { margin: .2em 0; } summary { cursor: pointer; }
+pre { white-space: pre-wrap; margin: .3em 0; }
+ul.tree { margin: .2em 0; padding-left: 1.2em; }
+";
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Path relative to `base`, or as given when it lies elsewhere.
+pub(crate) fn shown(path: &Path, base: &Path) -> String {
+    path.strip_prefix(base).unwrap_or(path).display().to_string()
+}
+
+/// Writes a self-contained HTML page (no scripts, no external assets) summarising the run:
+/// totals, one row per target with its status, and collapsible error, warning and include
+/// tree details.
+pub(crate) fn write_html(
+    results: &[TargetResult],
+    opts: &Options,
+    elapsed: Duration,
+) -> Result<PathBuf> {
+    let mut results: Vec<&TargetResult> = results.iter().collect();
+    results.sort_by(|a, b| a.source.cmp(&b.source));
+
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    let warned = results
+        .iter()
+        .filter(|r| r.error.is_none() && !r.expansion.warnings.is_empty())
+        .count();
+    let suspect = results.iter().filter(|r| r.expansion.suspect).count();
+    let poisoned: BTreeSet<&PathBuf> =
+        results.iter().flat_map(|r| r.expansion.poisoned.keys()).collect();
+
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>KiwiXML compile report</title>\n");
+    let _ = writeln!(html, "<style>{}</style>\n</head>\n<body>", STYLE);
+    html.push_str("<h1>KiwiXML compile report</h1>\n");
+    let _ = writeln!(
+        html,
+        "<p>{} &middot; {} &middot; build {} &middot; {:.2}s</p>",
+        escape(&opts.base_dir.display().to_string()),
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        escape(&opts.build_id),
+        elapsed.as_secs_f64()
+    );
+    let _ = writeln!(
+        html,
+        "<p class=\"summary\"><span>{} targets</span><span class=\"ok\">{} ok</span>\
+         <span class=\"warnings\">{} with warnings</span><span class=\"failed\">{} failed</span>\
+         <span>{} suspect</span><span class=\"failed\">{} poisoned files</span></p>",
+        results.len(),
+        results.len() - failed - warned,
+        warned,
+        failed,
+        suspect,
+        poisoned.len()
+    );
+
+    html.push_str("<table>\n<tr><th>Status</th><th>Source</th><th>Output</th><th>Details</th>");
+    html.push_str("</tr>\n");
+    for result in results {
+        let (class, status) = match (&result.error, result.expansion.warnings.is_empty()) {
+            (Some(_), _) => ("failed", "failed"),
+            (None, false) => ("warnings", "warnings"),
+            (None, true) => ("ok", "ok"),
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td class=\"{}\">{}{}</td><td>{}</td><td>{}</td><td>",
+            class,
+            status,
+            if result.expansion.suspect { " (suspect)" } else { "" },
+            escape(&shown(&result.source, &opts.base_dir)),
+            escape(&shown(&result.output, &opts.base_dir))
+        );
+        if let Some(err) = &result.error {
+            let _ = writeln!(
+                html,
+                "<details open><summary>Error ({})</summary><pre>{}</pre></details>",
+                err.kind(),
+                escape(&err.to_string())
+            );
+        }
+        let poisoned: Vec<String> = result
+            .expansion
+            .poisoned
+            .iter()
+            .map(|(path, error)| format!("{}: {}", shown(path, &opts.base_dir), error))
+            .collect();
+        list(&mut html, "Poisoned files", &poisoned);
+        list(&mut html, "Warnings", &result.expansion.warnings);
+        list(&mut html, "IO retries", &result.expansion.retries);
+        let shadowed: Vec<String> = result
+            .expansion
+            .shadowed_once()
+            .into_iter()
+            .map(|(used, shadowed)| {
+                let shadowed: Vec<String> =
+                    shadowed.iter().map(|path| shown(path, &opts.base_dir)).collect();
+                format!("{} overrides {}", shown(used, &opts.base_dir), shadowed.join(", "))
+            })
+            .collect();
+        list(&mut html, "Shadowed includes", &shadowed);
+        let deprecations: Vec<String> = result.expansion.deprecations.values().cloned().collect();
+        list(&mut html, "Deprecated includes", &deprecations);
+        if !result.expansion.edges.is_empty() {
+            let _ = write!(
+                html,
+                "<details><summary>Includes ({})</summary>",
+                result.expansion.includes.len()
+            );
+            let children = children(&result.expansion.edges);
+            let mut ancestors = vec![result.source.as_path()];
+            tree(&mut html, &children, &mut ancestors, &opts.base_dir);
+            html.push_str("</details>\n");
+        }
+        html.push_str("</td></tr>\n");
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    let path = opts.output_dir.join(REPORT_NAME);
+    fs::create_dir_all(&opts.output_dir)?;
+    fs::write(&path, html)?;
+    Ok(path)
+}
+
+fn list(html: &mut String, title: &str, items: &[String]) {
+    if items.is_empty() {
+        return;
+    }
+    let _ = write!(html, "<details><summary>{} ({})</summary>", title, items.len());
+    for item in items {
+        let _ = write!(html, "<pre>{}</pre>", escape(item));
+    }
+    html.push_str("</details>\n");
+}
+
+/// Distinct files each file includes, in first-seen order per parent.
+fn children(edges: &[(PathBuf, PathBuf)]) -> BTreeMap<&Path, Vec<&Path>> {
+    let mut seen = BTreeSet::new();
+    let mut children: BTreeMap<&Path, Vec<&Path>> = BTreeMap::new();
+    for (parent, child) in edges {
+        if seen.insert((parent, child)) {
+            children.entry(parent.as_path()).or_default().push(child.as_path());
+        }
+    }
+    children
+}
+
+/// Nested list of what the last of `ancestors` includes. A file already on the path is shown
+/// but not descended into, so a cycle can't recurse forever.
+fn tree<'a>(
+    html: &mut String,
+    children: &BTreeMap<&'a Path, Vec<&'a Path>>,
+    ancestors: &mut Vec<&'a Path>,
+    base: &Path,
+) {
+    let Some(kids) = ancestors.last().and_then(|parent| children.get(parent)) else {
+        return;
+    };
+    html.push_str("<ul class=\"tree\">");
+    for &kid in kids {
+        let _ = write!(html, "<li>{}", escape(&shown(kid, base)));
+        if ancestors.contains(&kid) {
+            html.push_str(" (cycle)");
+        } else {
+            ancestors.push(kid);
+            tree(html, children, ancestors, base);
+            ancestors.pop();
+        }
+        html.push_str("</li>");
+    }
+    html.push_str("</ul>");
+}
+
+/// Writes a JSON summary of the run: one entry per target with its status, includes (and
+/// those missing), warnings, output size and hash, and how long it took. Paths are relative to
+/// `base_dir`, so reports from different checkouts can be compared with `report compare`.
+pub(crate) fn write_json(path: &Path, results: &[TargetResult], opts: &Options) -> Result<()> {
+    let mut results: Vec<&TargetResult> = results.iter().collect();
+    results.sort_by(|a, b| a.source.cmp(&b.source));
+
+    let targets: Vec<Value> = results
+        .iter()
+        .map(|result| {
+            let includes: Vec<String> = result
+                .expansion
+                .includes
+                .iter()
+                .map(|include| shown(include, &opts.base_dir))
+                .collect();
+            let missing: Vec<String> = result
+                .expansion
+                .missing
+                .iter()
+                .map(|include| shown(include, &opts.base_dir))
+                .collect();
+            let poisoned: BTreeMap<String, &String> = result
+                .expansion
+                .poisoned
+                .iter()
+                .map(|(path, error)| (shown(path, &opts.base_dir), error))
+                .collect();
+            let bytes = result
+                .digest
+                .as_ref()
+                .and_then(|_| fs::metadata(&result.output).ok())
+                .map(|meta| meta.len());
+            json!({
+                "source": shown(&result.source, &opts.base_dir),
+                "output": shown(&result.output, &opts.base_dir),
+                "status": if result.error.is_none() { "ok" } else { "failed" },
+                "error": result.error.as_ref().map(|err| err.to_string()),
+                "error_kind": result.error.as_ref().map(|err| err.kind()),
+                "includes": includes,
+                "missing_includes": missing,
+                "warnings": result.expansion.warnings,
+                "shadowed": shadowed_json(&result.expansion),
+                "deprecations": result.expansion.deprecations.values().collect::<Vec<_>>(),
+                "poisoned": poisoned,
+                "bytes": bytes,
+                "sha256": result.digest,
+                "duration_ms": result.duration.as_millis() as u64,
+            })
+        })
+        .collect();
+    let report = json!({
+        "generated": Local::now().to_rfc3339(),
+        "build_id": opts.build_id,
+        "targets": targets,
+    });
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&report)? + "\n")?;
+    Ok(())
+}
+
+/// Sources of the targets a `--json-report` file records as failed, with their errors.
+pub(crate) fn failed_sources(path: &Path) -> Result<BTreeMap<String, String>> {
+    Ok(load(path)?
+        .into_iter()
+        .filter(|(_, target)| target["status"] != "ok")
+        .map(|(source, target)| {
+            let error = target["error"].as_str().unwrap_or("failed").to_string();
+            (source, error)
+        })
+        .collect())
+}
+
+/// Targets of a `--json-report` file, keyed by source.
+fn load(path: &Path) -> Result<BTreeMap<String, Value>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read report {}", path.display()))?;
+    let report: Value = serde_json::from_str(&text)
+        .with_context(|| format!("{} is not a KiwiXML JSON report", path.display()))?;
+    let targets = report["targets"]
+        .as_array()
+        .ok_or_else(|| anyhow!("{} has no targets list", path.display()))?;
+    Ok(targets
+        .iter()
+        .filter_map(|target| Some((target["source"].as_str()?.to_string(), target.clone())))
+        .collect())
+}
+
+fn strings(target: &Value, key: &str) -> BTreeSet<String> {
+    target[key]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_str().map(str::to_string))
+        .collect()
+}
+
+/// Compares two `--json-report` files and returns Markdown for release notes: targets that
+/// started or stopped failing, includes that resolve now and didn't before, targets that came
+/// or went, and how the size of each output changed.
+pub fn compare(old_path: &Path, new_path: &Path) -> Result<String> {
+    let old = load(old_path)?;
+    let new = load(new_path)?;
+    let failed = |target: &Value| target["status"] != "ok";
+
+    let mut newly_failing = Vec::new();
+    let mut fixed = Vec::new();
+    let mut resolved = Vec::new();
+    let mut sizes = Vec::new();
+    for (source, after) in &new {
+        let Some(before) = old.get(source) else {
+            continue;
+        };
+        if failed(after) && !failed(before) {
+            let error = after["error"].as_str().unwrap_or("failed");
+            newly_failing.push(format!("- `{}`: {}", source, error));
+        } else if !failed(after) && failed(before) {
+            fixed.push(format!("- `{}`", source));
+        }
+
+        let was_included = strings(before, "includes");
+        for include in strings(after, "includes").difference(&was_included) {
+            resolved.push(format!("- `{}` now includes `{}`", source, include));
+        }
+
+        if let (Some(was), Some(now)) = (before["bytes"].as_i64(), after["bytes"].as_i64())
+            && was != now
+        {
+            let output = after["output"].as_str().unwrap_or(source);
+            sizes.push(format!("| `{}` | {} | {} | {:+} |", output, was, now, now - was));
+        }
+    }
+    let added: Vec<String> = new
+        .keys()
+        .filter(|source| !old.contains_key(*source))
+        .map(|source| format!("- `{}`", source))
+        .collect();
+    let removed: Vec<String> = old
+        .keys()
+        .filter(|source| !new.contains_key(*source))
+        .map(|source| format!("- `{}`", source))
+        .collect();
+
+    let mut notes = String::new();
+    let mut section = |title: &str, header: Option<&str>, lines: &[String]| {
+        if lines.is_empty() {
+            return;
+        }
+        let _ = writeln!(notes, "## {}\n", title);
+        if let Some(header) = header {
+            let _ = writeln!(notes, "{}", header);
+        }
+        for line in lines {
+            let _ = writeln!(notes, "{}", line);
+        }
+        notes.push('\n');
+    };
+    section("Newly failing", None, &newly_failing);
+    section("Fixed", None, &fixed);
+    section("Newly resolved includes", None, &resolved);
+    section("New targets", None, &added);
+    section("Removed targets", None, &removed);
+    let header = "| Output | Old bytes | New bytes | Change |\n|---|---:|---:|---:|";
+    section("Size changes", Some(header), &sizes);
+
+    if notes.is_empty() {
+        notes.push_str("No changes.\n");
+    }
+    Ok(notes)
+}
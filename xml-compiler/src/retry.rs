@@ -0,0 +1,49 @@
+use std::{io, path::Path, thread};
+
+use crate::{log_message, options::Options};
+
+/// Errors worth another attempt: network filesystems report brief outages as EIO, stale
+/// handles or spurious permission failures that clear up on their own.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::StaleNetworkFileHandle
+    ) || (cfg!(unix) && err.raw_os_error() == Some(5))
+}
+
+/// Runs `op`, retrying transient failures up to `--io-retries` times with exponential backoff.
+/// Every retry is logged and appended to `retries` so it ends up in the report.
+pub fn io<T>(
+    opts: &Options,
+    action: &str,
+    path: &Path,
+    retries: &mut Vec<String>,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut delay = opts.io_backoff;
+    for attempt in 1.. {
+        match op() {
+            Err(err) if attempt <= opts.io_retries && is_transient(&err) => {
+                let msg = format!(
+                    "Retrying {} {} after {:?} (attempt {} of {}): {}",
+                    action,
+                    path.display(),
+                    delay,
+                    attempt + 1,
+                    opts.io_retries + 1,
+                    err
+                );
+                log_message(&msg);
+                retries.push(msg);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    unreachable!()
+}
@@ -0,0 +1,585 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader,
+};
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+use crate::{excerpt, LineCounter};
+
+/// An XSD element, named without its namespace prefix. Attribute names and values are kept as
+/// written.
+struct Node {
+    name: String,
+    attrs: BTreeMap<String, String>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.get(name).map(String::as_str)
+    }
+}
+
+/// `xs:string` becomes `string`; names in other schemas' namespaces lose their prefix too.
+fn local(name: &str) -> &str {
+    name.rsplit_once(':').map_or(name, |(_, local)| local)
+}
+
+fn parse(path: &Path) -> Result<Node> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let mut reader = Reader::from_str(&text);
+    let mut stack = vec![Node {
+        name: String::new(),
+        attrs: BTreeMap::new(),
+        children: Vec::new(),
+    }];
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+        match event {
+            Event::Start(ref tag) | Event::Empty(ref tag) => {
+                let mut attrs = BTreeMap::new();
+                for attr in tag.attributes().flatten() {
+                    let value = attr.unescape_value().unwrap_or_default().into_owned();
+                    attrs.insert(String::from_utf8_lossy(attr.key.as_ref()).into_owned(), value);
+                }
+                let node = Node {
+                    name: local(&String::from_utf8_lossy(tag.name().as_ref())).to_string(),
+                    attrs,
+                    children: Vec::new(),
+                };
+                match event {
+                    Event::Start(_) => stack.push(node),
+                    _ => stack.last_mut().unwrap().children.push(node),
+                }
+            }
+            Event::End(_) if stack.len() > 1 => {
+                let node = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(node);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    stack
+        .pop()
+        .and_then(|document| document.children.into_iter().find(|node| node.name == "schema"))
+        .ok_or_else(|| anyhow!("{} has no xs:schema element", path.display()))
+}
+
+/// Top-level declarations of every schema read, by local name.
+#[derive(Default)]
+struct Schema {
+    elements: BTreeMap<String, Node>,
+    types: BTreeMap<String, Node>,
+    groups: BTreeMap<String, Node>,
+    attribute_groups: BTreeMap<String, Node>,
+    attributes: BTreeMap<String, Node>,
+}
+
+impl Schema {
+    /// Reads `path` and every schema it includes, imports or redefines, each once.
+    fn load(&mut self, path: &Path, seen: &mut HashSet<PathBuf>) -> Result<()> {
+        if !seen.insert(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())) {
+            return Ok(());
+        }
+        let schema = parse(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for node in schema.children {
+            if matches!(&*node.name, "include" | "import" | "redefine")
+                && let Some(location) = node.attr("schemaLocation")
+            {
+                // Only local files; remote schemas are left out.
+                if !location.contains("://") {
+                    self.load(&dir.join(location), seen)?;
+                }
+                continue;
+            }
+            let Some(name) = node.attr("name").map(str::to_string) else {
+                continue;
+            };
+            let table = match &*node.name {
+                "element" => &mut self.elements,
+                "complexType" | "simpleType" => &mut self.types,
+                "group" => &mut self.groups,
+                "attributeGroup" => &mut self.attribute_groups,
+                "attribute" => &mut self.attributes,
+                _ => continue,
+            };
+            table.entry(name).or_insert(node);
+        }
+        Ok(())
+    }
+}
+
+/// Content model of one type, as collected from its declaration.
+#[derive(Default)]
+struct Content {
+    attributes: BTreeMap<String, Value>,
+    children: BTreeMap<String, Value>,
+    any: bool,
+    any_attribute: bool,
+}
+
+struct Builder<'a> {
+    schema: &'a Schema,
+    types: BTreeMap<String, Value>,
+}
+
+impl Builder<'_> {
+    /// Type id of an element declaration, building its entry on first use. Anonymous types are
+    /// named after where they are declared (`#Parent/Child`); built-in types have no id.
+    fn element_type(&mut self, element: &Node, context: &str) -> Value {
+        if let Some(reference) = element.attr("ref") {
+            let name = local(reference);
+            return match self.schema.elements.get(name) {
+                Some(global) => self.element_type(global, name),
+                None => Value::Null,
+            };
+        }
+        if let Some(type_name) = element.attr("type") {
+            return self.named_type(local(type_name));
+        }
+        let id = format!("#{}", context);
+        match element
+            .children
+            .iter()
+            .find(|node| node.name == "complexType" || node.name == "simpleType")
+        {
+            Some(node) => {
+                self.build(&id, node, context);
+                Value::String(id)
+            }
+            None => Value::Null,
+        }
+    }
+
+    fn named_type(&mut self, name: &str) -> Value {
+        let schema = self.schema;
+        match schema.types.get(name) {
+            Some(node) => {
+                self.build(name, node, name);
+                Value::String(name.to_string())
+            }
+            None => Value::Null,
+        }
+    }
+
+    fn build(&mut self, id: &str, node: &Node, context: &str) {
+        if self.types.contains_key(id) {
+            return;
+        }
+        // Inserted up front so a recursive content model refers back instead of looping.
+        self.types.insert(id.to_string(), Value::Null);
+        let entry = if node.name == "simpleType" {
+            json!({ "values": enumeration(node) })
+        } else {
+            let mut content = Content::default();
+            self.collect(node, context, &mut content);
+            let mut entry = json!({
+                "attributes": Value::Object(content.attributes.into_iter().collect()),
+                "children": Value::Object(content.children.into_iter().collect()),
+            });
+            if content.any {
+                entry["any"] = Value::Bool(true);
+            }
+            if content.any_attribute {
+                entry["anyAttribute"] = Value::Bool(true);
+            }
+            if let Some(values) = self.simple_content(node) {
+                entry["values"] = values;
+            }
+            entry
+        };
+        self.types.insert(id.to_string(), entry);
+    }
+
+    fn collect(&mut self, node: &Node, context: &str, content: &mut Content) {
+        let schema = self.schema;
+        for child in &node.children {
+            match &*child.name {
+                "sequence" | "choice" | "all" | "complexContent" | "simpleContent" => {
+                    self.collect(child, context, content)
+                }
+                "group" => {
+                    if let Some(group) = child.attr("ref").and_then(|r| schema.groups.get(local(r)))
+                    {
+                        self.collect(group, context, content);
+                    }
+                }
+                "attributeGroup" => {
+                    let reference = child.attr("ref").map(local);
+                    if let Some(group) = reference.and_then(|r| schema.attribute_groups.get(r)) {
+                        self.collect(group, context, content);
+                    }
+                }
+                "extension" => {
+                    // An extension adds to its base; a restriction restates what it keeps.
+                    if let Some(base) = child.attr("base").and_then(|b| schema.types.get(local(b)))
+                    {
+                        self.collect(base, context, content);
+                    }
+                    self.collect(child, context, content);
+                }
+                "restriction" => self.collect(child, context, content),
+                "element" => {
+                    let Some(name) = child.attr("name").or(child.attr("ref")).map(local) else {
+                        continue;
+                    };
+                    let id = self.element_type(child, &format!("{}/{}", context, name));
+                    content.children.insert(name.to_string(), id);
+                }
+                "attribute" => {
+                    if child.attr("use") == Some("prohibited") {
+                        continue;
+                    }
+                    let (name, declaration) = match child.attr("ref") {
+                        // `xml:lang` and friends keep their prefix, as they are written that way.
+                        Some(reference) => (
+                            reference.to_string(),
+                            schema.attributes.get(local(reference)).unwrap_or(child),
+                        ),
+                        None => match child.attr("name") {
+                            Some(name) => (name.to_string(), child),
+                            None => continue,
+                        },
+                    };
+                    let required = child.attr("use") == Some("required");
+                    let mut attribute = json!({ "required": required });
+                    let values = self.attribute_values(declaration);
+                    if !values.is_empty() {
+                        attribute["values"] = json!(values);
+                    }
+                    content.attributes.insert(name, attribute);
+                }
+                "any" => content.any = true,
+                "anyAttribute" => content.any_attribute = true,
+                _ => {}
+            }
+        }
+    }
+
+    /// Enumerated values allowed for an attribute, from its inline or named simple type.
+    fn attribute_values(&self, attribute: &Node) -> Vec<String> {
+        if let Some(inline) = attribute.children.iter().find(|node| node.name == "simpleType") {
+            return enumeration(inline);
+        }
+        attribute
+            .attr("type")
+            .and_then(|name| self.schema.types.get(local(name)))
+            .map(enumeration)
+            .unwrap_or_default()
+    }
+
+    /// Enumerated text values of a complex type with simple content, if it has any.
+    fn simple_content(&self, node: &Node) -> Option<Value> {
+        let simple = node.children.iter().find(|child| child.name == "simpleContent")?;
+        let derivation = simple.children.first()?;
+        let base = derivation.attr("base").and_then(|b| self.schema.types.get(local(b)));
+        let values = match base {
+            Some(base) => enumeration(base),
+            None => enumeration(derivation),
+        };
+        (!values.is_empty()).then(|| json!(values))
+    }
+}
+
+/// `xs:enumeration` values anywhere below `node`.
+fn enumeration(node: &Node) -> Vec<String> {
+    let mut values = Vec::new();
+    for child in &node.children {
+        if child.name == "enumeration" {
+            values.extend(child.attr("value").map(str::to_string));
+        } else {
+            values.extend(enumeration(child));
+        }
+    }
+    values
+}
+
+/// Reads the XSDs and returns completion data for editors: the elements allowed at the top of
+/// a document, each with its type id, and per type the attributes (whether required, and
+/// enumerated values) and child elements it allows. Following type ids from an element path
+/// gives exactly what is allowed in that context.
+pub fn completions(paths: &[PathBuf]) -> Result<Value> {
+    let mut schema = Schema::default();
+    let mut seen = HashSet::new();
+    for path in paths {
+        schema.load(path, &mut seen)?;
+    }
+
+    let mut builder = Builder {
+        schema: &schema,
+        types: BTreeMap::new(),
+    };
+    let mut roots = Map::new();
+    for (name, element) in &schema.elements {
+        roots.insert(name.clone(), builder.element_type(element, name));
+    }
+    Ok(json!({
+        "roots": roots,
+        "types": Value::Object(builder.types.into_iter().collect()),
+    }))
+}
+
+/// XSDs each output is checked against (`--schema`, `--schema-for`), in the model
+/// [`completions`] builds.
+pub struct Schemas {
+    /// Every schema given with `--schema`; an output uses it when its root element is declared.
+    merged: Option<Value>,
+    /// `--schema-for` mappings, tried first, in order: targets whose source path matches use
+    /// only that schema.
+    mapped: Vec<(Regex, Value)>,
+}
+
+impl Schemas {
+    /// Reads the XSDs in each `--schema` folder (or the file itself) and each mapped schema;
+    /// `None` when neither is given.
+    pub fn load(paths: &[PathBuf], mapped: &[(String, PathBuf)]) -> Result<Option<Schemas>> {
+        if paths.is_empty() && mapped.is_empty() {
+            return Ok(None);
+        }
+        let mut files = Vec::new();
+        for path in paths {
+            if !path.is_dir() {
+                files.push(path.clone());
+                continue;
+            }
+            let mut xsds: Vec<PathBuf> = fs::read_dir(path)
+                .with_context(|| format!("Cannot read {}", path.display()))?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|file| file.extension().is_some_and(|ext| ext == "xsd"))
+                .collect();
+            if xsds.is_empty() {
+                return Err(anyhow!("No .xsd files in {}", path.display()));
+            }
+            xsds.sort();
+            files.extend(xsds);
+        }
+        let merged = (!files.is_empty()).then(|| completions(&files)).transpose()?;
+        let mapped = mapped
+            .iter()
+            .map(|(pattern, xsd)| {
+                let re = Regex::new(pattern)
+                    .map_err(|err| anyhow!("Invalid --schema-for pattern {}: {}", pattern, err))?;
+                Ok((re, completions(std::slice::from_ref(xsd))?))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(Schemas { merged, mapped }))
+    }
+
+    /// Identifies what outputs are checked against, for the build cache.
+    pub(crate) fn digest(&self) -> String {
+        let mapped: Vec<(&str, &Value)> =
+            self.mapped.iter().map(|(re, model)| (re.as_str(), model)).collect();
+        crate::sha256_hex(json!([self.merged, mapped]).to_string().as_bytes())
+    }
+
+    /// Violations in `output`, compiled from the base XML at `source` (relative to the base
+    /// directory), as line numbers and messages. `None` when no schema applies: the source
+    /// matches no `--schema-for` pattern and no `--schema` declares the root element. Values
+    /// quoted in messages are cut to `excerpt` bytes.
+    pub(crate) fn validate(
+        &self,
+        source: &str,
+        output: &str,
+        excerpt: usize,
+    ) -> Option<Vec<(usize, String)>> {
+        let (model, mapped) = match self.mapped.iter().find(|(re, _)| re.is_match(source)) {
+            Some((_, model)) => (model, true),
+            None => (self.merged.as_ref()?, false),
+        };
+        let mut validator = Validator {
+            model,
+            lines: LineCounter::new(output),
+            excerpt,
+            violations: Vec::new(),
+        };
+        match validator.check(output, 0, false) {
+            Some(_) if !mapped => None,
+            Some(root) => Some(vec![(1, format!("<{}> is not declared in the schema", root))]),
+            None => Some(validator.violations),
+        }
+    }
+}
+
+/// An element being checked, with its type in the model if the schema says anything about it.
+struct Open<'a> {
+    name: String,
+    kind: Option<&'a Value>,
+    offset: usize,
+    text: String,
+}
+
+/// Checks one output against a schema model. This covers what the model records: which child
+/// elements and attributes are allowed, required attributes, and enumerated values. Order,
+/// occurrence counts and other facets are left to the device's own parser.
+struct Validator<'a> {
+    model: &'a Value,
+    /// Over the whole output.
+    lines: LineCounter<'a>,
+    /// The most bytes of a value quoted in a message.
+    excerpt: usize,
+    violations: Vec<(usize, String)>,
+}
+
+impl<'a> Validator<'a> {
+    fn report(&mut self, offset: usize, message: String) {
+        let line = self.lines.line(offset);
+        self.violations.push((line, message));
+    }
+
+    fn kind(&self, id: &Value) -> Option<&'a Value> {
+        let model = self.model;
+        model["types"].get(id.as_str()?)
+    }
+
+    /// Checks `text`, found at `offset` in the output. A fragment in CDATA is checked from each
+    /// top-level element the schema declares globally, and skipped elsewhere. Returns the root
+    /// element's name when `text` is the whole document and the schema doesn't declare it.
+    fn check(&mut self, text: &str, offset: usize, fragment: bool) -> Option<String> {
+        let model = self.model;
+        let mut reader = Reader::from_str(text);
+        reader.config_mut().check_end_names = false;
+        let mut open: Vec<Open> = Vec::new();
+        loop {
+            let start = offset + reader.buffer_position() as usize;
+            let Ok(event) = reader.read_event() else {
+                break;
+            };
+            match event {
+                Event::Start(ref tag) | Event::Empty(ref tag) => {
+                    let name = local(&String::from_utf8_lossy(tag.name().as_ref())).to_string();
+                    let kind = match open.last() {
+                        None => match model["roots"].get(&name) {
+                            Some(id) => self.kind(id),
+                            None if fragment => None,
+                            None => return Some(name),
+                        },
+                        Some(parent) => self.child(parent, &name, start),
+                    };
+                    if let Some(kind) = kind {
+                        self.attributes(tag, &name, kind, start);
+                    }
+                    let element = Open {
+                        name,
+                        kind,
+                        offset: start,
+                        text: String::new(),
+                    };
+                    match event {
+                        Event::Start(_) => open.push(element),
+                        _ => self.text(&element),
+                    }
+                }
+                Event::End(_) => {
+                    if let Some(element) = open.pop() {
+                        self.text(&element);
+                    }
+                }
+                Event::Text(ref text) => {
+                    if let Some(element) = open.last_mut() {
+                        element.text.push_str(&text.decode().unwrap_or_default());
+                    }
+                }
+                Event::CData(ref data) => {
+                    let inner = String::from_utf8_lossy(data.as_ref());
+                    self.check(&inner, start + "<![CDATA[".len(), true);
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Type of `name` as a child of `parent`, reporting it if `parent` doesn't allow it.
+    fn child(&mut self, parent: &Open<'a>, name: &str, offset: usize) -> Option<&'a Value> {
+        let kind = parent.kind?;
+        if let Some(id) = kind["children"].get(name) {
+            return self.kind(id);
+        }
+        if kind["any"] != Value::Bool(true) {
+            self.report(offset, format!("<{}> is not allowed in <{}>", name, parent.name));
+        }
+        None
+    }
+
+    fn attributes(&mut self, tag: &BytesStart, name: &str, kind: &Value, offset: usize) {
+        let Some(declared) = kind["attributes"].as_object() else {
+            return;
+        };
+        let mut present = HashSet::new();
+        for attr in tag.attributes().flatten() {
+            let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+            if key == "xmlns" || key.starts_with("xmlns:") || key.starts_with("xsi:") {
+                continue;
+            }
+            let Some(attribute) = declared.get(&key).or_else(|| declared.get(local(&key))) else {
+                if kind["anyAttribute"] != Value::Bool(true) {
+                    self.report(offset, format!("attribute {} is not allowed on <{}>", key, name));
+                }
+                continue;
+            };
+            let value = attr.unescape_value().unwrap_or_default();
+            let values: Vec<&str> = attribute["values"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect();
+            if !values.is_empty() && !values.contains(&&*value) {
+                self.report(
+                    offset,
+                    format!(
+                        "{}=\"{}\" on <{}> is not one of {}",
+                        key,
+                        excerpt::truncate(&value, self.excerpt),
+                        name,
+                        values.join(", ")
+                    ),
+                );
+            }
+            present.insert(local(&key).to_string());
+        }
+        for (attribute, declaration) in declared {
+            if declaration["required"] == Value::Bool(true) && !present.contains(local(attribute)) {
+                self.report(offset, format!("<{}> is missing attribute {}", name, attribute));
+            }
+        }
+    }
+
+    /// Reports the text of an element with enumerated values if it isn't one of them.
+    fn text(&mut self, element: &Open) {
+        let values: Vec<&str> = element
+            .kind
+            .and_then(|kind| kind["values"].as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect();
+        let text = element.text.trim();
+        if values.is_empty() || text.is_empty() || values.contains(&text) {
+            return;
+        }
+        self.report(
+            element.offset,
+            format!(
+                "<{}> contains \"{}\", not one of {}",
+                element.name,
+                excerpt::truncate(text, self.excerpt),
+                values.join(", ")
+            ),
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Result};
+use quick_xml::{escape::resolve_predefined_entity, events::Event, Reader};
+use rayon::prelude::*;
+use regex::Regex;
+
+use crate::{
+    discover_targets, excerpt, log_message,
+    options::{relative, Options},
+    render_output, Expansion, LineCounter,
+};
+
+/// What to look for in each output.
+pub enum Query {
+    Regex(Regex),
+    XPath(Vec<Step>),
+}
+
+/// One step of a location path: `name`, `*`, optionally with `[@attr]` or `[@attr='value']`
+/// predicates. `descendant` is set for steps written after `//`.
+pub struct Step {
+    descendant: bool,
+    name: String,
+    predicates: Vec<(String, Option<String>)>,
+}
+
+impl Query {
+    /// Parses the XPath subset `search --xpath` understands: absolute location paths of
+    /// child (`/`) and descendant (`//`) steps, each an element name or `*` with any number of
+    /// attribute predicates.
+    pub fn xpath(expr: &str) -> Result<Query> {
+        Ok(Query::XPath(parse_path(expr)?))
+    }
+}
+
+fn parse_path(expr: &str) -> Result<Vec<Step>> {
+    let invalid = || anyhow!("Unsupported XPath: {} (use paths like //Skill[@id='3'])", expr);
+    let mut steps = Vec::new();
+    let mut rest = expr.trim();
+    while !rest.is_empty() {
+        let descendant = rest.starts_with("//");
+        rest = rest.strip_prefix('/').ok_or_else(invalid)?;
+        rest = rest.strip_prefix('/').unwrap_or(rest);
+        // A step ends at the next `/` outside its predicates.
+        let mut depth = 0;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                match c {
+                    '[' => depth += 1,
+                    ']' => depth -= 1,
+                    _ => {}
+                }
+                c == '/' && depth == 0
+            })
+            .map_or(rest.len(), |(at, _)| at);
+        let (step, after) = rest.split_at(end);
+        steps.push(parse_step(step, descendant).ok_or_else(invalid)?);
+        rest = after;
+    }
+    if steps.is_empty() {
+        return Err(invalid());
+    }
+    Ok(steps)
+}
+
+/// A value `--index` extracts from every output: the text of the elements a location path
+/// selects, or with a final `/@attr` step, that attribute of them.
+pub struct Field {
+    pub expr: String,
+    steps: Vec<Step>,
+    attribute: Option<String>,
+}
+
+impl Field {
+    pub fn parse(expr: &str) -> Result<Field> {
+        let (path, attribute) = match expr.trim().rsplit_once('/') {
+            Some((path, attr)) if attr.starts_with('@') && !path.ends_with('/') => {
+                let attr = &attr[1..];
+                let valid = |c: char| c.is_alphanumeric() || "_-.:".contains(c);
+                if attr.is_empty() || !attr.chars().all(valid) {
+                    return Err(anyhow!("Unsupported XPath: {}", expr));
+                }
+                (path, Some(attr.to_string()))
+            }
+            _ => (expr, None),
+        };
+        Ok(Field {
+            expr: expr.trim().to_string(),
+            steps: parse_path(path)?,
+            attribute,
+        })
+    }
+
+    /// The field's value in each selected element of `text`, in document order and trimmed.
+    /// Element text includes that of child elements, and of CDATA sections.
+    pub fn values(&self, text: &str) -> Vec<String> {
+        let mut values = Vec::new();
+        self.gather(text, &mut Vec::new(), &mut Vec::new(), &mut values);
+        values.iter().map(|value| value.trim().to_string()).collect()
+    }
+
+    /// Like [`select`], but collecting values: `open` holds the depth and value index of each
+    /// selected element still open.
+    fn gather(
+        &self,
+        text: &str,
+        chain: &mut Vec<Element>,
+        open: &mut Vec<(usize, usize)>,
+        values: &mut Vec<String>,
+    ) {
+        let depth = chain.len();
+        let mut reader = Reader::from_str(text);
+        reader.config_mut().check_end_names = false;
+        while let Ok(event) = reader.read_event() {
+            match event {
+                Event::Start(ref tag) | Event::Empty(ref tag) => {
+                    let attrs = tag
+                        .attributes()
+                        .flatten()
+                        .map(|attr| {
+                            let value = attr.unescape_value().unwrap_or_default().into_owned();
+                            (String::from_utf8_lossy(attr.key.as_ref()).into_owned(), value)
+                        })
+                        .collect();
+                    let name = String::from_utf8_lossy(tag.name().as_ref()).into_owned();
+                    chain.push((name, attrs));
+                    if path_matches(&self.steps, chain) {
+                        match &self.attribute {
+                            Some(attr) => {
+                                let (_, attrs) = &chain[chain.len() - 1];
+                                values.extend(attrs.get(attr).cloned());
+                            }
+                            None => {
+                                open.push((chain.len(), values.len()));
+                                values.push(String::new());
+                            }
+                        }
+                    }
+                    if matches!(event, Event::Empty(_)) {
+                        open.retain(|&(level, _)| level < chain.len());
+                        chain.pop();
+                    }
+                }
+                Event::End(_) if chain.len() > depth => {
+                    open.retain(|&(level, _)| level < chain.len());
+                    chain.pop();
+                }
+                Event::Text(ref text) => append(open, values, &text.decode().unwrap_or_default()),
+                Event::GeneralRef(ref reference) => {
+                    let name = reference.decode().unwrap_or_default();
+                    let resolved = match reference.resolve_char_ref() {
+                        Ok(Some(c)) => Some(c.to_string()),
+                        _ => resolve_predefined_entity(&name).map(str::to_string),
+                    };
+                    append(open, values, &resolved.unwrap_or_else(|| format!("&{};", name)));
+                }
+                Event::CData(ref data) => {
+                    let inner = String::from_utf8_lossy(data.as_ref());
+                    // Included fragments are markup; anything else is just text.
+                    match inner.trim_start().starts_with('<') {
+                        true => self.gather(&inner, chain, open, values),
+                        false => append(open, values, &inner),
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        open.retain(|&(level, _)| level <= depth);
+        chain.truncate(depth);
+    }
+}
+
+/// Adds `text` to the value of every selected element still open.
+fn append(open: &[(usize, usize)], values: &mut [String], text: &str) {
+    for &(_, at) in open {
+        values[at].push_str(text);
+    }
+}
+
+fn parse_step(step: &str, descendant: bool) -> Option<Step> {
+    let (name, mut predicates) = step.split_once('[').unwrap_or((step, ""));
+    let valid = |c: char| c.is_alphanumeric() || "_-.:".contains(c);
+    if name.is_empty() || (name != "*" && !name.chars().all(valid)) {
+        return None;
+    }
+    let mut parsed = Vec::new();
+    while !predicates.is_empty() {
+        let (predicate, after) = predicates.split_once(']')?;
+        let attr = predicate.trim().strip_prefix('@')?;
+        parsed.push(match attr.split_once('=') {
+            Some((attr, value)) => (attr.trim().to_string(), Some(unquote(value.trim())?)),
+            None => (attr.trim().to_string(), None),
+        });
+        predicates = match after {
+            "" => "",
+            _ => after.strip_prefix('[')?,
+        };
+    }
+    Some(Step {
+        descendant,
+        name: name.to_string(),
+        predicates: parsed,
+    })
+}
+
+fn unquote(value: &str) -> Option<String> {
+    let inner = ['\'', '"']
+        .iter()
+        .find_map(|&quote| value.strip_prefix(quote)?.strip_suffix(quote))?;
+    Some(inner.to_string())
+}
+
+/// An open element: its name and attributes.
+type Element = (String, BTreeMap<String, String>);
+
+fn step_matches(step: &Step, (name, attrs): &Element) -> bool {
+    (step.name == "*" || step.name == *name)
+        && step.predicates.iter().all(|(attr, value)| match value {
+            Some(value) => attrs.get(attr) == Some(value),
+            None => attrs.contains_key(attr),
+        })
+}
+
+/// Whether `steps` select the last element of `chain`, the open elements from the root down.
+fn path_matches(steps: &[Step], chain: &[Element]) -> bool {
+    match (steps, chain) {
+        ([], []) => true,
+        ([], _) | (_, []) => false,
+        ([step, rest @ ..], _) if step.descendant => (0..chain.len()).any(|skip| {
+            step_matches(step, &chain[skip]) && path_matches(rest, &chain[skip + 1..])
+        }),
+        ([step, rest @ ..], [first, others @ ..]) => {
+            step_matches(step, first) && path_matches(rest, others)
+        }
+    }
+}
+
+/// Byte offsets, into `text`, of the start tags `steps` select. CDATA sections are searched
+/// as markup too, since that is where included fragments end up; ones that don't parse are
+/// skipped.
+fn select(
+    text: &str,
+    offset: usize,
+    steps: &[Step],
+    chain: &mut Vec<Element>,
+    hits: &mut Vec<usize>,
+) {
+    let depth = chain.len();
+    let mut reader = Reader::from_str(text);
+    reader.config_mut().check_end_names = false;
+    loop {
+        let start = reader.buffer_position() as usize;
+        let Ok(event) = reader.read_event() else {
+            break;
+        };
+        match event {
+            Event::Start(ref tag) | Event::Empty(ref tag) => {
+                let attrs = tag
+                    .attributes()
+                    .flatten()
+                    .map(|attr| {
+                        let value = attr.unescape_value().unwrap_or_default().into_owned();
+                        (String::from_utf8_lossy(attr.key.as_ref()).into_owned(), value)
+                    })
+                    .collect();
+                chain.push((String::from_utf8_lossy(tag.name().as_ref()).into_owned(), attrs));
+                if path_matches(steps, chain) {
+                    hits.push(offset + start);
+                }
+                if matches!(event, Event::Empty(_)) {
+                    chain.pop();
+                }
+            }
+            Event::End(_) if chain.len() > depth => {
+                chain.pop();
+            }
+            Event::CData(ref data) => {
+                let inner = String::from_utf8_lossy(data.as_ref());
+                select(&inner, offset + start + "<![CDATA[".len(), steps, chain, hits);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    chain.truncate(depth);
+}
+
+/// One matching line of an output.
+struct Hit {
+    line: usize,
+    text: String,
+    origin: Option<String>,
+}
+
+/// The source `needle` was copied from, with the line it is on there: the first include that
+/// contains it, or else the base XML itself.
+fn origin(needle: &str, file: &Path, exp: &Expansion, opts: &Options) -> Option<String> {
+    let needle = needle.trim();
+    if needle.is_empty() {
+        return None;
+    }
+    exp.includes
+        .iter()
+        .chain([&file.to_path_buf()])
+        .find_map(|source| {
+            let content = &exp.sources.get(source)?.content;
+            let at = content.find(needle)?;
+            let line = LineCounter::new(content).line(at);
+            Some(format!("{}:{}", relative(source, &opts.base_dir), line))
+        })
+}
+
+/// The line `offset` is on, cut to `context` bytes either side of it so a hit in a generated
+/// fragment that is one huge line doesn't print all of it. Returns whether it was cut at
+/// the start and at the end.
+fn line_around(text: &str, offset: usize, context: usize) -> (&str, bool, bool) {
+    let from = excerpt::boundary_after(text, offset.saturating_sub(context)).min(offset);
+    let to = excerpt::boundary_before(text, offset.saturating_add(context).min(text.len()))
+        .max(offset);
+    let start = text[from..offset].rfind('\n').map(|at| from + at + 1);
+    let end = text[offset..to].find('\n').map(|at| offset + at);
+    let (start, end) = (start.unwrap_or(from), end.unwrap_or(to));
+    let cut_start = start == from && from > 0 && !text[..from].ends_with('\n');
+    let cut_end = end == to && to < text.len() && !text[to..].starts_with('\n');
+    (&text[start..end], cut_start, cut_end)
+}
+
+fn search_output(
+    text: &str,
+    query: &Query,
+    file: &Path,
+    exp: &Expansion,
+    opts: &Options,
+) -> Vec<Hit> {
+    // Where each match starts, and the text that identifies it in its source.
+    let matches: Vec<(usize, &str)> = match query {
+        Query::Regex(re) => re.find_iter(text).map(|m| (m.start(), m.as_str())).collect(),
+        Query::XPath(steps) => {
+            let mut hits = Vec::new();
+            select(text, 0, steps, &mut Vec::new(), &mut hits);
+            // The start tag stands for the element.
+            hits.into_iter()
+                .map(|at| {
+                    let end = text[at..].find('>').map_or(text.len(), |end| at + end + 1);
+                    (at, &text[at..end])
+                })
+                .collect()
+        }
+    };
+    let mut hits: Vec<Hit> = Vec::new();
+    let mut lines = LineCounter::new(text);
+    for (at, matched) in matches {
+        let line = lines.line(at);
+        if hits.last().is_some_and(|hit| hit.line == line) {
+            continue;
+        }
+        let (line_text, cut_start, cut_end) = line_around(text, at, opts.excerpt_length / 2);
+        let shown = format!(
+            "{}{}{}",
+            if cut_start { "…" } else { "" },
+            line_text.trim(),
+            if cut_end { "…" } else { "" }
+        );
+        hits.push(Hit {
+            line,
+            text: shown,
+            origin: origin(line_text, file, exp, opts)
+                .or_else(|| origin(matched, file, exp, opts)),
+        });
+    }
+    hits
+}
+
+/// Compiles every target in memory, exactly as it would be written, and returns the lines
+/// `query` matches as `output:line: text (from source:line)`, naming the source the line was
+/// copied from where it can be found. Targets that fail to compile are logged and skipped.
+pub fn search(query: &Query, opts: &Options) -> Vec<String> {
+    let results: Vec<Vec<String>> = discover_targets(opts)
+        .par_iter()
+        .map(|(file, out_path)| {
+            let mut exp = Expansion::default();
+            let text = match render_output(file, out_path, opts, &mut exp) {
+                Ok(text) => text,
+                Err(err) => {
+                    log_message(&format!("Not searched {}: {}", file.display(), err));
+                    return Vec::new();
+                }
+            };
+            let output = relative(out_path, &opts.base_dir);
+            search_output(&text, query, file, &exp, opts)
+                .into_iter()
+                .map(|hit| {
+                    let origin = hit.origin.map(|origin| format!("  (from {})", origin));
+                    format!("{}:{}: {}{}", output, hit.line, hit.text, origin.unwrap_or_default())
+                })
+                .collect()
+        })
+        .collect();
+    results.concat()
+}
@@ -0,0 +1,464 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+
+use crate::{
+    auth::{Scope, Tokens},
+    discover_targets, encode_output, log_message,
+    options::{relative, Options},
+    render_output, sha256_hex, watch, Expansion,
+};
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:47814";
+
+/// Path the configured stylesheet is served under.
+const XSLT_PATH: &str = "/_preview.xsl";
+
+/// Path of the event stream announcing rebuilt outputs, with `--watch`.
+const EVENTS_PATH: &str = "/_events";
+
+/// How long an event stream may stay silent before a comment is sent, so a client that went
+/// away is noticed and proxies don't time the stream out.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Connections handled at once. Further ones wait to be accepted until a worker is free.
+const WORKERS: usize = 8;
+
+/// Clients that may follow the event stream at once. Each holds a thread of its own for as
+/// long as it stays, so it doesn't keep a worker from other requests.
+const MAX_EVENT_STREAMS: usize = 64;
+
+/// How long a client may take to send its request before the connection is dropped, so slow
+/// ones can't hold every worker.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Preview {
+    opts: Options,
+    xslt: Option<PathBuf>,
+    audit: Option<Mutex<File>>,
+    tokens: Option<Tokens>,
+    /// Clients following `EVENTS_PATH`, or `None` without `--watch`.
+    events: Option<Events>,
+}
+
+/// Every client following the event stream, sent the preview path of each output a watch
+/// rebuild compiled.
+#[derive(Default)]
+struct Events {
+    clients: Mutex<Vec<mpsc::Sender<String>>>,
+    /// Streams open now, at most `MAX_EVENT_STREAMS`.
+    streams: AtomicUsize,
+}
+
+impl Events {
+    /// Takes one of the `MAX_EVENT_STREAMS`, or returns false when none is left.
+    fn reserve(&self) -> bool {
+        let next = |open: usize| (open < MAX_EVENT_STREAMS).then_some(open + 1);
+        self.streams.fetch_update(Ordering::SeqCst, Ordering::SeqCst, next).is_ok()
+    }
+
+    fn release(&self) {
+        self.streams.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn subscribe(&self) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        self.clients.lock().unwrap_or_else(|err| err.into_inner()).push(tx);
+        rx
+    }
+
+    /// Sends `names` to every client, dropping those that disconnected.
+    fn broadcast(&self, names: &[String]) {
+        let mut clients = self.clients.lock().unwrap_or_else(|err| err.into_inner());
+        clients.retain(|client| names.iter().all(|name| client.send(name.clone()).is_ok()));
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+    /// Base XML the body was compiled from, for the audit log.
+    artifact: Option<PathBuf>,
+}
+
+impl Response {
+    fn new(status: &'static str, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            content_type,
+            body: body.into(),
+            artifact: None,
+        }
+    }
+
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", body.into())
+    }
+}
+
+/// Serves every base XML at `/<file name>`, compiled from source on each request so fragment
+/// edits show up on reload, as the build would write it. With a stylesheet, outputs also get
+/// an `<?xml-stylesheet?>` instruction pointing at it and the browser renders the transformed
+/// view; `?raw` skips it.
+/// With `audit_log`, every request is appended to it as one JSON object per line. With
+/// `tokens`, requests need an `Authorization: Bearer` token with the `read` scope.
+/// With `watch`, the targets are also compiled as in watch mode, and `/_events` streams the
+/// preview path of every output each rebuild compiled as Server-Sent Events.
+pub fn serve(
+    addr: &str,
+    opts: Options,
+    xslt: Option<PathBuf>,
+    audit_log: Option<&Path>,
+    tokens: Option<Tokens>,
+    watch: bool,
+) -> Result<()> {
+    if let Some(xslt) = &xslt
+        && !xslt.is_file()
+    {
+        return Err(anyhow!("Stylesheet not found: {}", xslt.display()));
+    }
+    let audit = match audit_log {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open audit log {}", path.display()))?,
+        )),
+        None => None,
+    };
+
+    let listener = TcpListener::bind(addr).with_context(|| format!("Failed to bind {}", addr))?;
+    log_message(&format!("Preview server listening on http://{}/", addr));
+    println!("Preview server listening on http://{}/", addr);
+
+    let preview = Arc::new(Preview {
+        opts,
+        xslt,
+        audit,
+        tokens,
+        events: watch.then(Events::default),
+    });
+    if watch {
+        let preview = Arc::clone(&preview);
+        thread::spawn(move || {
+            let result = watch::watch_with(&preview.opts, |outputs| {
+                let names: Vec<String> = outputs
+                    .iter()
+                    .map(|out_path| relative(out_path, &preview.opts.output_dir))
+                    .collect();
+                if let Some(events) = &preview.events {
+                    events.broadcast(&names);
+                }
+            });
+            if let Err(err) = result {
+                log_message(&format!("Watching stopped: {}", err));
+            }
+        });
+    }
+    let (queue, accepted) = mpsc::sync_channel::<TcpStream>(WORKERS);
+    let accepted = Arc::new(Mutex::new(accepted));
+    for _ in 0..WORKERS {
+        let (preview, accepted) = (Arc::clone(&preview), Arc::clone(&accepted));
+        thread::spawn(move || {
+            loop {
+                let stream = match accepted.lock().unwrap_or_else(|err| err.into_inner()).recv() {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                if let Err(err) = preview.handle(stream) {
+                    log_message(&format!("Preview request failed: {}", err));
+                }
+            }
+        });
+    }
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+                if queue.send(stream).is_err() {
+                    break;
+                }
+            }
+            Err(err) => log_message(&format!("Preview connection error: {}", err)),
+        }
+    }
+    Ok(())
+}
+
+impl Preview {
+    /// Base XMLs by output path relative to the output directory (just the file name unless
+    /// `--preserve-layout` is given), skipping anything under the output directory.
+    fn targets(&self) -> Vec<(String, PathBuf, PathBuf)> {
+        let mut targets: Vec<(String, PathBuf, PathBuf)> = discover_targets(&self.opts)
+            .into_iter()
+            .filter(|(source, _)| !source.starts_with(&self.opts.output_dir))
+            .map(|(source, out_path)| {
+                (relative(&out_path, &self.opts.output_dir), source, out_path)
+            })
+            .collect();
+        targets.sort();
+        targets
+    }
+
+    fn handle(self: &Arc<Self>, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let request = read_request(&mut reader)?;
+
+        let token = request.header("Authorization").and_then(|v| v.strip_prefix("Bearer "));
+        let response = if let Some(tokens) = &self.tokens
+            && !tokens.allows(token, Scope::Read)
+        {
+            Response::text("401 Unauthorized", "A read token is required")
+        } else if request.method != "GET" {
+            Response::text("405 Method Not Allowed", "Only GET is supported")
+        } else if request.path == EVENTS_PATH
+            && let Some(events) = &self.events
+        {
+            if events.reserve() {
+                let preview = Arc::clone(self);
+                thread::spawn(move || {
+                    let Some(events) = &preview.events else {
+                        return;
+                    };
+                    if let Err(err) = preview.stream_events(stream, &request, events) {
+                        log_message(&format!("Event stream ended: {}", err));
+                    }
+                    events.release();
+                });
+                return Ok(());
+            }
+            Response::text("503 Service Unavailable", "Too many clients following the events")
+        } else {
+            self.route(&request)
+        };
+
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\
+             Connection: close\r\n\r\n",
+            response.status,
+            response.content_type,
+            response.body.len()
+        )?;
+        stream.write_all(&response.body)?;
+
+        let client = stream.peer_addr().map_or_else(|_| "unknown".into(), |a| a.to_string());
+        self.audit(&client, &request, &response)
+    }
+
+    /// Sends an event for every output rebuilt from now on, until the client disconnects:
+    /// `event: changed` with the output's preview path, the one it's served at, as data.
+    fn stream_events(
+        &self,
+        mut stream: TcpStream,
+        request: &Request,
+        events: &Events,
+    ) -> Result<()> {
+        let client = stream.peer_addr().map_or_else(|_| "unknown".into(), |a| a.to_string());
+        let response = Response::new("200 OK", "text/event-stream", Vec::new());
+        self.audit(&client, request, &response)?;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nCache-Control: no-store\r\n\r\n",
+            response.status, response.content_type
+        )?;
+        stream.flush()?;
+        let rebuilt = events.subscribe();
+        loop {
+            match rebuilt.recv_timeout(KEEP_ALIVE) {
+                Ok(name) => write!(stream, "event: changed\ndata: {}\n\n", name)?,
+                Err(RecvTimeoutError::Timeout) => stream.write_all(b": keep-alive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            stream.flush()?;
+        }
+    }
+
+    /// Records who fetched what: client address, request, status and the digest of the
+    /// exact bytes served, so an artifact can be traced back to the source it came from.
+    fn audit(&self, client: &str, request: &Request, response: &Response) -> Result<()> {
+        let Some(audit) = &self.audit else {
+            return Ok(());
+        };
+        let record = serde_json::json!({
+            "time": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "client": client,
+            "user_agent": request.header("User-Agent"),
+            "method": request.method,
+            "path": request.path,
+            "query": request.query,
+            "status": response.status.split(' ').next().unwrap_or_default(),
+            "bytes": response.body.len(),
+            "sha256": sha256_hex(&response.body),
+            "artifact": response.artifact.as_ref().map(|path| path.display().to_string()),
+        });
+        let mut file = audit.lock().map_err(|_| anyhow!("Audit log lock poisoned"))?;
+        writeln!(file, "{}", record)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn route(&self, request: &Request) -> Response {
+        match request.path.as_str() {
+            "/" => self.index(),
+            XSLT_PATH => match &self.xslt {
+                Some(xslt) => match fs::read(xslt) {
+                    Ok(body) => Response::new("200 OK", "text/xsl; charset=utf-8", body),
+                    Err(err) => Response::text("500 Internal Server Error", err.to_string()),
+                },
+                None => Response::text("404 Not Found", "No stylesheet configured"),
+            },
+            path => self.target(path.trim_start_matches('/'), request.query == "raw"),
+        }
+    }
+
+    fn index(&self) -> Response {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>KiwiXML preview</title>\
+             </head><body><h1>KiwiXML preview</h1><ul>\n",
+        );
+        for (name, _, _) in self.targets() {
+            let name = escape_html(&name);
+            let _ = writeln!(html, "<li><a href=\"/{0}\">{0}</a></li>", name);
+        }
+        html.push_str("</ul></body></html>\n");
+        Response::new("200 OK", "text/html; charset=utf-8", html)
+    }
+
+    fn target(&self, name: &str, raw: bool) -> Response {
+        let target = self.targets().into_iter().find(|(n, _, _)| n == name);
+        let Some((_, source, out_path)) = target else {
+            return Response::text("404 Not Found", format!("No base XML named {}", name));
+        };
+
+        let mut exp = Expansion::default();
+        let response = match render_output(&source, &out_path, &self.opts, &mut exp) {
+            Ok(output) => {
+                let output = if self.xslt.is_some() && !raw {
+                    with_stylesheet(&output)
+                } else {
+                    output
+                };
+                let body = encode_output(&out_path, &output, &self.opts, &mut exp);
+                // In another encoding, the XML declaration says which.
+                let content_type = match self.opts.output_encoding {
+                    Some(_) => "application/xml",
+                    None => "application/xml; charset=utf-8",
+                };
+                Response::new("200 OK", content_type, body.into_owned())
+            }
+            Err(err) => Response::text(
+                "500 Internal Server Error",
+                format!("Error processing {}: {}", source.display(), err),
+            ),
+        };
+        Response {
+            artifact: Some(source),
+            ..response
+        }
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(anyhow!("Malformed request line: {:?}", line));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: percent_decode(path),
+        query: query.to_string(),
+        headers: Vec::new(),
+    };
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            request.headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok(request)
+}
+
+/// Inserts the stylesheet instruction after the XML declaration, or at the very start.
+fn with_stylesheet(xml: &str) -> String {
+    let pi = format!("<?xml-stylesheet type=\"text/xsl\" href=\"{}\"?>\n", XSLT_PATH);
+    let split = if xml.starts_with("<?xml ") {
+        xml.find("?>").map_or(0, |i| i + 2)
+    } else {
+        0
+    };
+    let (decl, rest) = xml.split_at(split);
+    if split > 0 {
+        format!("{}\n{}{}", decl, pi, rest.trim_start_matches(['\r', '\n']))
+    } else {
+        format!("{}{}", pi, rest)
+    }
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (bytes[i], hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn escape_html(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
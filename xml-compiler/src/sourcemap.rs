@@ -0,0 +1,162 @@
+use std::{
+    borrow::Cow,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::json;
+
+use crate::{
+    options::{relative, Options},
+    Expansion,
+};
+
+/// A mark left in the text while expanding, numbered into `Expansion::marks`. Private-use
+/// characters, like the tokens standing in for raw includes, so no pass mistakes it for markup.
+static MARK_RE: Lazy<Regex> = Lazy::new(|| Regex::new("\u{E002}(\\d+)\u{E003}").unwrap());
+
+/// Which source file and line each line of an output came from.
+#[derive(Debug, Default)]
+pub(crate) struct SourceMap {
+    sources: Vec<PathBuf>,
+    /// By output line, from the first: the index into `sources` and the line there, or `None`
+    /// for lines no source wrote, like a generated header.
+    lines: Vec<Option<(usize, usize)>>,
+}
+
+impl SourceMap {
+    /// The source file and line that output line `line` (1-based) came from.
+    pub(crate) fn locate(&self, line: usize) -> Option<(&Path, usize)> {
+        let (source, source_line) = (*self.lines.get(line.checked_sub(1)?)?)?;
+        Some((&self.sources[source], source_line))
+    }
+}
+
+/// A mark saying that the text from here on is `path` from line `line`, or nothing when this
+/// expansion isn't being mapped.
+pub(crate) fn mark(exp: &mut Expansion, path: &Path, line: usize) -> String {
+    let Some(marks) = &mut exp.marks else {
+        return String::new();
+    };
+    marks.push((path.to_path_buf(), line));
+    format!("\u{E002}{}\u{E003}", marks.len() - 1)
+}
+
+/// `content` of the fragment at `path` with a mark for its first line put after its leading
+/// whitespace, which so stays in one run with any before it for whitespace collapsing.
+pub(crate) fn open(exp: &mut Expansion, path: &Path, content: &str) -> String {
+    let at = content.len() - content.trim_start().len();
+    let mark = mark(exp, path, 1);
+    format!("{}{}{}", &content[..at], mark, &content[at..])
+}
+
+/// `content` included from `path` followed by a mark that what comes after it continues `path`
+/// from `line`, put before its trailing whitespace like `open` does.
+pub(crate) fn close(exp: &mut Expansion, path: &Path, line: usize, content: &str) -> String {
+    let at = content.trim_end().len();
+    let mark = mark(exp, path, line);
+    format!("{}{}{}", &content[..at], mark, &content[at..])
+}
+
+/// `text` without marks, for passes that look at content, such as placeholder checks.
+pub(crate) fn strip(text: &str) -> Cow<'_, str> {
+    MARK_RE.replace_all(text, "")
+}
+
+/// The last mark in `text`, which says where what follows it comes from, or "" if it has none.
+pub(crate) fn last(text: &str) -> &str {
+    MARK_RE.find_iter(text).last().map_or("", |mark| mark.as_str())
+}
+
+/// `output` without its marks, and the map they make up. A line comes from wherever the mark in
+/// effect at its first non-blank character points, counting on from the line the mark was on;
+/// a fragment collapsed onto one line therefore maps to the fragment, at its first line.
+pub(crate) fn resolve(output: &str, marks: &[(PathBuf, usize)]) -> (String, SourceMap) {
+    let mut clean = String::with_capacity(output.len());
+    // Where each mark sits in the clean text, in order.
+    let mut placed: Vec<(usize, usize)> = Vec::new();
+    let mut copied = 0;
+    for caps in MARK_RE.captures_iter(output) {
+        let whole = caps.get(0).unwrap();
+        clean.push_str(&output[copied..whole.start()]);
+        copied = whole.end();
+        if let Some(index) = caps[1].parse().ok().filter(|&index| index < marks.len()) {
+            placed.push((clean.len(), index));
+        }
+    }
+    clean.push_str(&output[copied..]);
+
+    let mut map = SourceMap::default();
+    let mut sources: Vec<&Path> = Vec::new();
+    let mut next = 0;
+    // The mark in effect, and the output line from which it counts.
+    let mut current: Option<(usize, usize)> = None;
+    let mut offset = 0;
+    for (number, line) in clean.split_inclusive('\n').enumerate() {
+        let first = offset + (line.len() - line.trim_start().len());
+        while let Some(&(_, index)) = placed.get(next).filter(|(at, _)| *at <= first) {
+            current = Some((index, number));
+            next += 1;
+        }
+        map.lines.push(current.map(|(index, marked_line)| {
+            let (path, line) = &marks[index];
+            let source = match sources.iter().position(|known| *known == path.as_path()) {
+                Some(source) => source,
+                None => {
+                    sources.push(path);
+                    sources.len() - 1
+                }
+            };
+            (source, line + number - marked_line)
+        }));
+        // A mark past the first non-blank character counts from the next line on, as the line
+        // after it continues its source.
+        let end = offset + line.len();
+        while let Some(&(_, index)) = placed.get(next).filter(|(at, _)| *at < end) {
+            current = Some((index, number));
+            next += 1;
+        }
+        offset = end;
+    }
+    map.sources = sources.into_iter().map(Path::to_path_buf).collect();
+    (clean, map)
+}
+
+/// Writes `<output>.map` next to `out_path`: the sources, relative to the base directory, and
+/// for each run of output lines that continue one source line after another, the first and
+/// last output line, the source and the source line the run starts at.
+pub(crate) fn write(out_path: &Path, map: &SourceMap, opts: &Options) -> Result<()> {
+    let mut ranges: Vec<[usize; 4]> = Vec::new();
+    for (number, origin) in map.lines.iter().enumerate() {
+        let Some((source, line)) = *origin else {
+            continue;
+        };
+        let output_line = number + 1;
+        match ranges.last_mut() {
+            Some(range)
+                if range[1] + 1 == output_line
+                    && range[2] == source
+                    && range[3] + (output_line - range[0]) == line =>
+            {
+                range[1] = output_line;
+            }
+            _ => ranges.push([output_line, output_line, source, line]),
+        }
+    }
+    let sources: Vec<String> =
+        map.sources.iter().map(|source| relative(source, &opts.base_dir)).collect();
+    let file = out_path.file_name().map(|name| name.to_string_lossy().into_owned());
+    let json = json!({
+        "version": 1,
+        "file": file,
+        "sources": sources,
+        "ranges": ranges,
+    });
+    let mut path = out_path.as_os_str().to_owned();
+    path.push(".map");
+    fs::write(path, serde_json::to_string_pretty(&json)? + "\n")?;
+    Ok(())
+}
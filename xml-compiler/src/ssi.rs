@@ -0,0 +1,171 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+use crate::{error::CompileError, log_message, LineCounter, DIRECTIVE_ATTR_RE};
+
+/// Server-side include directives other than `#include`, which KiwiXML handles itself, with
+/// the indentation before and line break after them when they are alone on their line.
+static SSI_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r"(?m)(^[ \t]*)?",
+        r"<!--\s*#(exec|echo|config|set|printenv|flastmod|fsize|if|elif|else|endif)\b",
+        r#"((?:\s+[\w-]+="[^"]*")*)\s*-->([ \t]*\r?\n)?"#
+    ))
+    .unwrap()
+});
+
+pub(crate) fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// One `#if` being read: whether it tests a `flag` (the others are legacy SSI, left for
+/// [`apply`] to strip), whether the branch being read is kept, and whether one was already.
+struct Branch {
+    flag: bool,
+    line: usize,
+    active: bool,
+    taken: bool,
+}
+
+/// Whether a `flag="..."` condition holds: any of its comma-separated names is set, or for
+/// `!name`, is not.
+fn holds(condition: &str, flags: &BTreeSet<String>) -> bool {
+    condition.split(',').map(str::trim).any(|name| match name.strip_prefix('!') {
+        Some(name) => !flags.contains(name),
+        None => flags.contains(name),
+    })
+}
+
+/// Evaluates `#if flag="..."` conditionals against the `--flag`s, keeping the first branch
+/// whose condition holds (or the `#else`) and dropping the others with the directives. A
+/// conditional without a `flag` is legacy SSI and passes through untouched, unless it sits in
+/// a dropped branch. Unbalanced directives fail the file, since guessing which branch was
+/// meant could ship another environment's config.
+pub fn conditionals<'a>(
+    content: &'a str,
+    path: &Path,
+    flags: &BTreeSet<String>,
+) -> Result<Cow<'a, str>, CompileError> {
+    let mut out = String::new();
+    let mut open: Vec<Branch> = Vec::new();
+    let mut copied = 0;
+    let mut lines = LineCounter::new(content);
+    for caps in SSI_RE.captures_iter(content) {
+        let directive = &caps[2];
+        if !matches!(directive, "if" | "elif" | "else" | "endif") {
+            continue;
+        }
+        let whole = caps.get(0).unwrap();
+        let line = lines.line(whole.start());
+        let fail = |reason| CompileError::Conditional {
+            path: path.to_path_buf(),
+            line,
+            reason,
+        };
+        let condition = DIRECTIVE_ATTR_RE
+            .captures_iter(&caps[3])
+            .find(|attr| &attr[1] == "flag")
+            .map(|attr| holds(&attr[2], flags));
+        let kept = open.iter().all(|branch| branch.active);
+        if kept {
+            out.push_str(&content[copied..whole.start()]);
+        }
+        copied = whole.end();
+        let flag = match (directive, open.last_mut()) {
+            ("if", _) => {
+                let active = condition.unwrap_or(true);
+                open.push(Branch {
+                    flag: condition.is_some(),
+                    line,
+                    active,
+                    taken: active,
+                });
+                condition.is_some()
+            }
+            (_, None) => return Err(fail("has no matching #if")),
+            ("elif", Some(branch)) if branch.flag => {
+                let Some(holds) = condition else {
+                    return Err(fail("needs a flag, like the #if it belongs to"));
+                };
+                branch.active = !branch.taken && holds;
+                branch.taken |= branch.active;
+                true
+            }
+            ("else", Some(branch)) if branch.flag => {
+                branch.active = !branch.taken;
+                branch.taken = true;
+                true
+            }
+            ("endif", Some(_)) => open.pop().is_some_and(|branch| branch.flag),
+            (_, Some(_)) => false,
+        };
+        if kept && !flag {
+            out.push_str(whole.as_str());
+        } else if kept && caps.get(1).is_none() {
+            // Not alone on its line, so the line break after it stays.
+            out.push_str(caps.get(4).map_or("", |m| m.as_str()));
+        }
+    }
+    if let Some(branch) = open.first() {
+        return Err(CompileError::Conditional {
+            path: path.to_path_buf(),
+            line: branch.line,
+            reason: "has no matching #endif",
+        });
+    }
+    if copied == 0 {
+        return Ok(Cow::Borrowed(content));
+    }
+    if open.iter().all(|branch| branch.active) {
+        out.push_str(&content[copied..]);
+    }
+    Ok(Cow::Owned(out))
+}
+
+/// Removes legacy SSI directives from `content`. A web server would run an `#exec` left in an
+/// output, so none of them may survive, not even in a base XML where other comments are kept.
+/// `#echo var="NAME"` is replaced with the value `--ssi-var` gives NAME. Everything else is
+/// dropped with a warning, logged only when `log` is set so a file read several times per
+/// target is reported once.
+pub fn apply<'a>(
+    content: &'a str,
+    path: &Path,
+    vars: &BTreeMap<String, String>,
+    log: bool,
+) -> Cow<'a, str> {
+    let mut lines = LineCounter::new(content);
+    SSI_RE.replace_all(content, |caps: &Captures| {
+        let line = lines.line(caps.get(0).map_or(0, |m| m.start()));
+        let var = DIRECTIVE_ATTR_RE
+            .captures_iter(&caps[3])
+            .find(|attr| &attr[1] == "var")
+            .map(|attr| attr[2].to_string());
+        let (replacement, problem) = match (&caps[2], var) {
+            ("echo", Some(var)) => match vars.get(&var) {
+                Some(value) => (escape(value), None),
+                None => (String::new(), Some(format!("#echo of unset variable {}", var))),
+            },
+            (directive, _) => (String::new(), Some(format!("SSI #{} ignored", directive))),
+        };
+        if log && let Some(problem) = problem {
+            log_message(&format!("Warning: {}:{}: {}", path.display(), line, problem));
+        }
+        let indent = caps.get(1).map_or("", |m| m.as_str());
+        let newline = caps.get(4).map_or("", |m| m.as_str());
+        match (replacement.is_empty(), caps.get(1).is_some() && caps.get(4).is_some()) {
+            // Nothing left on the line, so drop the line.
+            (true, true) => String::new(),
+            _ => format!("{}{}{}", indent, replacement, newline),
+        }
+    })
+}
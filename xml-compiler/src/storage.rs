@@ -0,0 +1,291 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use rayon::prelude::*;
+
+use crate::{
+    discover_targets,
+    options::{lexical, relative, Options},
+    sha256_hex, written,
+};
+
+/// How far back a match may reach in the compressed-size estimate; deflate's window.
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 258;
+/// Earlier positions with the same hash looked at for each match, like a fast zlib level.
+const CHAIN: usize = 32;
+/// Rough cost of one deflate match, its length and distance codes with their extra bits, fitted
+/// so that estimates land close to `gzip -6` on XML and text.
+const MATCH_BITS: f64 = 19.0;
+
+/// Content-defined chunks are cut where the rolling hash has these bits clear, about every
+/// 2 KiB, so content shared between outputs is cut the same way wherever it sits.
+const CHUNK_MASK: u64 = (1 << 11) - 1;
+const CHUNK_MIN: usize = 512;
+const CHUNK_MAX: usize = 16 * 1024;
+
+/// Share of the output bytes that must repeat across outputs before compressing them as one
+/// solid archive is recommended over compressing each file on its own.
+const SOLID_SHARE: f64 = 0.2;
+
+/// Random values for the rolling hash that cuts chunks (SplitMix64 from a fixed seed, so cuts
+/// are the same on every run).
+static GEAR: Lazy<[u64; 256]> = Lazy::new(|| {
+    let mut state: u64 = 0x6b76_792d_786d_6c73;
+    std::array::from_fn(|_| {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    })
+});
+
+/// What the storage analysis found for one output.
+struct Output {
+    path: PathBuf,
+    bytes: usize,
+    /// Estimated size compressed on its own.
+    compressed: usize,
+    /// Order-0 entropy, in bits per byte.
+    entropy: f64,
+    /// Bytes in chunks that other outputs contain too.
+    shared: usize,
+}
+
+/// What the outputs add up to, for sizing the artifact store.
+pub struct Storage {
+    /// The report, one line per output followed by the totals and a recommendation.
+    pub text: String,
+    /// Bytes repeated from other outputs, in percent of all output bytes.
+    pub duplication: f64,
+    /// Whether the outputs compress better as one solid archive than file by file.
+    pub solid: bool,
+}
+
+/// Measures the compiled outputs in the output directory, without compiling anything: for each
+/// its size, an estimate of its deflate-compressed size and its byte entropy, then how much of
+/// the content repeats across outputs. Compressed sizes come from a greedy LZ77 pass with
+/// deflate's window and an entropy-coded cost model, not an actual compressor, and are meant
+/// for planning, usually within a few percent of `gzip -6`. Duplication is measured on
+/// content-defined chunks, so a fragment included by many targets counts wherever it lands.
+pub fn analyze(opts: &Options) -> Result<Storage> {
+    let mut paths: BTreeSet<PathBuf> =
+        written::recorded(opts).iter().map(|path| lexical(path)).collect();
+    paths.extend(discover_targets(opts).into_iter().map(|(_, out_path)| lexical(&out_path)));
+    paths.retain(|path| path.is_file());
+    if paths.is_empty() {
+        return Err(anyhow!("No compiled outputs in {}", opts.output_dir.display()));
+    }
+
+    let mut measured: Vec<(Output, Vec<(String, usize)>)> = paths
+        .into_par_iter()
+        .map(|path| {
+            let data = fs::read(&path)?;
+            let chunks = chunks(&data)
+                .into_iter()
+                .map(|chunk| (sha256_hex(chunk), chunk.len()))
+                .collect();
+            let output = Output {
+                bytes: data.len(),
+                compressed: compressed_size(&data),
+                entropy: entropy(&data),
+                shared: 0,
+                path,
+            };
+            Ok((output, chunks))
+        })
+        .collect::<Result<_>>()?;
+
+    // The outputs each distinct chunk appears in.
+    let mut holders: HashMap<String, (usize, BTreeSet<usize>)> = HashMap::new();
+    for (index, (_, chunks)) in measured.iter().enumerate() {
+        for (digest, len) in chunks {
+            holders.entry(digest.clone()).or_insert((*len, BTreeSet::new())).1.insert(index);
+        }
+    }
+    let duplicated: usize =
+        holders.values().map(|(len, outputs)| len * (outputs.len() - 1)).sum();
+    for (output, chunks) in &mut measured {
+        output.shared = chunks
+            .iter()
+            .filter(|(digest, _)| holders[digest].1.len() > 1)
+            .map(|(_, len)| len)
+            .sum();
+    }
+    let outputs: Vec<Output> = measured.into_iter().map(|(output, _)| output).collect();
+
+    let total: usize = outputs.iter().map(|output| output.bytes).sum();
+    let compressed: usize = outputs.iter().map(|output| output.compressed).sum();
+    let share = duplicated as f64 / total.max(1) as f64;
+    // A solid archive stores what repeats across outputs about once.
+    let solid_compressed = (compressed as f64 * (1.0 - share)).round() as usize;
+    let solid = share >= SOLID_SHARE;
+
+    let shown: BTreeMap<String, &Output> = outputs
+        .iter()
+        .map(|output| (relative(&output.path, &opts.base_dir), output))
+        .collect();
+    let width = shown.keys().map(String::len).max().unwrap_or(0).max("total".len());
+    let mut text = format!(
+        "{:width$}  {:>12}  {:>12}  {:>6}  {:>7}  {:>6}\n",
+        "output", "bytes", "compressed", "ratio", "entropy", "shared"
+    );
+    for (name, output) in &shown {
+        text.push_str(&format!(
+            "{:width$}  {:>12}  {:>12}  {:>5.1}x  {:>7.2}  {:>5.0}%\n",
+            name,
+            output.bytes,
+            output.compressed,
+            ratio(output.bytes, output.compressed),
+            output.entropy,
+            percent(output.shared, output.bytes)
+        ));
+    }
+    text.push_str(&format!(
+        "{:width$}  {:>12}  {:>12}  {:>5.1}x\n\n",
+        "total",
+        total,
+        compressed,
+        ratio(total, compressed)
+    ));
+    text.push_str(&format!(
+        "Cross-output duplication: {} of {} bytes ({:.0}%) repeat content of other outputs\n",
+        duplicated,
+        total,
+        share * 100.0
+    ));
+    text.push_str(&format!(
+        "Estimated archive size: {} bytes compressed per file, {} bytes as one solid archive\n",
+        compressed, solid_compressed
+    ));
+    text.push_str(&match solid {
+        true => format!(
+            "Recommended: solid compression ({:.0}% of the content repeats across outputs)\n",
+            share * 100.0
+        ),
+        false => format!(
+            "Recommended: per-file compression (only {:.0}% of the content repeats across \
+             outputs, under {:.0}%)\n",
+            share * 100.0,
+            SOLID_SHARE * 100.0
+        ),
+    });
+    Ok(Storage {
+        text,
+        duplication: share * 100.0,
+        solid,
+    })
+}
+
+fn ratio(bytes: usize, compressed: usize) -> f64 {
+    bytes as f64 / compressed.max(1) as f64
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    part as f64 * 100.0 / whole.max(1) as f64
+}
+
+/// Order-0 entropy of `data` in bits per byte: 8 for random bytes, far less for markup.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0u64; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    coded_bits(&counts) / data.len().max(1) as f64
+}
+
+/// Bits an ideal entropy coder spends on symbols occurring `counts` times.
+fn coded_bits(counts: &[u64]) -> f64 {
+    let total: u64 = counts.iter().sum();
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| count as f64 * (total as f64 / count as f64).log2())
+        .sum()
+}
+
+/// Estimated size of `data` compressed with deflate: a greedy LZ77 parse with hash chains,
+/// literals costed at their entropy and each match at a flat `MATCH_BITS`.
+fn compressed_size(data: &[u8]) -> usize {
+    const HASH_BITS: u32 = 16;
+    let hash = |at: usize| -> usize {
+        let word = u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    };
+    // The latest position with each hash, and before each position the previous one.
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let insert = |at: usize, head: &mut [usize], prev: &mut [usize]| {
+        if at + MIN_MATCH <= data.len() {
+            let slot = hash(at);
+            prev[at] = head[slot];
+            head[slot] = at;
+        }
+    };
+
+    let mut literals = [0u64; 256];
+    let mut matches = 0u64;
+    let mut at = 0;
+    while at < data.len() {
+        let mut best = 0;
+        if at + MIN_MATCH <= data.len() {
+            let limit = MAX_MATCH.min(data.len() - at);
+            let mut candidate = head[hash(at)];
+            for _ in 0..CHAIN {
+                if candidate == usize::MAX || at - candidate > WINDOW {
+                    break;
+                }
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[at..at + limit])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                best = best.max(len);
+                if best == limit {
+                    break;
+                }
+                candidate = prev[candidate];
+            }
+        }
+        if best >= MIN_MATCH {
+            matches += 1;
+            for skipped in at..at + best {
+                insert(skipped, &mut head, &mut prev);
+            }
+            at += best;
+        } else {
+            literals[data[at] as usize] += 1;
+            insert(at, &mut head, &mut prev);
+            at += 1;
+        }
+    }
+    ((coded_bits(&literals) + matches as f64 * MATCH_BITS) / 8.0).ceil() as usize
+}
+
+/// `data` cut into content-defined chunks with a gear rolling hash, so an insertion only
+/// changes the chunks around it.
+fn chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut hash = 0u64;
+    for (at, &byte) in data.iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let len = at + 1 - start;
+        if (len >= CHUNK_MIN && hash & CHUNK_MASK == 0) || len >= CHUNK_MAX {
+            chunks.push(&data[start..=at]);
+            start = at + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+    chunks
+}
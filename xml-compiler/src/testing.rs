@@ -0,0 +1,223 @@
+//! Helpers for property tests of code built on this crate, such as custom include resolvers:
+//! random include trees written to disk, and an oracle checking what a compile of one must
+//! satisfy. Only built with the `testing` feature.
+//!
+//! ```no_run
+//! use kvy_xmls::{testing, Compiler};
+//!
+//! for seed in 0..100 {
+//!     let dir = std::env::temp_dir().join(format!("kvy-xmls-{}", seed));
+//!     let tree = testing::include_tree(&dir, seed, 12)?;
+//!     let compiler = Compiler::builder(&dir).build()?;
+//!     let output = compiler.expand(&tree.target)?;
+//!     if let Err(problems) = testing::check(&tree, &output, compiler.options()) {
+//!         panic!("seed {}: {}", seed, problems.join("; "));
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::options::Options;
+
+/// A small, seedable random number generator (SplitMix64), so a failing case can be replayed
+/// from its seed without another dependency.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`; `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// True with a probability of one in `n`.
+    pub fn one_in(&mut self, n: usize) -> bool {
+        self.below(n) == 0
+    }
+}
+
+/// A generated base XML and the fragments it can reach, as written to disk.
+#[derive(Clone, Debug)]
+pub struct IncludeTree {
+    /// The base directory to compile.
+    pub base_dir: PathBuf,
+    /// The base XML, `A/0_Gen.xml` under the base directory.
+    pub target: PathBuf,
+    /// Every fragment written, whether the target reaches it or not, by index.
+    pub fragments: Vec<PathBuf>,
+    /// Include edges by fragment index; `None` stands for the target. Fragments only include
+    /// fragments with a higher index, so there are no cycles.
+    pub edges: Vec<(Option<usize>, usize)>,
+}
+
+impl IncludeTree {
+    /// The fragments the target includes, directly or through other fragments.
+    pub fn closure(&self) -> BTreeSet<usize> {
+        let mut reached = BTreeSet::new();
+        let mut queue: Vec<usize> = self
+            .edges
+            .iter()
+            .filter(|(from, _)| from.is_none())
+            .map(|&(_, to)| to)
+            .collect();
+        while let Some(fragment) = queue.pop() {
+            if reached.insert(fragment) {
+                queue.extend(
+                    self.edges
+                        .iter()
+                        .filter(|(from, _)| *from == Some(fragment))
+                        .map(|&(_, to)| to),
+                );
+            }
+        }
+        reached
+    }
+}
+
+/// Text each fragment carries once, so the oracle can tell which fragments made it into an
+/// output.
+pub fn marker(fragment: usize) -> String {
+    format!("kvy-fragment-{}", fragment)
+}
+
+/// Writes a random include tree of `fragments` fragments under `dir`, replacing what a
+/// previous call left there: a base XML including some of the fragments at its root level,
+/// each fragment made of elements, comments, whitespace and includes of later fragments, some
+/// of them wrapped in `<placeholder>`. The same seed always writes the same tree.
+pub fn include_tree(dir: &Path, seed: u64, fragments: usize) -> io::Result<IncludeTree> {
+    let mut rng = Rng::new(seed);
+    let folder = dir.join("A");
+    let _ = fs::remove_dir_all(&folder);
+    fs::create_dir_all(folder.join("Gen"))?;
+
+    let mut edges = Vec::new();
+    let mut paths = Vec::with_capacity(fragments);
+    for index in 0..fragments {
+        let mut body = String::new();
+        for _ in 0..1 + rng.below(4) {
+            match rng.below(4) {
+                0 => body.push_str(&format!("  <!-- note {} -->\n", rng.below(100))),
+                1 if index + 1 < fragments => {
+                    let to = index + 1 + rng.below(fragments - index - 1);
+                    body.push_str(&format!("  <!-- #include file=\"f{}.xml\" -->\n", to));
+                    edges.push((Some(index), to));
+                }
+                _ => body.push_str(&format!(
+                    "  <Item n=\"{}\">  value   {}  </Item>\n",
+                    rng.below(1000),
+                    rng.below(1000)
+                )),
+            }
+        }
+        body.push_str(&format!("  <Mark>{}</Mark>\n", marker(index)));
+        let content = match rng.one_in(3) {
+            true => format!("<placeholder>\n{}</placeholder>\n", body),
+            false => body,
+        };
+        let path = folder.join("Gen").join(format!("f{}.xml", index));
+        fs::write(&path, content)?;
+        paths.push(path);
+    }
+
+    let mut target = String::from("<Root>\n");
+    for slot in 0..1 + rng.below(3) {
+        target.push_str(&format!("  <Slot n=\"{}\">\n", slot));
+        if fragments > 0 {
+            let to = rng.below(fragments);
+            target.push_str(&format!("    <!-- #include file=\"Gen/f{}.xml\" -->\n", to));
+            edges.push((None, to));
+        }
+        target.push_str("  </Slot>\n");
+    }
+    target.push_str("</Root>\n");
+    let target_path = folder.join("0_Gen.xml");
+    fs::write(&target_path, target)?;
+
+    Ok(IncludeTree {
+        base_dir: dir.to_path_buf(),
+        target: target_path,
+        fragments: paths,
+        edges,
+    })
+}
+
+/// Checks what any correct compile of `tree` to `output` satisfies: no include directive is
+/// left in it, CDATA sections are balanced and not nested, and exactly the fragments the
+/// target reaches made it in. Returns every problem found.
+pub fn check(tree: &IncludeTree, output: &str, opts: &Options) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+    if let Some(directive) = opts.directives.find(output) {
+        problems.push(format!("directive left in the output: {}", directive.as_str()));
+    }
+    if let Err(problem) = cdata_balanced(output) {
+        problems.push(problem);
+    }
+
+    let expected = tree.closure();
+    for fragment in 0..tree.fragments.len() {
+        let marker = marker(fragment);
+        // A marker is a prefix of the markers of fragments numbered with more digits.
+        let found = output.match_indices(&marker).any(|(at, _)| {
+            !output[at + marker.len()..].starts_with(|c: char| c.is_ascii_digit())
+        });
+        match (expected.contains(&fragment), found) {
+            (true, false) => problems.push(format!(
+                "{} is included but missing from the output",
+                tree.fragments[fragment].display()
+            )),
+            (false, true) => problems.push(format!(
+                "{} is not included but is in the output",
+                tree.fragments[fragment].display()
+            )),
+            _ => {}
+        }
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems),
+    }
+}
+
+/// Whether every `<![CDATA[` in `output` is closed by a `]]>` before the next one opens, and no
+/// `]]>` appears outside a section.
+fn cdata_balanced(output: &str) -> Result<(), String> {
+    const OPEN: &str = "<![CDATA[";
+    const CLOSE: &str = "]]>";
+    let mut at = 0;
+    loop {
+        let open = output[at..].find(OPEN).map(|i| at + i);
+        let close = output[at..].find(CLOSE).map(|i| at + i);
+        let Some(open) = open.filter(|&open| close.is_none_or(|close| open < close)) else {
+            return match close {
+                Some(close) => Err(format!("]]> outside a CDATA section at byte {}", close)),
+                None => Ok(()),
+            };
+        };
+        let inner = open + OPEN.len();
+        let Some(end) = output[inner..].find(CLOSE).map(|i| inner + i) else {
+            return Err(format!("CDATA section at byte {} is never closed", open));
+        };
+        if let Some(nested) = output[inner..end].find(OPEN) {
+            return Err(format!("CDATA section opened inside another at byte {}", inner + nested));
+        }
+        at = end + CLOSE.len();
+    }
+}
@@ -0,0 +1,46 @@
+//! The text transforms a compile is made of, one at a time, for tools that put together their
+//! own pipeline or test a transform on its own.
+//!
+//! ```no_run
+//! use kvy_xmls::{transform, Compiler, Encoding};
+//!
+//! let compiler = Compiler::builder("Config").build()?;
+//! let path = "Config/Wolf/0_Wolf.xml".as_ref();
+//! let source = std::fs::read_to_string(path)?;
+//! let expanded = transform::expand_includes(&source, path, compiler.options())?;
+//! let script = transform::minify("<!-- note --> a  =  1;", compiler.options());
+//! let cdata = transform::wrap_placeholder(&script, Encoding::Cdata);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::path::Path;
+
+use crate::{
+    error::CompileError, expand_passes, options::Options, placeholder,
+    remove_comments, strip_comments_and_format_spaces, Encoding, Expansion,
+};
+
+/// Resolves the include directives in `content` as if it were the base XML at `path`, so
+/// relative includes are found next to it: fragments are expanded, cleaned up and wrapped like
+/// in a compile, over as many passes as `--max-passes` allows. Only the expansion runs; folder
+/// prologues, `#extends`, patches and the output checks are left to the caller.
+pub fn expand_includes(content: &str, path: &Path, opts: &Options) -> Result<String, CompileError> {
+    expand_passes(path, content, opts, &mut Expansion::default())
+}
+
+/// `content` without its XML comments.
+pub fn strip_comments(content: &str) -> String {
+    remove_comments(content)
+}
+
+/// `content` without comments and line breaks, with every other whitespace run folded into
+/// one space, as included fragments are. Elements `--preserve-whitespace` names are kept as
+/// they are.
+pub fn minify(content: &str, opts: &Options) -> String {
+    strip_comments_and_format_spaces(content, opts)
+}
+
+/// Placeholder content written out in `encoding`, as it goes into the base XML.
+pub fn wrap_placeholder(content: &str, encoding: Encoding) -> String {
+    placeholder::wrap(Path::new("placeholder content"), encoding, content)
+}
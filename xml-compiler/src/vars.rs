@@ -0,0 +1,72 @@
+use std::{borrow::Cow, collections::BTreeMap, fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+use crate::{ssi::escape, LineCounter};
+
+/// `@{NAME}` references (`@@{NAME}` for a literal one) and `<!-- #value name="NAME" -->`
+/// directives.
+static VAR_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r"(@?)@\{([A-Za-z_][\w.-]*)\}",
+        r#"|<!--\s*#value\s+name="([A-Za-z_][\w.-]*)"\s*-->"#
+    ))
+    .unwrap()
+});
+
+/// Reads a `--defines` file: one `NAME=VALUE` per line, the value running to the end of the
+/// line. Blank lines and `#` comments are skipped.
+pub fn load(path: &Path) -> Result<BTreeMap<String, String>> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| anyhow!("Cannot read defines {}: {}", path.display(), err))?;
+    let mut vars = BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim_start();
+        if line.trim_end().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("{}:{}: expected NAME=VALUE", path.display(), n + 1))?;
+        vars.insert(name.trim_end().to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+/// Variables of `env` whose name starts with `prefix`, by the rest of their name.
+pub fn from_env(
+    prefix: &str,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> BTreeMap<String, String> {
+    env.into_iter()
+        .filter_map(|(name, value)| Some((name.strip_prefix(prefix)?.to_string(), value)))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// Replaces each variable in `content` with its XML-escaped value. Undefined ones are left as
+/// written and returned with their line.
+pub fn apply<'a>(
+    content: &'a str,
+    vars: &BTreeMap<String, String>,
+) -> (Cow<'a, str>, Vec<(usize, String)>) {
+    let mut undefined = Vec::new();
+    let mut lines = LineCounter::new(content);
+    let replaced = VAR_RE.replace_all(content, |caps: &Captures| {
+        if caps.get(1).is_some_and(|at| !at.is_empty()) {
+            return caps[0][1..].to_string();
+        }
+        let name = caps.get(2).or(caps.get(3)).map_or("", |m| m.as_str());
+        match vars.get(name) {
+            Some(value) => escape(value),
+            None => {
+                let start = caps.get(0).map_or(0, |m| m.start());
+                undefined.push((lines.line(start), name.to_string()));
+                caps[0].to_string()
+            }
+        }
+    });
+    (replaced, undefined)
+}
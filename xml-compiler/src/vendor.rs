@@ -0,0 +1,135 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    expand_targets, log_message,
+    options::{lexical, relative, Options},
+};
+
+/// Directory under the base directory that vendored fragments are copied into.
+pub const VENDOR_DIR: &str = "vendor";
+/// Manifest inside `VENDOR_DIR`, one `<name>\t<original directory>` line per vendored
+/// directory; the original is relative to the base directory.
+const MANIFEST_NAME: &str = "vendor.txt";
+
+/// Vendored directories of a base directory: `(copy, original)`, both lexically normalized.
+pub type Vendored = Vec<(PathBuf, PathBuf)>;
+
+/// Reads `base_dir/vendor/vendor.txt`. No manifest means nothing is vendored.
+pub fn load(base_dir: &Path) -> Result<Vendored> {
+    let vendor_dir = base_dir.join(VENDOR_DIR);
+    let manifest = vendor_dir.join(MANIFEST_NAME);
+    let Ok(text) = fs::read_to_string(&manifest) else {
+        return Ok(Vec::new());
+    };
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            let (name, original) = line.split_once('\t').ok_or_else(|| {
+                anyhow!("{}:{}: expected '<name>\\t<directory>'", manifest.display(), n + 1)
+            })?;
+            Ok((lexical(&vendor_dir.join(name)), lexical(&base_dir.join(original))))
+        })
+        .collect()
+}
+
+/// Where a file in a vendored directory originally lived, so the includes it makes resolve
+/// (and are overridden) exactly as they did before it was vendored.
+pub fn original(path: &Path, opts: &Options) -> Option<PathBuf> {
+    let path = lexical(path);
+    opts.vendored.iter().find_map(|(copy, original)| {
+        path.strip_prefix(copy).ok().map(|relative| original.join(relative))
+    })
+}
+
+/// The vendored copy of `path`, if it lies in a vendored directory and was copied.
+pub fn copy(path: &Path, opts: &Options) -> Option<PathBuf> {
+    let path = lexical(path);
+    opts.vendored.iter().find_map(|(copy, original)| {
+        Some(copy.join(path.strip_prefix(original).ok()?)).filter(|copy| copy.is_file())
+    })
+}
+
+/// Expands every target against the live include roots, then copies each included fragment
+/// that lies outside the base directory into `vendor/`, replacing what was vendored before.
+/// Fragments under an include root keep their path relative to the root; others keep theirs
+/// relative to their own directory. Returns how many files were copied.
+pub fn write(opts: &Options) -> Result<usize> {
+    let base_dir = lexical(&opts.base_dir);
+    let vendor_dir = base_dir.join(VENDOR_DIR);
+
+    let mut files = BTreeMap::new();
+    for (file, exp) in expand_targets(opts)? {
+        // A copy missing a fragment is no archive, and the old copies may be all that's left.
+        if let Some(warning) = exp.warnings.first() {
+            return Err(anyhow!("{}: {}, nothing vendored", file.display(), warning));
+        }
+        for include in &exp.includes {
+            let include = lexical(include);
+            if include.starts_with(&base_dir) {
+                continue;
+            }
+            // The root it came from, or failing that the directory it sits in.
+            let origin = opts
+                .include_roots
+                .iter()
+                .filter(|root| include.starts_with(root))
+                .max_by_key(|root| root.components().count())
+                .cloned()
+                .or_else(|| include.parent().map(Path::to_path_buf))
+                .unwrap_or_default();
+            files.insert(include, origin);
+        }
+    }
+
+    // One directory per origin, named after it; clashing names get a numeric suffix.
+    let mut names: BTreeMap<&Path, String> = BTreeMap::new();
+    for origin in files.values() {
+        if names.contains_key(origin.as_path()) {
+            continue;
+        }
+        let stem = origin.file_name().map_or("root".into(), |name| name.to_string_lossy());
+        let mut name = stem.to_string();
+        let mut n = 1;
+        while names.values().any(|taken| *taken == name) {
+            n += 1;
+            name = format!("{}-{}", stem, n);
+        }
+        names.insert(origin, name);
+    }
+
+    if vendor_dir.exists() {
+        // Only ever replace what a previous run vendored.
+        if !vendor_dir.join(MANIFEST_NAME).exists() {
+            return Err(anyhow!(
+                "{} exists but was not written by `KiwiXML vendor`, move it away first",
+                vendor_dir.display()
+            ));
+        }
+        fs::remove_dir_all(&vendor_dir)?;
+    }
+    for (file, origin) in &files {
+        let target = vendor_dir.join(&names[origin.as_path()]).join(file.strip_prefix(origin)?);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(file, &target)?;
+        log_message(&format!("Vendored: {} -> {}", file.display(), target.display()));
+    }
+
+    if !files.is_empty() {
+        let mut manifest =
+            String::from("# Vendored include directories. Regenerate with `KiwiXML vendor`.\n");
+        for (origin, name) in &names {
+            manifest.push_str(&format!("{}\t{}\n", name, relative(origin, &base_dir)));
+        }
+        fs::write(vendor_dir.join(MANIFEST_NAME), manifest)?;
+    }
+    Ok(files.len())
+}
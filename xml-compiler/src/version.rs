@@ -0,0 +1,53 @@
+use std::cmp::Ordering;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// `<!-- #fragment version="2.1" -->`, declared anywhere in a fragment.
+static FRAGMENT_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"<!--\s*#fragment\s+version="([^"]*)"\s*-->"#).unwrap());
+
+/// The version a fragment declares, if any.
+pub fn declared(content: &str) -> Option<&str> {
+    FRAGMENT_RE
+        .captures(content)
+        .and_then(|caps| caps.get(1))
+        .map(|version| version.as_str().trim())
+}
+
+/// Whether `version` meets `requirement`: comma-separated conditions that must all hold, each
+/// `=V`, `>=V`, `>V`, `<=V`, `<V`, or a bare `V` matching every version that starts with it
+/// (`2` accepts `2`, `2.0` and `2.7`). `None` if either doesn't parse.
+pub fn satisfies(version: &str, requirement: &str) -> Option<bool> {
+    let version = parse(version)?;
+    let mut result = true;
+    for condition in requirement.split(',').map(str::trim) {
+        let (op, wanted) = ["<=", ">=", "=", "<", ">"]
+            .iter()
+            .find_map(|op| Some((*op, condition.strip_prefix(op)?)))
+            .unwrap_or(("", condition));
+        let wanted = parse(wanted)?;
+        let ordering = compare(&version, &wanted);
+        result &= match op {
+            "=" => ordering == Ordering::Equal,
+            ">=" => ordering != Ordering::Less,
+            ">" => ordering == Ordering::Greater,
+            "<=" => ordering != Ordering::Greater,
+            "<" => ordering == Ordering::Less,
+            _ => version.starts_with(&wanted),
+        };
+    }
+    Some(result)
+}
+
+fn parse(version: &str) -> Option<Vec<u64>> {
+    version.trim().split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Compares as if the shorter version were padded with zeros, so `2` equals `2.0`.
+fn compare(a: &[u64], b: &[u64]) -> Ordering {
+    (0..a.len().max(b.len()))
+        .map(|i| a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
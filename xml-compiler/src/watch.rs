@@ -0,0 +1,265 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use notify::{event::ModifyKind, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rayon::prelude::*;
+
+use crate::{
+    compile_target, console, discover_targets, log_message, log_path, log_section,
+    options::{lexical, Options},
+    process_xml_files, sha256_hex, skip_compiled_sources, written, Expansion,
+};
+
+/// What a target read when it was last compiled, and whether that went cleanly.
+pub(crate) struct Build {
+    /// SHA-256 of each file read, as it was then.
+    sources: BTreeMap<PathBuf, String>,
+    clean: bool,
+}
+
+impl Build {
+    pub(crate) fn new(exp: &Expansion, compiled: bool) -> Build {
+        Build {
+            sources: exp
+                .sources
+                .iter()
+                .map(|(path, read)| (lexical(path), read.digest.clone()))
+                .collect(),
+            clean: compiled && exp.warnings.is_empty(),
+        }
+    }
+}
+
+/// Last build of each target, keyed by its lexically normalized base XML.
+pub(crate) type Builds = BTreeMap<PathBuf, Build>;
+
+/// Compiles every target, then keeps watching the base directory, the include roots and any
+/// other directory a target read from, recompiling the targets that read a changed file. New
+/// base XMLs are compiled when they appear, and targets that failed or had warnings are retried
+/// whenever a file is created, removed or renamed, as the include they were missing may just
+/// have appeared. Changes are batched until none has come for `--watch-debounce`, and files
+/// saved without changing their content, as well as editor swap and backup files, are ignored.
+/// Runs until the process is stopped.
+pub fn watch(opts: &Options) -> Result<()> {
+    watch_with(opts, |_| {})
+}
+
+/// Like [`watch`], calling `rebuilt` after every rebuild with the output paths of the targets
+/// it compiled, as discovered, before `--partition-by` moves them.
+pub(crate) fn watch_with(opts: &Options, mut rebuilt: impl FnMut(&[PathBuf])) -> Result<()> {
+    if opts.hermetic.is_some() {
+        return Err(anyhow!("--watch cannot be combined with --hermetic"));
+    }
+    // Files the build writes itself must not trigger another build.
+    let mut ignored = vec![opts.output_dir.clone(), opts.quarantine_dir.clone()];
+    ignored.extend(opts.json_report.clone());
+    ignored.extend(opts.msbuild_props.clone());
+    ignored.push(env::current_dir()?.join(log_path()));
+    let ignored: Vec<PathBuf> = ignored.iter().map(|path| lexical(path)).collect();
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    let mut watched = BTreeSet::new();
+
+    log_section(&format!(
+        "Starting processing in {} (build {})",
+        opts.base_dir.display(),
+        opts.build_id
+    ));
+    let mut builds = Builds::new();
+    if let Err(err) = process_xml_files(opts, &mut builds) {
+        log_message(&format!("Error: {}", err));
+    }
+    log_section(&format!("Watching {} for changes", opts.base_dir.display()));
+    println!("Watching {} for changes (Ctrl+C to stop)", opts.base_dir.display());
+
+    loop {
+        watch_sources(&mut watcher, &mut watched, opts, &builds);
+        let mut changes = Changes::default();
+        while !changes.collect(rx.recv()?, &ignored) {}
+        // Every change starts the wait over, so a burst of saves is rebuilt once it is done.
+        // Reads don't, or something polling the outputs would hold off rebuilds for good.
+        let mut settled = Instant::now() + opts.watch_debounce;
+        while let Some(left) = settled.checked_duration_since(Instant::now()) {
+            let Ok(event) = rx.recv_timeout(left) else {
+                break;
+            };
+            if changes.collect(event, &ignored) {
+                settled = Instant::now() + opts.watch_debounce;
+            }
+        }
+        let compiled = rebuild(opts, &changes.paths, changes.moved, &mut builds);
+        if !compiled.is_empty() {
+            rebuilt(&compiled);
+        }
+    }
+}
+
+/// Files changed while waiting for a rebuild.
+#[derive(Default)]
+struct Changes {
+    paths: BTreeSet<PathBuf>,
+    /// Whether any file was created, removed or renamed, which may resolve an include a
+    /// target couldn't find.
+    moved: bool,
+}
+
+impl Changes {
+    /// Adds the files `event` changed, leaving out those under `ignored` and editor temp files.
+    /// Returns whether there were any.
+    fn collect(&mut self, event: notify::Result<Event>, ignored: &[PathBuf]) -> bool {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                log_message(&format!("Watch error: {}", err));
+                return false;
+            }
+        };
+        let paths: Vec<PathBuf> = event
+            .paths
+            .iter()
+            .filter(|path| !is_editor_temp(path))
+            .map(|path| lexical(path))
+            .filter(|path| !ignored.iter().any(|ignored| path.starts_with(ignored)))
+            .collect();
+        match event.kind {
+            _ if paths.is_empty() => return false,
+            EventKind::Access(_) => return false,
+            EventKind::Create(_) | EventKind::Remove(_) => self.moved = true,
+            EventKind::Modify(ModifyKind::Name(_)) => self.moved = true,
+            _ => {}
+        }
+        self.paths.extend(paths);
+        true
+    }
+}
+
+/// Swap, backup and lock files editors keep next to the file being edited: `*.swp`, `*~` and
+/// `.#*`.
+fn is_editor_temp(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(".swp") || name.ends_with('~') || name.starts_with(".#")
+}
+
+/// Watches every include root recursively, and the directory of every source read from
+/// outside them (fragments reached through `../` paths).
+fn watch_sources(
+    watcher: &mut RecommendedWatcher,
+    watched: &mut BTreeSet<(PathBuf, bool)>,
+    opts: &Options,
+    builds: &Builds,
+) {
+    let roots: Vec<(PathBuf, bool)> = opts
+        .include_roots
+        .iter()
+        .map(|root| (root.clone(), true))
+        .collect();
+    let outside = builds
+        .values()
+        .flat_map(|build| build.sources.keys())
+        .filter(|source| !opts.include_roots.iter().any(|root| source.starts_with(root)))
+        .filter_map(|source| Some((source.parent()?.to_path_buf(), false)));
+    for (dir, recursive) in roots.into_iter().chain(outside.collect::<Vec<_>>()) {
+        if watched.contains(&(dir.clone(), recursive)) {
+            continue;
+        }
+        let mode = match recursive {
+            true => RecursiveMode::Recursive,
+            false => RecursiveMode::NonRecursive,
+        };
+        match watcher.watch(&dir, mode) {
+            Ok(()) => {
+                watched.insert((dir, recursive));
+            }
+            Err(err) => log_message(&format!("Cannot watch {}: {}", dir.display(), err)),
+        }
+    }
+}
+
+/// Recompiles the targets that read a file in `changed` whose content is no longer what they
+/// read, in parallel like a full run, and with `moved` those that failed or had warnings last
+/// time. A save-all that rewrites every open file as it was rebuilds nothing. Reports are not
+/// rewritten; they stay as the first build left them. Returns the output paths of the targets
+/// compiled.
+fn rebuild(
+    opts: &Options,
+    changed: &BTreeSet<PathBuf>,
+    moved: bool,
+    builds: &mut Builds,
+) -> Vec<PathBuf> {
+    let current: BTreeMap<&PathBuf, Option<String>> = changed
+        .iter()
+        .map(|path| (path, fs::read(path).ok().map(|data| sha256_hex(&data))))
+        .collect();
+    let edited = |build: &Build| {
+        build.sources.iter().any(|(path, digest)| {
+            current.get(path).is_some_and(|now| now.as_ref() != Some(digest))
+        })
+    };
+    let targets = skip_compiled_sources(discover_targets(opts), opts);
+    let live: BTreeSet<PathBuf> = targets.iter().map(|(source, _)| lexical(source)).collect();
+    builds.retain(|source, _| live.contains(source));
+    let affected: Vec<&(PathBuf, PathBuf)> = targets
+        .iter()
+        .filter(|(source, _)| {
+            let source = lexical(source);
+            match builds.get(&source) {
+                // A base XML that couldn't be read isn't among its sources.
+                Some(build) if !build.sources.contains_key(&source) => {
+                    moved || changed.contains(&source)
+                }
+                Some(build) => (moved && !build.clean) || edited(build),
+                None => true,
+            }
+        })
+        .collect();
+    if affected.is_empty() {
+        return Vec::new();
+    }
+
+    // Files a build read just as they are now aren't news.
+    let names: Vec<String> = current
+        .iter()
+        .filter(|(path, now)| {
+            !builds.values().any(|build| build.sources.get(**path) == now.as_ref())
+        })
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+    log_section(&format!("Changed: {}", names.join(", ")));
+    let results: Vec<(&Path, &Path, Expansion, bool)> = affected
+        .par_iter()
+        .map(|(source, out_path)| {
+            let ((exp, result), entries) = console::capture(|| {
+                let mut exp = Expansion::default();
+                let result = compile_target(source, out_path, opts, &mut exp);
+                if let Err(err) = &result {
+                    log_message(&format!("Failed {}: {}", source.display(), err));
+                }
+                (exp, result)
+            });
+            let status = match &result {
+                Ok(_) => console::Status::Compiled,
+                Err(err) => console::Status::Failed(err),
+            };
+            console::flush(source, status, &exp.warnings, &entries, opts);
+            (source.as_path(), out_path.as_path(), exp, result.is_ok())
+        })
+        .collect();
+    let mut outputs = Vec::new();
+    for (source, out_path, exp, compiled) in results {
+        builds.insert(lexical(source), Build::new(&exp, compiled));
+        if compiled {
+            outputs.push(out_path.to_path_buf());
+        }
+    }
+    if let Err(err) = written::save(opts) {
+        log_message(&format!("Error recording written outputs: {}", err));
+    }
+    outputs
+}
@@ -0,0 +1,95 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+
+/// One project of a workspace: a name to select it by, and the compile arguments (base
+/// directory first) it runs with.
+pub struct Project {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+/// Reads a workspace file: one `<name> <base dir> [options...]` line per project, in the same
+/// syntax as the command line. Double quotes group words containing spaces. Blank lines and `#`
+/// comments are skipped.
+pub fn load(path: &Path) -> Result<Vec<Project>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read workspace {}", path.display()))?;
+
+    let mut projects: Vec<Project> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words = split_words(line)
+            .ok_or_else(|| anyhow!("{}:{}: unterminated quote", path.display(), number + 1))?;
+        let Some((name, args)) = words.split_first().filter(|(_, args)| !args.is_empty()) else {
+            return Err(anyhow!(
+                "{}:{}: expected '<name> <base dir> [options...]'",
+                path.display(),
+                number + 1
+            ));
+        };
+        if projects.iter().any(|project| project.name == *name) {
+            return Err(anyhow!("{}:{}: duplicate project {}", path.display(), number + 1, name));
+        }
+        projects.push(Project {
+            name: name.clone(),
+            args: args.to_vec(),
+        });
+    }
+    Ok(projects)
+}
+
+/// The projects named in `selected` (each entry may list several, comma-separated), in
+/// workspace order, or every project when nothing is selected.
+pub fn select<'a>(projects: &'a [Project], selected: &[String]) -> Result<Vec<&'a Project>> {
+    let names: Vec<&str> = selected
+        .iter()
+        .flat_map(|names| names.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    if let Some(unknown) = names.iter().find(|name| !projects.iter().any(|p| p.name == **name)) {
+        let known: Vec<&str> = projects.iter().map(|project| project.name.as_str()).collect();
+        return Err(anyhow!("Unknown project: {} (known: {})", unknown, known.join(", ")));
+    }
+    Ok(projects
+        .iter()
+        .filter(|project| names.is_empty() || names.contains(&project.name.as_str()))
+        .collect())
+}
+
+/// Splits on whitespace, keeping double-quoted runs together. `None` if a quote isn't closed.
+fn split_words(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return None;
+    }
+    if in_word {
+        words.push(word);
+    }
+    Some(words)
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+
+use crate::{
+    error::CompileError,
+    options::{relative, Options},
+    sha256_hex,
+};
+
+/// Written into the output directory; the name never matches `DEFAULT_TARGET_PATTERN`.
+pub(crate) const WRITTEN_NAME: &str = ".kvy-xmls-written";
+
+/// One output as last written.
+#[derive(Clone)]
+struct Written {
+    /// SHA-256 of the output.
+    digest: String,
+    /// SHA-256 of its base XML as read then; unknown for outputs recorded before it was kept.
+    source: Option<String>,
+}
+
+/// Every output last written, by path relative to its output directory, for each output
+/// directory in use. Loaded on first use and dropped again by `save`, so a daemon or a watch
+/// loop rereads what other processes wrote in between.
+static WRITTEN: Lazy<Mutex<HashMap<PathBuf, BTreeMap<String, Written>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn load(opts: &Options) -> BTreeMap<String, Written> {
+    let state = fs::read_to_string(opts.output_dir.join(WRITTEN_NAME))
+        .ok()
+        .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+        .unwrap_or_default();
+    state
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(output, entry)| {
+            let written = match entry {
+                Value::String(digest) => Written {
+                    digest: digest.clone(),
+                    source: None,
+                },
+                _ => Written {
+                    digest: entry["sha256"].as_str()?.to_string(),
+                    source: entry["source"].as_str().map(str::to_string),
+                },
+            };
+            Some((output.clone(), written))
+        })
+        .collect()
+}
+
+/// Whether `out_path` is tracked: it lies in the output directory and the build isn't hermetic,
+/// where outputs belong to the build system.
+fn tracked(out_path: &Path, opts: &Options) -> bool {
+    opts.hermetic.is_none() && out_path.starts_with(&opts.output_dir)
+}
+
+/// Fails when `out_path` exists but is not what was last written there, so a hotfix applied
+/// directly to a compiled file isn't silently lost. Outputs never written by KiwiXML, or
+/// written before it kept track, pass. `--force` overwrites regardless.
+pub(crate) fn check(out_path: &Path, opts: &Options) -> Result<(), CompileError> {
+    if opts.force || !tracked(out_path, opts) {
+        return Ok(());
+    }
+    let expected = {
+        let mut written = WRITTEN.lock().unwrap_or_else(|err| err.into_inner());
+        let outputs = written.entry(opts.output_dir.clone()).or_insert_with(|| load(opts));
+        outputs.get(&relative(out_path, &opts.output_dir)).map(|written| written.digest.clone())
+    };
+    let (Some(expected), Ok(current)) = (expected, fs::read(out_path)) else {
+        return Ok(());
+    };
+    if sha256_hex(&current) == expected {
+        return Ok(());
+    }
+    Err(CompileError::HandEdited {
+        path: out_path.to_path_buf(),
+    })
+}
+
+/// Whether `out_path` is exactly what KiwiXML last wrote there.
+pub(crate) fn unchanged(out_path: &Path, opts: &Options) -> bool {
+    if !tracked(out_path, opts) {
+        return false;
+    }
+    let expected = {
+        let mut written = WRITTEN.lock().unwrap_or_else(|err| err.into_inner());
+        let outputs = written.entry(opts.output_dir.clone()).or_insert_with(|| load(opts));
+        outputs.get(&relative(out_path, &opts.output_dir)).map(|written| written.digest.clone())
+    };
+    expected.is_some_and(|expected| {
+        fs::read(out_path).is_ok_and(|current| sha256_hex(&current) == expected)
+    })
+}
+
+/// Every output KiwiXML has written to the output directory and kept track of.
+pub(crate) fn recorded(opts: &Options) -> Vec<PathBuf> {
+    if opts.hermetic.is_some() {
+        return Vec::new();
+    }
+    let mut written = WRITTEN.lock().unwrap_or_else(|err| err.into_inner());
+    let outputs = written.entry(opts.output_dir.clone()).or_insert_with(|| load(opts));
+    outputs.keys().map(|output| opts.output_dir.join(output)).collect()
+}
+
+/// The output last written with SHA-256 `digest`, if any, leaving out outputs identical to
+/// the base XML they were compiled from, as one without directives is. A source with that
+/// digest is a compiled file copied back among the sources.
+pub(crate) fn output_with_digest(digest: &str, opts: &Options) -> Option<PathBuf> {
+    if opts.hermetic.is_some() {
+        return None;
+    }
+    let mut written = WRITTEN.lock().unwrap_or_else(|err| err.into_inner());
+    let outputs = written.entry(opts.output_dir.clone()).or_insert_with(|| load(opts));
+    outputs
+        .iter()
+        .find(|(_, written)| {
+            written.digest == digest && written.source.as_deref().is_some_and(|read| read != digest)
+        })
+        .map(|(output, _)| opts.output_dir.join(output))
+}
+
+/// Notes what was just written to `out_path`, compiled from a base XML with SHA-256 `source`,
+/// for `save` to persist.
+pub(crate) fn record(out_path: &Path, content: &[u8], source: Option<&str>, opts: &Options) {
+    if !tracked(out_path, opts) {
+        return;
+    }
+    let mut written = WRITTEN.lock().unwrap_or_else(|err| err.into_inner());
+    let outputs = written.entry(opts.output_dir.clone()).or_insert_with(|| load(opts));
+    outputs.insert(
+        relative(out_path, &opts.output_dir),
+        Written {
+            digest: sha256_hex(content),
+            source: source.map(str::to_string),
+        },
+    );
+}
+
+/// Persists everything recorded for the output directory since it was loaded. Outputs not
+/// written this time keep their previous entry, as the file on disk is still that one.
+pub(crate) fn save(opts: &Options) -> Result<()> {
+    let outputs = {
+        let mut written = WRITTEN.lock().unwrap_or_else(|err| err.into_inner());
+        written.remove(&opts.output_dir)
+    };
+    if let Some(outputs) = outputs {
+        let outputs: BTreeMap<&String, Value> = outputs
+            .iter()
+            .map(|(output, written)| {
+                (output, json!({ "sha256": written.digest, "source": written.source }))
+            })
+            .collect();
+        fs::write(opts.output_dir.join(WRITTEN_NAME), serde_json::to_string_pretty(&outputs)?)?;
+    }
+    Ok(())
+}
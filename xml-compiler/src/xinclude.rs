@@ -0,0 +1,224 @@
+use std::{borrow::Cow, ops::Range, path::Path};
+
+use once_cell::sync::Lazy;
+use quick_xml::{events::Event, Reader};
+use regex::Regex;
+
+use crate::{
+    check_cycle, check_limits, console, error::CompileError, expand_open, extends, load_source,
+    log_message, options::Options, read_source, resolve_include, Expansion, Include, LineCounter,
+};
+
+/// The prefix a document binds to the XInclude namespace; `xi` when it binds none.
+static PREFIX_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"xmlns:([\w.-]+)\s*=\s*["']http://www\.w3\.org/2001/XInclude["']"#).unwrap()
+});
+
+/// An XML declaration, which an included document leaves behind.
+static DECLARATION_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^\u{FEFF}?\s*<\?xml[^>]*\?>").unwrap());
+
+/// One `<xi:include>` element.
+struct XInclude {
+    outer: Range<usize>,
+    href: Option<String>,
+    parse: Option<String>,
+    xpointer: bool,
+    /// What is inside its `<xi:fallback>`, if it has one.
+    fallback: Option<Range<usize>>,
+}
+
+/// `content` of the document at `file` with its `<xi:include>` elements replaced, when
+/// `--xinclude` is on: `parse="xml"` (the default) by the document `href` names, itself with
+/// its XInclude elements resolved and without its XML declaration, and `parse="text"` by the
+/// file's text, escaped. `href` is resolved like `file="..."` in an include directive. When it
+/// can't be, the element's `<xi:fallback>` content takes its place, and without a fallback it
+/// counts as a missing include.
+pub(crate) fn apply<'a>(
+    file: &Path,
+    content: &'a str,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<Cow<'a, str>, CompileError> {
+    if !opts.xinclude || !content.contains(&format!("<{}:include", prefix(content))) {
+        return Ok(Cow::Borrowed(content));
+    }
+    expand_open(file, exp, |exp| expand(file, content, opts, exp)).map(Cow::Owned)
+}
+
+fn prefix(content: &str) -> &str {
+    PREFIX_RE.captures(content).map_or("xi", |caps| caps.get(1).unwrap().as_str())
+}
+
+fn expand(
+    file: &Path,
+    content: &str,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let prefix = prefix(content);
+    if !content.contains(&format!("<{}:include", prefix)) {
+        return Ok(content.to_string());
+    }
+    let mut out = String::with_capacity(content.len());
+    let mut copied = 0;
+    let mut lines = LineCounter::new(content);
+    for include in find(file, content, prefix)? {
+        out.push_str(&content[copied..include.outer.start]);
+        let line = lines.line(include.outer.start);
+        out.push_str(&resolve(file, content, &include, line, opts, exp)?);
+        copied = include.outer.end;
+    }
+    out.push_str(&content[copied..]);
+    Ok(out)
+}
+
+/// What replaces one `<xi:include>`.
+fn resolve(
+    file: &Path,
+    content: &str,
+    include: &XInclude,
+    line: usize,
+    opts: &Options,
+    exp: &mut Expansion,
+) -> Result<String, CompileError> {
+    let fail = |reason| CompileError::XInclude {
+        path: file.to_path_buf(),
+        line,
+        reason,
+    };
+    if include.xpointer {
+        return Err(fail("with xpointer is not supported"));
+    }
+    let Some(href) = include.href.as_deref().filter(|href| !href.is_empty()) else {
+        return Err(fail("needs an href"));
+    };
+    let text = match include.parse.as_deref() {
+        None | Some("xml") => false,
+        Some("text") => true,
+        Some(_) => return Err(fail("parse must be xml or text")),
+    };
+
+    let dir = file.parent().unwrap_or_else(|| Path::new("."));
+    let resolved = match &include.fallback {
+        // Tried quietly first, so that a fallback in use isn't reported as a missing include.
+        Some(fallback) => {
+            let mut probe = Expansion::default();
+            let (probed, _) =
+                console::capture(|| resolve_include(dir, Include::File(href), opts, &mut probe));
+            if probed.is_err() {
+                // The file turning up later changes the output.
+                exp.missed.extend(probe.missing);
+                log_message(&format!("Fallback: {} for {}", file.display(), href));
+                return expand(file, &content[fallback.clone()], opts, exp);
+            }
+            resolve_include(dir, Include::File(href), opts, exp)
+        }
+        None => resolve_include(dir, Include::File(href), opts, exp),
+    };
+    let include_path = match resolved {
+        Ok(path) => path,
+        Err(comment) => return Ok(comment),
+    };
+    if let Some(comment) = check_cycle(file, &include_path, opts, exp)? {
+        return Ok(comment);
+    }
+    check_limits(file, &include_path, opts, exp)?;
+
+    let included = match text {
+        true => {
+            let source = read_source(&include_path, opts, exp)?;
+            let text = &source.content;
+            let escaped = text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+            exp.sources.insert(include_path.clone(), source);
+            escaped
+        }
+        false => {
+            let loaded = load_source(&include_path, opts, exp)?;
+            let body = DECLARATION_RE.replace(&loaded, "");
+            let expanded = expand_open(&include_path, exp, |exp| {
+                expand(&include_path, body.trim(), opts, exp)
+            })?;
+            // Its include directives still resolve from its own folder.
+            extends::anchor(&expanded, &include_path, opts).into_owned()
+        }
+    };
+    log_message(&format!("Included: {}", include_path.display()));
+    exp.includes.push(include_path.clone());
+    exp.edges.push((file.to_path_buf(), include_path));
+    Ok(included)
+}
+
+/// The outermost `<prefix:include>` elements of `content`, in document order.
+fn find(file: &Path, content: &str, prefix: &str) -> Result<Vec<XInclude>, CompileError> {
+    let include_name = format!("{}:include", prefix);
+    let fallback_name = format!("{}:fallback", prefix);
+    let malformed = |reader: &Reader<&[u8]>, message: String| CompileError::Malformed {
+        path: file.to_path_buf(),
+        position: reader.error_position(),
+        message,
+    };
+
+    let mut reader = Reader::from_str(content);
+    reader.config_mut().check_end_names = false;
+    let mut found = Vec::new();
+    // The include being read, how deep inside it the reader is, and where its fallback starts.
+    let mut current: Option<(XInclude, usize, usize)> = None;
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = reader.read_event().map_err(|err| malformed(&reader, err.to_string()))?;
+        let end = reader.buffer_position() as usize;
+        match (&event, &mut current) {
+            (Event::Eof, _) => break,
+            (Event::Start(tag) | Event::Empty(tag), None)
+                if tag.name().as_ref() == include_name.as_bytes() =>
+            {
+                let attr = |name: &str| -> Result<Option<String>, CompileError> {
+                    match tag.try_get_attribute(name) {
+                        Ok(Some(attr)) => attr
+                            .unescape_value()
+                            .map(|value| Some(value.into_owned()))
+                            .map_err(|err| malformed(&reader, err.to_string())),
+                        Ok(None) => Ok(None),
+                        Err(err) => Err(malformed(&reader, err.to_string())),
+                    }
+                };
+                let include = XInclude {
+                    outer: start..end,
+                    href: attr("href")?,
+                    parse: attr("parse")?,
+                    xpointer: attr("xpointer")?.is_some(),
+                    fallback: None,
+                };
+                match event {
+                    Event::Empty(_) => found.push(include),
+                    _ => current = Some((include, 0, 0)),
+                }
+            }
+            (Event::Start(tag), Some((_, depth, fallback_start))) => {
+                if *depth == 0 && tag.name().as_ref() == fallback_name.as_bytes() {
+                    *fallback_start = end;
+                }
+                *depth += 1;
+            }
+            (Event::Empty(tag), Some((include, 0, _)))
+                if tag.name().as_ref() == fallback_name.as_bytes() =>
+            {
+                include.fallback = Some(end..end);
+            }
+            (Event::End(tag), Some((include, depth, fallback_start))) => {
+                if *depth == 0 {
+                    include.outer.end = end;
+                    found.extend(current.take().map(|(include, _, _)| include));
+                    continue;
+                }
+                *depth -= 1;
+                if *depth == 0 && tag.name().as_ref() == fallback_name.as_bytes() {
+                    include.fallback = Some(*fallback_start..start);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(found)
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use common::Project;
+
+fn include(file: &str) -> String {
+    format!("<!-- #include file=\"{}\" -->", file)
+}
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write("A/0_One.xml", format!("<One>\n  {}\n</One>\n", include("outer.xml")))
+        .write("A/0_Two.xml", "<Two/>\n")
+        .write("A/outer.xml", format!("<placeholder>\n{}\n</placeholder>\n", include("inner.xml")))
+        .write("A/inner.xml", "<placeholder>\n<Inner/>\n</placeholder>\n");
+    project
+}
+
+#[test]
+fn unchanged_targets_are_up_to_date() {
+    let project = project("cache-unchanged");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Compiled A/0_One.xml"), "{}", run.text);
+    assert!(project.exists("compiled/.kvy-xmls-cache"));
+
+    // Rewriting a file with the same content changes its modification time only.
+    project.write("A/inner.xml", "<placeholder>\n<Inner/>\n</placeholder>\n");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Up to date A/0_One.xml"), "{}", run.text);
+    assert!(run.text.contains("Up to date A/0_Two.xml"), "{}", run.text);
+    assert!(project.read_text("processing.log").contains("Up to date: "));
+
+    let run = project.run(&["--force"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("Up to date"), "{}", run.text);
+}
+
+#[test]
+fn a_changed_transitive_include_rebuilds_only_its_targets() {
+    let project = project("cache-transitive");
+    assert!(project.run(&[]).success);
+    project.write("A/inner.xml", "<placeholder>\n<Changed/>\n</placeholder>\n");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Compiled A/0_One.xml"), "{}", run.text);
+    assert!(run.text.contains("Up to date A/0_Two.xml"), "{}", run.text);
+    assert!(project.read_text("compiled/0_One.xml").contains("<Changed/>"));
+}
+
+#[test]
+fn a_missing_output_or_other_options_rebuild() {
+    let project = project("cache-invalidated");
+    assert!(project.run(&[]).success);
+    std::fs::remove_file(project.path("compiled/0_Two.xml")).unwrap();
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Compiled A/0_Two.xml"), "{}", run.text);
+    assert!(run.text.contains("Up to date A/0_One.xml"), "{}", run.text);
+
+    let run = project.run(&["--flag", "beta"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("Up to date"), "{}", run.text);
+
+    project.write("compiled/.kvy-xmls-cache", "not json");
+    let run = project.run(&["--flag", "beta"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("Up to date"), "{}", run.text);
+}
+
+#[test]
+fn targets_with_warnings_are_always_built_again() {
+    let project = project("cache-warnings");
+    project.write("A/0_Two.xml", format!("<Two>\n  {}\n</Two>\n", include("Nope.xml")));
+    for _ in 0..2 {
+        let run = project.run(&[]);
+        assert!(run.success, "{}", run.text);
+        assert!(run.text.contains("Compiled A/0_Two.xml (1 warning)"), "{}", run.text);
+    }
+
+    // The include turning up where it was missing is a change too.
+    project.write("A/Nope.xml", "<placeholder>\n<Found/>\n</placeholder>\n");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("compiled/0_Two.xml").contains("<Found/>"));
+}
+
+#[test]
+fn an_include_resolving_to_another_file_rebuilds() {
+    let project = project("cache-resolution");
+    project
+        .write("A/0_Two.xml", format!("<Two>\n  {}\n</Two>\n", include("shared.xml")))
+        .write("Lib/shared.xml", "<placeholder>\n<FromLib/>\n</placeholder>\n");
+    let run = project.run(&["-I", "Lib"]);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("compiled/0_Two.xml").contains("<FromLib/>"));
+
+    // Same content, but now found in the directive's own folder first.
+    project.write("A/shared.xml", "<placeholder>\n<FromLib/>\n</placeholder>\n");
+    let run = project.run(&["-I", "Lib"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Compiled A/0_Two.xml"), "{}", run.text);
+    assert!(run.text.contains("Up to date A/0_One.xml"), "{}", run.text);
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use sha2::{Digest, Sha256};
+
+use common::Project;
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `count` elements with pseudo-random values, starting the sequence at `seed`.
+fn items(count: usize, seed: u64) -> String {
+    let mut seed = seed;
+    (0..count)
+        .map(|id| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            format!("<Item id=\"{}\" v=\"{}\"/>\n", id, seed >> 16)
+        })
+        .collect()
+}
+
+/// The `(offset, length, sha256)` lines of a manifest, after checking its header against
+/// `output`.
+fn chunks(manifest: &str, output: &[u8]) -> Vec<(usize, usize, String)> {
+    let mut lines = manifest.lines();
+    assert_eq!(lines.next(), Some("# KiwiXML chunks v1"));
+    assert!(lines.next().unwrap().starts_with("# build "));
+    assert_eq!(lines.next(), Some(&*format!("# size {}", output.len())));
+    assert_eq!(lines.next(), Some(&*format!("# sha256 {}", sha256(output))));
+    lines
+        .map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            (fields[0].parse().unwrap(), fields[1].parse().unwrap(), fields[2].to_string())
+        })
+        .collect()
+}
+
+#[test]
+fn the_manifest_covers_the_output_in_content_defined_chunks() {
+    let project = Project::new("chunks-manifest");
+    project.write("A/0_Big.xml", format!("<Big>\n{}</Big>\n", items(8000, 1)));
+    let run = project.run(&["--chunks", "--build-id", "b1"]);
+    assert!(run.success, "{}", run.text);
+    let output = project.read("compiled/0_Big.xml");
+    let manifest = project.read_text("compiled/0_Big.xml.chunks");
+    assert!(manifest.contains("# build b1\n"), "{}", manifest);
+    let chunks = chunks(&manifest, &output);
+    assert!(chunks.len() > 10, "{}", manifest);
+    let mut offset = 0;
+    for (i, (start, length, digest)) in chunks.iter().enumerate() {
+        assert_eq!(*start, offset, "{}", manifest);
+        assert!(*length <= 64 * 1024, "{}", manifest);
+        assert!(*length >= 2 * 1024 || i == chunks.len() - 1, "{}", manifest);
+        assert_eq!(*digest, sha256(&output[offset..offset + length]), "{}", manifest);
+        offset += length;
+    }
+    assert_eq!(offset, output.len());
+}
+
+#[test]
+fn an_edit_only_changes_the_chunks_around_it() {
+    let project = Project::new("chunks-edit");
+    let (head, tail) = (items(4000, 1), items(4000, 2));
+    project.write("A/0_Big.xml", format!("<Big>\n{}{}</Big>\n", head, tail));
+    assert!(project.run(&["--chunks"]).success);
+    let before = chunks(
+        &project.read_text("compiled/0_Big.xml.chunks"),
+        &project.read("compiled/0_Big.xml"),
+    );
+
+    project.write("A/0_Big.xml", format!("<Big>\n{}<New/>\n{}</Big>\n", head, tail));
+    assert!(project.run(&["--chunks"]).success);
+    let after = chunks(
+        &project.read_text("compiled/0_Big.xml.chunks"),
+        &project.read("compiled/0_Big.xml"),
+    );
+    let changed = after.iter().filter(|chunk| !before.iter().any(|old| old.2 == chunk.2)).count();
+    assert!((1..=2).contains(&changed), "{} of {} chunks changed", changed, after.len());
+}
+
+#[test]
+fn manifests_are_only_written_when_asked_for() {
+    let project = Project::new("chunks-optional");
+    project.write("A/0_Small.xml", "<Small/>\n");
+    assert!(project.run(&[]).success);
+    assert!(!project.exists("compiled/0_Small.xml.chunks"));
+
+    assert!(project.run(&["--chunks"]).success);
+    let manifest = project.read_text("compiled/0_Small.xml.chunks");
+    let chunks = chunks(&manifest, b"<Small/>\n");
+    assert_eq!(chunks, [(0, 9, sha256(b"<Small/>\n"))]);
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::Project;
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write("A/0_T.xml", "<Root>\n  <!-- #include file=\"F.xml\" -->\n</Root>\n")
+        .write("A/F.xml", "<F/>\n");
+    project
+}
+
+#[test]
+fn clean_removes_outputs_and_keeps_everything_else() {
+    let project = project("clean-outputs");
+    let run = project.run(&["--json-report", "report.json"]);
+    assert!(run.success, "{}", run.text);
+    project.write("compiled/notes.txt", "mine\n");
+    let run = project.run(&["clean", "--json-report", "report.json"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!project.exists("compiled/0_T.xml"));
+    for path in ["compiled/notes.txt", "report.json", "A/0_T.xml", "A/F.xml"] {
+        assert!(project.exists(path), "{}", path);
+    }
+}
+
+#[test]
+fn clean_all_refuses_to_remove_the_sources() {
+    let project = project("clean-all");
+    assert!(project.run(&[]).success);
+    project.write("compiled/notes.txt", "mine\n");
+    for output_dir in [".", "A"] {
+        let run = project.run(&["clean", "--all", "--output-dir", output_dir]);
+        assert!(!run.success, "{}: {}", output_dir, run.text);
+        assert!(run.text.contains("Refusing to remove"), "{}: {}", output_dir, run.text);
+    }
+    for path in ["A/0_T.xml", "A/F.xml", "compiled/0_T.xml"] {
+        assert!(project.exists(path), "{}", path);
+    }
+    let run = project.run(&["clean", "--all"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!project.exists("compiled"));
+    assert!(project.exists("A/0_T.xml"));
+}
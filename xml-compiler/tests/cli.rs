@@ -0,0 +1,63 @@
+mod common;
+
+use common::Project;
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write("src/A/0_T.xml", "<Root>\n  <!-- #include file=\"F.xml\" -->\n</Root>\n")
+        .write("src/A/F.xml", "<F/>\n");
+    project
+}
+
+#[test]
+fn every_command_has_help() {
+    let project = Project::new("cli-help");
+    let commands = [
+        "build", "watch", "check", "clean", "serve", "replay", "diff", "storage", "preview", "lock",
+        "vendor", "graph diff", "search", "report compare", "completions",
+    ];
+    for command in commands {
+        let mut args: Vec<&str> = command.split(' ').collect();
+        args.push("--help");
+        let run = project.run(&args);
+        assert!(run.success, "{}: {}", command, run.text);
+        assert!(run.text.contains("Usage: KiwiXML"), "{}: {}", command, run.text);
+    }
+}
+
+#[test]
+fn options_go_before_or_after_the_command() {
+    let project = project("cli-order");
+    assert!(project.run(&["--output-dir", "one", "--log", "one.log", "build", "src"]).success);
+    assert!(project.run(&["build", "src", "--output-dir", "two", "--log", "two.log"]).success);
+    for path in ["one/0_T.xml", "one.log", "two/0_T.xml", "two.log"] {
+        assert!(project.exists(path), "{}", path);
+    }
+}
+
+#[test]
+fn the_last_value_given_wins_over_earlier_ones_and_the_config() {
+    let project = project("cli-override");
+    project.write("src/kvy-xmls.toml", "output-dir = \"../config\"\n");
+    assert!(project.run(&["src"]).success);
+    assert!(project.exists("config/0_T.xml"));
+    assert!(project.run(&["src", "--output-dir", "first", "--output-dir", "last"]).success);
+    assert!(project.exists("last/0_T.xml"));
+    assert!(!project.exists("first"));
+}
+
+#[test]
+fn bad_values_name_the_option() {
+    let project = project("cli-errors");
+    for (args, option) in [
+        (&["--jobs", "0"][..], "--jobs"),
+        (&["--engine", "lexer"], "--engine"),
+        (&["--define", "NOVALUE"], "--define"),
+        (&["check", "--daemon"], "--daemon"),
+    ] {
+        let run = project.run(args);
+        assert!(!run.success, "{:?}: {}", args, run.text);
+        assert!(run.text.contains(option), "{:?}: {}", args, run.text);
+    }
+}
@@ -0,0 +1,143 @@
+//! A scratch project for running the `KiwiXML` binary against.
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    thread,
+    time::Duration,
+};
+
+/// A folder of sources under the system temp directory, removed when dropped.
+pub struct Project {
+    pub dir: PathBuf,
+}
+
+impl Project {
+    /// An empty project; `name` keeps tests running in parallel apart.
+    pub fn new(name: &str) -> Project {
+        let dir = std::env::temp_dir()
+            .join("kvy-xmls-tests")
+            .join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Project { dir }
+    }
+
+    /// Writes `content` to `path` below the project, creating folders as needed.
+    pub fn write(&self, path: &str, content: impl AsRef<[u8]>) -> &Project {
+        let path = self.dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        self
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    pub fn read(&self, path: &str) -> Vec<u8> {
+        fs::read(self.dir.join(path))
+            .unwrap_or_else(|err| panic!("cannot read {}: {}", path, err))
+    }
+
+    pub fn read_text(&self, path: &str) -> String {
+        String::from_utf8(self.read(path)).unwrap()
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.dir.join(path).exists()
+    }
+
+    /// Runs the binary in the project folder.
+    pub fn run(&self, args: &[&str]) -> Run {
+        run_in(&self.dir, args)
+    }
+
+    /// Starts the binary in the project folder without waiting for it, for servers.
+    pub fn spawn(&self, args: &[&str]) -> Background {
+        let child = Command::new(env!("CARGO_BIN_EXE_KiwiXML"))
+            .args(args)
+            .current_dir(&self.dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Background { child }
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A running binary, killed when dropped.
+pub struct Background {
+    child: Child,
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A local port nothing listens on right now.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// The whole response of the HTTP server at `addr`, waiting for it to start, to a GET of
+/// `path` with `token` as the bearer token.
+pub fn get(addr: &str, path: &str, token: Option<&str>) -> String {
+    String::from_utf8(get_bytes(addr, path, token)).unwrap()
+}
+
+/// [`get`], for a response that needn't be UTF-8.
+pub fn get_bytes(addr: &str, path: &str, token: Option<&str>) -> Vec<u8> {
+    let mut stream = (0..100)
+        .find_map(|_| {
+            TcpStream::connect(addr).ok().or_else(|| {
+                thread::sleep(Duration::from_millis(50));
+                None
+            })
+        })
+        .expect("server did not start");
+    let auth = token.map(|token| format!("Authorization: Bearer {}\r\n", token));
+    write!(stream, "GET {} HTTP/1.1\r\nHost: x\r\n{}\r\n", path, auth.unwrap_or_default())
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    response
+}
+
+/// What a run of the binary printed, and whether it succeeded.
+pub struct Run {
+    pub success: bool,
+    /// Standard output and standard error together.
+    pub text: String,
+}
+
+pub fn run_in(dir: &Path, args: &[&str]) -> Run {
+    let Output {
+        status,
+        stdout,
+        stderr,
+    } = Command::new(env!("CARGO_BIN_EXE_KiwiXML"))
+        .args(args)
+        .current_dir(dir)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap();
+    Run {
+        success: status.success(),
+        text: String::from_utf8_lossy(&stdout).into_owned() + &String::from_utf8_lossy(&stderr),
+    }
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use common::Project;
+
+#[test]
+fn copies_of_written_outputs_are_skipped() {
+    let project = Project::new("compiled-copy");
+    project
+        .write("A/0_T.xml", "<Root>\n  <!-- #include file=\"Fire.xml\" -->\n</Root>\n")
+        .write("A/Fire.xml", "<Fire>hot</Fire>\n");
+    assert!(project.run(&[]).success);
+    project.write("B/0_Copy.xml", project.read("compiled/0_T.xml"));
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Warning: skipped B/0_Copy.xml"), "{}", run.text);
+    assert!(run.text.contains("identical to an output KiwiXML wrote"), "{}", run.text);
+    assert!(!project.exists("compiled/0_Copy.xml"), "{}", run.text);
+}
+
+#[test]
+fn skipped_copies_do_not_clash_with_their_base_xml() {
+    let project = Project::new("compiled-clash");
+    project
+        .write("A/0_T.xml", "<Root>\n  <!-- #include file=\"Fire.xml\" -->\n</Root>\n")
+        .write("A/Fire.xml", "<Fire>hot</Fire>\n");
+    assert!(project.run(&[]).success);
+    let output = project.read("compiled/0_T.xml");
+    project.write("B/0_T.xml", &output);
+    let run = project.run(&["--force"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Warning: skipped B/0_T.xml"), "{}", run.text);
+    assert_eq!(project.read("compiled/0_T.xml"), output);
+}
+
+#[test]
+fn folders_earlier_builds_wrote_to_are_not_scanned() {
+    let project = Project::new("compiled-earlier");
+    project
+        .write("A/0_T.xml", "<Root>\n  <!-- #include file=\"Fire.xml\" -->\n</Root>\n")
+        .write("A/Fire.xml", "<Fire>hot</Fire>\n")
+        // Left by a build on another machine, before written outputs were recorded.
+        .write("Compiled/0_T.xml", "<Root>\n  <Fire>hot</Fire>\n</Root>\n");
+    let run = project.run(&["--output-dir", "first"]);
+    assert!(run.success, "{}", run.text);
+    let run = project.run(&["--output-dir", "second"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("skipped"), "{}", run.text);
+    assert_eq!(project.read("second/0_T.xml"), project.read("first/0_T.xml"));
+}
+
+#[test]
+fn base_xml_without_directives_is_not_its_own_copy() {
+    let project = Project::new("compiled-identity");
+    project.write("A/0_Plain.xml", "<Root>plain</Root>\n");
+    assert!(project.run(&[]).success);
+    let run = project.run(&["--force"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("looks compiled"), "{}", run.text);
+    assert!(!run.text.contains("identical to an output"), "{}", run.text);
+}
+
+#[test]
+fn identical_base_xmls_without_directives_are_not_copies() {
+    let project = Project::new("compiled-twins");
+    project.write("A/0_One.xml", "<Root/>\n").write("B/0_Two.xml", "<Root/>\n");
+    assert!(project.run(&[]).success);
+    let run = project.run(&["--force"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("identical to an output"), "{}", run.text);
+}
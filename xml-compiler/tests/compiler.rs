@@ -0,0 +1,17 @@
+mod common;
+
+use common::Project;
+use kvy_xmls::{set_log_path, Compiler};
+
+#[test]
+fn the_json_report_follows_the_builders_output_dir() {
+    let project = Project::new("builder-report");
+    project.write("A/0_T.xml", "<Root/>\n");
+    set_log_path(project.path("processing.log"));
+    let compiler =
+        Compiler::builder(&project.dir).json_report(true).output_dir("out").build().unwrap();
+    compiler.run().unwrap();
+    assert!(project.exists("out/0_T.xml"));
+    assert!(project.exists("out/report.json"));
+    assert!(!project.exists("compiled"));
+}
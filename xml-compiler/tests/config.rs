@@ -0,0 +1,62 @@
+mod common;
+
+use common::Project;
+
+#[test]
+fn full_toml_syntax_is_accepted() {
+    let project = Project::new("config-syntax");
+    project
+        .write(
+            "kvy-xmls.toml",
+            concat!(
+                "include-paths = [\n",
+                "    \"Shared\",  # fragments\n",
+                "    \"More\",\n",
+                "]\n",
+                "defines = { env = \"prod\", tier = 2 }\n",
+                "\n",
+                "[folders.\"B\"]\n",
+                "defines.env = '''\n",
+                "staging'''\n",
+            ),
+        )
+        .write(
+            "A/0_A.xml",
+            concat!(
+                "<Root env=\"@{env}\" tier=\"@{tier}\">\n",
+                "  <!-- #include file=\"Part.xml\" -->\n",
+                "</Root>\n",
+            ),
+        )
+        .write("B/0_B.xml", "<Root env=\"@{env}\"/>\n")
+        .write("Shared/Part.xml", "<Part/>\n");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    let a = project.read_text("compiled/0_A.xml");
+    assert!(a.contains("env=\"prod\" tier=\"2\""), "{}", a);
+    assert!(a.contains("<Part/>"), "{}", a);
+    assert!(project.read_text("compiled/0_B.xml").contains("env=\"staging\""));
+}
+
+#[test]
+fn syntax_errors_name_the_line() {
+    let project = Project::new("config-error");
+    project
+        .write("kvy-xmls.toml", "jobs = 2\n\nstrict = = true\n")
+        .write("A/0_A.xml", "<Root/>\n");
+    let run = project.run(&[]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("kvy-xmls.toml:3:"), "{}", run.text);
+}
+
+#[test]
+fn wrong_types_and_unknown_keys_are_errors() {
+    let project = Project::new("config-types");
+    project.write("A/0_A.xml", "<Root/>\n");
+    project.write("kvy-xmls.toml", "jobs = \"four\"\n");
+    let run = project.run(&[]);
+    assert!(run.text.contains("jobs must be a number"), "{}", run.text);
+    project.write("kvy-xmls.toml", "colour = \"blue\"\n");
+    let run = project.run(&[]);
+    assert!(run.text.contains("unknown setting colour"), "{}", run.text);
+}
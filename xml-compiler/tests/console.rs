@@ -0,0 +1,13 @@
+mod common;
+
+use common::Project;
+
+#[test]
+fn warnings_are_labelled_once() {
+    let project = Project::new("console-warning");
+    project.write("A/0_T.xml", "<Root>\n  <!-- #include file=\"none/*.xml\" -->\n</Root>\n");
+    let run = project.run(&[]);
+    assert!(run.text.contains("warning: "), "{}", run.text);
+    assert!(run.text.contains("no files match none/*.xml"), "{}", run.text);
+    assert!(!run.text.contains("warning: Warning:"), "{}", run.text);
+}
@@ -0,0 +1,98 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use common::{Project, Run};
+
+/// Runs `--client` against the daemon at `socket`, sending `token` if there is one.
+fn client(project: &Project, socket: &str, token: Option<&str>) -> Run {
+    client_with(project, socket, token, &[], &[])
+}
+
+/// [`client`], with more compile arguments and environment variables for the client.
+fn client_with(
+    project: &Project,
+    socket: &str,
+    token: Option<&str>,
+    args: &[&str],
+    vars: &[(&str, &str)],
+) -> Run {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_KiwiXML"));
+    command.args(["--client", "--addr", socket, "src"]).args(args).current_dir(&project.dir);
+    command.env("RUST_BACKTRACE", "0").env_remove("KIWIXML_TOKEN").envs(vars.iter().copied());
+    if let Some(token) = token {
+        command.env("KIWIXML_TOKEN", token);
+    }
+    let output = command.output().unwrap();
+    Run {
+        success: output.status.success(),
+        text: String::from_utf8_lossy(&output.stdout).into_owned()
+            + &String::from_utf8_lossy(&output.stderr),
+    }
+}
+
+fn wait_for_socket(project: &Project, socket: &str) {
+    let started = (0..100).any(|_| {
+        thread::sleep(Duration::from_millis(50));
+        fs::symlink_metadata(project.path(socket)).is_ok_and(|meta| meta.file_type().is_socket())
+    });
+    assert!(started, "daemon did not start");
+}
+
+#[test]
+fn the_socket_is_private_to_the_user() {
+    let project = Project::new("daemon-socket");
+    project.write("src/A/0_T.xml", "<Root/>\n");
+    let _daemon = project.spawn(&["--daemon", "--addr", "d.sock"]);
+    wait_for_socket(&project, "d.sock");
+    let mode = fs::metadata(project.path("d.sock")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let run = client(&project, "d.sock", None);
+    assert!(run.success, "{}", run.text);
+    assert!(project.exists("src/compiled/0_T.xml"));
+}
+
+#[test]
+fn requests_without_a_compile_token_are_rejected() {
+    let project = Project::new("daemon-tokens");
+    project
+        .write("tokens.txt", "compile pipeline\nread reader\n")
+        .write("src/A/0_T.xml", "<Root/>\n");
+    let _daemon = project.spawn(&["--daemon", "--addr", "d.sock", "--tokens", "tokens.txt"]);
+    wait_for_socket(&project, "d.sock");
+    for token in [None, Some("wrong"), Some("reader")] {
+        let run = client(&project, "d.sock", token);
+        assert!(!run.success, "{:?}: {}", token, run.text);
+        assert!(run.text.contains("unauthorized"), "{:?}: {}", token, run.text);
+    }
+    assert!(!project.exists("src/compiled"));
+    let run = client(&project, "d.sock", Some("pipeline"));
+    assert!(run.success, "{}", run.text);
+    assert!(project.exists("src/compiled/0_T.xml"));
+}
+
+#[test]
+fn the_client_gets_the_console_output_and_its_environment_is_used() {
+    let project = Project::new("daemon-console");
+    project
+        .write("src/A/0_T.xml", "<Root env=\"@{env}\"/>\n")
+        .write("src/B/0_Loop.xml", "<Root><!-- #include file=\"0_Loop.xml\" --></Root>\n");
+    let _daemon = project.spawn(&["--daemon", "--addr", "d.sock"]);
+    wait_for_socket(&project, "d.sock");
+    let vars = [("KX_TEST_env", "from the client")];
+    let run = client_with(&project, "d.sock", None, &["--env-prefix", "KX_TEST_"], &vars);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("Compiled A/0_T.xml"), "{}", run.text);
+    assert!(run.text.contains("Failed targets:\n  B/0_Loop.xml: "), "{}", run.text);
+    assert!(run.text.contains("Daemon reported: 1 of 2 targets failed"), "{}", run.text);
+    let output = project.read_text("src/compiled/0_T.xml");
+    assert!(output.contains("env=\"from the client\""), "{}", output);
+}
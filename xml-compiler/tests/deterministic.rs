@@ -0,0 +1,42 @@
+mod common;
+
+use common::Project;
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write(
+            "A/0_Skills.xml",
+            "<Root name=\"Zürich\">\n  <!-- #include file=\"Fire.xml\" -->\n</Root>\n",
+        )
+        .write("A/Fire.xml", "<placeholder>\n<Skill price=\"5€\"/>\n</placeholder>\n");
+    project
+}
+
+#[test]
+fn verify_deterministic_hashes_the_encoded_output() {
+    for encoding in ["utf-16", "utf-16be", "windows-1252", "iso-8859-1"] {
+        let project = project(&format!("verify-{}", encoding));
+        let run = project.run(&["--verify-deterministic", "--output-encoding", encoding]);
+        assert!(run.success, "--output-encoding {}:\n{}", encoding, run.text);
+        assert!(!run.text.contains("ondeterministic"), "{}", run.text);
+    }
+}
+
+#[test]
+fn verify_deterministic_renders_partitioned_outputs_for_their_original_path() {
+    let project = Project::new("verify-partition");
+    project
+        .write("A/0_Eu.xml", "<Root>\n  <Region>EU</Region>\n</Root>\n")
+        .write("header.txt", "{output} on {date}\n");
+    let args = ["--partition-by", "//Region", "--header", "header.txt", "--verify-deterministic"];
+    let run = project.run(&args);
+    assert!(run.success, "{}", run.text);
+    let output = project.read_text("compiled/EU/0_Eu.xml");
+    assert!(output.contains("compiled/0_Eu.xml on "), "{}", output);
+
+    // Reused from the build cache, the target is verified against the header it was built with.
+    let run = project.run(&args);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("up to date"), "{}", run.text);
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use common::Project;
+
+#[test]
+fn utf8_byte_order_marks_are_dropped() {
+    let project = Project::new("utf8-bom");
+    project
+        .write("A/0_T.xml", "\u{FEFF}<Root>\n  <!-- #include file=\"Part.xml\" -->\n</Root>\n")
+        .write("A/Part.xml", "\u{FEFF}<placeholder>\n<Part/>\n</placeholder>\n");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    let output = project.read_text("compiled/0_T.xml");
+    assert!(!output.contains('\u{FEFF}'), "{:?}", output);
+    assert!(output.starts_with("<Root>"), "{:?}", output);
+}
+
+/// `bytes` decoded from `encoding`, as one of the charsets KiwiXML writes.
+fn decode(bytes: &[u8], encoding: &str) -> String {
+    let units = |bytes: &[u8], unit: fn([u8; 2]) -> u16| -> Vec<u16> {
+        bytes.chunks(2).map(|pair| unit([pair[0], pair[1]])).collect()
+    };
+    match encoding {
+        "utf-16le" => String::from_utf16(&units(&bytes[2..], u16::from_le_bytes)).unwrap(),
+        "utf-16be" => String::from_utf16(&units(&bytes[2..], u16::from_be_bytes)).unwrap(),
+        // Both agree with Unicode for the characters used here.
+        _ => bytes.iter().map(|&byte| char::from(byte)).collect(),
+    }
+}
+
+#[test]
+fn declared_and_byte_order_marked_sources_are_transcoded() {
+    let project = Project::new("encoding-sources");
+    project
+        .write(
+            "A/0_T.xml",
+            b"<?xml version=\"1.0\" encoding=\"windows-1252\"?>\n<Root city=\"Z\xfcrich\">\n  \
+              <!-- #include file=\"Part.xml\" -->\n</Root>\n",
+        )
+        .write(
+            "A/Part.xml",
+            [&[0xFF, 0xFE][..], &"<placeholder><Caf\u{e9}/></placeholder>\n"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<u8>>()]
+            .concat(),
+        );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    let output = project.read_text("compiled/0_T.xml");
+    assert!(output.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"), "{:?}", output);
+    assert!(output.contains("city=\"Z\u{fc}rich\""), "{:?}", output);
+    assert!(output.contains("<Caf\u{e9}/>"), "{:?}", output);
+}
+
+#[test]
+fn outputs_round_trip_through_every_output_encoding() {
+    let project = Project::new("encoding-outputs");
+    project
+        .write(
+            "src/A/0_T.xml",
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Root name=\"Caf\u{e9}\">\n  \
+             <!-- #include file=\"Part.xml\" -->\n</Root>\n",
+        )
+        .write("src/A/Part.xml", "<placeholder>\n<Z\u{fc}rich/>\n</placeholder>\n");
+    assert!(project.run(&["src", "--output-dir", "utf-8"]).success);
+    let expected = project.read_text("utf-8/0_T.xml");
+    assert!(expected.contains("<Z\u{fc}rich/>"), "{:?}", expected);
+    for (encoding, label, start) in [
+        ("utf-16le", "UTF-16", &[0xFF, 0xFE][..]),
+        ("utf-16be", "UTF-16", &[0xFE, 0xFF]),
+        ("windows-1252", "windows-1252", b"<?xml"),
+        ("iso-8859-1", "ISO-8859-1", b"<?xml"),
+    ] {
+        let run = project.run(&["src", "--output-encoding", encoding, "--output-dir", encoding]);
+        assert!(run.success, "{}: {}", encoding, run.text);
+        let bytes = project.read(&format!("{}/0_T.xml", encoding));
+        assert!(bytes.starts_with(start), "{}: {:?}", encoding, bytes);
+        let output = decode(&bytes, encoding);
+        let declaration = format!("<?xml version=\"1.0\" encoding=\"{}\"?>", label);
+        assert_eq!(
+            output.replacen(&declaration, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>", 1),
+            expected,
+            "{}",
+            encoding
+        );
+    }
+}
+
+#[test]
+fn characters_the_output_encoding_lacks_become_references() {
+    let project = Project::new("encoding-references");
+    project.write("A/0_T.xml", "<Root>A \u{2192} B, \u{e9}</Root>\n");
+    let run = project.run(&["--output-encoding", "windows-1252"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("1 characters not in windows-1252"), "{}", run.text);
+    let output = project.read("compiled/0_T.xml");
+    assert!(output.ends_with(b"\n<Root>A &#x2192; B, \xe9</Root>\n"), "{:?}", output);
+}
+
+#[test]
+fn undeclared_sources_that_are_not_utf8_need_an_input_encoding() {
+    let project = Project::new("encoding-input");
+    project.write("A/0_T.xml", b"<Root>Z\xfcrich</Root>\n");
+    let run = project.run(&[]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("--input-encoding"), "{}", run.text);
+    let run = project.run(&["--input-encoding", "iso-8859-1"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(project.read_text("compiled/0_T.xml"), "<Root>Z\u{fc}rich</Root>\n");
+}
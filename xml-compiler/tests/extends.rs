@@ -0,0 +1,178 @@
+mod common;
+
+use common::Project;
+
+const BASE: &str = concat!(
+    "<Skill>\n",
+    "  <!-- #block stats -->\n",
+    "  <Damage>10</Damage>\n",
+    "  <!-- #endblock -->\n",
+    "  <!-- #include file=\"parts/effects.xml\" -->\n",
+    "</Skill>\n",
+);
+
+#[test]
+fn a_target_overrides_the_blocks_of_its_base() {
+    let project = Project::new("extends-override");
+    project
+        .write("A/base_skill.xml", BASE)
+        .write("A/parts/effects.xml", "<placeholder>\n<Burn/>\n</placeholder>\n")
+        .write(
+            "A/0_Fire.xml",
+            concat!(
+                "<!-- #extends file=\"base_skill.xml\" -->\n",
+                "<Ignored/>\n",
+                "<!-- #block stats -->\n",
+                "  <Damage>25</Damage>\n",
+                "<!-- #endblock -->\n",
+            ),
+        )
+        .write("A/0_Plain.xml", "<!-- #extends file=\"base_skill.xml\" -->\n");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_Fire.xml"),
+        "<Skill>\n  <Damage>25</Damage>\n  <![CDATA[\n<Burn/>\n]]>\n</Skill>\n"
+    );
+    assert_eq!(
+        project.read_text("compiled/0_Plain.xml"),
+        "<Skill>\n  <Damage>10</Damage>\n  <![CDATA[\n<Burn/>\n]]>\n</Skill>\n"
+    );
+
+    // The base is an input of the targets extending it.
+    project.write("A/base_skill.xml", BASE.replace("<Skill>", "<Skill id=\"2\">"));
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("compiled/0_Fire.xml").starts_with("<Skill id=\"2\">"));
+}
+
+#[test]
+fn bases_chain_and_resolve_includes_where_they_are_written() {
+    let project = Project::new("extends-chain");
+    project
+        .write(
+            "Bases/root.xml",
+            concat!(
+                "<Unit>\n",
+                "  <!-- #block name --><Name>Unit</Name><!-- #endblock -->\n",
+                "  <!-- #block body -->\n",
+                "  <!-- #include file=\"parts/body.xml\" -->\n",
+                "  <!-- #endblock -->\n",
+                "</Unit>\n",
+            ),
+        )
+        .write("Bases/parts/body.xml", "<placeholder>\n<Body/>\n</placeholder>\n")
+        .write(
+            "Bases/Middle/soldier.xml",
+            concat!(
+                "<!-- #extends file=\"../root.xml\" -->\n",
+                "<!-- #block name --><Name>Soldier</Name><!-- #endblock -->\n",
+            ),
+        )
+        .write(
+            "A/0_Archer.xml",
+            concat!(
+                "<!-- #extends file=\"../Bases/Middle/soldier.xml\" -->\n",
+                "<!-- #block body -->\n",
+                "  <!-- #include file=\"bow.xml\" -->\n",
+                "<!-- #endblock -->\n",
+            ),
+        )
+        .write("A/bow.xml", "<placeholder>\n<Bow/>\n</placeholder>\n")
+        .write("A/0_Soldier.xml", "<!-- #extends file=\"../Bases/Middle/soldier.xml\" -->\n");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_Archer.xml"),
+        "<Unit>\n  <Name>Soldier</Name>\n  <![CDATA[\n<Bow/>\n]]>\n</Unit>\n"
+    );
+    assert_eq!(
+        project.read_text("compiled/0_Soldier.xml"),
+        "<Unit>\n  <Name>Soldier</Name>\n  <![CDATA[\n<Body/>\n]]>\n</Unit>\n"
+    );
+}
+
+#[test]
+fn blocks_nest_and_their_markers_never_reach_the_output() {
+    let project = Project::new("extends-nested");
+    let base = concat!(
+        "<Item>\n",
+        "  <!-- #block all -->\n",
+        "  <Cost><!-- #block cost -->5<!-- #endblock --></Cost>\n",
+        "  <Tags>a <!-- #block tag -->b<!-- #endblock --></Tags>\n",
+        "  <!-- #endblock -->\n",
+        "</Item>\n",
+    );
+    project
+        .write("A/0_Base.xml", base)
+        .write(
+            "A/0_Cheap.xml",
+            "<!-- #extends file=\"0_Base.xml\" -->\n<!-- #block cost -->1<!-- #endblock -->\n",
+        )
+        .write(
+            "A/0_Empty.xml",
+            "<!-- #extends file=\"0_Base.xml\" -->\n<!-- #block all --><!-- #endblock -->\n",
+        );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_Base.xml"),
+        "<Item>\n  <Cost>5</Cost>\n  <Tags>a b</Tags>\n</Item>\n"
+    );
+    assert_eq!(
+        project.read_text("compiled/0_Cheap.xml"),
+        "<Item>\n  <Cost>1</Cost>\n  <Tags>a b</Tags>\n</Item>\n"
+    );
+    assert_eq!(project.read_text("compiled/0_Empty.xml"), "<Item>\n</Item>\n");
+}
+
+#[test]
+fn a_block_the_base_lacks_is_a_warning() {
+    let project = Project::new("extends-unknown-block");
+    project
+        .write("A/base_skill.xml", BASE)
+        .write("A/parts/effects.xml", "<placeholder>\n<Burn/>\n</placeholder>\n")
+        .write(
+            "A/0_Fire.xml",
+            "<!-- #extends file=\"base_skill.xml\" -->\n<!-- #block cost -->1<!-- #endblock -->\n",
+        );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("block cost is not in "), "{}", run.text);
+    assert!(project.read_text("compiled/0_Fire.xml").contains("<Damage>10</Damage>"));
+}
+
+#[test]
+fn malformed_blocks_fail_the_target_with_their_line() {
+    let cases = [
+        ("\n<!-- #block stats -->\n", "0_T.xml:3: block directive has no matching #endblock"),
+        ("\n\n<!-- #endblock -->\n", "0_T.xml:4: block directive has no matching #block"),
+        ("<!-- #block -->\n<!-- #endblock -->\n", "0_T.xml:2: block directive needs a name"),
+        (
+            "<!-- #block stats --><!-- #endblock -->\n<!-- #block stats --><!-- #endblock -->\n",
+            "0_T.xml:3: block directive is defined twice",
+        ),
+    ];
+    for (blocks, error) in cases {
+        let project = Project::new("extends-malformed");
+        project
+            .write("A/base_skill.xml", BASE)
+            .write("A/parts/effects.xml", "<placeholder>\n<Burn/>\n</placeholder>\n")
+            .write("A/0_T.xml", format!("<!-- #extends file=\"base_skill.xml\" -->\n{}", blocks));
+        let run = project.run(&[]);
+        assert!(!run.success, "{}: {}", blocks, run.text);
+        assert!(run.text.contains(error), "{}: {}", blocks, run.text);
+    }
+}
+
+#[test]
+fn an_extends_chain_back_to_itself_is_a_cycle() {
+    let project = Project::new("extends-cycle");
+    project
+        .write("A/0_T.xml", "<!-- #extends file=\"one.xml\" -->\n")
+        .write("A/one.xml", "<!-- #extends file=\"two.xml\" -->\n")
+        .write("A/two.xml", "<!-- #extends file=\"one.xml\" -->\n");
+    let run = project.run(&[]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("include cycle: "), "{}", run.text);
+}
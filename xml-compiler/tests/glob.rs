@@ -0,0 +1,110 @@
+mod common;
+
+use common::Project;
+
+const ENGINES: [&str; 2] = ["regex", "parser"];
+
+fn fragment(name: &str) -> String {
+    format!("<placeholder>\n<{}/>\n</placeholder>\n", name)
+}
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write("A/items/b.xml", fragment("b"))
+        .write("A/items/a.xml", fragment("a"))
+        .write("A/items/sub/s.xml", fragment("s"))
+        .write("A/items/sub/deep/d.xml", fragment("d"))
+        .write("A/items/.h.xml", fragment("h"))
+        .write("A/items/.hidden/x.xml", fragment("x"));
+    project
+}
+
+/// The elements of the fragments in `output`, in order.
+fn included(output: &str) -> Vec<&str> {
+    output.split("<![CDATA[\n").skip(1).map(|part| &part[1..part.find("/>").unwrap()]).collect()
+}
+
+#[test]
+fn matches_are_included_in_sorted_order() {
+    for engine in ENGINES {
+        let project = project(&format!("glob-order-{}", engine));
+        for (pattern, expected) in [
+            ("items/*.xml", &["a", "b"][..]),
+            ("items/?.xml", &["a", "b"]),
+            ("items/**/*.xml", &["a", "b", "d", "s"]),
+            ("items/.*.xml", &["h"]),
+            ("items/.*/*.xml", &["x"]),
+        ] {
+            let include = format!("<!-- #include file=\"{}\" -->", pattern);
+            project.write("A/0_T.xml", format!("<T>\n  {}\n</T>\n", include));
+            let run = project.run(&["--engine", engine]);
+            assert!(run.success, "{} {}: {}", engine, pattern, run.text);
+            let output = project.read_text("compiled/0_T.xml");
+            assert_eq!(included(&output), expected, "{} {}: {}", engine, pattern, output);
+        }
+    }
+}
+
+#[test]
+fn each_match_lands_where_the_directive_was() {
+    let project = project("glob-placement");
+    project.write(
+        "A/0_T.xml",
+        concat!(
+            "<T>\n",
+            "  <!-- #include file=\"items/*.xml\" -->\n",
+            "  <X><!-- #include file=\"items/?.xml\" --></X>\n",
+            "</T>\n",
+        ),
+    );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_T.xml"),
+        concat!(
+            "<T>\n",
+            "  <![CDATA[\n<a/>\n]]>\n",
+            "  <![CDATA[\n<b/>\n]]>\n",
+            "  <X><![CDATA[\n<a/>\n]]><![CDATA[\n<b/>\n]]></X>\n",
+            "</T>\n",
+        )
+    );
+}
+
+#[test]
+fn a_pattern_matching_nothing_leaves_a_comment_and_a_warning() {
+    let project = project("glob-none");
+    project.write(
+        "A/0_T.xml",
+        "<T>\n  <!-- #include file=\"none/*.xml\" -->\n  <!-- #include file=\"*.xml\" -->\n</T>\n",
+    );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("0_T.xml: no files match none/*.xml"), "{}", run.text);
+    // The target itself is the only match of the second pattern.
+    assert!(run.text.contains("0_T.xml: no files match *.xml"), "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_T.xml"),
+        "<T>\n  <!-- No files match: none/*.xml -->\n  <!-- No files match: *.xml -->\n</T>\n"
+    );
+}
+
+#[test]
+fn adding_or_removing_a_match_rebuilds() {
+    let project = project("glob-cache");
+    project.write("A/0_T.xml", "<T>\n  <!-- #include file=\"items/*.xml\" -->\n</T>\n");
+    assert!(project.run(&[]).success);
+    let run = project.run(&[]);
+    assert!(run.text.contains("Up to date A/0_T.xml"), "{}", run.text);
+
+    project.write("A/items/c.xml", fragment("c"));
+    let run = project.run(&[]);
+    assert!(run.text.contains("Compiled A/0_T.xml"), "{}", run.text);
+    assert_eq!(included(&project.read_text("compiled/0_T.xml")), ["a", "b", "c"]);
+
+    std::fs::remove_file(project.path("A/items/a.xml")).unwrap();
+    let run = project.run(&[]);
+    assert!(run.text.contains("Compiled A/0_T.xml"), "{}", run.text);
+    assert_eq!(included(&project.read_text("compiled/0_T.xml")), ["b", "c"]);
+}
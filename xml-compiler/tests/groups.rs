@@ -0,0 +1,37 @@
+mod common;
+
+use common::Project;
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write("kvy-xmls.toml", "[groups]\npricing = [\"3_*.xml\", \"KFM/4_*.xml\"]\n")
+        .write("KFM/3_Prices.xml", "<Root/>\n")
+        .write("KFM/4_Offers.xml", "<Root/>\n")
+        .write("SF/3_Prices.xml", "<Root/>\n")
+        .write("SF/4_Offers.xml", "<Root/>\n")
+        .write("SF/0_Skills.xml", "<Root/>\n");
+    project
+}
+
+#[test]
+fn only_the_group_is_compiled() {
+    let project = project("groups-select");
+    let run = project.run(&["--group", "pricing", "--preserve-layout"]);
+    assert!(run.success, "{}", run.text);
+    assert!(project.exists("compiled/KFM/3_Prices.xml"));
+    assert!(project.exists("compiled/KFM/4_Offers.xml"));
+    assert!(project.exists("compiled/SF/3_Prices.xml"));
+    assert!(!project.exists("compiled/SF/4_Offers.xml"));
+    assert!(!project.exists("compiled/SF/0_Skills.xml"));
+}
+
+#[test]
+fn unknown_groups_are_errors() {
+    let project = project("groups-unknown");
+    let run = project.run(&["--group", "billing"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("Unknown group billing"), "{}", run.text);
+    assert!(run.text.contains("defines: pricing"), "{}", run.text);
+    assert!(!project.exists("compiled"));
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::Project;
+
+const ENGINES: [&str; 2] = ["regex", "parser"];
+
+fn include(file: &str) -> String {
+    format!("<!-- #include file=\"{}\" -->", file)
+}
+
+#[test]
+fn include_cycles_fail_unless_commented_out() {
+    for engine in ENGINES {
+        let project = Project::new(&format!("includes-cycle-{}", engine));
+        project
+            .write("A/0_T.xml", format!("<Root>\n  {}\n</Root>\n", include("a.xml")))
+            .write("A/a.xml", format!("<placeholder>\n{}\n</placeholder>\n", include("b.xml")))
+            .write("A/b.xml", format!("<placeholder>\n{}\n</placeholder>\n", include("a.xml")));
+        let cycle = "include cycle: 0_T.xml -> a.xml -> b.xml -> a.xml";
+        let run = project.run(&["--engine", engine]);
+        assert!(!run.success, "--engine {}: {}", engine, run.text);
+        assert!(run.text.contains(cycle), "--engine {}: {}", engine, run.text);
+        let run = project.run(&["--engine", engine, "--on-cycle", "comment"]);
+        assert!(run.success, "--engine {}: {}", engine, run.text);
+        assert!(run.text.contains(cycle), "--engine {}: {}", engine, run.text);
+    }
+}
+
+#[test]
+fn includes_nested_or_grown_past_the_limits_fail() {
+    for engine in ENGINES {
+        let project = Project::new(&format!("includes-limits-{}", engine));
+        project
+            .write("A/0_T.xml", format!("<Root>\n  {}\n</Root>\n", include("a.xml")))
+            .write("A/a.xml", format!("<placeholder>\n{}\n</placeholder>\n", include("b.xml")))
+            .write("A/b.xml", "<placeholder>\n<B/>\n</placeholder>\n");
+        for (args, error) in [
+            (["--max-include-depth", "1"], "includes nest more than 1 levels deep"),
+            (["--max-expanded-size", "10"], "includes add up to more than 10 bytes"),
+        ] {
+            let run = project.run(&[&["--engine", engine][..], &args].concat());
+            assert!(!run.success, "--engine {} {:?}: {}", engine, args, run.text);
+            assert!(run.text.contains(error), "--engine {} {:?}: {}", engine, args, run.text);
+        }
+        let run = project.run(&["--engine", engine, "--max-include-depth", "2"]);
+        assert!(run.success, "--engine {}: {}", engine, run.text);
+    }
+}
+
+#[test]
+fn inline_includes_are_escaped_where_they_land() {
+    let project = Project::new("includes-inline");
+    let base = format!("<Root desc=\"{0}\">\n  <Text>{0}</Text>\n</Root>\n", include("F.xml"));
+    project
+        .write("A/0_T.xml", base.as_str())
+        .write("A/F.xml", "<placeholder>a & \"b\" <c></placeholder>\n");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    let output = project.read_text("compiled/0_T.xml");
+    assert!(output.contains("desc=\"a &amp; &quot;b&quot; &lt;c>\""), "{}", output);
+    assert!(output.contains("<Text><![CDATA["), "{}", output);
+    let run = project.run(&["--inline-includes", "error"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains(":1: include directive inside an attribute value"), "{}", run.text);
+    let run = project.run(&["--inline-includes", "ignore", "--force"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(project.read_text("compiled/0_T.xml"), base);
+}
+
+#[test]
+fn the_parser_leaves_directives_in_attribute_values_as_written() {
+    let project = Project::new("includes-attribute-parser");
+    let directive = include("F.xml");
+    project
+        .write("A/0_T.xml", format!("<Root desc=\"{0}\">\n  {0}\n</Root>\n", directive))
+        .write("A/F.xml", "<placeholder>\n<F/>\n</placeholder>\n");
+    let run = project.run(&["--engine", "parser"]);
+    assert!(run.success, "{}", run.text);
+    let output = project.read_text("compiled/0_T.xml");
+    assert!(output.contains(&format!("desc=\"{}\"", directive)), "{}", output);
+    assert!(output.contains("<F/>"), "{}", output);
+    let run = project.run(&["--engine", "parser", "--inline-includes", "error"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains(":1: include directive inside an attribute value"), "{}", run.text);
+}
+
+#[test]
+fn escaped_directives_are_written_literally() {
+    for engine in ENGINES {
+        let project = Project::new(&format!("includes-literal-{}", engine));
+        project
+            .write(
+                "A/0_T.xml",
+                format!(
+                    "<Root>\n  <!-- ##include file=\"X.xml\" -->\n  {}\n</Root>\n",
+                    include("F.xml")
+                ),
+            )
+            .write(
+                "A/F.xml",
+                "<placeholder>\n<F>a]]>b</F>\n<!-- ###include file=\"Y.xml\" -->\n</placeholder>\n",
+            );
+        let run = project.run(&["--engine", engine]);
+        assert!(run.success, "--engine {}: {}", engine, run.text);
+        let output = project.read_text("compiled/0_T.xml");
+        for expected in [
+            "<!-- #include file=\"X.xml\" -->",
+            "<!-- ##include file=\"Y.xml\" -->",
+            "a]]]]><![CDATA[>b",
+        ] {
+            assert!(output.contains(expected), "--engine {}: {}", engine, output);
+        }
+    }
+}
+
+#[test]
+fn strict_fails_only_targets_missing_an_include() {
+    let project = Project::new("strict");
+    project
+        .write("A/0_T.xml", "<Root v=\"@{undefined}\"/>\n")
+        .write("B/0_U.xml", format!("<Root>\n{}\n</Root>\n", include("Gone.xml")))
+        .write("C/0_V.xml", format!("<Root>\n{}\n</Root>\n", include("Folder.xml")))
+        .write("C/Folder.xml/Part.xml", "<Part/>\n");
+    for engine in ENGINES {
+        let run = project.run(&["--strict", "--engine", engine]);
+        assert!(!run.success, "{}: {}", engine, run.text);
+        assert!(run.text.contains("2 of 3 targets failed"), "{}: {}", engine, run.text);
+        assert!(project.read_text("compiled/0_T.xml").contains("@{undefined}"));
+        assert!(!project.exists("compiled/0_U.xml"), "{}", engine);
+        assert!(!project.exists("compiled/0_V.xml"), "{}", engine);
+    }
+
+    let run = project.run(&["--strict", "--on-warnings", "quarantine"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(project.exists("quarantine/0_U.xml"));
+    assert!(project.exists("quarantine/0_V.xml"));
+}
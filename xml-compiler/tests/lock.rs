@@ -0,0 +1,82 @@
+mod common;
+
+use common::{run_in, Project, Run};
+
+/// A product in `P` using the shared library in `Shared` next to it.
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write(
+            "P/A/0_T.xml",
+            concat!(
+                "<T>\n",
+                "  <!-- #include file=\"../../Shared/Wolf/Main.xml\" -->\n",
+                "  <!-- #include file=\"local.xml\" -->\n",
+                "</T>\n",
+            ),
+        )
+        .write("P/A/local.xml", "<placeholder>\n<Local/>\n</placeholder>\n")
+        .write(
+            "Shared/Wolf/Main.xml",
+            "<placeholder>\n<!-- #include file=\"Human.xml\" -->\n</placeholder>\n",
+        )
+        .write("Shared/Wolf/Human.xml", "<placeholder>\n<Human/>\n</placeholder>\n");
+    project
+}
+
+fn run(project: &Project, args: &[&str]) -> Run {
+    run_in(&project.path("P"), &[args, &["--include-root", "../Shared"]].concat())
+}
+
+#[test]
+fn lock_records_every_fragment_without_compiling() {
+    let project = project("lock-write");
+    let run = run(&project, &["lock"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Locked 3 fragments in "), "{}", run.text);
+    assert!(!project.exists("P/compiled"));
+    let lock = project.read_text("P/kiwixml.lock");
+    let paths: Vec<&str> =
+        lock.lines().filter(|line| !line.starts_with('#')).map(|line| &line[66..]).collect();
+    assert_eq!(paths, ["../Shared/Wolf/Human.xml", "../Shared/Wolf/Main.xml", "A/local.xml"]);
+
+    let run = self::run(&project, &["lock", "--lockfile", "other.lock"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(project.read_text("P/other.lock"), lock);
+}
+
+#[test]
+fn locked_builds_fail_targets_whose_fragments_changed() {
+    let project = project("lock-verify");
+    assert!(run(&project, &["lock"]).success);
+    let run = self::run(&project, &["--locked"]);
+    assert!(run.success, "{}", run.text);
+    let previous = project.read_text("P/compiled/0_T.xml");
+
+    project
+        .write("Shared/Wolf/Human.xml", "<placeholder>\n<Changed/>\n</placeholder>\n")
+        .write("P/A/0_U.xml", "<U>\n  <!-- #include file=\"new.xml\" -->\n</U>\n")
+        .write("P/A/new.xml", "<placeholder/>\n");
+    let run = self::run(&project, &["--locked"]);
+    assert!(!run.success, "{}", run.text);
+    let changed = "Wolf/Human.xml has changed since the lockfile was written, see --locked";
+    assert!(run.text.contains(changed), "{}", run.text);
+    assert!(run.text.contains("new.xml is not in the lockfile, see --locked"), "{}", run.text);
+    assert_eq!(project.read_text("P/compiled/0_T.xml"), previous);
+    assert!(!project.exists("P/compiled/0_U.xml"));
+
+    // Without --locked the lockfile is not consulted; locking again accepts the changes.
+    assert!(self::run(&project, &[]).success);
+    assert!(self::run(&project, &["lock"]).success);
+    let run = self::run(&project, &["--locked"]);
+    assert!(run.success, "{}", run.text);
+}
+
+#[test]
+fn locked_builds_need_a_lockfile() {
+    let project = project("lock-missing");
+    let run = run(&project, &["--locked"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("Cannot read lockfile "), "{}", run.text);
+    assert!(run.text.contains("run `KiwiXML lock`"), "{}", run.text);
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use common::Project;
+
+const ENGINES: [&str; 2] = ["regex", "parser"];
+
+/// `e` followed by a combining acute accent, as macOS tends to save it.
+const DECOMPOSED: &str = "e\u{301}";
+
+#[test]
+fn text_attributes_and_cdata_are_normalized_but_not_markup() {
+    for engine in ENGINES {
+        let project = Project::new(&format!("normalize-nfc-{}", engine));
+        let d = DECOMPOSED;
+        project
+            .write(
+                "A/0_T.xml",
+                format!(
+                    concat!(
+                        "<Caf{0} name=\"caf{0}\" alt='{0}'>\n",
+                        "  <!-- caf{0} -->\n",
+                        "  <Text>caf{0} &amp; th{0}</Text>\n",
+                        "  <Data><![CDATA[caf{0}]]></Data>\n",
+                        "  <!-- #include file=\"menu.xml\" -->\n",
+                        "</Caf{0}>\n",
+                    ),
+                    d
+                ),
+            )
+            .write(
+                "A/menu.xml",
+                format!("<placeholder>\n<Menu>cr{}me</Menu>\n</placeholder>\n", d),
+            );
+
+        let run = project.run(&["--engine", engine]);
+        assert!(run.success, "{}", run.text);
+        let output = project.read_text("compiled/0_T.xml");
+        assert_eq!(output.matches(d).count(), 9, "{}", output);
+
+        let run = project.run(&["--engine", engine, "--normalize", "nfc"]);
+        assert!(run.success, "{}", run.text);
+        assert_eq!(
+            project.read_text("compiled/0_T.xml"),
+            format!(
+                concat!(
+                    "<Caf{0} name=\"café\" alt='é'>\n",
+                    "  <!-- caf{0} -->\n",
+                    "  <Text>café &amp; thé</Text>\n",
+                    "  <Data><![CDATA[café]]></Data>\n",
+                    "  <![CDATA[\n<Menu>créme</Menu>\n]]>\n",
+                    "</Caf{0}>\n",
+                ),
+                d
+            ),
+            "--engine {}",
+            engine
+        );
+    }
+}
+
+#[test]
+fn nfkc_escapes_what_it_folds_into_markup() {
+    let project = Project::new("normalize-nfkc");
+    project.write(
+        "A/0_T.xml",
+        "<T a=\"\u{FF02}x\u{FF02}\" b='\u{FF07}'>\u{FB01}le \u{FF1C}1\u{FF1E} \u{FF06}</T>\n",
+    );
+    let run = project.run(&["--normalize", "nfkc"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_T.xml"),
+        "<T a=\"&quot;x&quot;\" b='&apos;'>file &lt;1> &amp;</T>\n"
+    );
+
+    // NFC leaves compatibility characters alone.
+    let run = project.run(&["--normalize", "nfc"]);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("compiled/0_T.xml").contains("\u{FB01}le \u{FF1C}1\u{FF1E}"));
+
+    let run = project.run(&["--normalize", "nfd"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("invalid value 'nfd' for '--normalize <FORM>'"), "{}", run.text);
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::Project;
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write("KFM/1_config.xml", "<Root>\n  <!-- #include file=\"Fire.xml\" -->\n</Root>\n")
+        .write("KFM/Fire.xml", "<placeholder>\n<Fire/>\n</placeholder>\n")
+        .write("SF/2_ice.xml", "<Root>\n  <Ice/>\n</Root>\n");
+    project
+}
+
+#[test]
+fn same_named_base_xmls_fail_flat_and_keep_their_folders_with_preserve_layout() {
+    let project = project("output-layout");
+    project.write("SF/1_config.xml", "<Root>\n  <Ice/>\n</Root>\n");
+    let run = project.run(&[]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("would both be compiled to"), "{}", run.text);
+    let run = project.run(&["--preserve-layout"]);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("compiled/KFM/1_config.xml").contains("<Fire/>"));
+    assert!(project.read_text("compiled/SF/1_config.xml").contains("<Ice/>"));
+}
+
+#[test]
+fn a_project_can_move_to_another_output_dir() {
+    let project = project("output-move");
+    assert!(project.run(&["--preserve-layout"]).success);
+    for args in [
+        &["--out-dir", "o", "--preserve-layout"][..],
+        &["--output-dir", "flat"],
+        &["--preserve-layout", "--force"],
+    ] {
+        let run = project.run(args);
+        assert!(run.success, "{:?}: {}", args, run.text);
+        assert!(!run.text.contains("skipped"), "{:?}: {}", args, run.text);
+    }
+    assert_eq!(project.read("o/KFM/1_config.xml"), project.read("compiled/KFM/1_config.xml"));
+    assert_eq!(project.read("flat/2_ice.xml"), project.read("compiled/SF/2_ice.xml"));
+}
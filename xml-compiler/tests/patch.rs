@@ -0,0 +1,163 @@
+mod common;
+
+use common::Project;
+
+const SOURCE: &str = concat!(
+    "<Root>\n",
+    "  <Skills>\n",
+    "    <Skill Name=\"Fire\" Cooldown=\"3\"/>\n",
+    "    <Skill Name=\"Ice\" Cooldown=\"4\">\n",
+    "      <Effect/>\n",
+    "    </Skill>\n",
+    "    <Skill Name=\"Debug\"/>\n",
+    "  </Skills>\n",
+    "  <Empty/>\n",
+    "</Root>\n",
+);
+
+/// A project with `SOURCE` as its one target and `ops` as the target's patch in `P`.
+fn patched(name: &str, ops: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write("A/0_T.xml", SOURCE)
+        .write("P/A/0_T.xml", format!("<diff>\n{}</diff>\n", ops));
+    project
+}
+
+#[test]
+fn operations_apply_in_order_and_fit_the_indentation() {
+    let project = patched(
+        "patch-operations",
+        concat!(
+            "  <replace sel=\"/Root/Skills/Skill[@Name='Fire']/@Cooldown\">5 \"s\"</replace>\n",
+            "  <remove sel=\"/Root/Skills/Skill[@Name='Debug']\"/>\n",
+            "  <remove sel=\"/Root/Skills/Skill[2]/@Cooldown\"/>\n",
+            "  <add sel=\"/Root/Skills\" pos=\"append\">\n",
+            "        <Skill Name=\"Wind\">\n",
+            "          <Effect/>\n",
+            "        </Skill>\n",
+            "  </add>\n",
+            "  <add sel=\"/Root/Skills\" pos=\"prepend\"><Skill Name=\"First\"/></add>\n",
+            "  <add sel=\"/Root/Skills/Skill[@Name='Ice']\" pos=\"before\"><Before/></add>\n",
+            "  <add sel=\"/Root/Skills/Skill[@Name='Ice']\" pos=\"after\"><After/></add>\n",
+            "  <add sel=\"/Root/Empty\"><Child/></add>\n",
+            "  <add sel=\"/Root/Skills/Skill[@Cooldown]\" type=\"@Level\">2</add>\n",
+            "  <replace sel=\"/Root/*[1]/Skill[@Name='Ice']/Effect\">\n",
+            "    <Effect Kind=\"cold\">\n",
+            "      <Slow/>\n",
+            "    </Effect>\n",
+            "  </replace>\n",
+        ),
+    );
+    let run = project.run(&["--patches", "P"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_T.xml"),
+        concat!(
+            "<Root>\n",
+            "  <Skills>\n",
+            "    <Skill Name=\"First\"/>\n",
+            "    <Skill Name=\"Fire\" Cooldown=\"5 &quot;s&quot;\" Level=\"2\"/>\n",
+            "    <Before/>\n",
+            "    <Skill Name=\"Ice\">\n",
+            "      <Effect Kind=\"cold\">\n",
+            "        <Slow/>\n",
+            "      </Effect>\n",
+            "    </Skill>\n",
+            "    <After/>\n",
+            "    <Skill Name=\"Wind\">\n",
+            "      <Effect/>\n",
+            "    </Skill>\n",
+            "  </Skills>\n",
+            "  <Empty>\n",
+            "    <Child/>\n",
+            "  </Empty>\n",
+            "</Root>\n",
+        )
+    );
+}
+
+#[test]
+fn elements_inline_with_others_are_patched_in_place() {
+    let project = Project::new("patch-inline");
+    project
+        .write("A/0_T.xml", "<Root><A/><B>b</B></Root>\n")
+        .write(
+            "P/A/0_T.xml",
+            concat!(
+                "<diff>\n",
+                "  <add sel=\"/Root/A\" pos=\"after\">\n    <New/>\n  </add>\n",
+                "  <add sel=\"/Root/B\" pos=\"prepend\"><First/></add>\n",
+                "  <remove sel=\"/Root/A\"/>\n",
+                "</diff>\n",
+            ),
+        );
+    let run = project.run(&["--patches", "P"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(project.read_text("compiled/0_T.xml"), "<Root><New/><B><First/>b</B></Root>\n");
+}
+
+#[test]
+fn a_selector_must_pick_exactly_one_node() {
+    let cases = [
+        ("<remove sel=\"/Root/Skills/Skill[@Name='None']\"/>", "matches nothing"),
+        ("<remove sel=\"/Root/Skills/Skill[4]\"/>", "matches nothing"),
+        ("<remove sel=\"/Root/Skills/Skill\"/>", "sel=\"/Root/Skills/Skill\" matches 3 elements"),
+        ("<remove sel=\"/Root/*/*[@Cooldown]\"/>", "matches 2 elements"),
+        ("<remove sel=\"/Root/Empty/@Gone\"/>", "the element has no attribute Gone"),
+        ("<remove sel=\"Root/Empty\"/>", "sel=\"Root/Empty\" is not an absolute path"),
+        ("<remove/>", "<remove> has no sel"),
+    ];
+    for (op, error) in cases {
+        let ops = format!("  <add sel=\"/Root/Empty\" type=\"@Seen\">1</add>\n  {}\n", op);
+        let project = patched("patch-selectors", &ops);
+        let run = project.run(&["--patches", "P"]);
+        assert!(!run.success, "{}: {}", op, run.text);
+        assert!(run.text.contains("P/A/0_T.xml:3: patch "), "{}: {}", op, run.text);
+        assert!(run.text.contains(error), "{}: {}", op, run.text);
+        assert!(!project.exists("compiled/0_T.xml"), "{}", op);
+    }
+}
+
+#[test]
+fn operations_that_make_no_sense_fail_the_target() {
+    let cases = [
+        ("<add sel=\"/Root/Empty\" pos=\"inside\"><X/></add>", "pos=\"inside\" is not before"),
+        ("<add sel=\"/Root/Skills/Skill[1]\" type=\"@Name\">x</add>", "already has attribute Name"),
+        ("<add sel=\"/Root/Empty\" type=\"Name\">x</add>", "type=\"Name\" is not an attribute"),
+        ("<add sel=\"/Root/Skills/Skill[1]/@Name\"><X/></add>", "can only add next to or inside"),
+        ("<move sel=\"/Root/Empty\"/>", "<move> is not add, replace or remove"),
+    ];
+    for (op, error) in cases {
+        let project = patched("patch-operations-invalid", &format!("  {}\n", op));
+        let run = project.run(&["--patches", "P"]);
+        assert!(!run.success, "{}: {}", op, run.text);
+        assert!(run.text.contains("P/A/0_T.xml:2: patch "), "{}: {}", op, run.text);
+        assert!(run.text.contains(error), "{}: {}", op, run.text);
+    }
+}
+
+#[test]
+fn targets_without_a_patch_compile_as_they_are() {
+    let project = patched("patch-missing", "  <remove sel=\"/Root/Empty\"/>\n");
+    project.write("B/0_U.xml", SOURCE);
+    let run = project.run(&["--patches", "P"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!project.read_text("compiled/0_T.xml").contains("<Empty/>"));
+    assert_eq!(project.read_text("compiled/0_U.xml"), SOURCE);
+    // Patch files are never targets of their own.
+    assert!(!run.text.contains("P/A/0_T.xml"), "{}", run.text);
+}
+
+#[test]
+fn a_changed_or_new_patch_rebuilds_its_target() {
+    let project = patched("patch-cache", "  <remove sel=\"/Root/Empty\"/>\n");
+    project.write("B/0_U.xml", SOURCE);
+    assert!(project.run(&["--patches", "P"]).success);
+    project.write("P/A/0_T.xml", "<diff>\n  <remove sel=\"/Root/Skills\"/>\n</diff>\n");
+    project.write("P/B/0_U.xml", "<diff>\n  <remove sel=\"/Root/Skills\"/>\n</diff>\n");
+    let run = project.run(&["--patches", "P"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(project.read_text("compiled/0_T.xml"), "<Root>\n  <Empty/>\n</Root>\n");
+    assert_eq!(project.read_text("compiled/0_U.xml"), "<Root>\n  <Empty/>\n</Root>\n");
+}
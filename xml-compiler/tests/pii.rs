@@ -0,0 +1,89 @@
+mod common;
+
+use common::Project;
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write(
+            "A/0_W.xml",
+            concat!(
+                "<Config>\n",
+                "  <!-- #include file=\"Test Data.xml\" -->\n",
+                "  <Owner>dev@kiwi-games.com</Owner>\n",
+                "</Config>\n",
+            ),
+        )
+        .write(
+            "A/Test Data.xml",
+            concat!(
+                "<placeholder>\n",
+                "<Pay iban=\"DE89 3704 0044 0532 0130 00\" other=\"DE89370400440532013001\"/>\n",
+                "<Card>4111 1111 1111 1111</Card><Card>4111 1111 1111 1112</Card>\n",
+                "<Mail>jane.doe@example.com</Mail>\n",
+                "</placeholder>\n",
+            ),
+        );
+    project
+}
+
+#[test]
+fn matches_are_masked_warnings_naming_their_source() {
+    let project = project("pii-scan");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("warning"), "{}", run.text);
+
+    let run = project.run(&["--scan-pii"]);
+    assert!(run.success, "{}", run.text);
+    let fragment = project.path("A/Test Data.xml");
+    for warning in [
+        format!("0_W.xml:3: possible email address j***@example.com, from {}", fragment.display()),
+        format!("0_W.xml:3: possible IBAN DE89****3000, from {}", fragment.display()),
+        format!("0_W.xml:3: possible card number ****1111, from {}", fragment.display()),
+        "0_W.xml:5: possible email address d***@kiwi-games.com, from ".to_string(),
+    ] {
+        assert!(run.text.contains(&warning), "{}: {}", warning, run.text);
+    }
+    // A bad checksum or Luhn digit is not a finding.
+    assert_eq!(run.text.matches("possible IBAN").count(), 1, "{}", run.text);
+    assert_eq!(run.text.matches("possible card number").count(), 1, "{}", run.text);
+    assert!(!run.text.contains("jane.doe") && !run.text.contains("4111 1111"), "{}", run.text);
+}
+
+#[test]
+fn fail_on_pii_keeps_the_previous_output() {
+    let project = project("pii-fail");
+    project.write("compiled/0_W.xml", "previous");
+    let run = project.run(&["--fail-on-pii"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(
+        run.text.contains("error: 4 possible personal data match(es), see --fail-on-pii"),
+        "{}",
+        run.text
+    );
+    assert_eq!(project.read_text("compiled/0_W.xml"), "previous");
+
+    project.write(
+        "A/Test Data.xml",
+        "<placeholder>\n<Mail>support@kiwi-games.com</Mail>\n</placeholder>\n",
+    );
+    let run = project.run(&["--fail-on-pii", "--pii-allow", "@kiwi-games\\.com$"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("possible"), "{}", run.text);
+    assert!(project.read_text("compiled/0_W.xml").contains("support@kiwi-games.com"));
+}
+
+#[test]
+fn allowed_matches_are_not_reported() {
+    let project = project("pii-allow");
+    let allow = ["--pii-allow", "@kiwi-games\\.com$", "--pii-allow", "^DE"];
+    let run = project.run(&[&["--scan-pii"][..], &allow].concat());
+    assert!(run.success, "{}", run.text);
+    assert_eq!(run.text.matches("possible ").count(), 2, "{}", run.text);
+    assert!(!run.text.contains("kiwi-games") && !run.text.contains("IBAN"), "{}", run.text);
+
+    let run = project.run(&["--pii-allow", "("]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("invalid value '(' for '--pii-allow <REGEX>'"), "{}", run.text);
+}
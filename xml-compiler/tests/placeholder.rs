@@ -0,0 +1,162 @@
+mod common;
+
+use common::Project;
+
+/// A target including each of `fragments`, written with their content.
+fn project(name: &str, fragments: &[(&str, &str)]) -> Project {
+    let project = Project::new(name);
+    let includes: String = fragments
+        .iter()
+        .map(|(file, _)| format!("  <!-- #include file=\"{}\" -->\n", file))
+        .collect();
+    project.write("A/0_T.xml", format!("<T>\n{}</T>\n", includes));
+    for (file, content) in fragments {
+        project.write(&format!("A/{}", file), content);
+    }
+    project
+}
+
+#[test]
+fn fragments_with_a_language_keep_their_lines() {
+    let script = "    // kept on its own line\n    var skills = [113210, 113670];\n";
+    let project = project(
+        "placeholder-lines",
+        &[
+            ("script.js", &format!("<placeholder lang=\"js\">\n{}</placeholder>\n", script)),
+            ("plain.xml", "<placeholder>\n  <A/>\n  <B/>\n</placeholder>\n"),
+        ],
+    );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("warning"), "{}", run.text);
+    let output = project.read_text("compiled/0_T.xml");
+    assert!(output.contains(script), "{}", output);
+    assert!(output.contains("<![CDATA[\n <A/> <B/>\n]]>"), "{}", output);
+}
+
+#[test]
+fn problems_in_the_content_are_warnings_for_the_target() {
+    let project = project(
+        "placeholder-checks",
+        &[
+            ("a.js", "<placeholder type=\"js\">\nf(1, [2);\n// ] ignored\n</placeholder>\n"),
+            ("b.js", "<placeholder lang=\"js\">\nvar s = \"unclosed;\n</placeholder>\n"),
+            ("c.html", "<placeholder lang=\"html\">\n<div><p>x</p><br>\n</placeholder>\n"),
+            ("d.xml", "<placeholder lang=\"cobol\">\nMOVE A TO B.\n</placeholder>\n"),
+        ],
+    );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    for warning in [
+        "a.js (js): ')' at byte 8 does not match '[' at byte 6",
+        "a.js (js): unclosed '(' at byte 2",
+        "b.js (js): unterminated \" string starting at byte 9",
+        "c.html (html): <div> is never closed",
+    ] {
+        assert!(run.text.contains(warning), "{}: {}", warning, run.text);
+    }
+    assert!(run.text.contains("Compiled A/0_T.xml (4 warnings)"), "{}", run.text);
+    assert!(!run.text.contains("cobol"), "{}", run.text);
+    let log = project.read_text("processing.log");
+    assert!(log.contains("unknown placeholder language 'cobol', not validated"), "{}", log);
+
+    let run = project.run(&["--on-warnings", "skip"]);
+    assert!(!run.success, "{}", run.text);
+}
+
+#[test]
+fn the_default_language_applies_to_undeclared_placeholders() {
+    let project = project(
+        "placeholder-default",
+        &[
+            ("a.xml", "<placeholder>\nvar a = (1;\n</placeholder>\n"),
+            ("b.xml", "<placeholder lang=\"html\">\n<p>(</p>\n</placeholder>\n"),
+        ],
+    );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("warning"), "{}", run.text);
+    let run = project.run(&["--placeholder-lang", "js"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("a.xml (js): unclosed '(' at byte 9"), "{}", run.text);
+    assert!(!run.text.contains("b.xml"), "{}", run.text);
+}
+
+#[test]
+fn a_premature_cdata_end_is_split_across_sections() {
+    let fragment = ("a.xml", "<placeholder>\nx ]]> y\n</placeholder>\n");
+    let project = project("placeholder-cdata-end", &[fragment]);
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    let output = project.read_text("compiled/0_T.xml");
+    assert!(output.contains("<![CDATA[\nx ]]]]><![CDATA[> y\n]]>"), "{}", output);
+    let log = project.read_text("processing.log");
+    assert!(log.contains("contains ']]>', splitting the CDATA section around it"), "{}", log);
+}
+
+#[test]
+fn json_content_must_parse() {
+    let project = project(
+        "placeholder-json",
+        &[
+            (
+                "good.json",
+                concat!(
+                    "<placeholder type=\"json\">\n",
+                    "<!-- #fragment version=\"1\" -->\n",
+                    "{\"a\": [1, 2], \"b\": \"x\"}\n",
+                    "</placeholder>\n",
+                ),
+            ),
+            ("bad.json", "<placeholder type=\"json\">\n{\"a\": 1,\n \"b\": }\n</placeholder>\n"),
+        ],
+    );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(
+        run.text.contains("bad.json (json): invalid JSON: expected value at line 3 column 7"),
+        "{}",
+        run.text
+    );
+    assert!(!run.text.contains("good.json"), "{}", run.text);
+}
+
+#[test]
+fn sql_content_is_lexed() {
+    let project = project(
+        "placeholder-sql",
+        &[
+            (
+                "good.sql",
+                concat!(
+                    "<placeholder lang=\"sql\">\n",
+                    "SELECT \"Quoted\"\" id\", [x] FROM t WHERE a = 'it''s' AND (b > $1)",
+                    " -- comment (\n",
+                    "/* block ' */;\n",
+                    "</placeholder>\n",
+                ),
+            ),
+            (
+                "open.sql",
+                "<placeholder lang=\"sql\">\nSELECT (a FROM t WHERE x = 'open;\n</placeholder>\n",
+            ),
+            ("ident.sql", "<placeholder lang=\"sql\">\nSELECT `a FROM t\n</placeholder>\n"),
+            ("comment.sql", "<placeholder lang=\"sql\">\nSELECT a) /* open\n</placeholder>\n"),
+            ("symbol.sql", "<placeholder lang=\"sql\">\nSELECT a { b\n</placeholder>\n"),
+        ],
+    );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    for warning in [
+        "open.sql (sql): unterminated string starting at byte 28",
+        "open.sql (sql): unclosed '(' at byte 8",
+        "ident.sql (sql): unterminated quoted identifier starting at byte 8",
+        "comment.sql (sql): unmatched ')' at byte 9",
+        "comment.sql (sql): unterminated block comment starting at byte 11",
+        "symbol.sql (sql): unexpected '{' at byte 10",
+    ] {
+        assert!(run.text.contains(warning), "{}: {}", warning, run.text);
+    }
+    assert!(!run.text.contains("good.sql"), "{}", run.text);
+    assert!(run.text.contains("Compiled A/0_T.xml (6 warnings)"), "{}", run.text);
+}
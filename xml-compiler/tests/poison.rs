@@ -0,0 +1,18 @@
+mod common;
+
+use common::Project;
+
+#[test]
+fn both_engines_poison_fragments_over_the_pass_timeout() {
+    for engine in ["regex", "parser"] {
+        let project = Project::new(&format!("poison-{}", engine));
+        project
+            .write("A/0_T.xml", "<Root>\n  <!-- #include file=\"Big.xml\" -->\n</Root>\n")
+            .write("A/Big.xml", "<placeholder>\n<Item   a=\"1\"/>\n<Item/>\n</placeholder>\n");
+        // A nanosecond is over any pass.
+        let run = project.run(&["--engine", engine, "--pass-timeout", "0.000000001"]);
+        assert!(run.text.contains("Poisoned files:"), "--engine {}:\n{}", engine, run.text);
+        assert!(run.text.contains("A/Big.xml: "), "--engine {}:\n{}", engine, run.text);
+        assert!(run.text.contains("over --pass-timeout"), "--engine {}:\n{}", engine, run.text);
+    }
+}
@@ -0,0 +1,161 @@
+mod common;
+
+use common::Project;
+
+const XSD: &str = r#"<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">
+  <xs:element name="Skill">
+    <xs:complexType>
+      <xs:sequence>
+        <xs:element name="condition" maxOccurs="unbounded">
+          <xs:complexType>
+            <xs:attribute name="hk" use="required">
+              <xs:simpleType>
+                <xs:restriction base="xs:string">
+                  <xs:enumeration value="special"/>
+                  <xs:enumeration value="off-cd"/>
+                </xs:restriction>
+              </xs:simpleType>
+            </xs:attribute>
+          </xs:complexType>
+        </xs:element>
+        <xs:element name="mode" type="Mode"/>
+      </xs:sequence>
+    </xs:complexType>
+  </xs:element>
+  <xs:element name="Effect">
+    <xs:complexType>
+      <xs:attribute name="kind" type="Mode"/>
+    </xs:complexType>
+  </xs:element>
+  <xs:simpleType name="Mode">
+    <xs:restriction base="xs:string">
+      <xs:enumeration value="fast"/>
+      <xs:enumeration value="slow"/>
+    </xs:restriction>
+  </xs:simpleType>
+</xs:schema>
+"#;
+
+const GOOD: &str = "<Skill>\n  <condition hk=\"off-cd\"/>\n  <mode>fast</mode>\n</Skill>\n";
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project.write("Schemas/Skill.xsd", XSD).write("A/0_Good.xml", GOOD);
+    project
+}
+
+#[test]
+fn violations_are_warnings_with_their_output_line() {
+    let project = project("schema-violations");
+    project.write(
+        "A/0_Bad.xml",
+        concat!(
+            "<Skill>\n",
+            "  <condition hk=\"special\"/>\n",
+            "  <condition hk=\"on-cd\" flag=\"1\"/>\n",
+            "  <condition/>\n",
+            "  <mode>medium</mode>\n",
+            "  <other/>\n",
+            "</Skill>\n",
+        ),
+    );
+    let run = project.run(&["--schema", "Schemas"]);
+    assert!(run.success, "{}", run.text);
+    for warning in [
+        "0_Bad.xml:3: schema: hk=\"on-cd\" on <condition> is not one of special, off-cd",
+        "0_Bad.xml:3: schema: attribute flag is not allowed on <condition>",
+        "0_Bad.xml:4: schema: <condition> is missing attribute hk",
+        "0_Bad.xml:5: schema: <mode> contains \"medium\", not one of fast, slow",
+        "0_Bad.xml:6: schema: <other> is not allowed in <Skill>",
+    ] {
+        assert!(run.text.contains(warning), "{}: {}", warning, run.text);
+    }
+    assert!(!run.text.contains("0_Good.xml:"), "{}", run.text);
+    assert!(project.exists("compiled/0_Bad.xml"));
+
+    project.write("compiled/0_Bad.xml", "stale");
+    let run = project.run(&["--schema", "Schemas/Skill.xsd", "--on-warnings", "skip"]);
+    assert!(!run.success, "{}", run.text);
+    assert_eq!(project.read_text("compiled/0_Bad.xml"), "stale");
+    assert_eq!(project.read_text("compiled/0_Good.xml"), GOOD);
+}
+
+#[test]
+fn only_the_first_violations_of_an_output_are_listed() {
+    let project = project("schema-many");
+    project.write("A/0_Many.xml", format!("<Skill>\n{}</Skill>\n", "  <x/>\n".repeat(25)));
+    let run = project.run(&["--schema", "Schemas"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(run.text.matches("<x> is not allowed in <Skill>").count(), 20, "{}", run.text);
+    assert!(run.text.contains("0_Many.xml: schema: 5 more violations"), "{}", run.text);
+}
+
+#[test]
+fn roots_no_schema_declares_are_left_alone_unless_mapped() {
+    let project = project("schema-for");
+    project.write("A/0_Other.xml", "<Other><x/></Other>\n").write("B/0_Stray.xml", "<Other/>\n");
+    let run = project.run(&["--schema", "Schemas"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("warning"), "{}", run.text);
+
+    let run = project.run(&["--force", "--schema-for", "^A/=Schemas/Skill.xsd"]);
+    assert!(run.success, "{}", run.text);
+    assert!(
+        run.text.contains("0_Other.xml:1: schema: <Other> is not declared in the schema"),
+        "{}",
+        run.text
+    );
+    assert_eq!(run.text.matches("is not declared").count(), 1, "{}", run.text);
+}
+
+#[test]
+fn fragments_in_cdata_are_checked_from_their_global_elements() {
+    let project = project("schema-fragments");
+    project
+        .write("A/0_Frag.xml", "<Skill>\n  <!-- #include file=\"frag.xml\" -->\n</Skill>\n")
+        .write(
+            "A/frag.xml",
+            concat!(
+                "<placeholder>\n",
+                "<condition hk=\"local elements are not checked\"/>\n",
+                "<Effect kind=\"odd\"/>\n",
+                "</placeholder>\n",
+            ),
+        );
+    let run = project.run(&["--schema", "Schemas"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("schema: kind=\"odd\" on <Effect> is not one of fast, slow"));
+    assert_eq!(run.text.matches("schema:").count(), 1, "{}", run.text);
+
+    // With a source map the violation points at the fragment.
+    let run = project.run(&["--schema", "Schemas", "--source-map"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("frag.xml:1: schema: kind=\"odd\""), "{}", run.text);
+    assert!(run.text.contains("(at "), "{}", run.text);
+}
+
+#[test]
+fn a_changed_schema_checks_outputs_again() {
+    let project = project("schema-cache");
+    assert!(project.run(&["--schema", "Schemas"]).success);
+    project.write("Schemas/Skill.xsd", XSD.replace("\"fast\"", "\"quick\""));
+    let run = project.run(&["--schema", "Schemas"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("<mode> contains \"fast\", not one of quick, slow"), "{}", run.text);
+}
+
+#[test]
+fn unusable_schemas_stop_the_build() {
+    let project = project("schema-errors");
+    project.write("Empty/readme.txt", "");
+    for (args, error) in [
+        (["--schema", "Empty"], "No .xsd files in "),
+        (["--schema-for", "(=Schemas/Skill.xsd"], "Invalid --schema-for pattern ("),
+        (["--schema-for", "A=Schemas/Nope.xsd"], "Cannot read "),
+    ] {
+        let run = project.run(&args);
+        assert!(!run.success, "{:?}: {}", args, run.text);
+        assert!(run.text.contains(error), "{:?}: {}", args, run.text);
+        assert!(!project.exists("compiled/0_Good.xml"), "{:?}", args);
+    }
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use common::Project;
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write(
+            "A/0_A.xml",
+            concat!(
+                "<Config>\n",
+                "  <!-- #include file=\"skills.xml\" -->\n",
+                "  <Other name=\"Ice\"/>\n",
+                "</Config>\n",
+            ),
+        )
+        .write(
+            "A/skills.xml",
+            concat!(
+                "<placeholder>\n",
+                "<Skill id=\"3\" name=\"Ice\"> <Cost>5</Cost></Skill>\n",
+                "<Skill id=\"4\"><Cost>7</Cost></Skill>\n",
+                "</placeholder>\n",
+            ),
+        );
+    project
+}
+
+const FRAGMENT_LINE: &str = concat!(
+    "compiled/0_A.xml:3: <Skill id=\"3\" name=\"Ice\"> <Cost>5</Cost></Skill>",
+    "<Skill id=\"4\"><Cost>7</Cost></Skill>  (from A/skills.xml:2)",
+);
+
+const OTHER_LINE: &str = "compiled/0_A.xml:5: <Other name=\"Ice\"/>  (from A/0_A.xml:3)";
+
+#[test]
+fn hits_name_the_source_they_came_from() {
+    let project = project("search-regex");
+    project.write("compiled/0_A.xml", "<Stale name=\"Ice\"/>\n");
+    let run = project.run(&["search", "name=\"Ice\""]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        run.text,
+        format!("{}\n{}\n", FRAGMENT_LINE, OTHER_LINE)
+    );
+    // Outputs are compiled in memory; nothing on disk is read or written.
+    assert_eq!(project.read_text("compiled/0_A.xml"), "<Stale name=\"Ice\"/>\n");
+    assert_eq!(project.run(&["search", "Stale"]).text, "No matches.\n");
+}
+
+#[test]
+fn xpath_reaches_into_included_fragments() {
+    let project = project("search-xpath");
+    let run = project.run(&["search", "--xpath", "//Skill[@id='3']/Cost"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(run.text, format!("{}\n", FRAGMENT_LINE));
+
+    // A fragment's top-level elements sit where it was included.
+    let run = project.run(&["search", "--xpath", "/Config/*[@name]"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(run.text, format!("{}\n{}\n", FRAGMENT_LINE, OTHER_LINE));
+
+    let run = project.run(&["search", "--xpath", "/Config/Other"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(run.text, format!("{}\n", OTHER_LINE));
+    assert!(!project.exists("compiled"));
+}
+
+#[test]
+fn targets_that_fail_are_skipped_and_logged() {
+    let project = project("search-failed");
+    project.write(
+        "A/0_Loop.xml",
+        "<Config name=\"Ice\">\n  <!-- #include file=\"0_Loop.xml\" -->\n</Config>\n",
+    );
+    let run = project.run(&["search", "name=\"Ice\""]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("0_Loop"), "{}", run.text);
+    assert!(run.text.contains("(from A/0_A.xml:3)"), "{}", run.text);
+    let log = project.read_text("processing.log");
+    assert!(log.contains("Not searched ") && log.contains("include cycle: "), "{}", log);
+}
+
+#[test]
+fn hits_in_long_lines_are_cut_around_the_match() {
+    let project = Project::new("search-long");
+    project.write(
+        "A/0_C.xml",
+        format!("<Config>{}<Hit/>{}</Config>\n", "x".repeat(300), "y".repeat(300)),
+    );
+    let run = project.run(&["search", "Hit", "--excerpt-length", "20"]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(run.text, "compiled/0_C.xml:1: …xxxxxxxxx<Hit/>yyyyy…  (from A/0_C.xml:1)\n");
+}
+
+#[test]
+fn bad_queries_are_errors() {
+    let project = project("search-errors");
+    for (args, error) in [
+        (["search", "--xpath", "bad["], "Unsupported XPath: bad["),
+        (["search", "(", "."], "regex parse error"),
+    ] {
+        let run = project.run(&args);
+        assert!(!run.success, "{:?}: {}", args, run.text);
+        assert!(run.text.contains(error), "{:?}: {}", args, run.text);
+    }
+}
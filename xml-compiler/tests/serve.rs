@@ -0,0 +1,116 @@
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use common::{free_port, get, get_bytes, Project};
+
+#[test]
+fn serve_preview_expands_targets_on_request() {
+    let project = Project::new("serve-preview");
+    project
+        .write("A/0_T.xml", "<Root>\n  <!-- #include file=\"Fire.xml\" -->\n</Root>\n")
+        .write("A/Fire.xml", "<Fire>hot</Fire>\n");
+    let addr = format!("127.0.0.1:{}", free_port());
+    let _server = project.spawn(&["serve", "--preview", "--addr", &addr]);
+    assert!(get(&addr, "/", None).contains("href=\"/0_T.xml\""));
+    assert!(get(&addr, "/0_T.xml", None).contains("<Fire>hot</Fire>"));
+    project.write("A/Fire.xml", "<Fire>cold</Fire>\n");
+    assert!(get(&addr, "/0_T.xml", None).contains("<Fire>cold</Fire>"));
+    assert!(!project.exists("compiled"));
+}
+
+#[test]
+fn preview_only_goes_with_serve() {
+    let project = Project::new("serve-usage");
+    for args in [&["serve"][..], &["--preview"], &["check", "--preview"]] {
+        let run = project.run(args);
+        assert!(!run.success, "{}", run.text);
+        assert!(run.text.contains("serve --preview"), "{}", run.text);
+    }
+}
+
+#[test]
+fn serve_watch_streams_rebuilt_outputs() {
+    let project = Project::new("serve-events");
+    project
+        .write("A/0_T.xml", "<Root>\n  <!-- #include file=\"Fire.xml\" -->\n</Root>\n")
+        .write("A/Fire.xml", "<Fire>hot</Fire>\n");
+    let addr = format!("127.0.0.1:{}", free_port());
+    let _server = project.spawn(&["serve", "--preview", "--watch", "--addr", &addr]);
+    assert!(get(&addr, "/", None).contains("href=\"/0_T.xml\""));
+    let mut events = TcpStream::connect(&addr).unwrap();
+    write!(events, "GET /_events HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    events.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let mut received = String::new();
+    let mut buffer = [0; 1024];
+    // Edits are repeated until one lands after the watcher is up.
+    for attempt in 0..30 {
+        if received.contains("data: 0_T.xml") {
+            break;
+        }
+        project.write("A/Fire.xml", format!("<Fire>{}</Fire>\n", attempt));
+        if let Ok(read) = events.read(&mut buffer) {
+            received.push_str(&String::from_utf8_lossy(&buffer[..read]));
+        }
+    }
+    assert!(received.contains("Content-Type: text/event-stream"), "{}", received);
+    assert!(received.contains("event: changed\ndata: 0_T.xml\n\n"), "{}", received);
+    assert!(project.exists("compiled/0_T.xml"));
+}
+
+#[test]
+fn events_need_watch() {
+    let project = Project::new("serve-no-events");
+    project.write("A/0_T.xml", "<Root/>\n");
+    let addr = format!("127.0.0.1:{}", free_port());
+    let _server = project.spawn(&["serve", "--preview", "--addr", &addr]);
+    assert!(get(&addr, "/_events", None).starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn serve_preview_serves_what_the_build_writes() {
+    let project = Project::new("serve-rendered");
+    project
+        .write("header.txt", "Built from {source}\n")
+        .write("A/0_T.xml", "<Root>caf\u{e9}</Root>\n");
+    let args = ["--header", "header.txt", "--output-encoding", "iso-8859-1"];
+    let run = project.run(&args);
+    assert!(run.success, "{}", run.text);
+    let written = String::from_utf8_lossy(&project.read("compiled/0_T.xml")).into_owned();
+    let addr = format!("127.0.0.1:{}", free_port());
+    let _server = project.spawn(&[&["serve", "--preview", "--addr", &addr][..], &args].concat());
+    let response = String::from_utf8_lossy(&get_bytes(&addr, "/0_T.xml", None)).into_owned();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains("Content-Type: application/xml\r\n"), "{}", head);
+    assert!(body.contains("Built from"), "{}", body);
+    assert_eq!(body, written);
+}
+
+#[test]
+fn event_streams_are_capped_and_leave_requests_alone() {
+    let project = Project::new("serve-capped");
+    project.write("A/0_T.xml", "<Root/>\n");
+    let addr = format!("127.0.0.1:{}", free_port());
+    let _server = project.spawn(&["serve", "--preview", "--watch", "--addr", &addr]);
+    assert!(get(&addr, "/", None).starts_with("HTTP/1.1 200"));
+    let streams: Vec<TcpStream> = (0..64)
+        .map(|_| {
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            write!(stream, "GET /_events HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0];
+            while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                head.push(byte[0]);
+            }
+            assert!(head.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&head));
+            stream
+        })
+        .collect();
+    assert!(get(&addr, "/0_T.xml", None).starts_with("HTTP/1.1 200"));
+    assert!(get(&addr, "/_events", None).starts_with("HTTP/1.1 503"));
+    drop(streams);
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use std::{
+    fs,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use common::Project;
+
+#[test]
+fn a_target_expanded_again_keeps_its_trace() {
+    let project = Project::new("settle-trace");
+    let slow = "<a>  </a>\n".repeat(1 << 15);
+    project
+        .write(
+            "A/0_T.xml",
+            "<Root>\n  <!-- #include file=\"Edited.xml\" -->\n  \
+             <!-- #include file=\"Slow.xml\" -->\n</Root>\n",
+        )
+        .write("A/Edited.xml", "<Edited/>\n")
+        // Long enough to expand that the file above is rewritten meanwhile.
+        .write("A/Slow.xml", format!("<placeholder>{}</placeholder>\n", slow));
+    let (path, staged) = (project.path("A/Edited.xml"), project.path("Edited.xml"));
+    let done = AtomicBool::new(false);
+    let run = thread::scope(|scope| {
+        scope.spawn(|| {
+            for edit in 0.. {
+                if done.load(Ordering::Relaxed) {
+                    break;
+                }
+                // Renamed into place, so every read sees one whole version.
+                let _ = fs::write(&staged, format!("<Edited n=\"{}\"/>\n", edit));
+                let _ = fs::rename(&staged, &path);
+            }
+        });
+        let run = project.run(&["--trace-includes", "A/0_T.xml", "--on-warnings", "write"]);
+        done.store(true, Ordering::Relaxed);
+        run
+    });
+    let retried = run.text.split("Sources changed, expanding again").collect::<Vec<_>>();
+    assert!(retried.len() > 1, "{}", run.text);
+    assert!(retried[0].contains("Tracing "), "{}", run.text);
+    assert!(retried[1].contains("Include file=\"Edited.xml\""), "{}", run.text);
+}
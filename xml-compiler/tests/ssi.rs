@@ -0,0 +1,113 @@
+mod common;
+
+use common::Project;
+
+const SERVER: &str = r#"<Config>
+  <!-- #if flag="qa" -->
+  <Server>qa.internal</Server>
+  <!-- #elif flag="dev,local" -->
+  <Server>localhost</Server>
+  <!-- #if flag="!verbose" -->
+  <Quiet/>
+  <!-- #endif -->
+  <!-- #else -->
+  <Server>kiwi-games.com</Server>
+  <!-- #include file="Prod.xml" -->
+  <!-- #endif -->
+</Config>
+"#;
+
+#[test]
+fn the_first_branch_whose_flags_hold_is_kept() {
+    let project = Project::new("ssi-branches");
+    project.write("A/0_T.xml", SERVER);
+    for (args, output) in [
+        (&[][..], "<Server>kiwi-games.com</Server>\n  <!-- Include not found: "),
+        (&["--flag", "qa"], "<Server>qa.internal</Server>\n</Config>"),
+        (&["--flag", "local"], "<Server>localhost</Server>\n  <Quiet/>\n</Config>"),
+        (&["--flag", "dev,verbose"], "<Server>localhost</Server>\n</Config>"),
+        (&["--flag", "verbose", "--flag", "dev"], "<Server>localhost</Server>\n</Config>"),
+    ] {
+        let run = project.run(args);
+        assert!(run.success, "{:?}: {}", args, run.text);
+        let compiled = project.read_text("compiled/0_T.xml");
+        assert!(compiled.starts_with("<Config>\n  <Server>"), "{:?}: {}", args, compiled);
+        assert!(compiled.contains(output), "{:?}: {}", args, compiled);
+        assert!(!compiled.contains("#if") && !compiled.contains("#endif"), "{}", compiled);
+        // A dropped branch's include is never read.
+        assert_eq!(run.text.contains("Missing include"), args.is_empty(), "{}", run.text);
+    }
+}
+
+const INCLUDE: &str = "<!-- #include file=\"../Shared/server.xml\" -->";
+
+#[test]
+fn fragments_and_folders_are_evaluated_too() {
+    let project = Project::new("ssi-fragments");
+    project
+        .write("kvy-xmls.toml", "[folders.\"Staging\"]\nflags = [\"qa\"]\n")
+        .write("Live/0_Live.xml", format!("<Live>\n  {}\n</Live>\n", INCLUDE))
+        .write("Staging/0_Staging.xml", format!("<Staging>\n  {}\n</Staging>\n", INCLUDE))
+        .write(
+            "Shared/server.xml",
+            concat!(
+                "<placeholder>\n",
+                "<!-- #if flag=\"qa\" -->qa<!-- #else -->live<!-- #endif -->\n",
+                "</placeholder>\n",
+            ),
+        );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("compiled/0_Live.xml").contains("\nlive\n"));
+    assert!(project.read_text("compiled/0_Staging.xml").contains("\nqa\n"));
+}
+
+#[test]
+fn legacy_conditionals_keep_every_branch() {
+    let project = Project::new("ssi-legacy");
+    project.write(
+        "A/0_T.xml",
+        concat!(
+            "<Config>\n",
+            "  <!-- #if expr=\"$HTTPS\" -->\n",
+            "  <Secure/>\n",
+            "  <!-- #else -->\n",
+            "  <Plain/>\n",
+            "  <!-- #endif -->\n",
+            "  <!-- #if flag=\"qa\" -->\n",
+            "  <!-- #if expr=\"$QA\" --><Dropped/><!-- #endif -->\n",
+            "  <!-- #endif -->\n",
+            "</Config>\n",
+        ),
+    );
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_T.xml"),
+        "<Config>\n  <Secure/>\n  <Plain/>\n</Config>\n"
+    );
+    let log = project.read_text("processing.log");
+    assert!(log.contains("0_T.xml:2: SSI #if ignored"), "{}", log);
+}
+
+#[test]
+fn unbalanced_conditionals_fail_the_file_with_their_line() {
+    let cases = [
+        ("<C>\n<!-- #if flag=\"a\" -->\n<X/>\n</C>\n", 2, "has no matching #endif"),
+        ("<C>\n\n<!-- #endif -->\n</C>\n", 3, "has no matching #if"),
+        ("<C>\n<!-- #else -->\n</C>\n", 2, "has no matching #if"),
+        (
+            "<C>\n<!-- #if flag=\"a\" -->\n<!-- #elif expr=\"1\" -->\n<!-- #endif -->\n</C>\n",
+            3,
+            "needs a flag, like the #if it belongs to",
+        ),
+    ];
+    for (source, line, reason) in cases {
+        let error = format!("0_T.xml:{}: conditional directive {}", line, reason);
+        let project = Project::new("ssi-unbalanced");
+        project.write("A/0_T.xml", source);
+        let run = project.run(&[]);
+        assert!(!run.success, "{}: {}", source, run.text);
+        assert!(run.text.contains(&error), "{}: {}", source, run.text);
+    }
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use common::Project;
+
+/// `count` elements with pseudo-random values, so content only repeats where it is included
+/// twice.
+fn items(name: &str, count: usize, seed: &mut u64) -> String {
+    (0..count)
+        .map(|id| {
+            *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            format!("<{} id=\"{}\" v=\"{}\"/>\n", name, id, *seed >> 16)
+        })
+        .collect()
+}
+
+fn project(name: &str, shared: bool) -> Project {
+    let mut seed = 1;
+    let project = Project::new(name);
+    project
+        .write(
+            "A/shared.xml",
+            format!("<placeholder>\n{}</placeholder>\n", items("Item", 600, &mut seed)),
+        )
+        .write("A/0_Small.xml", "<S/>\n");
+    let include = if shared { "  <!-- #include file=\"shared.xml\" -->\n" } else { "" };
+    for target in ["One", "Two"] {
+        project.write(
+            &format!("A/0_{}.xml", target),
+            format!("<{0}>\n{1}{2}</{0}>\n", target, include, items("Own", 100, &mut seed)),
+        );
+    }
+    project
+}
+
+#[test]
+fn outputs_sharing_content_are_worth_a_solid_archive() {
+    let project = project("storage-shared", true);
+    assert!(project.run(&[]).success);
+    let run = project.run(&["storage"]);
+    assert!(run.success, "{}", run.text);
+    let lines: Vec<&str> = run.text.lines().collect();
+    assert!(lines[0].starts_with("output "), "{}", run.text);
+    assert!(lines[0].ends_with("ratio  entropy  shared"), "{}", run.text);
+    let rows: Vec<(&str, &str)> = lines[1..5]
+        .iter()
+        .map(|line| (line.split(' ').next().unwrap(), line.rsplit(' ').next().unwrap()))
+        .collect();
+    assert_eq!(rows[1], ("compiled/0_Small.xml", "0%"), "{}", run.text);
+    assert_eq!(rows[3].0, "total", "{}", run.text);
+    for row in [rows[0], rows[2]] {
+        let shared: u32 = row.1.trim_end_matches('%').parse().unwrap();
+        assert!(shared > 50, "{}", run.text);
+    }
+    assert!(run.text.contains("Recommended: solid compression ("), "{}", run.text);
+    let sizes: Vec<usize> = lines
+        .iter()
+        .find_map(|line| line.strip_prefix("Estimated archive size: "))
+        .unwrap()
+        .split(' ')
+        .filter_map(|word| word.parse().ok())
+        .collect();
+    assert!(sizes[1] < sizes[0], "{}", run.text);
+
+    let run = project.run(&["storage", "--max-duplication", "10"]);
+    assert!(!run.success, "{}", run.text);
+    let error = "of the output content repeats across outputs, over --max-duplication 10";
+    assert!(run.text.contains(error), "{}", run.text);
+    assert!(project.run(&["storage", "--max-duplication", "90"]).success);
+}
+
+#[test]
+fn distinct_outputs_are_compressed_one_by_one() {
+    let project = project("storage-distinct", false);
+    assert!(project.run(&[]).success);
+    let run = project.run(&["storage"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Cross-output duplication: 0 of "), "{}", run.text);
+    assert!(run.text.contains("Recommended: per-file compression (only 0% "), "{}", run.text);
+}
+
+#[test]
+fn storage_measures_what_is_compiled_without_compiling() {
+    let project = project("storage-nothing", true);
+    let run = project.run(&["storage"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("No compiled outputs in "), "{}", run.text);
+    assert!(!project.exists("compiled/0_One.xml"));
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use common::{free_port, get, Project};
+
+/// The status line the preview server at `addr` answers `GET /` with.
+fn status(addr: &str, token: Option<&str>) -> String {
+    get(addr, "/", token).lines().next().unwrap_or_default().to_string()
+}
+
+#[test]
+fn config_tokens_guard_the_preview_server() {
+    let project = Project::new("tokens-config");
+    project
+        .write("kvy-xmls.toml", "[tokens]\nread = [\"reader\"]\ncompile = [\"pipeline\"]\n")
+        .write("A/0_T.xml", "<Root/>\n");
+    let addr = format!("127.0.0.1:{}", free_port());
+    let _server = project.spawn(&["serve", "--preview", "--addr", &addr]);
+    assert!(status(&addr, None).contains("401"));
+    assert!(status(&addr, Some("wrong")).contains("401"));
+    assert!(status(&addr, Some("reader")).contains("200"));
+    assert!(status(&addr, Some("pipeline")).contains("200"));
+}
+
+#[test]
+fn unknown_scopes_in_the_config_are_errors() {
+    let project = Project::new("tokens-scope");
+    project.write("kvy-xmls.toml", "[tokens]\nadmin = [\"secret\"]\n");
+    let run = project.run(&["serve", "--preview", "--addr", "127.0.0.1:1"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("unknown scope tokens.admin"), "{}", run.text);
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use std::process::{Command, Output};
+
+use common::{Project, Run};
+
+const SERVER: &str = concat!(
+    "<Server env=\"@{ENVIRONMENT}\" literal=\"@@{NAME}\">\n",
+    "    <Host><!-- #value name=\"host\" --></Host>\n",
+    "    <!-- #include file=\"port.xml\" -->\n",
+    "</Server>\n",
+);
+
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write("A/0_T.xml", SERVER)
+        .write("A/port.xml", "<placeholder>\n<Port>@{port}</Port>\n</placeholder>\n")
+        .write("env/a.txt", "# comment\n\nhost=file.example.com\nport=80\nENVIRONMENT=a&b\n")
+        .write("env/b.txt", "port=81\n");
+    project
+}
+
+/// Runs KiwiXML in `project` with `vars` added to its environment.
+fn run_with_env(project: &Project, args: &[&str], vars: &[(&str, &str)]) -> Run {
+    let Output {
+        status,
+        stdout,
+        stderr,
+    } = Command::new(env!("CARGO_BIN_EXE_KiwiXML"))
+        .args(args)
+        .current_dir(&project.dir)
+        .env("RUST_BACKTRACE", "0")
+        .envs(vars.iter().copied())
+        .output()
+        .unwrap();
+    Run {
+        success: status.success(),
+        text: String::from_utf8_lossy(&stdout).into_owned() + &String::from_utf8_lossy(&stderr),
+    }
+}
+
+#[test]
+fn values_come_from_defines_files_and_the_environment_in_order() {
+    let project = project("vars-precedence");
+    let env = [("KIWI_host", "env.example.com"), ("KIWI_port", "99"), ("KIWI_ENVIRONMENT", "env")];
+    let run = run_with_env(
+        &project,
+        &[
+            "--defines",
+            "env/a.txt",
+            "--defines",
+            "env/b.txt",
+            "--env-prefix",
+            "KIWI_",
+            "--define",
+            "host=prod.example.com",
+        ],
+        &env,
+    );
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_T.xml"),
+        concat!(
+            "<Server env=\"a&amp;b\" literal=\"@{NAME}\">\n",
+            "    <Host>prod.example.com</Host>\n",
+            "    <![CDATA[\n<Port>81</Port>\n]]>\n",
+            "</Server>\n",
+        )
+    );
+
+    // The environment is only read with --env-prefix.
+    let defines = ["--define", "host=h", "--define", "ENVIRONMENT=e"];
+    let run = run_with_env(&project, &defines, &env);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("compiled/0_T.xml").contains("<Port>@{port}</Port>"));
+    let run = run_with_env(&project, &[&defines[..], &["--env-prefix", "KIWI_"]].concat(), &env);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("compiled/0_T.xml").contains("<Port>99</Port>"));
+}
+
+#[test]
+fn variables_without_a_value_are_left_as_written() {
+    let project = project("vars-undefined");
+    let run = project.run(&["--define", "port=1"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("0_T.xml:1: undefined variable ENVIRONMENT"), "{}", run.text);
+    assert!(run.text.contains("0_T.xml:2: undefined variable host"), "{}", run.text);
+    assert_eq!(run.text.matches("undefined variable").count(), 2, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_T.xml"),
+        SERVER.replace("@@{", "@{").replace(
+            "<!-- #include file=\"port.xml\" -->",
+            "<![CDATA[\n<Port>1</Port>\n]]>"
+        )
+    );
+
+    let run = project.run(&["--define", "port=1", "--fail-on-undefined-vars"]);
+    assert!(!run.success, "{}", run.text);
+    let error = "0_T.xml:1: variable ENVIRONMENT has no value, see --fail-on-undefined-vars";
+    assert!(run.text.contains(error), "{}", run.text);
+}
+
+#[test]
+fn a_changed_value_rebuilds() {
+    let project = project("vars-cache");
+    assert!(project.run(&["--defines", "env/a.txt"]).success);
+    project.write("env/a.txt", "host=other.example.com\nport=80\nENVIRONMENT=x\n");
+    let run = project.run(&["--defines", "env/a.txt"]);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("compiled/0_T.xml").contains("<Host>other.example.com</Host>"));
+}
+
+#[test]
+fn bad_defines_stop_the_build() {
+    let project = project("vars-errors");
+    project.write("env/bad.txt", "# fine\ngarbage\n");
+    for (args, error) in [
+        (["--defines", "env/nope.txt"], "Cannot read defines "),
+        (["--defines", "env/bad.txt"], "bad.txt:2: expected NAME=VALUE"),
+        (["--define", "novalue"], "invalid value 'novalue' for '--define <NAME=VALUE>'"),
+    ] {
+        let run = project.run(&args);
+        assert!(!run.success, "{:?}: {}", args, run.text);
+        assert!(run.text.contains(error), "{:?}: {}", args, run.text);
+    }
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use std::fs;
+
+use common::{run_in, Project, Run};
+
+/// A product in `P` using fragments from the library in `Shared` and a folder in `Other`, with
+/// its own copy of one library fragment.
+fn project(name: &str) -> Project {
+    let project = Project::new(name);
+    project
+        .write(
+            "P/A/0_T.xml",
+            concat!(
+                "<T>\n",
+                "  <!-- #include file=\"../../Shared/Wolf/Main.xml\" -->\n",
+                "  <!-- #include file=\"../../Other/x.xml\" -->\n",
+                "</T>\n",
+            ),
+        )
+        .write("P/Wolf/Human.xml", "<placeholder>\n<Project/>\n</placeholder>\n")
+        .write(
+            "Shared/Wolf/Main.xml",
+            "<placeholder>\n<!-- #include file=\"Human.xml\" -->\n</placeholder>\n",
+        )
+        .write("Shared/Wolf/Human.xml", "<placeholder>\n<Library/>\n</placeholder>\n")
+        .write("Other/x.xml", "<placeholder>\n<X/>\n</placeholder>\n");
+    project
+}
+
+fn run(project: &Project, args: &[&str]) -> Run {
+    run_in(&project.path("P"), &[args, &["--include-root", "../Shared"]].concat())
+}
+
+const OUTPUT: &str = "<T>\n  <![CDATA[\n<Project/>\n]]>\n  <![CDATA[\n<X/>\n]]>\n</T>\n";
+
+#[test]
+fn vendored_trees_build_without_the_library() {
+    let project = project("vendor-copy");
+    let run = self::run(&project, &["vendor"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Vendored 2 files into "), "{}", run.text);
+    assert_eq!(
+        project.read("P/vendor/Shared/Wolf/Main.xml"),
+        project.read("Shared/Wolf/Main.xml")
+    );
+    assert!(project.exists("P/vendor/Other/x.xml"));
+    assert!(!project.exists("P/vendor/Shared/Wolf/Human.xml"));
+    let manifest = project.read_text("P/vendor/vendor.txt");
+    assert!(manifest.ends_with("Other\t../Other\nShared\t../Shared\n"), "{}", manifest);
+    assert!(!project.exists("P/compiled"));
+
+    fs::remove_dir_all(project.path("Shared")).unwrap();
+    fs::remove_dir_all(project.path("Other")).unwrap();
+    let run = self::run(&project, &[]);
+    assert!(run.success, "{}", run.text);
+    // The vendored Main.xml still picks up the project's override of Human.xml.
+    assert_eq!(project.read_text("P/compiled/0_T.xml"), OUTPUT);
+}
+
+#[test]
+fn vendoring_again_refreshes_the_copies() {
+    let project = project("vendor-refresh");
+    assert!(run(&project, &["vendor"]).success);
+    project.write("Other/x.xml", "<placeholder>\n<Fresh/>\n</placeholder>\n");
+    let run = self::run(&project, &[]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(project.read_text("P/compiled/0_T.xml"), OUTPUT);
+
+    // Without the override, the library's Human.xml is needed and vendored too.
+    fs::remove_file(project.path("P/Wolf/Human.xml")).unwrap();
+    let run = self::run(&project, &["vendor"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Vendored 3 files into "), "{}", run.text);
+    assert!(project.read_text("P/vendor/Other/x.xml").contains("<Fresh/>"));
+    assert!(project.exists("P/vendor/Shared/Wolf/Human.xml"));
+
+    // Deleting vendor/ goes back to the live library.
+    fs::remove_dir_all(project.path("P/vendor")).unwrap();
+    project.write("Shared/Wolf/Human.xml", "<placeholder>\n<Live/>\n</placeholder>\n");
+    let run = self::run(&project, &[]);
+    assert!(run.success, "{}", run.text);
+    assert!(project.read_text("P/compiled/0_T.xml").contains("<Live/>"));
+}
+
+#[test]
+fn vendor_refuses_unresolved_includes_and_foreign_folders() {
+    let project = project("vendor-refuse");
+    project.write("P/A/0_U.xml", "<U>\n  <!-- #include file=\"Nope.xml\" -->\n</U>\n");
+    let run = self::run(&project, &["vendor"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("Missing include: "), "{}", run.text);
+    assert!(run.text.contains(", nothing vendored"), "{}", run.text);
+    assert!(!project.exists("P/vendor"));
+
+    fs::remove_file(project.path("P/A/0_U.xml")).unwrap();
+    project.write("P/vendor/mine.txt", "not ours");
+    let run = self::run(&project, &["vendor"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("was not written by `KiwiXML vendor`"), "{}", run.text);
+    assert_eq!(project.read_text("P/vendor/mine.txt"), "not ours");
+}
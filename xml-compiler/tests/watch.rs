@@ -0,0 +1,66 @@
+mod common;
+
+use std::{thread, time::Duration};
+
+use common::Project;
+
+/// Waits up to ten seconds for `done`.
+fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    (0..200).any(|_| {
+        thread::sleep(Duration::from_millis(50));
+        done()
+    })
+}
+
+#[test]
+fn editor_temp_files_dont_trigger_rebuilds() {
+    let project = Project::new("watch-temp");
+    project
+        .write("kvy-xmls.toml", "watch-debounce = 50\n")
+        .write("A/0_T.xml", "<Root>\n  <!-- #include file=\"Fire.xml\" -->\n</Root>\n")
+        .write("A/Fire.xml", "<Fire>hot</Fire>\n");
+    let _watch = project.spawn(&["watch"]);
+    assert!(wait_for(|| project.exists("compiled/0_T.xml")));
+    assert!(wait_for(|| project.read_text("processing.log").contains("Watching")));
+
+    project
+        .write("A/.Fire.xml.swp", "swap")
+        .write("A/Fire.xml~", "<Fire>old</Fire>\n")
+        .write("A/.#Fire.xml", "lock");
+    thread::sleep(Duration::from_millis(500));
+    assert!(!project.read_text("processing.log").contains("Changed:"));
+
+    project.write("A/Fire.xml", "<Fire>cold</Fire>\n");
+    assert!(wait_for(|| project.read_text("compiled/0_T.xml").contains("cold")));
+    let log = project.read_text("processing.log");
+    assert!(!log.contains(".swp") && !log.contains("Fire.xml~"), "{}", log);
+}
+
+#[test]
+fn rapid_saves_are_batched_and_unchanged_saves_ignored() {
+    let project = Project::new("watch-batch");
+    project
+        .write("A/0_T.xml", "<Root>\n  <!-- #include file=\"Fire.xml\" -->\n</Root>\n")
+        .write("A/Fire.xml", "<Fire>hot</Fire>\n");
+    let _watch = project.spawn(&["watch", "--watch-debounce", "300"]);
+    assert!(wait_for(|| project.exists("compiled/0_T.xml")));
+    assert!(wait_for(|| project.read_text("processing.log").contains("Watching")));
+
+    // A save-all that rewrites the file as it was.
+    for _ in 0..5 {
+        let base = project.read("A/0_T.xml");
+        project.write("A/Fire.xml", "<Fire>hot</Fire>\n").write("A/0_T.xml", base);
+    }
+    thread::sleep(Duration::from_millis(800));
+    assert!(!project.read_text("processing.log").contains("Changed:"));
+
+    for heat in ["warm", "cool", "cold"] {
+        project.write("A/Fire.xml", format!("<Fire>{}</Fire>\n", heat));
+        thread::sleep(Duration::from_millis(20));
+    }
+    let cold = wait_for(|| project.read_text("compiled/0_T.xml").contains("cold"));
+    assert!(cold, "{}", project.read_text("processing.log"));
+    thread::sleep(Duration::from_millis(800));
+    let log = project.read_text("processing.log");
+    assert_eq!(log.matches("Changed:").count(), 1, "{}", log);
+}
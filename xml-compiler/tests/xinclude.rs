@@ -0,0 +1,142 @@
+mod common;
+
+use common::Project;
+
+const XI: &str = "xmlns:xi=\"http://www.w3.org/2001/XInclude\"";
+
+#[test]
+fn documents_and_text_are_included_when_asked() {
+    let project = Project::new("xinclude-parse");
+    let base = format!(
+        concat!(
+            "<Root {}>\n",
+            "  <xi:include href=\"Parts/Skills.xml\"/>\n",
+            "  <Notes><xi:include href=\"Parts/Notes.txt\" parse=\"text\"/></Notes>\n",
+            "</Root>\n",
+        ),
+        XI
+    );
+    project
+        .write("A/0_T.xml", &base)
+        .write(
+            "A/Parts/Skills.xml",
+            concat!(
+                "<?xml version=\"1.0\"?>\n",
+                "<Skills xmlns:x=\"http://www.w3.org/2001/XInclude\">\n",
+                "  <x:include href=\"Fire.xml\" parse=\"xml\"/>\n",
+                "</Skills>\n",
+            ),
+        )
+        .write("A/Parts/Fire.xml", "<Fire/>\n")
+        .write("A/Parts/Notes.txt", "a < b & \"c\"\n");
+
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(project.read_text("compiled/0_T.xml"), base);
+
+    let run = project.run(&["--xinclude"]);
+    assert!(run.success, "{}", run.text);
+    let output = project.read_text("compiled/0_T.xml");
+    assert!(!output.contains("<?xml version"), "{}", output);
+    assert!(output.contains("<Skills xmlns:x="), "{}", output);
+    // Its own includes resolve from its folder, under the prefix it binds.
+    assert!(output.contains("  <Fire/>\n</Skills>"), "{}", output);
+    assert!(output.contains("<Notes>a &lt; b &amp; \"c\"\n</Notes>"), "{}", output);
+}
+
+#[test]
+fn a_fallback_stands_in_quietly_for_a_missing_document() {
+    let project = Project::new("xinclude-fallback");
+    project.write(
+        "A/0_T.xml",
+        format!(
+            concat!(
+                "<Root {}>\n",
+                "  <xi:include href=\"Optional.xml\">\n",
+                "    <xi:fallback><Default/></xi:fallback>\n",
+                "  </xi:include>\n",
+                "  <xi:include href=\"Nothing.xml\"><xi:fallback/></xi:include>\n",
+                "</Root>\n",
+            ),
+            XI
+        ),
+    );
+    let run = project.run(&["--xinclude"]);
+    assert!(run.success, "{}", run.text);
+    assert!(!run.text.contains("warning"), "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_T.xml"),
+        format!("<Root {}>\n  <Default/>\n  \n</Root>\n", XI)
+    );
+
+    // The document turning up later is picked up.
+    project.write("A/Optional.xml", "<Optional/>\n");
+    let run = project.run(&["--xinclude"]);
+    assert!(run.success, "{}", run.text);
+    let output = project.read_text("compiled/0_T.xml");
+    assert!(output.contains("<Optional/>") && !output.contains("<Default/>"), "{}", output);
+}
+
+#[test]
+fn a_missing_document_without_a_fallback_is_a_missing_include() {
+    let project = Project::new("xinclude-missing");
+    project.write(
+        "A/0_T.xml",
+        format!("<Root {}>\n  <xi:include href=\"Gone.xml\"/>\n</Root>\n", XI),
+    );
+    let run = project.run(&["--xinclude"]);
+    assert!(run.success, "{}", run.text);
+    assert!(run.text.contains("Missing include: "), "{}", run.text);
+    assert!(project.read_text("compiled/0_T.xml").contains("<!-- Include not found: "));
+    let run = project.run(&["--xinclude", "--strict"]);
+    assert!(!run.success, "{}", run.text);
+}
+
+#[test]
+fn unsupported_includes_fail_with_their_line() {
+    let cases = [
+        (
+            "<xi:include href=\"Part.xml\" xpointer=\"id(a)\"/>",
+            "xi:include with xpointer is not supported",
+        ),
+        ("<xi:include/>", "xi:include needs an href"),
+        ("<xi:include href=\"\"/>", "xi:include needs an href"),
+        ("<xi:include href=\"Part.xml\" parse=\"json\"/>", "xi:include parse must be xml or text"),
+    ];
+    for (element, error) in cases {
+        let project = Project::new("xinclude-unsupported");
+        project
+            .write("A/0_T.xml", format!("<Root {}>\n\n  {}\n</Root>\n", XI, element))
+            .write("A/Part.xml", "<Part/>\n");
+        let run = project.run(&["--xinclude"]);
+        assert!(!run.success, "{}: {}", element, run.text);
+        assert!(run.text.contains(&format!("0_T.xml:3: {}", error)), "{}: {}", element, run.text);
+        assert!(!run.text.contains("--inline-includes"), "{}: {}", element, run.text);
+    }
+}
+
+#[test]
+fn cycles_through_xinclude_fail_the_target() {
+    let project = Project::new("xinclude-cycle");
+    project
+        .write("A/0_T.xml", format!("<Root {}>\n  <xi:include href=\"Loop.xml\"/>\n</Root>\n", XI))
+        .write("A/Loop.xml", format!("<Loop {}><xi:include href=\"Loop.xml\"/></Loop>\n", XI));
+    let run = project.run(&["--xinclude"]);
+    assert!(!run.success, "{}", run.text);
+    assert!(run.text.contains("include cycle: "), "{}", run.text);
+}
+
+#[test]
+fn the_configuration_file_can_turn_xinclude_on() {
+    let project = Project::new("xinclude-config");
+    project
+        .write("kvy-xmls.toml", "xinclude = true\n")
+        .write("A/0_T.xml", format!("<Root {}>\n  <xi:include href=\"Part.xml\"/>\n</Root>\n", XI))
+        .write("A/Part.xml", "<Part/>\n");
+    let run = project.run(&[]);
+    assert!(run.success, "{}", run.text);
+    assert_eq!(
+        project.read_text("compiled/0_T.xml"),
+        format!("<Root {}>\n  <Part/>\n</Root>\n", XI)
+    );
+}